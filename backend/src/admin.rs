@@ -0,0 +1,268 @@
+//! Lets admins manage users through the API, the same way the CLI does.
+//!
+//! Everything here is under `/api/admin`, which is guarded by
+//! [`RequireAdmin`](crate::admin_middleware::RequireAdmin). Admins can't
+//! delete themselves or change their own type, so the server can't be left
+//! without an admin by accident.
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post, put,
+    web::{self, ReqData},
+    HttpResponse, HttpResponseBuilder,
+};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, QueryOrder,
+    Set,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{
+        add_new_user, create_user_folder, delete_user, delete_user_tokens, set_password,
+        validate_username, Password, USER_NOBODY,
+    },
+    entity::user,
+    group::group_exists,
+    state::{AppState, Authorized, UserType},
+    storage::empty_ok_response,
+};
+
+#[cfg(feature = "generate_types")]
+use typescript_type_def::TypeDef;
+
+#[derive(Debug, derive_more::Display, thiserror::Error)]
+pub enum AdminError {
+    #[display(fmt = "User {} does not exist", _0)]
+    NotFound(String),
+    #[display(fmt = "The name {} is already used by a user or group", _0)]
+    NameTaken(String),
+    #[display(
+        fmt = "You can't use {} as a username. Try to avoid special characters.",
+        _0
+    )]
+    BadUsername(String),
+    #[display(fmt = "Admins can't delete themselves or change their own type")]
+    OwnAccount,
+    #[display(fmt = "Passwords are managed by the directory server, change it there")]
+    ExternalPassword,
+    #[display(fmt = "Database error {}", _0)]
+    Database(#[from] DbErr),
+    #[display(fmt = "{}", _0)]
+    Failed(String),
+}
+
+impl Serialize for AdminError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let s = format!("{}", self);
+        serializer.serialize_str(&s)
+    }
+}
+
+impl From<anyhow::Error> for AdminError {
+    fn from(err: anyhow::Error) -> Self {
+        AdminError::Failed(err.to_string())
+    }
+}
+
+impl actix_web::error::ResponseError for AdminError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AdminError::NotFound(_) => StatusCode::NOT_FOUND,
+            AdminError::NameTaken(_) => StatusCode::CONFLICT,
+            AdminError::BadUsername(_) => StatusCode::BAD_REQUEST,
+            AdminError::OwnAccount => StatusCode::BAD_REQUEST,
+            AdminError::ExternalPassword => StatusCode::CONFLICT,
+            AdminError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AdminError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponseBuilder::new(self.status_code()).json(self)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct AdminUser {
+    pub username: String,
+    pub user_type: UserType,
+    /// How many bytes the user can store, if there is a limit.
+    pub quota: Option<u64>,
+    /// How many bytes the files of the user take up, if it is known.
+    pub usage: Option<u64>,
+}
+
+impl From<user::Model> for AdminUser {
+    fn from(user: user::Model) -> Self {
+        AdminUser {
+            user_type: UserType::from_db_value(&user.user_type),
+            username: user.username,
+            quota: user.quota.map(|quota| quota as u64),
+            usage: user.usage.map(|usage| usage as u64),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct AdminUsers {
+    pub users: Vec<AdminUser>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct NewUser {
+    pub username: String,
+    pub password: Password,
+    #[serde(default)]
+    pub user_type: UserType,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct PasswordReset {
+    pub password: Password,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct UserTypeChange {
+    pub user_type: UserType,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DeleteUserQuery {
+    /// Delete the store of the user too.
+    #[serde(default)]
+    pub delete_files: bool,
+}
+
+/// True if the user exists and is an admin.
+pub async fn is_admin(db: &DatabaseConnection, username: &str) -> Result<bool, DbErr> {
+    Ok(user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(db)
+        .await?
+        .is_some_and(|user| UserType::from_db_value(&user.user_type) == UserType::Admin))
+}
+
+async fn find_user(state: &AppState, username: &str) -> Result<user::Model, AdminError> {
+    user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        // The nobody user is only there to resist user probing
+        .filter(user::Column::Username.ne(USER_NOBODY))
+        .one(&state.db)
+        .await?
+        .ok_or_else(|| AdminError::NotFound(username.to_string()))
+}
+
+/// Fails if the admin is trying to change their own account.
+fn check_not_self(
+    authorized: &Option<ReqData<Authorized>>,
+    username: &str,
+) -> Result<(), AdminError> {
+    let is_self = authorized
+        .as_deref()
+        .and_then(|authorized| authorized.admin_username())
+        .is_some_and(|user| user.0 == username);
+    if is_self {
+        return Err(AdminError::OwnAccount);
+    }
+    Ok(())
+}
+
+#[tracing::instrument(skip(state))]
+#[get("/users")]
+pub async fn get_users(state: web::Data<AppState>) -> Result<web::Json<AdminUsers>, AdminError> {
+    let users = user::Entity::find()
+        .filter(user::Column::Username.ne(USER_NOBODY))
+        .order_by_asc(user::Column::Username)
+        .all(&state.db)
+        .await?
+        .into_iter()
+        .map(AdminUser::from)
+        .collect();
+    Ok(web::Json(AdminUsers { users }))
+}
+
+#[tracing::instrument(skip(state, new_user))]
+#[post("/users")]
+pub async fn post_user(
+    state: web::Data<AppState>,
+    new_user: web::Json<NewUser>,
+) -> Result<web::Json<AdminUser>, AdminError> {
+    let new_user = new_user.into_inner();
+    validate_username(&new_user.username)
+        .map_err(|_| AdminError::BadUsername(new_user.username.clone()))?;
+    let user_exists = user::Entity::find()
+        .filter(user::Column::Username.eq(&new_user.username))
+        .one(&state.db)
+        .await?
+        .is_some();
+    if user_exists || group_exists(&state.db, &new_user.username).await? {
+        return Err(AdminError::NameTaken(new_user.username));
+    }
+    add_new_user(
+        &new_user.username,
+        &new_user.password.0,
+        new_user.user_type,
+        &state,
+    )
+    .await?;
+    create_user_folder(&state, &new_user.username).await?;
+    Ok(web::Json(
+        find_user(&state, &new_user.username).await?.into(),
+    ))
+}
+
+#[tracing::instrument(skip(state))]
+#[delete("/users/{username}")]
+pub async fn delete_user_account(
+    state: web::Data<AppState>,
+    username: web::Path<String>,
+    query: web::Query<DeleteUserQuery>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, AdminError> {
+    check_not_self(&authorized, &username)?;
+    find_user(&state, &username).await?;
+    delete_user(&state, &username, query.delete_files).await?;
+    Ok(empty_ok_response())
+}
+
+#[tracing::instrument(skip(state, reset))]
+#[put("/users/{username}/password")]
+pub async fn put_user_password(
+    state: web::Data<AppState>,
+    username: web::Path<String>,
+    reset: web::Json<PasswordReset>,
+) -> Result<HttpResponse, AdminError> {
+    if !state.auth.manages_passwords() {
+        return Err(AdminError::ExternalPassword);
+    }
+    find_user(&state, &username).await?;
+    set_password(&state, &username, &reset.password.0).await?;
+    // Anyone who knew the old password shouldn't stay logged in
+    delete_user_tokens(&state.db, &username).await?;
+    Ok(empty_ok_response())
+}
+
+#[tracing::instrument(skip(state))]
+#[put("/users/{username}/type")]
+pub async fn put_user_type(
+    state: web::Data<AppState>,
+    username: web::Path<String>,
+    change: web::Json<UserTypeChange>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<web::Json<AdminUser>, AdminError> {
+    check_not_self(&authorized, &username)?;
+    let user = find_user(&state, &username).await?;
+    let mut user: user::ActiveModel = user.into();
+    user.user_type = Set(change.user_type.db_value().to_owned());
+    let user = user.update(&state.db).await?;
+    Ok(web::Json(user.into()))
+}
@@ -0,0 +1,84 @@
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{self, ServiceRequest, ServiceResponse};
+use actix_web::dev::{Service, Transform};
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use futures::future::LocalBoxFuture;
+use tracing_unwrap::ResultExt;
+
+use crate::admin::is_admin;
+use crate::state::{AppState, Authorized};
+
+/// Only lets admins through. This has to be inside [`CheckLogin`], which finds
+/// out who made the request.
+///
+/// [`CheckLogin`]: crate::auth_middleware::CheckLogin
+#[derive(Clone)]
+pub struct RequireAdmin {
+    pub state: web::Data<AppState>,
+}
+
+impl<S: 'static, B> Transform<S, ServiceRequest> for RequireAdmin
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequireAdminMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireAdminMiddleware {
+            service: Rc::new(service),
+            state: self.state.clone(),
+        }))
+    }
+}
+pub struct RequireAdminMiddleware<S> {
+    service: Rc<S>,
+    state: web::Data<AppState>,
+}
+
+impl<S: 'static, B> Service<ServiceRequest> for RequireAdminMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let state = self.state.clone();
+        let service = self.service.clone();
+        let username = req
+            .extensions()
+            .get::<Authorized>()
+            .and_then(|authorized| authorized.admin_username().cloned());
+
+        Box::pin(async move {
+            let allowed = match username {
+                Some(username) => is_admin(&state.db, &username.0).await.unwrap_or_log(),
+                None => false,
+            };
+            if allowed {
+                service
+                    .call(req)
+                    .await
+                    .map(ServiceResponse::map_into_left_body)
+            } else {
+                tracing::debug!("User is not an admin");
+                let response = HttpResponse::Forbidden()
+                    .json("Only admins can do this.")
+                    .map_into_right_body();
+                Ok(req.into_response(response))
+            }
+        })
+    }
+}
@@ -0,0 +1,372 @@
+//! Long-lived keys for scripts and CI, so they don't need the password of the
+//! user.
+//!
+//! Keys are sent like user tokens, in the `Authorization` header, or as the
+//! password with HTTP Basic authentication for WebDAV. Each key has a scope
+//! that limits what it can do, and can be limited to a path in the store of
+//! the user. The auth middleware refuses requests that the scope doesn't
+//! allow, and [`get_authorized_path`] refuses paths outside of the key's path.
+//! Keys can't manage the account of the user, like its sessions, password or
+//! other keys.
+//!
+//! The key is only shown when it is made, only its hash is stored.
+//!
+//! [`get_authorized_path`]: crate::storage::get_authorized_path
+use actix_web::{
+    delete, get,
+    http::{Method, StatusCode},
+    post,
+    web::{self, ReqData},
+    HttpResponse, HttpResponseBuilder,
+};
+use chrono::Utc;
+use nanoid::nanoid;
+use sea_orm::{
+    sea_query::Condition, ActiveModelTrait, ColumnTrait, DbErr, EntityTrait, QueryFilter,
+    QueryOrder, Set,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::is_admin,
+    auth_middleware::path_segments,
+    entity::{api_key, user},
+    state::{AppState, Authorized, Token, Username},
+    storage::empty_ok_response,
+    user_share::is_read_method,
+};
+
+#[cfg(feature = "generate_types")]
+use typescript_type_def::TypeDef;
+
+/// Keys start with this, so they can be told apart from user tokens.
+const API_KEY_PREFIX: &str = "bck_";
+
+/// How long the random part of a key is.
+const API_KEY_LENGTH: usize = 40;
+
+/// Names longer than this are refused, they are only there to tell the keys
+/// apart.
+const MAX_NAME_LENGTH: usize = 64;
+
+/// What requests an API key can make. Each scope can do everything the ones
+/// before it can.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum,
+)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyScope {
+    /// Only see and download files.
+    Read,
+    /// Also upload, change and delete files.
+    Write,
+    /// Also use the admin API, if the user is an admin.
+    Admin,
+}
+
+impl ApiKeyScope {
+    /// The value stored in the `scope` column of the database.
+    pub fn db_value(&self) -> &'static str {
+        match self {
+            ApiKeyScope::Read => "read",
+            ApiKeyScope::Write => "write",
+            ApiKeyScope::Admin => "admin",
+        }
+    }
+
+    pub fn from_db_value(value: &str) -> ApiKeyScope {
+        match value {
+            "admin" => ApiKeyScope::Admin,
+            "write" => ApiKeyScope::Write,
+            // If we can't tell, the key gets the least it could need
+            _ => ApiKeyScope::Read,
+        }
+    }
+}
+
+/// What the API key used for a request is allowed to do.
+#[derive(Clone, Debug)]
+pub struct ApiKeyAccess {
+    pub scope: ApiKeyScope,
+    /// The path in the store of the user that the key is limited to, without
+    /// leading or trailing slashes.
+    pub path: Option<String>,
+}
+
+impl ApiKeyAccess {
+    /// True if the scope of the key allows requests with this method.
+    pub fn allows(&self, method: &Method) -> bool {
+        self.scope >= ApiKeyScope::Write || is_read_method(method)
+    }
+
+    /// True if the key can access the path in the store of the user. Keys
+    /// that are limited to a path can't access anything that belongs to the
+    /// whole store, which is when there is no path.
+    pub fn covers(&self, path: Option<&str>) -> bool {
+        let Some(folder) = &self.path else {
+            return true;
+        };
+        let requested = path.and_then(path_segments);
+        match (requested, path_segments(folder)) {
+            (Some(requested), Some(folder)) => requested.starts_with(&folder),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, derive_more::Display, thiserror::Error)]
+pub enum ApiKeyError {
+    #[display(fmt = "User is not authorized to manage API keys.")]
+    NotAuthorized,
+    #[display(fmt = "API key {} does not exist", _0)]
+    NotFound(String),
+    #[display(fmt = "The name of an API key can't be empty, or longer than {MAX_NAME_LENGTH}")]
+    BadName,
+    #[display(fmt = "There is already an API key named {}", _0)]
+    Duplicate(String),
+    #[display(fmt = "The path of an API key can't contain . or ..")]
+    BadPath,
+    #[display(fmt = "Only admins can make keys with the admin scope")]
+    AdminOnly,
+    #[display(fmt = "Database error {}", _0)]
+    Database(#[from] DbErr),
+}
+
+impl Serialize for ApiKeyError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let s = format!("{}", self);
+        serializer.serialize_str(&s)
+    }
+}
+
+impl actix_web::error::ResponseError for ApiKeyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiKeyError::NotAuthorized => StatusCode::UNAUTHORIZED,
+            ApiKeyError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiKeyError::BadName => StatusCode::BAD_REQUEST,
+            ApiKeyError::Duplicate(_) => StatusCode::CONFLICT,
+            ApiKeyError::BadPath => StatusCode::BAD_REQUEST,
+            ApiKeyError::AdminOnly => StatusCode::FORBIDDEN,
+            ApiKeyError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponseBuilder::new(self.status_code()).json(self)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct ApiKeyOptions {
+    /// Tells the keys of the user apart, like the script that uses it.
+    pub name: String,
+    pub scope: ApiKeyScope,
+    /// Limits the key to a file or folder in the store of the user.
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scope: ApiKeyScope,
+    /// The file or folder the key is limited to, relative to the store.
+    pub path: Option<String>,
+    pub created_at: String,
+}
+
+impl From<api_key::Model> for ApiKey {
+    fn from(key: api_key::Model) -> Self {
+        ApiKey {
+            id: key.id,
+            name: key.name,
+            scope: ApiKeyScope::from_db_value(&key.scope),
+            path: key.path,
+            created_at: key.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct ApiKeys {
+    pub keys: Vec<ApiKey>,
+}
+
+/// A key that was just made. This is the only time the key is shown.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct NewApiKey {
+    pub key: Token,
+    pub info: ApiKey,
+}
+
+/// True if the token looks like an API key rather than a user token.
+pub fn is_api_key(token: &Token) -> bool {
+    token.reveal().starts_with(API_KEY_PREFIX)
+}
+
+async fn find_user(state: &AppState, username: &str) -> Result<user::Model, ApiKeyError> {
+    user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(&state.db)
+        .await?
+        .ok_or(ApiKeyError::NotAuthorized)
+}
+
+/// Makes a new API key for the user.
+#[tracing::instrument(skip(state))]
+pub async fn create_api_key(
+    state: &AppState,
+    username: &str,
+    options: &ApiKeyOptions,
+) -> Result<NewApiKey, ApiKeyError> {
+    let name = options.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(ApiKeyError::BadName);
+    }
+    let path = match options.path.as_deref().map(|path| path.trim_matches('/')) {
+        Some(path) if path_segments(path).is_none() => return Err(ApiKeyError::BadPath),
+        Some("") | None => None,
+        Some(path) => Some(path.to_string()),
+    };
+    if options.scope == ApiKeyScope::Admin && !is_admin(&state.db, username).await? {
+        return Err(ApiKeyError::AdminOnly);
+    }
+    let user = find_user(state, username).await?;
+    let existing = api_key::Entity::find()
+        .filter(api_key::Column::UserId.eq(&user.id))
+        .filter(api_key::Column::Name.eq(name))
+        .one(&state.db)
+        .await?;
+    if existing.is_some() {
+        return Err(ApiKeyError::Duplicate(name.to_string()));
+    }
+
+    let key = Token::read(&format!("{API_KEY_PREFIX}{}", nanoid!(API_KEY_LENGTH)));
+    let info = api_key::ActiveModel {
+        id: Set(nanoid!()),
+        user_id: Set(user.id),
+        name: Set(name.to_string()),
+        token: Set(state.hash_token(&key)),
+        scope: Set(options.scope.db_value().to_string()),
+        path: Set(path),
+        created_at: Set(Utc::now().to_rfc3339()),
+    }
+    .insert(&state.db)
+    .await?;
+    Ok(NewApiKey {
+        key,
+        info: info.into(),
+    })
+}
+
+/// Lists the API keys of the user, newest first.
+pub async fn list_api_keys(state: &AppState, username: &str) -> Result<Vec<ApiKey>, ApiKeyError> {
+    let user = find_user(state, username).await?;
+    Ok(api_key::Entity::find()
+        .filter(api_key::Column::UserId.eq(user.id))
+        .order_by_desc(api_key::Column::CreatedAt)
+        .all(&state.db)
+        .await?
+        .into_iter()
+        .map(ApiKey::from)
+        .collect())
+}
+
+/// Revokes the API key of the user with this ID or name. The key stops
+/// working right away.
+#[tracing::instrument(skip(state))]
+pub async fn revoke_api_key(
+    state: &AppState,
+    username: &str,
+    key: &str,
+) -> Result<(), ApiKeyError> {
+    let user = find_user(state, username).await?;
+    let result = api_key::Entity::delete_many()
+        .filter(api_key::Column::UserId.eq(user.id))
+        .filter(
+            Condition::any()
+                .add(api_key::Column::Id.eq(key))
+                .add(api_key::Column::Name.eq(key)),
+        )
+        .exec(&state.db)
+        .await?;
+    if result.rows_affected == 0 {
+        return Err(ApiKeyError::NotFound(key.to_string()));
+    }
+    Ok(())
+}
+
+/// Finds the user of the API key, and what the key can do.
+pub(crate) async fn verify_api_key(
+    state: &AppState,
+    key: &Token,
+) -> Result<Option<(Username, ApiKeyAccess)>, DbErr> {
+    let found = api_key::Entity::find()
+        .filter(api_key::Column::Token.eq(state.hash_token(key)))
+        .find_also_related(user::Entity)
+        .one(&state.db)
+        .await?;
+    Ok(match found {
+        Some((key, Some(user))) => Some((
+            Username(user.username),
+            ApiKeyAccess {
+                scope: ApiKeyScope::from_db_value(&key.scope),
+                path: key.path,
+            },
+        )),
+        _ => None,
+    })
+}
+
+fn request_user(authorized: &Option<ReqData<Authorized>>) -> Result<&Username, ApiKeyError> {
+    authorized
+        .as_deref()
+        .and_then(|authorized| authorized.username())
+        .ok_or(ApiKeyError::NotAuthorized)
+}
+
+#[tracing::instrument(skip(state))]
+#[get("/keys")]
+pub async fn get_api_keys(
+    state: web::Data<AppState>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<web::Json<ApiKeys>, ApiKeyError> {
+    let username = request_user(&authorized)?;
+    Ok(web::Json(ApiKeys {
+        keys: list_api_keys(&state, &username.0).await?,
+    }))
+}
+
+#[tracing::instrument(skip(state))]
+#[post("/keys")]
+pub async fn post_api_key(
+    state: web::Data<AppState>,
+    options: web::Json<ApiKeyOptions>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<web::Json<NewApiKey>, ApiKeyError> {
+    let username = request_user(&authorized)?;
+    Ok(web::Json(
+        create_api_key(&state, &username.0, &options).await?,
+    ))
+}
+
+#[tracing::instrument(skip(state))]
+#[delete("/keys/{id}")]
+pub async fn delete_api_key(
+    state: web::Data<AppState>,
+    id: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, ApiKeyError> {
+    let username = request_user(&authorized)?;
+    revoke_api_key(&state, &username.0, &id).await?;
+    Ok(empty_ok_response())
+}
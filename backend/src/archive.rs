@@ -0,0 +1,347 @@
+//! Downloads of whole folders as a single archive, and extracting archives
+//! that were uploaded into a store.
+//!
+//! Archives are built while they are being sent, and read while they are being
+//! extracted, so they are never held in memory or copied to the disk.
+use std::path::{Path, PathBuf};
+
+use actix_web::{
+    http::header::{self, ContentDisposition, DispositionParam, DispositionType},
+    web, HttpResponse,
+};
+use async_compression::tokio::bufread::GzipDecoder;
+use async_zip::{
+    base::read::stream::ZipFileReader as ZipStreamReader, tokio::write::ZipFileWriter, Compression,
+    ZipDateTime, ZipEntryBuilder,
+};
+use chrono::{DateTime, Utc};
+use futures::{AsyncRead, AsyncReadExt, AsyncWriteExt, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use tokio::io::{duplex, AsyncBufRead, BufReader, DuplexStream};
+use tokio_util::{
+    compat::TokioAsyncReadCompatExt,
+    io::{ReaderStream, StreamReader},
+};
+
+use crate::{
+    kv::KVBackend,
+    quota::QuotaBudget,
+    share::DownloadResponse,
+    state::AppState,
+    storage::{commit_part, ConflictPolicy, StorageError},
+};
+
+/// How much of the archive can be built ahead of what the client has received.
+const ARCHIVE_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    Zip,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ArchiveQuery {
+    pub archive: Option<ArchiveFormat>,
+}
+
+/// Responds with an archive of everything inside the folder.
+#[tracing::instrument(skip(state))]
+pub async fn archive_response(
+    state: web::Data<AppState>,
+    store_path: PathBuf,
+    format: ArchiveFormat,
+) -> Result<HttpResponse, StorageError> {
+    if state.storage.metadata(&store_path).await?.is_file {
+        return Err(StorageError::BadPath);
+    }
+    let name = store_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let (reader, writer) = duplex(ARCHIVE_BUFFER_SIZE);
+    match format {
+        ArchiveFormat::Zip => {
+            actix_web::rt::spawn(async move {
+                // If the client goes away, writing fails and this stops
+                if let Err(err) = write_zip(state.storage.as_ref(), &store_path, writer).await {
+                    tracing::warn!(error = ?err, "Failed to create the archive");
+                }
+            });
+        }
+    }
+
+    let mut response = HttpResponse::Ok();
+    response.extensions_mut().insert(DownloadResponse);
+    Ok(response
+        .content_type("application/zip")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!("{name}.zip"))],
+        })
+        // The archive is built on the fly, there is nothing to cache
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .streaming(ReaderStream::new(reader)))
+}
+
+fn zip_date(modified: Option<std::time::SystemTime>) -> ZipDateTime {
+    let modified: DateTime<Utc> = modified.map(DateTime::from).unwrap_or_else(Utc::now);
+    ZipDateTime::from_chrono(&modified)
+}
+
+async fn write_zip(
+    storage: &dyn KVBackend,
+    root: &Path,
+    writer: DuplexStream,
+) -> anyhow::Result<()> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    // Walk the folder without recursion, names inside the archive are
+    // relative to the folder being downloaded
+    let mut pending = vec![PathBuf::new()];
+    while let Some(folder) = pending.pop() {
+        let mut entries = storage.read_dir(&root.join(&folder)).await?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        for entry in entries {
+            let relative = folder.join(&entry.name);
+            let name = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            if entry.meta.is_file {
+                let builder = ZipEntryBuilder::new(name.into(), Compression::Deflate)
+                    .last_modification_date(zip_date(entry.meta.modified))
+                    .unix_permissions(0o100644);
+                let mut entry_writer = zip.write_entry_stream(builder).await?;
+                let mut body = storage.read(&root.join(&relative), None).await?;
+                while let Some(chunk) = body.try_next().await? {
+                    entry_writer.write_all(&chunk).await?;
+                }
+                entry_writer.close().await?;
+            } else {
+                // Folders are written too, so empty folders are not lost
+                let builder = ZipEntryBuilder::new(format!("{name}/").into(), Compression::Stored)
+                    .last_modification_date(zip_date(entry.meta.modified))
+                    .unix_permissions(0o040755);
+                zip.write_entry_whole(builder, &[]).await?;
+                pending.push(relative);
+            }
+        }
+    }
+    zip.close().await?;
+    Ok(())
+}
+
+/// Extracting an archive stops if it would write more than this many bytes.
+pub const MAX_EXTRACT_BYTES: u64 = 16 * 1024 * 1024 * 1024;
+/// Extracting an archive stops if it has more than this many files and folders.
+pub const MAX_EXTRACT_ENTRIES: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+enum ExtractFormat {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ExtractFormat {
+    fn detect(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        if name.ends_with(".zip") {
+            Some(ExtractFormat::Zip)
+        } else if name.ends_with(".tar") {
+            Some(ExtractFormat::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ExtractFormat::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+/// Turns the name of an entry in an archive into a path relative to the
+/// folder the archive is being extracted into. Each part of the path is
+/// sanitized the same way uploaded file names are, and names that try to
+/// escape the folder are rejected.
+fn sanitize_entry_path(name: &str) -> Result<PathBuf, StorageError> {
+    if name.starts_with(['/', '\\']) {
+        return Err(StorageError::UnsafeArchivePath(name.to_string()));
+    }
+    let mut path = PathBuf::new();
+    for segment in name.split(['/', '\\']) {
+        match segment {
+            "" | "." => {}
+            ".." => return Err(StorageError::UnsafeArchivePath(name.to_string())),
+            segment => {
+                let segment = sanitize_filename::sanitize(segment);
+                if segment.is_empty() {
+                    return Err(StorageError::UnsafeArchivePath(name.to_string()));
+                }
+                path.push(segment);
+            }
+        }
+    }
+    Ok(path)
+}
+
+/// Writes the entries of an archive into the target folder, keeping track of
+/// how much has been extracted.
+struct Extractor<'a> {
+    storage: &'a dyn KVBackend,
+    target: &'a Path,
+    budget: &'a mut QuotaBudget,
+    bytes: u64,
+    entries: usize,
+}
+
+impl<'a> Extractor<'a> {
+    fn count_entry(&mut self) -> Result<(), StorageError> {
+        self.entries += 1;
+        if self.entries > MAX_EXTRACT_ENTRIES {
+            return Err(StorageError::ArchiveTooLarge);
+        }
+        Ok(())
+    }
+
+    async fn folder(&mut self, name: &str) -> Result<(), StorageError> {
+        self.count_entry()?;
+        let path = sanitize_entry_path(name)?;
+        self.storage.create_dir_all(&self.target.join(path)).await?;
+        Ok(())
+    }
+
+    async fn file<R: AsyncRead + Unpin>(
+        &mut self,
+        name: &str,
+        mut reader: R,
+    ) -> Result<(), StorageError> {
+        self.count_entry()?;
+        let path = sanitize_entry_path(name)?;
+        let (Some(folder), Some(filename)) = (path.parent(), path.file_name()) else {
+            return Err(StorageError::UnsafeArchivePath(name.to_string()));
+        };
+        let folder = self.target.join(folder);
+        let filename = filename.to_string_lossy();
+        self.storage.create_dir_all(&folder).await?;
+
+        let mut part = self.storage.create_part(&folder, &filename).await?;
+        let mut buffer = vec![0; 64 * 1024];
+        let written: Result<(), StorageError> = async {
+            loop {
+                let read = reader.read(&mut buffer).await?;
+                if read == 0 {
+                    return Ok(());
+                }
+                // Count what was actually unpacked, sizes in the headers can lie
+                self.bytes += read as u64;
+                if self.bytes > MAX_EXTRACT_BYTES {
+                    return Err(StorageError::ArchiveTooLarge);
+                }
+                self.budget.spend(read as u64)?;
+                part.write(&buffer[..read]).await?;
+            }
+        }
+        .await;
+        let committed = match written {
+            Ok(()) => commit_part(part.as_mut(), &folder, &filename, ConflictPolicy::Rename)
+                .await
+                .map(|_| ()),
+            Err(err) => Err(err),
+        };
+        if committed.is_err() {
+            if let Err(discard_err) = part.discard().await {
+                tracing::warn!(error = ?discard_err, "Failed to clean up a failed extraction");
+            }
+        }
+        committed
+    }
+
+    async fn zip(&mut self, reader: impl AsyncBufRead + Unpin) -> Result<(), StorageError> {
+        let mut zip = ZipStreamReader::new(reader.compat());
+        while let Some(mut entry) = zip.next_with_entry().await.map_err(bad_archive)? {
+            let name = entry
+                .reader()
+                .entry()
+                .filename()
+                .as_str()
+                .map_err(bad_archive)?
+                .to_string();
+            if name.ends_with('/') {
+                self.folder(&name).await?;
+            } else {
+                self.file(&name, entry.reader_mut()).await?;
+            }
+            zip = entry.done().await.map_err(bad_archive)?;
+        }
+        Ok(())
+    }
+
+    async fn tar(&mut self, reader: impl tokio::io::AsyncRead + Unpin) -> Result<(), StorageError> {
+        let mut archive = tokio_tar::Archive::new(reader);
+        let mut entries = archive.entries()?;
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            let name = String::from_utf8_lossy(&entry.path_bytes()).to_string();
+            let entry_type = entry.header().entry_type();
+            if entry_type.is_dir() {
+                self.folder(&name).await?;
+            } else if entry_type.is_file() {
+                self.file(&name, entry.compat()).await?;
+            } else {
+                // Links could point anywhere, and special files are not useful here
+                tracing::debug!(name, "Skipping unsupported archive entry");
+            }
+        }
+        Ok(())
+    }
+}
+
+fn bad_archive(err: async_zip::error::ZipError) -> StorageError {
+    StorageError::BadArchive(err.to_string())
+}
+
+/// Extracts the archive at `archive_path` into the `target` folder, stopping
+/// if the extracted files don't fit in the `budget`. If the target folder
+/// didn't exist before, it is removed again when the extraction fails.
+#[tracing::instrument(skip(storage))]
+pub async fn extract_archive(
+    storage: &dyn KVBackend,
+    archive_path: &Path,
+    target: &Path,
+    budget: &mut QuotaBudget,
+) -> Result<(), StorageError> {
+    let format = ExtractFormat::detect(archive_path).ok_or_else(|| {
+        StorageError::BadArchive("Only zip, tar and tar.gz archives can be extracted".to_string())
+    })?;
+    if !storage.metadata(archive_path).await?.is_file {
+        return Err(StorageError::BadPath);
+    }
+    let target_existed = storage.metadata(target).await.is_ok();
+    storage.create_dir_all(target).await?;
+
+    let reader = BufReader::new(StreamReader::new(storage.read(archive_path, None).await?));
+    let mut extractor = Extractor {
+        storage,
+        target,
+        budget,
+        bytes: 0,
+        entries: 0,
+    };
+    let extracted = match format {
+        ExtractFormat::Zip => extractor.zip(reader).await,
+        ExtractFormat::Tar => extractor.tar(reader).await,
+        ExtractFormat::TarGz => extractor.tar(GzipDecoder::new(reader)).await,
+    };
+    if extracted.is_err() && !target_existed {
+        if let Err(err) = storage.remove(target).await {
+            tracing::warn!(error = ?err, "Failed to clean up a failed extraction");
+        }
+    }
+    tracing::debug!(
+        bytes = extractor.bytes,
+        entries = extractor.entries,
+        "Extracted archive"
+    );
+    extracted
+}
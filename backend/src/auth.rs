@@ -2,20 +2,29 @@ use crate::{
     entity::{user, user_token},
     error::ServerError,
     folder::{STORAGE, USERS_DIR},
+    group::{group_exists, remove_from_groups},
+    ldap::{LdapAuth, LdapOptions},
+    oidc::OidcOptions,
+    proxy_auth::ProxyAuthOptions,
+    session::client_info,
     state::{AppState, Token, UserType},
+    totp::verify_login_code,
+    trash::empty_trash,
+    user_share::remove_user_shares,
+    versions::remove_store_versions,
 };
-use std::path::PathBuf;
+use std::{fmt::Debug, path::PathBuf};
 
-use actix_web::{http, post, web, HttpResponse, HttpResponseBuilder};
+use actix_web::{http, post, web, HttpRequest, HttpResponse, HttpResponseBuilder};
 use anyhow::Result;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use async_trait::async_trait;
 use nanoid::nanoid;
 use sanitize_filename::is_sanitized_with_options;
-use scrypt::{
-    password_hash::{
-        rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, Salt, SaltString,
-    },
-    Params, Scrypt,
-};
+use scrypt::Scrypt;
 use sea_orm::{
     ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, ModelTrait, QueryFilter, Set,
 };
@@ -25,7 +34,6 @@ use tracing_unwrap::{OptionExt, ResultExt};
 use typescript_type_def::TypeDef;
 
 use serde::{Deserialize, Serialize};
-use tokio::fs;
 use tracing::instrument;
 
 pub fn path_user_file(username: &str) -> PathBuf {
@@ -34,36 +42,109 @@ pub fn path_user_file(username: &str) -> PathBuf {
         .with_extension("toml")
 }
 
-fn scrypt_params() -> Params {
-    // During debugging, use insecure parameters to speed up logins. Because
-    // without optimizations logins can take 3+ seconds each.
-    #[cfg(debug_assertions)]
-    {
-        Params::new(5, 2, 1, 32).unwrap_or_log()
+// During debugging, use insecure parameters to speed up logins. Because
+// without optimizations logins can take 3+ seconds each.
+#[cfg(debug_assertions)]
+const DEFAULT_ARGON2_MEMORY: u32 = 256;
+#[cfg(debug_assertions)]
+const DEFAULT_ARGON2_ITERATIONS: u32 = 1;
+// During release, follow the recommended parameters to sufficiently slow
+// down logins.
+#[cfg(not(debug_assertions))]
+const DEFAULT_ARGON2_MEMORY: u32 = Params::DEFAULT_M_COST;
+#[cfg(not(debug_assertions))]
+const DEFAULT_ARGON2_ITERATIONS: u32 = Params::DEFAULT_T_COST;
+const DEFAULT_ARGON2_PARALLELISM: u32 = Params::DEFAULT_P_COST;
+
+/// The cost of hashing passwords. Passwords are hashed with Argon2id, and
+/// older hashes are replaced the next time the user logs in.
+#[derive(clap::Args, Debug, Clone)]
+pub struct PasswordHashOptions {
+    #[clap(long, env = "BULGUR_CLOUD_ARGON2_MEMORY", default_value_t = DEFAULT_ARGON2_MEMORY)]
+    /// How much memory hashing a password uses, in KiB.
+    pub argon2_memory: u32,
+
+    #[clap(long, env = "BULGUR_CLOUD_ARGON2_ITERATIONS", default_value_t = DEFAULT_ARGON2_ITERATIONS)]
+    /// How many passes hashing a password makes over the memory.
+    pub argon2_iterations: u32,
+
+    #[clap(long, env = "BULGUR_CLOUD_ARGON2_PARALLELISM", default_value_t = DEFAULT_ARGON2_PARALLELISM)]
+    /// How many lanes hashing a password uses.
+    pub argon2_parallelism: u32,
+}
+
+impl Default for PasswordHashOptions {
+    fn default() -> Self {
+        PasswordHashOptions {
+            argon2_memory: DEFAULT_ARGON2_MEMORY,
+            argon2_iterations: DEFAULT_ARGON2_ITERATIONS,
+            argon2_parallelism: DEFAULT_ARGON2_PARALLELISM,
+        }
     }
-    // During release, follow the recommended parameters to sufficiently slow
-    // down logins.
-    #[cfg(not(debug_assertions))]
-    {
-        Params::default()
+}
+
+impl PasswordHashOptions {
+    pub fn params(&self) -> anyhow::Result<Params> {
+        Params::new(
+            self.argon2_memory,
+            self.argon2_iterations,
+            self.argon2_parallelism,
+            None,
+        )
+        .map_err(|err| anyhow::anyhow!("The Argon2 parameters are not valid: {err}"))
+    }
+
+    fn hasher(&self) -> anyhow::Result<Argon2<'static>> {
+        Ok(Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            self.params()?,
+        ))
+    }
+
+    /// True if the hash was made with another algorithm or other parameters
+    /// than the ones that are set now.
+    pub fn needs_rehash(&self, password_hash: &str) -> bool {
+        let Ok(hash) = PasswordHash::new(password_hash) else {
+            return true;
+        };
+        if hash.algorithm != Algorithm::Argon2id.ident()
+            || hash.version != Some(Version::V0x13.into())
+        {
+            return true;
+        }
+        match Params::try_from(&hash) {
+            Ok(params) => {
+                params.m_cost() != self.argon2_memory
+                    || params.t_cost() != self.argon2_iterations
+                    || params.p_cost() != self.argon2_parallelism
+            }
+            Err(_) => true,
+        }
     }
 }
 
-async fn hash_password(password: &str) -> anyhow::Result<String> {
+pub(crate) async fn hash_password(
+    options: &PasswordHashOptions,
+    password: &str,
+) -> anyhow::Result<String> {
     let salt = SaltString::generate(&mut OsRng);
-    let password_hash: String = Scrypt
-        .hash_password_customized(
-            password.as_bytes(),
-            None,
-            None,
-            scrypt_params(),
-            Salt::try_from(&salt)?,
-        )?
+    let password_hash: String = options
+        .hasher()?
+        .hash_password(password.as_bytes(), &salt)?
         .to_string();
     Ok(password_hash)
 }
 
-const USER_NOBODY: &str = "nobody";
+/// Checks a password against a hash in the PHC string format. Both Argon2id
+/// hashes and the scrypt hashes of older versions are accepted.
+pub(crate) fn verify_password_hash(password_hash: &str, password: &str) -> anyhow::Result<()> {
+    let password_hash = PasswordHash::new(password_hash)?;
+    password_hash.verify_password(&[&Argon2::default(), &Scrypt], password)?;
+    Ok(())
+}
+
+pub(crate) const USER_NOBODY: &str = "nobody";
 
 #[derive(thiserror::Error, Debug)]
 pub enum BadUsername {
@@ -85,70 +166,234 @@ pub async fn add_new_user(
     username: &str,
     password: &str,
     user_type: UserType,
-    db: &DatabaseConnection,
+    state: &AppState,
 ) -> anyhow::Result<()> {
     validate_username(username)?;
-    let password_hash = hash_password(password).await?;
+    let db = &state.db;
+    // The store of the user would be the same as the store of the group
+    if group_exists(db, username).await? {
+        anyhow::bail!("The name {username} is already used by a group");
+    }
+    let password_hash = hash_password(&state.password_hashing, password).await?;
     let user = user::ActiveModel {
         id: Set(nanoid!()),
         username: Set(username.to_owned()),
         password_hash: Set(password_hash),
-        user_type: match user_type {
-            UserType::User => Set("U".to_owned()),
-            UserType::Admin => Set("A".to_owned()),
-        },
+        user_type: Set(user_type.db_value().to_owned()),
+        // Usage is calculated when it's first needed, the store may already
+        // have files in it
+        ..Default::default()
     };
     user.insert(db).await?;
 
     Ok(())
 }
 
-pub async fn create_user_folder(username: &str) -> anyhow::Result<()> {
+/// Replaces the password of a user.
+pub async fn set_password(state: &AppState, username: &str, password: &str) -> anyhow::Result<()> {
+    let user = user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(&state.db)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("User {username} does not exist"))?;
+    let password_hash = hash_password(&state.password_hashing, password).await?;
+    let mut user: user::ActiveModel = user.into();
+    user.password_hash = Set(password_hash);
+    user.update(&state.db).await?;
+    Ok(())
+}
+
+/// Deletes all tokens of a user, logging them out everywhere.
+pub async fn delete_user_tokens(db: &DatabaseConnection, username: &str) -> anyhow::Result<()> {
+    let user = user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(db)
+        .await?;
+    if let Some(user) = user {
+        user_token::Entity::delete_many()
+            .filter(user_token::Column::UserId.eq(user.id))
+            .exec(db)
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn create_user_folder(state: &AppState, username: &str) -> anyhow::Result<()> {
     let path = PathBuf::from(STORAGE).join(username);
-    fs::create_dir_all(path).await?;
+    state.storage.create_dir_all(&path).await?;
 
     Ok(())
 }
 
 pub async fn delete_user(
-    db: &DatabaseConnection,
+    state: &AppState,
     username: &str,
     delete_files: bool,
 ) -> anyhow::Result<()> {
     let user = user::Entity::find()
         .filter(user::Column::Username.eq(username))
-        .one(db)
+        .one(&state.db)
         .await?;
     if let Some(user) = user {
-        user.delete(db).await.unwrap();
+        user.delete(&state.db).await.unwrap();
+        remove_user_shares(state, username)
+            .await
+            .map_err(|err| anyhow::anyhow!("Failed to remove shares: {err}"))?;
+        remove_from_groups(state, username).await?;
 
         if delete_files {
             let store = PathBuf::from(STORAGE).join(username);
-            fs::remove_dir_all(store).await?;
+            state.storage.remove(&store).await?;
+            empty_trash(state, username)
+                .await
+                .map_err(|err| anyhow::anyhow!("Failed to empty the trash: {err}"))?;
+            remove_store_versions(state, username)
+                .await
+                .map_err(|err| anyhow::anyhow!("Failed to remove file versions: {err}"))?;
         }
     }
     Ok(())
 }
 
 /// Creates a "nobody" user which is used to resist user probing
-pub async fn create_nobody(db: &DatabaseConnection) -> anyhow::Result<()> {
+pub async fn create_nobody(state: &AppState) -> anyhow::Result<()> {
     let existing = user::Entity::find()
         .filter(user::Column::Username.eq(USER_NOBODY))
-        .one(db)
+        .one(&state.db)
         .await?;
-    if existing.is_none() {
-        add_new_user(USER_NOBODY, &nanoid!(), UserType::User, db).await?;
+    match existing {
+        None => add_new_user(USER_NOBODY, &nanoid!(), UserType::User, state).await?,
+        // Checking a password against nobody should take as long as checking
+        // one against a real user, so the hash has to be kept up to date
+        Some(nobody) if state.password_hashing.needs_rehash(&nobody.password_hash) => {
+            set_password(state, USER_NOBODY, &nanoid!()).await?
+        }
+        Some(_) => (),
+    }
+
+    Ok(())
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AuthKind {
+    /// Check passwords against the hashes kept in the datastore.
+    #[default]
+    Local,
+    /// Check passwords by binding to a directory server.
+    Ldap,
+}
+
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct AuthOptions {
+    #[clap(long = "auth", env = "BULGUR_CLOUD_AUTH", value_enum, default_value_t)]
+    /// Where usernames and passwords are checked.
+    pub kind: AuthKind,
+
+    #[clap(flatten)]
+    pub ldap: LdapOptions,
+
+    #[clap(flatten)]
+    pub oidc: OidcOptions,
+
+    #[clap(flatten)]
+    pub proxy: ProxyAuthOptions,
+
+    #[clap(flatten)]
+    pub hashing: PasswordHashOptions,
+}
+
+/// Checks the usernames and passwords of users, selected with the `--auth`
+/// option.
+#[async_trait(?Send)]
+pub trait AuthProvider: Debug + Send + Sync {
+    /// Checks the password of the user. Providers that keep their users
+    /// somewhere else add the user to the datastore if they are missing.
+    async fn verify(&self, state: &AppState, username: &str, password: &Password) -> Result<()>;
+
+    /// False if the passwords are kept somewhere else, and can't be changed
+    /// here.
+    fn manages_passwords(&self) -> bool {
+        true
     }
+}
+
+/// Keeps the users and the hashes of their passwords in the datastore.
+#[derive(Debug)]
+pub struct LocalAuth;
+
+#[async_trait(?Send)]
+impl AuthProvider for LocalAuth {
+    async fn verify(&self, state: &AppState, username: &str, password: &Password) -> Result<()> {
+        verify_local_pass(state, username, password).await
+    }
+}
+
+/// Sets up the authentication provider picked in the options.
+pub fn setup_auth(options: &AuthOptions) -> anyhow::Result<Box<dyn AuthProvider>> {
+    match options.kind {
+        AuthKind::Local => Ok(Box::new(LocalAuth)),
+        AuthKind::Ldap => Ok(Box::new(LdapAuth::new(&options.ldap)?)),
+    }
+}
 
+/// Makes sure a user who was checked by a provider outside of the datastore
+/// has an account and a store. If the provider decides the type of the user,
+/// the type is updated to match.
+pub(crate) async fn provision_user(
+    state: &AppState,
+    username: &str,
+    user_type: Option<UserType>,
+) -> anyhow::Result<()> {
+    let existing = user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(&state.db)
+        .await?;
+    match existing {
+        Some(user) => {
+            if let Some(user_type) = user_type {
+                if user.user_type != user_type.db_value() {
+                    let mut user: user::ActiveModel = user.into();
+                    user.user_type = Set(user_type.db_value().to_owned());
+                    user.update(&state.db).await?;
+                }
+            }
+        }
+        None => {
+            tracing::info!(username, "Adding a new user on their first login");
+            // The password is never used, the provider checks it instead
+            add_new_user(
+                username,
+                &nanoid!(),
+                user_type.unwrap_or(UserType::User),
+                state,
+            )
+            .await?;
+            create_user_folder(state, username).await?;
+        }
+    }
     Ok(())
 }
 
-#[tracing::instrument]
+/// Checks the password of the user with the configured provider.
+#[tracing::instrument(skip(state))]
 pub async fn verify_pass(
+    state: &AppState,
+    username: &str,
+    password_input: &Password,
+) -> Result<()> {
+    state.auth.verify(state, username, password_input).await
+}
+
+/// Checks the password against the hash kept in the datastore. Hashes made
+/// with an older algorithm or other parameters are replaced once the password
+/// is known to be correct.
+#[tracing::instrument(skip(state))]
+async fn verify_local_pass(
+    state: &AppState,
     username: &str,
     password_input: &Password,
-    db: &DatabaseConnection,
 ) -> Result<()> {
+    let db = &state.db;
     let user = match user::Entity::find()
         .filter(user::Column::Username.eq(username))
         .one(db)
@@ -156,7 +401,7 @@ pub async fn verify_pass(
     {
         Some(data) => data,
         None => user::Entity::find()
-            .filter(user::Column::Username.eq(format!("{USER_NOBODY}")))
+            .filter(user::Column::Username.eq(USER_NOBODY))
             .one(db)
             .await?
             .unwrap_or_log(),
@@ -175,15 +420,29 @@ pub async fn verify_pass(
     }
 
     let password_input_clone = password_input.0.clone();
-    web::block(move || -> anyhow::Result<()> {
-        let password_in_file = PasswordHash::new(&user.password_hash)?;
-        Ok(Scrypt.verify_password(password_input_clone.as_bytes(), &password_in_file)?)
-    })
-    .await??;
+    let password_hash = user.password_hash.clone();
+    web::block(move || verify_password_hash(&password_hash, &password_input_clone)).await??;
+
+    if user.username == username && state.password_hashing.needs_rehash(&user.password_hash) {
+        // The login already succeeded, failing to update the hash shouldn't
+        // stop it
+        if let Err(err) = rehash_password(state, user, password_input).await {
+            tracing::warn!(username, %err, "Failed to rehash the password");
+        }
+    }
 
     Ok(())
 }
 
+async fn rehash_password(state: &AppState, user: user::Model, password: &Password) -> Result<()> {
+    let password_hash = hash_password(&state.password_hashing, &password.0).await?;
+    let mut user: user::ActiveModel = user.into();
+    user.password_hash = Set(password_hash);
+    user.update(&state.db).await?;
+    tracing::info!("Rehashed the password with the current parameters");
+    Ok(())
+}
+
 #[derive(
     Serialize,
     Deserialize,
@@ -203,17 +462,30 @@ pub struct Password(pub String);
 pub struct Login {
     pub username: String,
     pub password: Password,
+    /// A code from the authenticator of the user, or one of their recovery
+    /// codes. Only needed if the user set up two-factor authentication.
+    #[serde(default)]
+    pub code: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 #[cfg_attr(feature = "generate_types", derive(TypeDef))]
 pub struct LoginResponse {
     pub access_token: Token,
+    /// When the token stops working, unless it is refreshed before then.
+    pub expires_at: Option<String>,
 }
 
 #[derive(Debug, derive_more::Display, thiserror::Error)]
-#[display(fmt = "Login failed, incorrect username or password.")]
-pub struct LoginFailed;
+pub enum LoginFailed {
+    #[display(fmt = "Login failed, incorrect username or password.")]
+    WrongPassword,
+    /// The password was right, but the user has to enter a code too.
+    #[display(fmt = "Enter the code from your authenticator app, or a recovery code.")]
+    CodeRequired,
+    #[display(fmt = "Login failed, incorrect code.")]
+    WrongCode,
+}
 
 impl Serialize for LoginFailed {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -227,7 +499,10 @@ impl Serialize for LoginFailed {
 
 impl actix_web::error::ResponseError for LoginFailed {
     fn status_code(&self) -> http::StatusCode {
-        actix_web::http::StatusCode::UNAUTHORIZED
+        match self {
+            LoginFailed::CodeRequired => http::StatusCode::FORBIDDEN,
+            LoginFailed::WrongPassword | LoginFailed::WrongCode => http::StatusCode::UNAUTHORIZED,
+        }
     }
 
     fn error_response(&self) -> HttpResponse {
@@ -235,9 +510,47 @@ impl actix_web::error::ResponseError for LoginFailed {
     }
 }
 
+/// Checks the password of the user, and the code from their authenticator if
+/// they set up two-factor authentication.
+pub async fn verify_login(
+    state: &AppState,
+    username: &str,
+    password: &Password,
+    code: Option<&str>,
+) -> Result<(), LoginFailed> {
+    if verify_pass(state, username, password).await.is_err() {
+        return Err(LoginFailed::WrongPassword);
+    }
+    let user = user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(&state.db)
+        .await
+        .unwrap_or_log()
+        .ok_or(LoginFailed::WrongPassword)?;
+    if !user.totp_enabled {
+        return Ok(());
+    }
+    match code.filter(|code| !code.trim().is_empty()) {
+        None => Err(LoginFailed::CodeRequired),
+        Some(code) if verify_login_code(state, &user, code).await.unwrap_or_log() => Ok(()),
+        Some(_) => Err(LoginFailed::WrongCode),
+    }
+}
+
 // Makes an of access token.
 #[instrument(skip(state))]
 pub async fn make_token(state: &web::Data<AppState>, username: &str) -> anyhow::Result<Token> {
+    make_session_token(state, username, None).await
+}
+
+/// Makes an access token, remembering which client it was made for so the
+/// user can tell their sessions apart.
+#[instrument(skip(state))]
+pub async fn make_session_token(
+    state: &web::Data<AppState>,
+    username: &str,
+    client: Option<String>,
+) -> anyhow::Result<Token> {
     // generate and save token
     let access_token = Token::new();
 
@@ -248,8 +561,9 @@ pub async fn make_token(state: &web::Data<AppState>, username: &str) -> anyhow::
         .unwrap_or_log()
         .id;
 
+    let token_hash = state.hash_token(&access_token);
     let existing_token = user_token::Entity::find()
-        .filter(user_token::Column::Token.eq(access_token.reveal()))
+        .filter(user_token::Column::Token.eq(&token_hash))
         .one(&state.db)
         .await?;
 
@@ -258,10 +572,14 @@ pub async fn make_token(state: &web::Data<AppState>, username: &str) -> anyhow::
         "Extremely rare token collision! You're the one-in-a-trillion unlucky, or there's something wrong with the random number generator."
     );
 
+    let now = chrono::Utc::now().to_rfc3339();
     let user_token = user_token::ActiveModel {
-        token: Set(access_token.reveal().to_string()),
+        token: Set(token_hash),
         user_id: Set(user_id),
-        created_at: Set(chrono::Utc::now().to_rfc3339()),
+        created_at: Set(now.clone()),
+        session_id: Set(nanoid!()),
+        client: Set(client),
+        last_used_at: Set(now),
     };
     user_token.insert(&state.db).await?;
 
@@ -269,18 +587,24 @@ pub async fn make_token(state: &web::Data<AppState>, username: &str) -> anyhow::
 }
 
 #[post("/login")]
-#[instrument(skip(data, state))]
+#[instrument(skip(req, data, state))]
 pub async fn login(
+    req: HttpRequest,
     data: web::Json<Login>,
     state: web::Data<AppState>,
 ) -> Result<web::Json<LoginResponse>, LoginFailed> {
-    if verify_pass(&data.username, &data.password, &state.db)
+    verify_login(&state, &data.username, &data.password, data.code.as_deref()).await?;
+    let access_token = make_session_token(&state, &data.username, client_info(&req))
         .await
-        .is_ok()
-    {
-        let access_token = make_token(&state, &data.username).await.unwrap_or_log();
+        .unwrap_or_log();
 
-        return Ok(web::Json(LoginResponse { access_token }));
-    }
-    Err(LoginFailed)
+    let expires_at = state
+        .tokens
+        .expires_at(chrono::Utc::now())
+        .map(|at| at.to_rfc3339());
+
+    Ok(web::Json(LoginResponse {
+        access_token,
+        expires_at,
+    }))
 }
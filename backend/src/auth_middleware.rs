@@ -5,15 +5,27 @@ use actix_web::body::EitherBody;
 use actix_web::dev::{self, ServiceRequest, ServiceResponse};
 use actix_web::dev::{Service, Transform};
 use actix_web::{http, web, Error, HttpMessage, HttpRequest, HttpResponse};
-use chrono::{DateTime, Utc};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
 use futures::future::LocalBoxFuture;
 use qstring::QString;
 use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
 use serde::Serialize;
 use tracing_unwrap::ResultExt;
 
+use crate::api_key::{is_api_key, verify_api_key};
+use crate::auth::{verify_login, Password};
 use crate::entity::{path_token, user, user_token};
-use crate::state::{AppState, Authorized, Token, Username};
+use crate::group::user_groups;
+use crate::proxy_auth::verify_proxy_user;
+use crate::ratelimit_middleware::{too_many_requests, RateLimit};
+use crate::session::use_token;
+use crate::share::{
+    counts_as_download, is_active, is_expired, return_download, share_grant_from_request,
+    take_download, verify_share_grant, DownloadResponse,
+};
+use crate::state::{AppState, Authorized, SharedAccess, Token, Username};
+use crate::user_share::{is_read_method, shared_paths};
 
 #[derive(Clone)]
 pub struct CheckLogin {
@@ -22,6 +34,11 @@ pub struct CheckLogin {
     /// If true, path tokens attached as query parameters `?token=...` will be
     /// accepted as long as the token is correct for the given path
     pub allow_path_tokens: bool,
+    /// If set, the username and password can be sent with HTTP Basic
+    /// authentication instead of a token. This is meant for clients like
+    /// WebDAV that can't log in to get a token. Failed attempts are throttled
+    /// with this rate limit, like logins are.
+    pub basic_auth: Option<RateLimit>,
 }
 
 impl<S: 'static, B> Transform<S, ServiceRequest> for CheckLogin
@@ -40,6 +57,7 @@ where
             service: Rc::new(service),
             state: self.state.clone(),
             allow_path_tokens: self.allow_path_tokens,
+            basic_auth: self.basic_auth.clone(),
         }))
     }
 }
@@ -47,6 +65,7 @@ pub struct CheckLoginMiddleware<S> {
     service: Rc<S>,
     state: web::Data<AppState>,
     allow_path_tokens: bool,
+    basic_auth: Option<RateLimit>,
 }
 
 impl<S: 'static, B> Service<ServiceRequest> for CheckLoginMiddleware<S>
@@ -65,29 +84,139 @@ where
         let (request, payload) = req.into_parts();
         let service = self.service.clone();
 
+        // A trusted proxy already logged the user in, nothing else is needed.
+        let proxy_user = state
+            .proxy_auth
+            .as_ref()
+            .and_then(|proxy_auth| proxy_auth.username(&request));
+        let basic_auth = match &self.basic_auth {
+            Some(_) if proxy_user.is_none() => get_basic_auth_from_header(&request),
+            _ => None,
+        };
+        // Clients that guessed wrong too many times can't try again yet
+        let retry_after = match (&self.basic_auth, &basic_auth) {
+            (Some(rate_limit), Some(_)) => rate_limit.retry_after(&request),
+            _ => None,
+        };
         // The user token could be either in the header or the cookie, we allow both.
-        let user_token =
-            get_token_from_header(&request).or_else(|| get_token_from_cookie(&request));
+        // API keys are sent the same way.
+        let user_token = if basic_auth.is_some() || proxy_user.is_some() {
+            None
+        } else {
+            get_user_token(&request)
+        };
         // Path tokens are part of the query path.
         let path_token = if self.allow_path_tokens {
             get_token_from_query(&request)
         } else {
             None
         };
+        let share_grant = path_token
+            .as_ref()
+            .and_then(|token| share_grant_from_request(&request, token.reveal()));
+        let is_download = counts_as_download(&request);
+
+        let basic_auth_limit = self.basic_auth.clone();
 
         Box::pin(async move {
-            match verify_auth(state, user_token, path_token, request.path()).await {
+            if let Some(retry_after) = retry_after {
+                // The password isn't even checked
+                let response = too_many_requests(retry_after).map_into_right_body();
+                return Ok(ServiceResponse::new(request, response));
+            }
+            let share = match path_token {
+                Some(path_token) => {
+                    verify_path_token(&state, path_token, share_grant, request.path(), is_download)
+                        .await
+                }
+                None => None,
+            };
+            let authorized = match basic_auth {
+                Some((username, password)) => {
+                    let authorized = verify_basic_auth(state.clone(), username, password).await;
+                    if let (Err(_), Some(rate_limit)) = (&authorized, &basic_auth_limit) {
+                        rate_limit.count_failure(&request);
+                    }
+                    authorized
+                }
+                None => verify_auth(state.clone(), proxy_user, user_token, share.is_some()).await,
+            };
+            let authorized = match authorized {
+                Ok(Authorized::User(user)) => {
+                    Ok(with_shared_access(&state, user, request.method()).await)
+                }
+                // API keys can only make the requests their scope allows
+                Ok(Authorized::Key(_, access)) if !access.allows(request.method()) => {
+                    tracing::debug!("API key scope doesn't allow the request");
+                    Err(AuthMiddlewareError::KeyScope)
+                }
+                authorized => authorized,
+            };
+            // Only downloads use up the link, and not when owners are
+            // looking at their own files
+            let share = share.filter(|share| {
+                is_download
+                    && match &authorized {
+                        Ok(Authorized::Both(user)) => {
+                            share.store.as_deref() != Some(user.0.as_str())
+                        }
+                        _ => true,
+                    }
+            });
+            // The download is taken before the file is sent, so that
+            // downloads happening at the same time can't go over the limit
+            let (share, authorized) = match share {
+                Some(share) if authorized.is_ok() => {
+                    if take_download(&state, &share).await? {
+                        (Some(share), authorized)
+                    } else {
+                        tracing::debug!("Share link has no downloads left");
+                        let authorized = match authorized {
+                            Ok(Authorized::Both(user)) => Ok(Authorized::User(user)),
+                            _ => Err(AuthMiddlewareError::Failed),
+                        };
+                        (None, authorized)
+                    }
+                }
+                _ => (None, authorized),
+            };
+            match authorized {
                 Ok(authorized) => {
                     tracing::debug!("Request authorized, inserting authorization token");
                     request.extensions_mut().insert(authorized);
-                    let service_request =
-                        service.call(ServiceRequest::from_parts(request, payload));
-                    service_request
-                        .await
-                        .map(ServiceResponse::map_into_left_body)
+                    let response = service
+                        .call(ServiceRequest::from_parts(request, payload))
+                        .await?;
+                    if let Some(share) = share {
+                        let downloaded = response.status().is_success()
+                            && response
+                                .response()
+                                .extensions()
+                                .contains::<DownloadResponse>();
+                        if !downloaded {
+                            if let Err(err) = return_download(&state, &share).await {
+                                tracing::error!(error = ?err, "Failed to give back the download");
+                            }
+                        }
+                    }
+                    Ok(response.map_into_left_body())
+                }
+                Err(AuthMiddlewareError::KeyScope) => {
+                    let response = HttpResponse::Forbidden()
+                        .json(AuthMiddlewareError::KeyScope)
+                        .map_into_right_body();
+                    Ok(ServiceResponse::new(request, response))
                 }
                 Err(err) => {
-                    let response = HttpResponse::Unauthorized().json(err).map_into_right_body();
+                    let mut response = HttpResponse::Unauthorized();
+                    if basic_auth_limit.is_some() {
+                        // Prompts clients to send the username and password
+                        response.insert_header((
+                            http::header::WWW_AUTHENTICATE,
+                            r#"Basic realm="Bulgur Cloud", charset="UTF-8""#,
+                        ));
+                    }
+                    let response = response.json(err).map_into_right_body();
 
                     Ok(ServiceResponse::new(request, response))
                 }
@@ -100,63 +229,139 @@ where
 enum AuthMiddlewareError {
     #[error("Auth token is missing or invalid, but authorization is required for this route.")]
     Failed,
+    #[error("The API key is not allowed to do this.")]
+    KeyScope,
 }
 type AuthMiddlewareResult<T> = std::result::Result<T, AuthMiddlewareError>;
 
-pub const PATH_TOKENS_VALID_SECONDS: i64 = 24 * 60 * 60;
+/// Scopes where a folder token works. The token is made for the `/storage`
+/// path of the folder, but it can also be used to browse the same folder in
+/// the basic interface.
+const FOLDER_TOKEN_SCOPES: [&str; 2] = ["storage", "basic"];
+
+/// Splits a decoded path into its segments. Returns `None` if any segment is
+/// `.` or `..`, these are never needed and could be used to escape a shared
+/// folder.
+pub(crate) fn path_segments(path: &str) -> Option<Vec<&str>> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    if segments.iter().any(|s| *s == "." || *s == "..") {
+        None
+    } else {
+        Some(segments)
+    }
+}
+
+/// Checks if a folder token made for `token_path` allows access to
+/// `request_path`, which must be the folder itself or something inside it.
+/// Both paths must already be decoded.
+fn folder_token_covers(token_path: &str, request_path: &str) -> bool {
+    let (Some(token_path), Some(request_path)) =
+        (path_segments(token_path), path_segments(request_path))
+    else {
+        return false;
+    };
+    match (token_path.split_first(), request_path.split_first()) {
+        (Some((_, folder)), Some((scope, requested))) => {
+            // A token for a whole scope would be far too broad
+            !folder.is_empty()
+                && FOLDER_TOKEN_SCOPES.contains(scope)
+                && requested.starts_with(folder)
+        }
+        _ => false,
+    }
+}
+
+/// Finds the path token, if it is valid for the given path and can still be
+/// used. Share links with a password also need the right password.
+///
+/// Once the downloads of a share link are used up, it only works for
+/// requests that continue a download, see [`counts_as_download`].
+#[tracing::instrument(skip(state, grant))]
+async fn verify_path_token(
+    state: &AppState,
+    path_token: Token,
+    grant: Option<String>,
+    path: &str,
+    is_download: bool,
+) -> Option<path_token::Model> {
+    let path = urlencoding::decode(path).ok()?;
+    tracing::debug!("Found path token attached to request {:?}", path);
+
+    let known_token = path_token::Entity::find()
+        .filter(path_token::Column::Token.eq(state.hash_token(&path_token)))
+        .one(&state.db)
+        .await
+        .unwrap_or_log()?;
+
+    tracing::debug!("Token exists for path {:?}", &path);
+    let covers_path = if known_token.is_folder {
+        folder_token_covers(&known_token.path, &path)
+    } else {
+        known_token.path.eq(&path)
+    };
+    // File drops are only for uploads, they are handled by the share pages
+    let now = Utc::now();
+    let usable = if is_download {
+        is_active(&known_token, now)
+    } else {
+        !is_expired(&known_token, now)
+    };
+    if known_token.file_drop || !covers_path || !usable {
+        return None;
+    }
+    if !verify_share_grant(state, &known_token, grant.as_deref()) {
+        tracing::debug!("Share password was not entered");
+        return None;
+    }
+    Some(known_token)
+}
 
 #[tracing::instrument(skip(state))]
-/// If the user token is valid, returns the user. If user token is missing or is invalid, then tries the path token.
-/// If the path token is valid for the given path, then returns Either::Right.
-/// If the path token is missing or invalid too, then returns `AuthMiddlewareError.TokenMissing`.
+/// If the user token or API key is valid, or a trusted proxy logged the user
+/// in, returns the user. If none worked, then checks if the path token was
+/// valid.
 async fn verify_auth(
     state: web::Data<AppState>,
+    proxy_user: Option<String>,
     user_token: Option<Token>,
-    path_token: Option<Token>,
-    path: &str,
+    path_authorized: bool,
 ) -> AuthMiddlewareResult<Authorized> {
     tracing::trace!("Starting to verify user");
 
-    let username = if let Some(user_token) = user_token {
+    let username = if let Some(username) = proxy_user {
+        tracing::debug!("Found user from a trusted proxy");
+        match verify_proxy_user(&state, &username).await {
+            Ok(()) => Some(username),
+            Err(err) => {
+                tracing::warn!(error = %err, username, "Rejected the user sent by the proxy");
+                None
+            }
+        }
+    } else if let Some(key) = user_token.as_ref().filter(|token| is_api_key(token)) {
+        tracing::debug!("Found API key attached to request");
+        if let Some((username, access)) = verify_api_key(&state, key).await.unwrap_or_log() {
+            tracing::debug!("Authorized API key");
+            return Ok(Authorized::Key(username, access));
+        }
+        None
+    } else if let Some(user_token) = user_token {
         tracing::debug!("Found user token attached to request");
 
-        user_token::Entity::find()
-            .filter(user_token::Column::Token.eq(user_token.reveal()))
+        let found = user_token::Entity::find()
+            .filter(user_token::Column::Token.eq(state.hash_token(&user_token)))
             .find_also_related(user::Entity)
             .one(&state.db)
             .await
-            .unwrap_or_log()
-            .and_then(|u| u.1)
-            .map(|u| u.username)
-    } else {
-        None
-    };
-
-    let path_authorized = (if let Some(path_token) = path_token {
-        if let Ok(path) = urlencoding::decode(path) {
-            tracing::debug!("Found path token attached to request {:?}", path);
-
-            let known_token = path_token::Entity::find()
-                .filter(path_token::Column::Token.eq(path_token.reveal()))
-                .one(&state.db)
-                .await
-                .unwrap_or_log();
-
-            tracing::debug!("Token exists for path {:?}", &path);
-            known_token.map(|known_token| {
-                let created_at =
-                    DateTime::parse_from_rfc3339(&known_token.created_at).unwrap_or_log();
-
-                let seconds_old = Utc::now().signed_duration_since(created_at).num_seconds();
-                known_token.path.eq(&path) && seconds_old < PATH_TOKENS_VALID_SECONDS
-            })
-        } else {
-            None
+            .unwrap_or_log();
+        match found {
+            Some((token, Some(user))) if use_token(&state, &token).await.unwrap_or_log() => {
+                Some(user.username)
+            }
+            _ => None,
         }
     } else {
         None
-    })
-    .unwrap_or(false);
+    };
 
     if let Some(username) = username {
         if path_authorized {
@@ -175,6 +380,74 @@ async fn verify_auth(
     }
 }
 
+/// Adds the stores of the user's groups, and the paths other users shared with
+/// them if the shares allow the method of the request.
+async fn with_shared_access(state: &AppState, user: Username, method: &http::Method) -> Authorized {
+    let write = !is_read_method(method);
+    let access = SharedAccess {
+        groups: user_groups(state, &user.0).await.unwrap_or_log(),
+        paths: shared_paths(state, &user.0, write).await.unwrap_or_log(),
+    };
+    if access.is_empty() {
+        Authorized::User(user)
+    } else {
+        tracing::debug!("User has access to shared stores or paths");
+        Authorized::Shared(user, access)
+    }
+}
+
+#[tracing::instrument(skip(state))]
+/// Checks a username and password sent with HTTP Basic authentication.
+async fn verify_basic_auth(
+    state: web::Data<AppState>,
+    username: String,
+    password: Password,
+) -> AuthMiddlewareResult<Authorized> {
+    // Scripts can use an API key instead of the password
+    let key = Token::read(&password.0);
+    if is_api_key(&key) {
+        return match verify_api_key(&state, &key).await.unwrap_or_log() {
+            Some((user, access)) if user.0 == username => {
+                tracing::debug!("Authorized API key with basic auth");
+                Ok(Authorized::Key(user, access))
+            }
+            _ => {
+                tracing::debug!("Basic auth failed");
+                Err(AuthMiddlewareError::Failed)
+            }
+        };
+    }
+    // Users with two-factor authentication can't log in with just a password
+    match verify_login(&state, &username, &password, None).await {
+        Ok(_) => {
+            tracing::debug!("Authorized user with basic auth");
+            Ok(Authorized::User(Username(username)))
+        }
+        Err(_) => {
+            tracing::debug!("Basic auth failed");
+            Err(AuthMiddlewareError::Failed)
+        }
+    }
+}
+
+fn get_basic_auth_from_header(request: &HttpRequest) -> Option<(String, Password)> {
+    let header = request
+        .headers()
+        .get(http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?;
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), Password(password.to_string())))
+}
+
+/// The user token of the request. It could be either in the header or the
+/// cookie.
+pub(crate) fn get_user_token(request: &HttpRequest) -> Option<Token> {
+    get_token_from_header(request).or_else(|| get_token_from_cookie(request))
+}
+
 fn get_token_from_header(request: &HttpRequest) -> Option<Token> {
     let header_token = request.headers().get(http::header::AUTHORIZATION);
     if let Some(token) = header_token {
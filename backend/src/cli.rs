@@ -5,10 +5,17 @@ use rpassword::prompt_password;
 use clap::{Parser, Subcommand};
 
 use crate::{
-    auth::{add_new_user, create_user_folder, delete_user, validate_username},
+    api_key::{create_api_key, list_api_keys, revoke_api_key, ApiKeyOptions, ApiKeyScope},
+    auth::{add_new_user, create_user_folder, delete_user, validate_username, AuthOptions},
     db::get_db,
+    group::{add_member, create_group, delete_group, remove_member},
+    kv::KVOptions,
+    quota::set_quota,
     server::setup_app_deps,
+    session::TokenOptions,
     state::UserType,
+    totp::reset_totp,
+    versions::VersionOptions,
 };
 
 #[derive(Parser, Debug)]
@@ -36,6 +43,25 @@ pub struct UserRemove {
     pub delete_files: bool,
 }
 
+#[derive(Parser, Debug)]
+/// Limit how much a user can store.
+pub struct UserQuota {
+    #[clap(short, long)]
+    pub username: String,
+    #[clap(long)]
+    /// The most bytes the user can store. Leave this out to remove the limit.
+    pub quota: Option<u64>,
+}
+
+#[derive(Parser, Debug)]
+/// Turn off two-factor authentication for a user who lost their authenticator
+/// and recovery codes. They can log in with just their password, and set it up
+/// again.
+pub struct UserResetTotp {
+    #[clap(short, long)]
+    pub username: String,
+}
+
 #[derive(Subcommand, Debug)]
 /// Manage users, who can edit the survey and view results.
 pub enum User {
@@ -43,12 +69,115 @@ pub enum User {
     UserAdd(UserAdd),
     #[clap(name = "remove")]
     UserRemove(UserRemove),
+    #[clap(name = "quota")]
+    UserQuota(UserQuota),
+    #[clap(name = "reset-2fa")]
+    UserResetTotp(UserResetTotp),
+}
+
+#[derive(Parser, Debug)]
+/// Add a new group. The group gets its own store, which all members can use.
+pub struct GroupAdd {
+    #[clap(short, long)]
+    pub name: String,
+}
+
+#[derive(Parser, Debug)]
+/// Remove a group.
+pub struct GroupRemove {
+    #[clap(short, long)]
+    pub name: String,
+    #[clap(name = "delete-files", long)]
+    /// Delete the store for this group. Files will be removed, and may be irrecoverable.
+    pub delete_files: bool,
+}
+
+#[derive(Parser, Debug)]
+/// Add a user to a group, or change whether they are an admin of it.
+pub struct GroupAddMember {
+    #[clap(short, long)]
+    pub group: String,
+    #[clap(short, long)]
+    pub username: String,
+    #[clap(long)]
+    /// Admins of the group can add and remove members.
+    pub admin: bool,
+}
+
+#[derive(Parser, Debug)]
+/// Remove a user from a group.
+pub struct GroupRemoveMember {
+    #[clap(short, long)]
+    pub group: String,
+    #[clap(short, long)]
+    pub username: String,
+}
+
+#[derive(Subcommand, Debug)]
+/// Manage groups, which own a store together.
+pub enum Group {
+    #[clap(name = "add")]
+    GroupAdd(GroupAdd),
+    #[clap(name = "remove")]
+    GroupRemove(GroupRemove),
+    #[clap(name = "add-member")]
+    GroupAddMember(GroupAddMember),
+    #[clap(name = "remove-member")]
+    GroupRemoveMember(GroupRemoveMember),
+}
+
+#[derive(Parser, Debug)]
+/// Make an API key for a user, for scripts that shouldn't use their password.
+/// The key is only shown once.
+pub struct KeyAdd {
+    #[clap(short, long)]
+    pub username: String,
+    #[clap(short, long)]
+    /// Tells the keys of the user apart, like the script that uses it.
+    pub name: String,
+    #[clap(long, value_enum, default_value_t = ApiKeyScope::Read)]
+    pub scope: ApiKeyScope,
+    #[clap(long)]
+    /// Limit the key to a file or folder in the store of the user.
+    pub path: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+/// List the API keys of a user.
+pub struct KeyList {
+    #[clap(short, long)]
+    pub username: String,
+}
+
+#[derive(Parser, Debug)]
+/// Revoke an API key of a user.
+pub struct KeyRemove {
+    #[clap(short, long)]
+    pub username: String,
+    #[clap(short, long)]
+    /// The name or ID of the key.
+    pub name: String,
+}
+
+#[derive(Subcommand, Debug)]
+/// Manage API keys, which let scripts use the server as a user.
+pub enum Key {
+    #[clap(name = "add")]
+    KeyAdd(KeyAdd),
+    #[clap(name = "list")]
+    KeyList(KeyList),
+    #[clap(name = "remove")]
+    KeyRemove(KeyRemove),
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     #[clap(subcommand)]
     User(User),
+    #[clap(subcommand)]
+    Group(Group),
+    #[clap(subcommand)]
+    Key(Key),
 }
 
 #[derive(Parser)]
@@ -79,6 +208,24 @@ pub struct Opt {
     /// is set to the number of CPU threads. The actual number of threads
     /// launched may be greater than this.
     pub workers: usize,
+
+    #[clap(long, env = "BULGUR_CLOUD_TRASH_RETENTION_DAYS", default_value_t = 30)]
+    /// Deleted files and folders are kept in the trash for this many days,
+    /// then they are removed for good. Set to 0 to keep them until the trash
+    /// is emptied by hand.
+    pub trash_retention_days: u32,
+
+    #[clap(flatten)]
+    pub kv: KVOptions,
+
+    #[clap(flatten)]
+    pub versions: VersionOptions,
+
+    #[clap(flatten)]
+    pub tokens: TokenOptions,
+
+    #[clap(flatten)]
+    pub auth: AuthOptions,
 }
 
 pub trait CLIContext {
@@ -104,22 +251,113 @@ pub async fn cli_command<Ctx: CLIContext>(opt: Opt) -> anyhow::Result<()> {
                         None => Ctx::prompt_password()?,
                     };
                     let connection = get_db(&opt.datastore).await?;
-                    let (state, _) = setup_app_deps(env::current_dir().unwrap(), connection)
-                        .await
-                        .unwrap();
+                    let (state, _) = setup_app_deps(
+                        env::current_dir().unwrap(),
+                        connection,
+                        &opt.kv,
+                        &opt.versions,
+                        &opt.tokens,
+                        &opt.auth,
+                    )
+                    .await
+                    .unwrap();
 
-                    add_new_user(&add.username, &password, add.user_type, &state.db).await?;
-                    create_user_folder(&add.username).await?;
+                    add_new_user(&add.username, &password, add.user_type, &state).await?;
+                    create_user_folder(&state, &add.username).await?;
                 }
                 User::UserRemove(remove) => {
                     let connection = get_db(&opt.datastore).await?;
-                    let (state, _) = setup_app_deps(env::current_dir().unwrap(), connection)
-                        .await
-                        .unwrap();
+                    let (state, _) = setup_app_deps(
+                        env::current_dir().unwrap(),
+                        connection,
+                        &opt.kv,
+                        &opt.versions,
+                        &opt.tokens,
+                        &opt.auth,
+                    )
+                    .await
+                    .unwrap();
 
-                    delete_user(&state.db, &remove.username, remove.delete_files).await?
+                    delete_user(&state, &remove.username, remove.delete_files).await?
+                }
+                User::UserQuota(quota) => {
+                    let connection = get_db(&opt.datastore).await?;
+                    set_quota(&connection, &quota.username, quota.quota).await?;
+                }
+                User::UserResetTotp(reset) => {
+                    let connection = get_db(&opt.datastore).await?;
+                    reset_totp(&connection, &reset.username)
+                        .await
+                        .map_err(|err| anyhow::anyhow!("{err}"))?;
                 }
             },
+            Commands::Group(group) => {
+                let connection = get_db(&opt.datastore).await?;
+                let (state, _) = setup_app_deps(
+                    env::current_dir().unwrap(),
+                    connection,
+                    &opt.kv,
+                    &opt.versions,
+                    &opt.tokens,
+                    &opt.auth,
+                )
+                .await
+                .unwrap();
+
+                let result = match group {
+                    Group::GroupAdd(add) => create_group(&state, &add.name).await,
+                    Group::GroupRemove(remove) => {
+                        delete_group(&state, &remove.name, remove.delete_files).await
+                    }
+                    Group::GroupAddMember(add) => {
+                        add_member(&state, &add.group, &add.username, add.admin).await
+                    }
+                    Group::GroupRemoveMember(remove) => {
+                        remove_member(&state, &remove.group, &remove.username).await
+                    }
+                };
+                result.map_err(|err| anyhow::anyhow!("{err}"))?;
+            }
+            Commands::Key(key) => {
+                let connection = get_db(&opt.datastore).await?;
+                let (state, _) = setup_app_deps(
+                    env::current_dir().unwrap(),
+                    connection,
+                    &opt.kv,
+                    &opt.versions,
+                    &opt.tokens,
+                    &opt.auth,
+                )
+                .await
+                .unwrap();
+
+                match key {
+                    Key::KeyAdd(add) => {
+                        let options = ApiKeyOptions {
+                            name: add.name,
+                            scope: add.scope,
+                            path: add.path,
+                        };
+                        let new_key = create_api_key(&state, &add.username, &options).await?;
+                        println!("{}", new_key.key.reveal());
+                    }
+                    Key::KeyList(list) => {
+                        for key in list_api_keys(&state, &list.username).await? {
+                            println!(
+                                "{}\t{}\t{}\t{}\t{}",
+                                key.id,
+                                key.name,
+                                key.scope.db_value(),
+                                key.path.as_deref().unwrap_or("/"),
+                                key.created_at
+                            );
+                        }
+                    }
+                    Key::KeyRemove(remove) => {
+                        revoke_api_key(&state, &remove.username, &remove.name).await?
+                    }
+                }
+            }
         },
     };
     Ok(())
@@ -0,0 +1,418 @@
+//! A WebDAV interface to the stores, so they can be mounted in file managers
+//! and tools like rclone. It is mounted under `/dav` and uses the same store
+//! layout as `/storage`.
+use std::{io, path::Path, str::FromStr};
+
+use actix_web::{
+    http::{
+        header::{self, HttpDate},
+        Method, StatusCode,
+    },
+    web::{self, Payload, ReqData},
+    Either, HttpRequest, HttpResponse,
+};
+use futures::StreamExt;
+use nanoid::nanoid;
+
+use crate::{
+    kv::Metadata,
+    quota::{add_usage, check_quota, path_size, QuotaBudget},
+    state::{AppState, Authorized},
+    storage::{
+        common_delete, get_authorized_path, get_storage_internal, parse_params, parse_store_path,
+        StorageError,
+    },
+    versions::{copy_to_versions, move_to_versions},
+};
+
+/// All the routes of the WebDAV interface start with this.
+pub const DAV_PREFIX: &str = "/dav";
+
+/// How long clients are told that their locks last.
+const LOCK_TIMEOUT_SECONDS: u32 = 3600;
+
+/// The methods the WebDAV interface responds to, on top of the standard
+/// `GET`, `HEAD`, `PUT`, `DELETE` and `OPTIONS`.
+pub const DAV_METHODS: [&str; 6] = ["PROPFIND", "MKCOL", "MOVE", "COPY", "LOCK", "UNLOCK"];
+
+pub fn dav_method(name: &str) -> Method {
+    Method::from_str(name).unwrap()
+}
+
+#[tracing::instrument]
+pub async fn dav_options() -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("DAV", "1, 2"))
+        .insert_header((
+            header::ALLOW,
+            format!(
+                "OPTIONS, GET, HEAD, PUT, DELETE, {}",
+                DAV_METHODS.join(", ")
+            ),
+        ))
+        // Windows won't mount the folder without this
+        .insert_header(("MS-Author-Via", "DAV"))
+        .finish()
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn dav_get(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    params: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, path) = parse_params(&params);
+    match get_storage_internal(&req, &state, (store, path), &authorized).await? {
+        Either::Left(file) => Ok(file),
+        Either::Right(folder) => Ok(HttpResponse::Ok().json(folder.0)),
+    }
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn dav_head(
+    state: web::Data<AppState>,
+    params: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, path) = parse_params(&params);
+    let store_path = get_authorized_path(&authorized, store, Some(path))?;
+    let meta = state.storage.metadata(&store_path).await?;
+
+    let mut response = HttpResponse::Ok();
+    if meta.is_file {
+        response
+            .content_type(mime_guess::from_path(&store_path).first_or_octet_stream())
+            .no_chunking(meta.size);
+    }
+    if let Some(modified) = meta.modified {
+        response.insert_header(header::LastModified(modified.into()));
+    }
+    Ok(response.finish())
+}
+
+#[tracing::instrument(skip(state, payload))]
+pub async fn dav_put(
+    state: web::Data<AppState>,
+    params: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+    mut payload: Payload,
+) -> Result<HttpResponse, StorageError> {
+    let (store, path) = parse_params(&params);
+    let store_path = get_authorized_path(&authorized, store, Some(path))?;
+    let (folder, name) = split_path(&store_path)?;
+
+    let existing = state.storage.metadata(&store_path).await.ok();
+    let existed = existing.is_some();
+    let replaced = existing
+        .filter(|meta| meta.is_file)
+        .map(|meta| meta.size)
+        .unwrap_or(0);
+    let mut budget = QuotaBudget::for_store(&state, store).await?;
+    // The file that is being overwritten makes room for the new one
+    budget.credit(replaced);
+    let mut part = state.storage.create_part(folder, name).await?;
+    let mut written: Result<(), StorageError> = Ok(());
+    while let Some(chunk) = payload.next().await {
+        written = match chunk {
+            Ok(chunk) => match budget.spend(chunk.len() as u64) {
+                Ok(()) => part.write(&chunk).await.map_err(StorageError::from),
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(io::Error::other(err).into()),
+        };
+        if written.is_err() {
+            break;
+        }
+    }
+    if written.is_ok() && existed {
+        // Keep what is about to be overwritten
+        written = copy_to_versions(&state, &store_path).await.map(|_| ());
+    }
+    if written.is_ok() {
+        written = part.replace(&store_path).await.map_err(StorageError::from);
+    }
+    if let Err(err) = written {
+        if let Err(discard_err) = part.discard().await {
+            tracing::warn!(error = ?discard_err, "Failed to clean up a failed upload");
+        }
+        return Err(err);
+    }
+    add_usage(&state, store, budget.used() as i64 - replaced as i64).await?;
+
+    if existed {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Ok(HttpResponse::Created().finish())
+    }
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn dav_delete(
+    state: web::Data<AppState>,
+    params: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, path) = parse_params(&params);
+    common_delete(&state, &authorized, store, Some(path)).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn dav_mkcol(
+    state: web::Data<AppState>,
+    params: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, path) = parse_params(&params);
+    let store_path = get_authorized_path(&authorized, store, Some(path))?;
+    match state.storage.create_dir(&store_path).await {
+        Ok(_) => Ok(HttpResponse::Created().finish()),
+        // MKCOL is only allowed on paths that don't exist yet
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            Ok(HttpResponse::MethodNotAllowed().finish())
+        }
+        // The parent folder is missing
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(HttpResponse::Conflict().finish()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn dav_move(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    params: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    transfer(req, state, params, authorized, Transfer::Move).await
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn dav_copy(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    params: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    transfer(req, state, params, authorized, Transfer::Copy).await
+}
+
+enum Transfer {
+    Move,
+    Copy,
+}
+
+async fn transfer(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    params: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+    transfer: Transfer,
+) -> Result<HttpResponse, StorageError> {
+    let (store, path) = parse_params(&params);
+    let store_path = get_authorized_path(&authorized, store, Some(path))?;
+
+    let destination = req
+        .headers()
+        .get("Destination")
+        .and_then(|destination| destination.to_str().ok())
+        .and_then(destination_path)
+        .ok_or(StorageError::BadPath)?;
+    // A destination with `..` in it could point into another store
+    let (to_store, to_path) = parse_store_path(&destination).ok_or(StorageError::NotAuthorized)?;
+    let to_store_path = get_authorized_path(&authorized, to_store, Some(&to_path))?;
+
+    let overwrite = req
+        .headers()
+        .get("Overwrite")
+        .map(|overwrite| overwrite.as_bytes() != b"F")
+        .unwrap_or(true);
+    let existed = state.storage.metadata(&to_store_path).await.is_ok();
+    if existed && !overwrite {
+        return Ok(HttpResponse::PreconditionFailed().finish());
+    }
+    let size = path_size(state.storage.as_ref(), &store_path).await?;
+    let replaced = if existed {
+        path_size(state.storage.as_ref(), &to_store_path).await?
+    } else {
+        0
+    };
+    let grows_by = match transfer {
+        Transfer::Move if store == to_store => 0,
+        _ => size.saturating_sub(replaced),
+    };
+    check_quota(&state, to_store, grows_by).await?;
+    if existed {
+        // Files that get replaced are kept as a version, folders are removed
+        if !move_to_versions(&state, &to_store_path).await? {
+            state.storage.remove(&to_store_path).await?;
+        }
+    }
+
+    match transfer {
+        Transfer::Move => {
+            state.storage.rename(&store_path, &to_store_path).await?;
+            add_usage(&state, store, -(size as i64)).await?;
+        }
+        Transfer::Copy => state.storage.copy(&store_path, &to_store_path).await?,
+    }
+    add_usage(&state, to_store, size as i64 - replaced as i64).await?;
+
+    if existed {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Ok(HttpResponse::Created().finish())
+    }
+}
+
+/// Turns the `Destination` header, which is a full URL, into a store and
+/// path.
+fn destination_path(destination: &str) -> Option<String> {
+    // Strip the scheme and host if there is one
+    let path = match destination.split_once("://") {
+        Some((_, rest)) => rest.find('/').map(|start| &rest[start..])?,
+        None => destination,
+    };
+    let path = path.strip_prefix(DAV_PREFIX)?;
+    urlencoding::decode(path).ok().map(|path| path.to_string())
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn dav_propfind(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    params: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, path) = parse_params(&params);
+    let store_path = get_authorized_path(&authorized, store, Some(path))?;
+    let meta = state.storage.metadata(&store_path).await?;
+
+    let href = href_for(store, path, !meta.is_file);
+    let mut body =
+        String::from(r#"<?xml version="1.0" encoding="utf-8"?><D:multistatus xmlns:D="DAV:">"#);
+    body.push_str(&prop_response(&href, name_of(store, path), &meta));
+
+    // We don't support infinite depth, since that could mean listing every
+    // file in the store. Clients fall back to walking the folders themselves.
+    let depth = req
+        .headers()
+        .get("Depth")
+        .and_then(|depth| depth.to_str().ok())
+        .unwrap_or("infinity");
+    if !meta.is_file && depth != "0" {
+        for entry in state.storage.read_dir(&store_path).await? {
+            let child_href = format!(
+                "{href}{}{}",
+                urlencoding::encode(&entry.name),
+                if entry.meta.is_file { "" } else { "/" }
+            );
+            body.push_str(&prop_response(&child_href, &entry.name, &entry.meta));
+        }
+    }
+    body.push_str("</D:multistatus>");
+
+    Ok(HttpResponse::build(StatusCode::from_u16(207).unwrap())
+        .content_type("application/xml; charset=utf-8")
+        .body(body))
+}
+
+fn prop_response(href: &str, name: &str, meta: &Metadata) -> String {
+    let mut props = format!("<D:displayname>{}</D:displayname>", xml_escape(name));
+    if meta.is_file {
+        props.push_str("<D:resourcetype/>");
+        props.push_str(&format!(
+            "<D:getcontentlength>{}</D:getcontentlength>",
+            meta.size
+        ));
+        props.push_str(&format!(
+            "<D:getcontenttype>{}</D:getcontenttype>",
+            xml_escape(
+                mime_guess::from_path(name)
+                    .first_or_octet_stream()
+                    .essence_str()
+            )
+        ));
+    } else {
+        props.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    }
+    if let Some(modified) = meta.modified {
+        props.push_str(&format!(
+            "<D:getlastmodified>{}</D:getlastmodified>",
+            HttpDate::from(modified)
+        ));
+    }
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>{props}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        xml_escape(href)
+    )
+}
+
+/// Locks are not enforced, but some clients like Finder and Windows Explorer
+/// refuse to write files unless they can lock them first.
+#[tracing::instrument]
+pub async fn dav_lock(
+    params: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, path) = parse_params(&params);
+    get_authorized_path(&authorized, store, Some(path))?;
+
+    let token = format!("opaquelocktoken:{}", nanoid!());
+    let body = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?><D:prop xmlns:D="DAV:"><D:lockdiscovery><D:activelock><D:locktype><D:write/></D:locktype><D:lockscope><D:exclusive/></D:lockscope><D:depth>infinity</D:depth><D:timeout>Second-{LOCK_TIMEOUT_SECONDS}</D:timeout><D:locktoken><D:href>{token}</D:href></D:locktoken><D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock></D:lockdiscovery></D:prop>"#,
+        xml_escape(&href_for(store, path, false)),
+    );
+    Ok(HttpResponse::Ok()
+        .insert_header(("Lock-Token", format!("<{token}>")))
+        .content_type("application/xml; charset=utf-8")
+        .body(body))
+}
+
+#[tracing::instrument]
+pub async fn dav_unlock(
+    params: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, path) = parse_params(&params);
+    get_authorized_path(&authorized, store, Some(path))?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn split_path(path: &Path) -> Result<(&Path, &str), StorageError> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok((parent, name.to_str().ok_or(StorageError::BadPath)?)),
+        _ => Err(StorageError::BadPath),
+    }
+}
+
+fn name_of<'a>(store: &'a str, path: &'a str) -> &'a str {
+    path.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .unwrap_or(store)
+}
+
+fn href_for(store: &str, path: &str, is_folder: bool) -> String {
+    let mut href = format!("{DAV_PREFIX}/{}/", urlencoding::encode(store));
+    let segments: Vec<String> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| urlencoding::encode(segment).to_string())
+        .collect();
+    href.push_str(&segments.join("/"));
+    if is_folder && !segments.is_empty() {
+        href.push('/');
+    }
+    href
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -2,6 +2,15 @@
 
 pub mod prelude;
 
+pub mod api_key;
+pub mod file_version;
+pub mod group;
+pub mod group_member;
 pub mod path_token;
+pub mod recovery_code;
+pub mod sso_login;
+pub mod trash;
+pub mod upload;
 pub mod user;
+pub mod user_share;
 pub mod user_token;
@@ -9,6 +9,20 @@ pub struct Model {
     pub token: String,
     pub path: String,
     pub created_at: String,
+    pub is_folder: bool,
+    pub store: Option<String>,
+    pub label: Option<String>,
+    pub valid_until: Option<String>,
+    pub password_hash: Option<String>,
+    pub max_downloads: Option<i32>,
+    pub downloads: i32,
+    pub file_drop: bool,
+    pub max_file_size: Option<i64>,
+    pub max_files: Option<i32>,
+    pub uploads: i32,
+    pub is_share: bool,
+    #[sea_orm(unique)]
+    pub id: String,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
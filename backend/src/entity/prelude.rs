@@ -1,5 +1,14 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.12.4
 
+pub use super::api_key::Entity as ApiKey;
+pub use super::file_version::Entity as FileVersion;
+pub use super::group::Entity as Group;
+pub use super::group_member::Entity as GroupMember;
 pub use super::path_token::Entity as PathToken;
+pub use super::recovery_code::Entity as RecoveryCode;
+pub use super::sso_login::Entity as SsoLogin;
+pub use super::trash::Entity as Trash;
+pub use super::upload::Entity as Upload;
 pub use super::user::Entity as User;
+pub use super::user_share::Entity as UserShare;
 pub use super::user_token::Entity as UserToken;
@@ -10,14 +10,37 @@ pub struct Model {
     pub username: String,
     pub password_hash: String,
     pub user_type: String,
+    pub quota: Option<i64>,
+    pub usage: Option<i64>,
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    pub totp_last_step: Option<i64>,
+    pub sso_issuer: Option<String>,
+    pub sso_subject: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
+    #[sea_orm(has_many = "super::api_key::Entity")]
+    ApiKey,
+    #[sea_orm(has_many = "super::recovery_code::Entity")]
+    RecoveryCode,
     #[sea_orm(has_many = "super::user_token::Entity")]
     UserToken,
 }
 
+impl Related<super::api_key::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ApiKey.def()
+    }
+}
+
+impl Related<super::recovery_code::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::RecoveryCode.def()
+    }
+}
+
 impl Related<super::user_token::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::UserToken.def()
@@ -9,6 +9,10 @@ pub struct Model {
     pub token: String,
     pub user_id: String,
     pub created_at: String,
+    #[sea_orm(unique)]
+    pub session_id: String,
+    pub client: Option<String>,
+    pub last_used_at: String,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -1,3 +1,6 @@
 pub const USERS_DIR: &str = "users";
 pub const STORAGE: &str = "storage";
+pub const TRASH: &str = "trash";
+pub const VERSIONS: &str = "versions";
+pub const UPLOADS: &str = "uploads";
 pub const BANNER: &str = "banner";
@@ -0,0 +1,427 @@
+//! Groups let a team share a store, instead of every store belonging to a
+//! single user.
+//!
+//! A group has its own store under the storage folder, named after the group,
+//! so group names can't be the same as usernames. Every member of a group can
+//! do anything in its store that the owner of a store can. Admins of a group
+//! can also add and remove members. Groups are created and deleted with the
+//! CLI.
+use std::path::PathBuf;
+
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    put,
+    web::{self, ReqData},
+    HttpResponse, HttpResponseBuilder,
+};
+use chrono::Utc;
+use nanoid::nanoid;
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, IntoActiveModel,
+    ModelTrait, PaginatorTrait, QueryFilter, QueryOrder, Set,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::validate_username,
+    entity::{group, group_member, user},
+    folder,
+    state::{AppState, Authorized},
+    storage::{empty_ok_response, StorageError},
+    trash::empty_trash,
+    user_share::remove_user_shares,
+    versions::remove_store_versions,
+};
+
+#[cfg(feature = "generate_types")]
+use typescript_type_def::TypeDef;
+
+#[derive(Debug, derive_more::Display, thiserror::Error)]
+pub enum GroupError {
+    #[display(fmt = "User is not authorized to view this.")]
+    NotAuthorized,
+    #[display(fmt = "Only admins of the group can do this")]
+    NotAdmin,
+    #[display(fmt = "Group {} does not exist", _0)]
+    NotFound(String),
+    #[display(fmt = "User {} does not exist", _0)]
+    UnknownUser(String),
+    #[display(fmt = "User {} is not a member of the group", _0)]
+    NotMember(String),
+    #[display(fmt = "The name {} is already used by a user or group", _0)]
+    NameTaken(String),
+    #[display(
+        fmt = "You can't use {} as a group name. Try to avoid special characters.",
+        _0
+    )]
+    BadName(String),
+    #[display(fmt = "The group needs at least one admin")]
+    LastAdmin,
+    #[display(fmt = "Database error {}", _0)]
+    Database(#[from] DbErr),
+    #[display(fmt = "{}", _0)]
+    Storage(#[from] StorageError),
+}
+
+impl Serialize for GroupError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let s = format!("{}", self);
+        serializer.serialize_str(&s)
+    }
+}
+
+impl From<std::io::Error> for GroupError {
+    fn from(err: std::io::Error) -> Self {
+        GroupError::Storage(err.into())
+    }
+}
+
+impl actix_web::error::ResponseError for GroupError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            GroupError::NotAuthorized => StatusCode::UNAUTHORIZED,
+            GroupError::NotAdmin => StatusCode::FORBIDDEN,
+            GroupError::NotFound(_) => StatusCode::NOT_FOUND,
+            GroupError::UnknownUser(_) => StatusCode::BAD_REQUEST,
+            GroupError::NotMember(_) => StatusCode::NOT_FOUND,
+            GroupError::NameTaken(_) => StatusCode::CONFLICT,
+            GroupError::BadName(_) => StatusCode::BAD_REQUEST,
+            GroupError::LastAdmin => StatusCode::BAD_REQUEST,
+            GroupError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            GroupError::Storage(err) => err.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponseBuilder::new(self.status_code()).json(self)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct GroupMember {
+    pub username: String,
+    pub is_admin: bool,
+}
+
+impl From<group_member::Model> for GroupMember {
+    fn from(member: group_member::Model) -> Self {
+        GroupMember {
+            username: member.username,
+            is_admin: member.is_admin,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct GroupMembers {
+    pub members: Vec<GroupMember>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct UserGroup {
+    /// The name of the group, which is also the name of its store.
+    pub name: String,
+    /// True if the user can add and remove members.
+    pub is_admin: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct UserGroups {
+    pub groups: Vec<UserGroup>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct GroupMemberOptions {
+    /// Admins can add and remove members.
+    #[serde(default)]
+    pub is_admin: bool,
+}
+
+async fn find_group(state: &AppState, name: &str) -> Result<group::Model, GroupError> {
+    group::Entity::find()
+        .filter(group::Column::Name.eq(name))
+        .one(&state.db)
+        .await?
+        .ok_or_else(|| GroupError::NotFound(name.to_string()))
+}
+
+async fn find_member(
+    state: &AppState,
+    group: &str,
+    username: &str,
+) -> Result<Option<group_member::Model>, DbErr> {
+    group_member::Entity::find()
+        .filter(group_member::Column::GroupName.eq(group))
+        .filter(group_member::Column::Username.eq(username))
+        .one(&state.db)
+        .await
+}
+
+/// True if there is a group with this name.
+pub async fn group_exists(db: &DatabaseConnection, name: &str) -> Result<bool, DbErr> {
+    Ok(group::Entity::find()
+        .filter(group::Column::Name.eq(name))
+        .count(db)
+        .await?
+        > 0)
+}
+
+/// Creates a group and its store.
+#[tracing::instrument(skip(state))]
+pub async fn create_group(state: &AppState, name: &str) -> Result<(), GroupError> {
+    validate_username(name).map_err(|_| GroupError::BadName(name.to_string()))?;
+    let user_exists = user::Entity::find()
+        .filter(user::Column::Username.eq(name))
+        .count(&state.db)
+        .await?
+        > 0;
+    if user_exists || group_exists(&state.db, name).await? {
+        return Err(GroupError::NameTaken(name.to_string()));
+    }
+    group::ActiveModel {
+        id: Set(nanoid!()),
+        name: Set(name.to_string()),
+        created_at: Set(Utc::now().to_rfc3339()),
+    }
+    .insert(&state.db)
+    .await?;
+    state
+        .storage
+        .create_dir_all(&PathBuf::from(folder::STORAGE).join(name))
+        .await?;
+    Ok(())
+}
+
+/// Deletes a group and its members. The files in the store of the group are
+/// only deleted if `delete_files` is true.
+#[tracing::instrument(skip(state))]
+pub async fn delete_group(
+    state: &AppState,
+    name: &str,
+    delete_files: bool,
+) -> Result<(), GroupError> {
+    let group = find_group(state, name).await?;
+    group_member::Entity::delete_many()
+        .filter(group_member::Column::GroupName.eq(name))
+        .exec(&state.db)
+        .await?;
+    group.delete(&state.db).await?;
+    remove_user_shares(state, name).await?;
+
+    if delete_files {
+        state
+            .storage
+            .remove(&PathBuf::from(folder::STORAGE).join(name))
+            .await?;
+        empty_trash(state, name).await?;
+        remove_store_versions(state, name).await?;
+    }
+    Ok(())
+}
+
+/// Adds a user to a group, or changes whether they are an admin if they are
+/// already a member.
+#[tracing::instrument(skip(state))]
+pub async fn add_member(
+    state: &AppState,
+    group: &str,
+    username: &str,
+    is_admin: bool,
+) -> Result<(), GroupError> {
+    find_group(state, group).await?;
+    let user_exists = user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .count(&state.db)
+        .await?
+        > 0;
+    if !user_exists {
+        return Err(GroupError::UnknownUser(username.to_string()));
+    }
+    match find_member(state, group, username).await? {
+        Some(member) => {
+            let mut member = member.into_active_model();
+            member.is_admin = Set(is_admin);
+            member.update(&state.db).await?;
+        }
+        None => {
+            group_member::ActiveModel {
+                id: Set(nanoid!()),
+                group_name: Set(group.to_string()),
+                username: Set(username.to_string()),
+                is_admin: Set(is_admin),
+            }
+            .insert(&state.db)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes a user from a group.
+#[tracing::instrument(skip(state))]
+pub async fn remove_member(
+    state: &AppState,
+    group: &str,
+    username: &str,
+) -> Result<(), GroupError> {
+    let member = find_member(state, group, username)
+        .await?
+        .ok_or_else(|| GroupError::NotMember(username.to_string()))?;
+    member.delete(&state.db).await?;
+    Ok(())
+}
+
+/// Removes a user from all groups. Used when the user is deleted.
+pub async fn remove_from_groups(state: &AppState, username: &str) -> Result<(), DbErr> {
+    group_member::Entity::delete_many()
+        .filter(group_member::Column::Username.eq(username))
+        .exec(&state.db)
+        .await?;
+    Ok(())
+}
+
+/// Lists the members of a group, sorted by username.
+pub async fn list_members(state: &AppState, group: &str) -> Result<Vec<GroupMember>, GroupError> {
+    Ok(group_member::Entity::find()
+        .filter(group_member::Column::GroupName.eq(group))
+        .order_by_asc(group_member::Column::Username)
+        .all(&state.db)
+        .await?
+        .into_iter()
+        .map(GroupMember::from)
+        .collect())
+}
+
+/// Finds the names of the groups a user is a member of.
+pub async fn user_groups(state: &AppState, username: &str) -> Result<Vec<String>, DbErr> {
+    Ok(group_member::Entity::find()
+        .filter(group_member::Column::Username.eq(username))
+        .all(&state.db)
+        .await?
+        .into_iter()
+        .map(|member| member.group_name)
+        .collect())
+}
+
+/// Finds the membership of the user who made the request.
+async fn authorized_member(
+    state: &AppState,
+    authorized: &Option<ReqData<Authorized>>,
+    group: &str,
+) -> Result<group_member::Model, GroupError> {
+    let username = authorized
+        .as_deref()
+        .and_then(|authorized| authorized.username())
+        .ok_or(GroupError::NotAuthorized)?;
+    find_member(state, group, &username.0)
+        .await?
+        .ok_or(GroupError::NotAuthorized)
+}
+
+#[tracing::instrument(skip(state))]
+#[get("/groups")]
+pub async fn get_groups(
+    state: web::Data<AppState>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<web::Json<UserGroups>, GroupError> {
+    let username = authorized
+        .as_deref()
+        .and_then(|authorized| authorized.username())
+        .ok_or(GroupError::NotAuthorized)?;
+    let groups = group_member::Entity::find()
+        .filter(group_member::Column::Username.eq(&username.0))
+        .order_by_asc(group_member::Column::GroupName)
+        .all(&state.db)
+        .await?
+        .into_iter()
+        .map(|member| UserGroup {
+            name: member.group_name,
+            is_admin: member.is_admin,
+        })
+        .collect();
+    Ok(web::Json(UserGroups { groups }))
+}
+
+#[tracing::instrument(skip(state))]
+#[get("/groups/{group}/members")]
+pub async fn get_group_members(
+    state: web::Data<AppState>,
+    group: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<web::Json<GroupMembers>, GroupError> {
+    authorized_member(&state, &authorized, &group).await?;
+    Ok(web::Json(GroupMembers {
+        members: list_members(&state, &group).await?,
+    }))
+}
+
+#[tracing::instrument(skip(state))]
+#[put("/groups/{group}/members/{username}")]
+pub async fn put_group_member(
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    options: web::Json<GroupMemberOptions>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, GroupError> {
+    let (group, username) = params.as_ref();
+    if !authorized_member(&state, &authorized, group)
+        .await?
+        .is_admin
+    {
+        return Err(GroupError::NotAdmin);
+    }
+    if !options.is_admin {
+        check_other_admins(&state, group, username).await?;
+    }
+    add_member(&state, group, username, options.is_admin).await?;
+    Ok(empty_ok_response())
+}
+
+#[tracing::instrument(skip(state))]
+#[delete("/groups/{group}/members/{username}")]
+pub async fn delete_group_member(
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, GroupError> {
+    let (group, username) = params.as_ref();
+    let member = authorized_member(&state, &authorized, group).await?;
+    // Members can leave the group on their own
+    if !member.is_admin && &member.username != username {
+        return Err(GroupError::NotAdmin);
+    }
+    check_other_admins(&state, group, username).await?;
+    remove_member(&state, group, username).await?;
+    Ok(empty_ok_response())
+}
+
+/// Fails if `username` is the only admin of the group, so the group isn't
+/// left without anyone to manage it.
+async fn check_other_admins(
+    state: &AppState,
+    group: &str,
+    username: &str,
+) -> Result<(), GroupError> {
+    let other_admins = group_member::Entity::find()
+        .filter(group_member::Column::GroupName.eq(group))
+        .filter(group_member::Column::IsAdmin.eq(true))
+        .filter(group_member::Column::Username.ne(username))
+        .count(&state.db)
+        .await?;
+    let is_admin = find_member(state, group, username)
+        .await?
+        .is_some_and(|member| member.is_admin);
+    if is_admin && other_admins == 0 {
+        return Err(GroupError::LastAdmin);
+    }
+    Ok(())
+}
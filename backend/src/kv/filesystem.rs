@@ -0,0 +1,198 @@
+//! Stores files in a folder on the local file system.
+use std::{
+    ffi::OsStr,
+    io::{self, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use actix_web::web;
+use async_trait::async_trait;
+use nanoid::nanoid;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tokio_util::io::ReaderStream;
+use tracing_unwrap::ResultExt;
+
+use super::{ByteStream, Entry, KVBackend, Metadata, PartFile};
+
+#[derive(Debug)]
+pub struct Filesystem {
+    /// All paths are resolved relative to this folder.
+    root: PathBuf,
+}
+
+impl Filesystem {
+    pub fn new(root: PathBuf) -> Self {
+        Filesystem { root }
+    }
+
+    fn resolve(&self, path: &Path) -> PathBuf {
+        self.root.join(path)
+    }
+}
+
+impl From<std::fs::Metadata> for Metadata {
+    fn from(meta: std::fs::Metadata) -> Self {
+        Metadata {
+            is_file: meta.is_file(),
+            size: meta.len(),
+            modified: meta.modified().ok(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl KVBackend for Filesystem {
+    async fn read_dir(&self, path: &Path) -> io::Result<Vec<Entry>> {
+        let mut entries = fs::read_dir(self.resolve(path)).await?;
+        let mut out = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            out.push(Entry {
+                name: entry.file_name().to_string_lossy().to_string(),
+                meta: entry.metadata().await?.into(),
+            });
+        }
+        Ok(out)
+    }
+
+    async fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        Ok(fs::metadata(self.resolve(path)).await?.into())
+    }
+
+    async fn read(&self, path: &Path, range: Option<Range<u64>>) -> io::Result<ByteStream> {
+        let mut file = fs::File::open(self.resolve(path)).await?;
+        match range {
+            Some(range) => {
+                file.seek(SeekFrom::Start(range.start)).await?;
+                let limited = file.take(range.end.saturating_sub(range.start));
+                Ok(Box::pin(ReaderStream::new(limited)))
+            }
+            None => Ok(Box::pin(ReaderStream::new(file))),
+        }
+    }
+
+    async fn create_part(&self, folder: &Path, name: &str) -> io::Result<Box<dyn PartFile>> {
+        // Start writing to a temporary, random file name to make sure it
+        // doesn't conflict with any existing files
+        let path = self
+            .resolve(folder)
+            .join(format!(".{name}.{}.part", nanoid!(8)));
+        let file = fs::File::create(&path).await?;
+        Ok(Box::new(FilesystemPart {
+            root: self.root.clone(),
+            path,
+            file,
+        }))
+    }
+
+    async fn resume_part(&self, folder: &Path, part: &str) -> io::Result<Box<dyn PartFile>> {
+        // The part name must not be able to point outside the folder
+        if Path::new(part).file_name() != Some(OsStr::new(part)) {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let path = self.resolve(folder).join(part);
+        let file = fs::OpenOptions::new().append(true).open(&path).await?;
+        Ok(Box::new(FilesystemPart {
+            root: self.root.clone(),
+            path,
+            file,
+        }))
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(self.resolve(from), self.resolve(to)).await
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let from = self.resolve(from);
+        let to = self.resolve(to);
+        if fs::metadata(&from).await?.is_file() {
+            fs::copy(&from, &to).await?;
+            return Ok(());
+        }
+
+        // Walk the folder without recursion, copying everything inside it
+        let mut pending = vec![(from, to)];
+        while let Some((from, to)) = pending.pop() {
+            match fs::create_dir(&to).await {
+                Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+                _ => {}
+            }
+            let mut entries = fs::read_dir(&from).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let to = to.join(entry.file_name());
+                if entry.file_type().await?.is_dir() {
+                    pending.push((entry.path(), to));
+                } else {
+                    fs::copy(entry.path(), to).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        let path = self.resolve(path);
+        if fs::metadata(&path).await?.is_file() {
+            fs::remove_file(&path).await
+        } else {
+            fs::remove_dir_all(&path).await
+        }
+    }
+
+    async fn create_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir(self.resolve(path)).await
+    }
+
+    async fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(self.resolve(path)).await
+    }
+}
+
+pub struct FilesystemPart {
+    root: PathBuf,
+    path: PathBuf,
+    file: fs::File,
+}
+
+#[async_trait(?Send)]
+impl PartFile for FilesystemPart {
+    fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.file.write_all(chunk).await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.file.flush().await
+    }
+
+    async fn commit(&mut self, path: &Path) -> io::Result<()> {
+        self.file.flush().await?;
+        let from = self.path.clone();
+        let to = self.root.join(path);
+        web::block(move || atomic_rename::rename(from, to))
+            .await
+            // Very unlikely/unrecoverable
+            .unwrap_or_log()
+    }
+
+    async fn replace(&mut self, path: &Path) -> io::Result<()> {
+        self.file.flush().await?;
+        // Renames within the same file system replace the target atomically
+        fs::rename(&self.path, self.root.join(path)).await
+    }
+
+    async fn discard(self: Box<Self>) -> io::Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.path).await
+    }
+}
@@ -0,0 +1,175 @@
+//! Storage backends that hold the actual files and folders of the stores.
+//!
+//! Handlers never touch the file system directly, they go through a
+//! [`KVBackend`] which is selected at build time with the `kv_*` features.
+//! All paths given to a backend are relative paths like
+//! `storage/{store}/{path}`, as returned by
+//! [`get_authorized_path`](crate::storage::get_authorized_path). Errors are
+//! reported as [`std::io::Error`]s, so `NotFound` and `AlreadyExists` keep
+//! meaning the same thing regardless of the backend.
+use std::{
+    fmt::Debug,
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::Stream;
+
+#[cfg(feature = "kv_filesystem")]
+pub mod filesystem;
+#[cfg(feature = "kv_s3")]
+pub mod s3;
+
+#[cfg(not(any(feature = "kv_filesystem", feature = "kv_s3")))]
+compile_error!(
+    "At least one storage backend has to be enabled, for example the `kv_filesystem` feature."
+);
+
+/// A stream of bytes read from a file in the backend.
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>>>>;
+
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub is_file: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub meta: Metadata,
+}
+
+#[async_trait(?Send)]
+pub trait KVBackend: Debug + Send + Sync {
+    /// Lists the direct children of a folder.
+    async fn read_dir(&self, path: &Path) -> io::Result<Vec<Entry>>;
+
+    async fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Opens a file for reading. If a range is given, only the bytes in that
+    /// range are read.
+    async fn read(&self, path: &Path, range: Option<Range<u64>>) -> io::Result<ByteStream>;
+
+    /// Starts writing a new file inside `folder`. The contents are kept in a
+    /// temporary location until the returned part is committed, so a failed
+    /// or partial write never shows up under the real name.
+    async fn create_part(&self, folder: &Path, name: &str) -> io::Result<Box<dyn PartFile>>;
+
+    /// Reopens a part that was created earlier, so more data can be appended
+    /// to it. `part` is the name returned by [`PartFile::name`]. Backends that
+    /// can't append to an existing part fail with `Unsupported`.
+    async fn resume_part(&self, folder: &Path, part: &str) -> io::Result<Box<dyn PartFile>>;
+
+    /// Moves a file or folder, replacing the destination if it exists.
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Copies a file or folder, replacing the destination if it exists.
+    async fn copy(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Deletes a file, or a folder along with everything inside it.
+    async fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates a folder. Fails if the parent is missing or the folder already
+    /// exists.
+    async fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates a folder along with any missing parents.
+    async fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+}
+
+/// A file that is being written, but is not visible under its real name yet.
+#[async_trait(?Send)]
+pub trait PartFile {
+    /// Identifies the part within its folder, so it can be resumed later.
+    fn name(&self) -> String;
+
+    /// Makes sure everything written so far is stored, so the part can be
+    /// resumed later.
+    async fn flush(&mut self) -> io::Result<()>;
+
+    async fn write(&mut self, chunk: &[u8]) -> io::Result<()>;
+
+    /// Makes the finished file visible at `path`. If something already exists
+    /// at `path` this fails with `AlreadyExists` and the part is kept, so the
+    /// commit can be retried with another name.
+    async fn commit(&mut self, path: &Path) -> io::Result<()>;
+
+    /// Makes the finished file visible at `path`, replacing any file that is
+    /// already there. Readers see either the old or the new file, never a
+    /// partially written one.
+    async fn replace(&mut self, path: &Path) -> io::Result<()>;
+
+    /// Throws away a part that won't be committed.
+    async fn discard(self: Box<Self>) -> io::Result<()>;
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KVKind {
+    /// Keep files in the `storage` folder.
+    #[default]
+    Filesystem,
+    /// Keep files in an S3 compatible bucket.
+    S3,
+}
+
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct KVOptions {
+    #[clap(
+        long = "storage",
+        env = "BULGUR_CLOUD_STORAGE",
+        value_enum,
+        default_value_t
+    )]
+    /// Where the files in the stores are kept. User data and other metadata
+    /// is always kept in the datastore.
+    pub kind: KVKind,
+
+    #[clap(long, env = "BULGUR_CLOUD_S3_BUCKET")]
+    /// The bucket to keep files in, required with `--storage s3`.
+    pub s3_bucket: Option<String>,
+
+    #[clap(long, env = "BULGUR_CLOUD_S3_ENDPOINT")]
+    /// The URL of the S3 service. Set this to use self hosted services like
+    /// MinIO, leave it unset for AWS. Credentials are read from the standard
+    /// `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` variables.
+    pub s3_endpoint: Option<String>,
+
+    #[clap(long, env = "BULGUR_CLOUD_S3_REGION")]
+    /// The region of the bucket. If unset, the region is picked up from the
+    /// AWS environment variables and config.
+    pub s3_region: Option<String>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum KVSetupError {
+    #[error("This server was built without the {0:?} storage backend, rebuild it with the {1} feature enabled")]
+    NotEnabled(KVKind, &'static str),
+}
+
+/// Sets up the storage backend picked in the options. `base_folder` is the
+/// folder the server was launched in.
+#[allow(unused_variables)]
+pub async fn setup_backend(
+    base_folder: PathBuf,
+    options: &KVOptions,
+) -> anyhow::Result<Box<dyn KVBackend>> {
+    match options.kind {
+        #[cfg(feature = "kv_filesystem")]
+        KVKind::Filesystem => Ok(Box::new(filesystem::Filesystem::new(base_folder))),
+        #[cfg(not(feature = "kv_filesystem"))]
+        KVKind::Filesystem => {
+            Err(KVSetupError::NotEnabled(KVKind::Filesystem, "kv_filesystem").into())
+        }
+        #[cfg(feature = "kv_s3")]
+        KVKind::S3 => Ok(Box::new(s3::S3::new(options).await?)),
+        #[cfg(not(feature = "kv_s3"))]
+        KVKind::S3 => Err(KVSetupError::NotEnabled(KVKind::S3, "kv_s3").into()),
+    }
+}
@@ -0,0 +1,650 @@
+//! Stores files in an S3 compatible bucket, such as AWS S3 or MinIO.
+//!
+//! Object storage has no real folders, so folders are kept as empty marker
+//! objects whose keys end with a `/`. Folders also exist implicitly if any
+//! object has a key under them.
+use std::{
+    error::Error,
+    io,
+    ops::Range,
+    path::{Component, Path},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use aws_sdk_s3::{
+    config::http::HttpResponse,
+    error::SdkError,
+    primitives::{ByteStream as S3ByteStream, DateTime},
+    types::{CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier},
+    Client,
+};
+use nanoid::nanoid;
+use tokio_util::io::ReaderStream;
+
+use super::{ByteStream, Entry, KVBackend, KVOptions, Metadata, PartFile};
+
+/// Uploads are sent to the bucket in parts of this size. S3 requires parts
+/// other than the last one to be at least 5 MiB.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+/// S3 can't copy objects larger than this in a single request.
+const MAX_COPY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+/// S3 can delete at most this many objects in a single request.
+const MAX_DELETE_KEYS: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct S3 {
+    client: Client,
+    bucket: String,
+}
+
+impl S3 {
+    pub async fn new(options: &KVOptions) -> anyhow::Result<Self> {
+        let bucket = options.s3_bucket.clone().ok_or_else(|| {
+            anyhow::anyhow!("The S3 storage backend needs a bucket, use --s3-bucket to set one")
+        })?;
+
+        // Credentials are picked up from the usual AWS environment variables
+        // and config files.
+        let mut loader = aws_config::from_env();
+        if let Some(region) = &options.s3_region {
+            loader = loader.region(aws_config::Region::new(region.clone()));
+        }
+        let shared_config = loader.load().await;
+        let mut config = aws_sdk_s3::config::Builder::from(&shared_config);
+        if let Some(endpoint) = &options.s3_endpoint {
+            // Self hosted services like MinIO typically don't support virtual
+            // hosted buckets.
+            config = config.endpoint_url(endpoint).force_path_style(true);
+        }
+
+        Ok(S3 {
+            client: Client::from_conf(config.build()),
+            bucket,
+        })
+    }
+
+    async fn head(&self, key: &str) -> io::Result<Metadata> {
+        let head = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(to_io_error)?;
+        Ok(Metadata {
+            is_file: true,
+            size: head.content_length().unwrap_or(0) as u64,
+            modified: head.last_modified().and_then(to_system_time),
+        })
+    }
+
+    async fn folder_exists(&self, key: &str) -> io::Result<bool> {
+        let list = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(folder_key(key))
+            .max_keys(1)
+            .send()
+            .await
+            .map_err(to_io_error)?;
+        Ok(!list.contents().is_empty())
+    }
+
+    /// Lists every object under a folder, including the folder marker.
+    async fn list_all(&self, key: &str) -> io::Result<Vec<(String, u64)>> {
+        let mut out = vec![];
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let list = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(folder_key(key))
+                .set_continuation_token(continuation_token)
+                .send()
+                .await
+                .map_err(to_io_error)?;
+            out.extend(list.contents().iter().filter_map(|object| {
+                object
+                    .key()
+                    .map(|key| (key.to_string(), object.size().unwrap_or(0) as u64))
+            }));
+            match list.next_continuation_token() {
+                Some(token) => continuation_token = Some(token.to_string()),
+                None => break,
+            }
+        }
+        Ok(out)
+    }
+
+    async fn put_empty(&self, key: &str) -> io::Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(S3ByteStream::from_static(b""))
+            .send()
+            .await
+            .map_err(to_io_error)?;
+        Ok(())
+    }
+
+    async fn copy_object(&self, from: &str, to: &str, size: u64) -> io::Result<()> {
+        let source = copy_source(&self.bucket, from);
+        if size <= MAX_COPY_SIZE {
+            self.client
+                .copy_object()
+                .bucket(&self.bucket)
+                .copy_source(source)
+                .key(to)
+                .send()
+                .await
+                .map_err(to_io_error)?;
+            return Ok(());
+        }
+
+        // Large objects have to be copied in parts
+        let upload = Multipart::start(self, to).await?;
+        let mut parts = vec![];
+        let mut start = 0;
+        while start < size {
+            let end = (start + MAX_COPY_SIZE).min(size) - 1;
+            let part_number = parts.len() as i32 + 1;
+            let copied = self
+                .client
+                .upload_part_copy()
+                .bucket(&self.bucket)
+                .key(to)
+                .upload_id(&upload.upload_id)
+                .part_number(part_number)
+                .copy_source(&source)
+                .copy_source_range(format!("bytes={start}-{end}"))
+                .send()
+                .await
+                .map_err(to_io_error);
+            let copied = match copied {
+                Ok(copied) => copied,
+                Err(err) => {
+                    upload.abort(self).await;
+                    return Err(err);
+                }
+            };
+            parts.push(
+                CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(
+                        copied
+                            .copy_part_result()
+                            .and_then(|result| result.e_tag())
+                            .map(|tag| tag.to_string()),
+                    )
+                    .build(),
+            );
+            start = end + 1;
+        }
+        upload.complete(self, parts, true).await
+    }
+
+    async fn delete_keys(&self, keys: Vec<String>) -> io::Result<()> {
+        for chunk in keys.chunks(MAX_DELETE_KEYS) {
+            let objects = chunk
+                .iter()
+                .map(|key| ObjectIdentifier::builder().key(key).build())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(io::Error::other)?;
+            self.client
+                .delete_objects()
+                .bucket(&self.bucket)
+                .delete(
+                    Delete::builder()
+                        .set_objects(Some(objects))
+                        .build()
+                        .map_err(io::Error::other)?,
+                )
+                .send()
+                .await
+                .map_err(to_io_error)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl KVBackend for S3 {
+    async fn read_dir(&self, path: &Path) -> io::Result<Vec<Entry>> {
+        let key = to_key(path);
+        let prefix = folder_key(&key);
+        if !self.folder_exists(&key).await? {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+
+        let mut entries = vec![];
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let list = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(&prefix)
+                .delimiter("/")
+                .set_continuation_token(continuation_token)
+                .send()
+                .await
+                .map_err(to_io_error)?;
+
+            entries.extend(list.common_prefixes().iter().filter_map(|folder| {
+                let name = folder
+                    .prefix()?
+                    .strip_prefix(&prefix)?
+                    .trim_end_matches('/');
+                Some(Entry {
+                    name: name.to_string(),
+                    meta: Metadata {
+                        is_file: false,
+                        size: 0,
+                        modified: None,
+                    },
+                })
+            }));
+            entries.extend(list.contents().iter().filter_map(|object| {
+                let name = object.key()?.strip_prefix(&prefix)?;
+                // This is the marker for the folder itself
+                if name.is_empty() {
+                    return None;
+                }
+                Some(Entry {
+                    name: name.to_string(),
+                    meta: Metadata {
+                        is_file: true,
+                        size: object.size().unwrap_or(0) as u64,
+                        modified: object.last_modified().and_then(to_system_time),
+                    },
+                })
+            }));
+
+            match list.next_continuation_token() {
+                Some(token) => continuation_token = Some(token.to_string()),
+                None => break,
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let key = to_key(path);
+        match self.head(&key).await {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                if self.folder_exists(&key).await? {
+                    Ok(Metadata {
+                        is_file: false,
+                        size: 0,
+                        modified: None,
+                    })
+                } else {
+                    Err(err)
+                }
+            }
+            result => result,
+        }
+    }
+
+    async fn read(&self, path: &Path, range: Option<Range<u64>>) -> io::Result<ByteStream> {
+        let object = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(to_key(path))
+            .set_range(range.map(|range| format!("bytes={}-{}", range.start, range.end - 1)))
+            .send()
+            .await
+            .map_err(to_io_error)?;
+        Ok(Box::pin(ReaderStream::new(object.body.into_async_read())))
+    }
+
+    async fn create_part(&self, folder: &Path, name: &str) -> io::Result<Box<dyn PartFile>> {
+        let part_key = to_key(&folder.join(format!(".{name}.{}.part", nanoid!(8))));
+        Ok(Box::new(S3Part {
+            backend: self.clone(),
+            key: to_key(&folder.join(name)),
+            part_key,
+            buffer: Vec::new(),
+            upload: None,
+            parts: vec![],
+            uploaded: false,
+        }))
+    }
+
+    async fn resume_part(&self, _folder: &Path, _part: &str) -> io::Result<Box<dyn PartFile>> {
+        // Parts are buffered in memory and sent in large chunks, which can't
+        // be picked up again by another request.
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "The S3 storage backend does not support resuming uploads",
+        ))
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        // There is no rename in S3, objects have to be copied then deleted
+        KVBackend::copy(self, from, to).await?;
+        self.remove(from).await
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let from = to_key(from);
+        let to = to_key(to);
+        match self.head(&from).await {
+            Ok(meta) => self.copy_object(&from, &to, meta.size).await,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                // Copying a folder means copying everything inside it
+                let objects = self.list_all(&from).await?;
+                if objects.is_empty() {
+                    return Err(err);
+                }
+                let from_prefix = folder_key(&from);
+                let to_prefix = folder_key(&to);
+                for (key, size) in &objects {
+                    let relative = key.strip_prefix(&from_prefix).unwrap_or(key);
+                    self.copy_object(key, &format!("{to_prefix}{relative}"), *size)
+                        .await?;
+                }
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        let key = to_key(path);
+        if self.metadata(path).await?.is_file {
+            self.delete_keys(vec![key]).await
+        } else {
+            let objects = self.list_all(&key).await?;
+            self.delete_keys(objects.into_iter().map(|(key, _)| key).collect())
+                .await
+        }
+    }
+
+    async fn create_dir(&self, path: &Path) -> io::Result<()> {
+        if self.metadata(path).await.is_ok() {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        if let Some(parent) = path.parent() {
+            if !to_key(parent).is_empty() && self.metadata(parent).await?.is_file {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+        }
+        self.put_empty(&folder_key(&to_key(path))).await
+    }
+
+    async fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let key = to_key(path);
+        if key.is_empty() || self.folder_exists(&key).await? {
+            return Ok(());
+        }
+        self.put_empty(&folder_key(&key)).await
+    }
+}
+
+struct Multipart {
+    key: String,
+    upload_id: String,
+}
+
+impl Multipart {
+    async fn start(backend: &S3, key: &str) -> io::Result<Self> {
+        let upload = backend
+            .client
+            .create_multipart_upload()
+            .bucket(&backend.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(to_io_error)?;
+        Ok(Multipart {
+            key: key.to_string(),
+            upload_id: upload
+                .upload_id()
+                .ok_or_else(|| io::Error::other("S3 did not return an upload id"))?
+                .to_string(),
+        })
+    }
+
+    /// Puts the parts together into the object. Unless `overwrite` is set,
+    /// this fails if the object already exists, and the upload can still be
+    /// completed later.
+    async fn complete(
+        &self,
+        backend: &S3,
+        parts: Vec<CompletedPart>,
+        overwrite: bool,
+    ) -> io::Result<()> {
+        let mut request = backend
+            .client
+            .complete_multipart_upload()
+            .bucket(&backend.bucket)
+            .key(&self.key)
+            .upload_id(&self.upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            );
+        if !overwrite {
+            request = request.if_none_match("*");
+        }
+        request.send().await.map_err(to_io_error)?;
+        Ok(())
+    }
+
+    async fn abort(&self, backend: &S3) {
+        let aborted = backend
+            .client
+            .abort_multipart_upload()
+            .bucket(&backend.bucket)
+            .key(&self.key)
+            .upload_id(&self.upload_id)
+            .send()
+            .await;
+        if let Err(err) = aborted {
+            tracing::warn!(error = ?err, key = self.key, "Failed to abort a multipart upload");
+        }
+    }
+}
+
+pub struct S3Part {
+    backend: S3,
+    /// Where the file is meant to end up. Large uploads are sent straight
+    /// there, since S3 only shows them once they are completed.
+    key: String,
+    /// Where large uploads are kept instead if a file already exists at
+    /// `key`, because the upload may end up under another name.
+    part_key: String,
+    /// Data that has not been sent to the bucket yet.
+    buffer: Vec<u8>,
+    upload: Option<Multipart>,
+    parts: Vec<CompletedPart>,
+    /// Set once the multipart upload has been completed into `part_key`.
+    uploaded: bool,
+}
+
+impl S3Part {
+    async fn send_part(&mut self) -> io::Result<()> {
+        if self.upload.is_none() {
+            // The key of a multipart upload can't be changed later
+            let key = match self.backend.head(&self.key).await {
+                Ok(_) => &self.part_key,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => &self.key,
+                Err(err) => return Err(err),
+            };
+            self.upload = Some(Multipart::start(&self.backend, key).await?);
+        }
+        let upload = self.upload.as_ref().expect("upload was started above");
+        let part_number = self.parts.len() as i32 + 1;
+        let body = std::mem::take(&mut self.buffer);
+        let sent = self
+            .backend
+            .client
+            .upload_part()
+            .bucket(&self.backend.bucket)
+            .key(&upload.key)
+            .upload_id(&upload.upload_id)
+            .part_number(part_number)
+            .body(S3ByteStream::from(body))
+            .send()
+            .await
+            .map_err(to_io_error)?;
+        self.parts.push(
+            CompletedPart::builder()
+                .part_number(part_number)
+                .set_e_tag(sent.e_tag().map(|tag| tag.to_string()))
+                .build(),
+        );
+        Ok(())
+    }
+
+    async fn finish(&mut self, key: &str, overwrite: bool) -> io::Result<()> {
+        if self.upload.is_none() {
+            // Small files fit in a single request, which S3 can refuse
+            // atomically if the file already exists.
+            let mut request = self
+                .backend
+                .client
+                .put_object()
+                .bucket(&self.backend.bucket)
+                .key(key)
+                .body(S3ByteStream::from(self.buffer.clone()));
+            if !overwrite {
+                request = request.if_none_match("*");
+            }
+            request.send().await.map_err(to_io_error)?;
+            return Ok(());
+        }
+
+        if !self.buffer.is_empty() {
+            self.send_part().await?;
+        }
+        let upload = self.upload.as_ref().expect("upload was checked above");
+        if upload.key == key {
+            return upload
+                .complete(&self.backend, self.parts.clone(), overwrite)
+                .await;
+        }
+        if upload.key != self.part_key {
+            // Someone else created the file while it was being uploaded, and
+            // the upload can't be moved to another name
+            return Err(io::Error::other(
+                "The upload can't be saved under another name",
+            ));
+        }
+
+        if !self.uploaded {
+            upload
+                .complete(&self.backend, self.parts.clone(), true)
+                .await?;
+            self.uploaded = true;
+        }
+        if !overwrite {
+            // S3 can't refuse a copy if the target exists, so there is a small
+            // window where a file created at the same time may be overwritten.
+            match self.backend.head(key).await {
+                Ok(_) => return Err(io::ErrorKind::AlreadyExists.into()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        // Copies in S3 replace the target atomically
+        let size = self.backend.head(&self.part_key).await?.size;
+        self.backend.copy_object(&self.part_key, key, size).await?;
+        self.backend.delete_keys(vec![self.part_key.clone()]).await
+    }
+}
+
+#[async_trait(?Send)]
+impl PartFile for S3Part {
+    async fn flush(&mut self) -> io::Result<()> {
+        // Parts can't be resumed, so there is nothing to persist early
+        Ok(())
+    }
+
+    fn name(&self) -> String {
+        self.part_key
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.buffer.extend_from_slice(chunk);
+        if self.buffer.len() >= PART_SIZE {
+            self.send_part().await?;
+        }
+        Ok(())
+    }
+
+    async fn commit(&mut self, path: &Path) -> io::Result<()> {
+        self.finish(&to_key(path), false).await
+    }
+
+    async fn replace(&mut self, path: &Path) -> io::Result<()> {
+        self.finish(&to_key(path), true).await
+    }
+
+    async fn discard(self: Box<Self>) -> io::Result<()> {
+        if self.uploaded {
+            // Only uploads kept at `part_key` are completed before committing
+            self.backend
+                .delete_keys(vec![self.part_key.clone()])
+                .await?;
+        } else if let Some(upload) = &self.upload {
+            upload.abort(&self.backend).await;
+        }
+        Ok(())
+    }
+}
+
+/// Turns a path into an object key, with the segments separated by `/`.
+fn to_key(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(segment) => Some(segment.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The prefix shared by all objects inside a folder, which is also the key of
+/// the folder marker.
+fn folder_key(key: &str) -> String {
+    format!("{key}/")
+}
+
+fn copy_source(bucket: &str, key: &str) -> String {
+    let key = key
+        .split('/')
+        .map(|segment| urlencoding::encode(segment).to_string())
+        .collect::<Vec<_>>()
+        .join("/");
+    format!("{bucket}/{key}")
+}
+
+fn to_system_time(time: &DateTime) -> Option<SystemTime> {
+    SystemTime::try_from(*time).ok()
+}
+
+fn to_io_error<E: Error + Send + Sync + 'static>(err: SdkError<E, HttpResponse>) -> io::Error {
+    let kind = match err
+        .raw_response()
+        .map(|response| response.status().as_u16())
+    {
+        Some(404) => io::ErrorKind::NotFound,
+        // Writes with `If-None-Match: *` fail like this if the object exists
+        Some(412) => io::ErrorKind::AlreadyExists,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, err)
+}
@@ -0,0 +1,163 @@
+//! Checks passwords against a directory server, with the `--auth ldap` option.
+//!
+//! Users log in by binding to the server as themselves, the DN they bind as
+//! is made from their username. Users are added to the datastore and get a
+//! store the first time they log in. If an admin group is set, users become
+//! admins or regular users based on their membership every time they log in.
+use std::time::Duration;
+
+use async_trait::async_trait;
+use ldap3::{dn_escape, ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope};
+
+use crate::{
+    auth::{provision_user, validate_username, AuthProvider, Password, USER_NOBODY},
+    state::{AppState, UserType},
+};
+
+/// How long to wait for the directory server to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Replaced with the username in `--ldap-user-dn`.
+const USERNAME_PLACEHOLDER: &str = "{username}";
+
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct LdapOptions {
+    #[clap(long, env = "BULGUR_CLOUD_LDAP_URL")]
+    /// The directory server to check passwords with, like
+    /// `ldaps://ldap.example.com`. Required with `--auth ldap`.
+    pub ldap_url: Option<String>,
+
+    #[clap(long, env = "BULGUR_CLOUD_LDAP_STARTTLS")]
+    /// Upgrade `ldap://` connections to TLS with StartTLS.
+    pub ldap_starttls: bool,
+
+    #[clap(long, env = "BULGUR_CLOUD_LDAP_USER_DN")]
+    /// The DN users bind as, with `{username}` in place of the username. For
+    /// example `uid={username},ou=people,dc=example,dc=com`. Required with
+    /// `--auth ldap`.
+    pub ldap_user_dn: Option<String>,
+
+    #[clap(long, env = "BULGUR_CLOUD_LDAP_ADMIN_GROUP")]
+    /// The DN of a group whose members are admins, everyone else is a regular
+    /// user. If not set, the types of users are managed by hand.
+    pub ldap_admin_group: Option<String>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum LdapSetupError {
+    #[error("The {0} option is required with `--auth ldap`")]
+    Missing(&'static str),
+    #[error("The `--ldap-user-dn` option has to contain {USERNAME_PLACEHOLDER}")]
+    NoUsername,
+}
+
+#[derive(Debug)]
+pub struct LdapAuth {
+    url: String,
+    starttls: bool,
+    user_dn: String,
+    admin_group: Option<String>,
+}
+
+impl LdapAuth {
+    pub fn new(options: &LdapOptions) -> Result<LdapAuth, LdapSetupError> {
+        let url = options
+            .ldap_url
+            .clone()
+            .ok_or(LdapSetupError::Missing("`--ldap-url`"))?;
+        let user_dn = options
+            .ldap_user_dn
+            .clone()
+            .ok_or(LdapSetupError::Missing("`--ldap-user-dn`"))?;
+        if !user_dn.contains(USERNAME_PLACEHOLDER) {
+            return Err(LdapSetupError::NoUsername);
+        }
+        Ok(LdapAuth {
+            url,
+            starttls: options.ldap_starttls,
+            user_dn,
+            admin_group: options.ldap_admin_group.clone(),
+        })
+    }
+
+    async fn connect(&self) -> anyhow::Result<Ldap> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(CONNECT_TIMEOUT)
+            .set_starttls(self.starttls);
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.url).await?;
+        tokio::spawn(async move {
+            if let Err(err) = conn.drive().await {
+                tracing::warn!(error = %err, "LDAP connection failed");
+            }
+        });
+        Ok(ldap)
+    }
+
+    /// Checks if the user is in the admin group. The group can list its
+    /// members by DN, like `groupOfNames` and `groupOfUniqueNames` do, or by
+    /// username like `posixGroup` does.
+    async fn is_admin(
+        &self,
+        ldap: &mut Ldap,
+        group: &str,
+        dn: &str,
+        username: &str,
+    ) -> anyhow::Result<bool> {
+        let filter = format!(
+            "(|(member={dn})(uniqueMember={dn})(memberUid={username}))",
+            dn = ldap_escape(dn),
+            username = ldap_escape(username),
+        );
+        let (entries, _) = ldap
+            .search(group, Scope::Base, &filter, vec!["1.1"])
+            .await?
+            .success()?;
+        Ok(!entries.is_empty())
+    }
+}
+
+#[async_trait(?Send)]
+impl AuthProvider for LdapAuth {
+    async fn verify(
+        &self,
+        state: &AppState,
+        username: &str,
+        password: &Password,
+    ) -> anyhow::Result<()> {
+        // Binding with an empty password is an anonymous bind, which
+        // succeeds regardless of the DN
+        if password.0.is_empty() {
+            anyhow::bail!("The password is empty");
+        }
+        validate_username(username)?;
+        if username == USER_NOBODY {
+            anyhow::bail!("{USER_NOBODY} can't log in");
+        }
+        let dn = self
+            .user_dn
+            .replace(USERNAME_PLACEHOLDER, &dn_escape(username));
+
+        let mut ldap = self.connect().await?;
+        ldap.simple_bind(&dn, &password.0).await?.success()?;
+        let user_type = match &self.admin_group {
+            Some(group) => match self.is_admin(&mut ldap, group, &dn, username).await {
+                Ok(true) => Some(UserType::Admin),
+                Ok(false) => Some(UserType::User),
+                Err(err) => {
+                    tracing::error!(error = %err, group, "Failed to look up the admin group");
+                    return Err(err);
+                }
+            },
+            None => None,
+        };
+        if let Err(err) = ldap.unbind().await {
+            tracing::warn!(error = %err, "Failed to unbind from the directory server");
+        }
+
+        provision_user(state, username, user_type).await
+    }
+
+    fn manages_passwords(&self) -> bool {
+        false
+    }
+}
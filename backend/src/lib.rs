@@ -1,17 +1,35 @@
 //! This is not a stable API. It may have breaking changes in minor updates. The
 //! API is only exposed for internal use, please avoid using this as a library
 //! or you risk breaking changes in all updates.
+pub mod admin;
+pub mod admin_middleware;
+pub mod api_key;
+pub mod archive;
 pub mod auth;
 pub mod auth_middleware;
 pub mod cli;
+pub mod dav;
 pub mod db;
 pub mod entity;
 pub mod error;
 pub mod folder;
+pub mod group;
+pub mod kv;
+pub mod ldap;
 pub mod meta;
+pub mod oidc;
 pub mod pages;
+pub mod proxy_auth;
+pub mod quota;
 pub mod ratelimit_middleware;
 pub mod server;
+pub mod session;
+pub mod share;
 pub mod state;
 pub mod static_files;
 pub mod storage;
+pub mod totp;
+pub mod trash;
+pub mod tus;
+pub mod user_share;
+pub mod versions;
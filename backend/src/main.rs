@@ -2,6 +2,10 @@ use bulgur_cloud::{
     cli::{cli_command, CLITerminalContext, Opt},
     db::get_db,
     server::{setup_app, setup_app_deps},
+    session::expire_tokens_task,
+    trash::purge_expired_task,
+    tus::remove_expired_uploads_task,
+    versions::prune_versions_task,
 };
 
 use clap::Parser;
@@ -104,10 +108,26 @@ async fn main() -> anyhow::Result<()> {
         None => {
             // Running the server
             let connections = get_db(&opts.datastore).await?;
-            let (state, login_governor) =
-                setup_app_deps(env::current_dir().unwrap_or_log(), connections).await?;
+            let (state, login_governor) = setup_app_deps(
+                env::current_dir().unwrap_or_log(),
+                connections,
+                &opts.kv,
+                &opts.versions,
+                &opts.tokens,
+                &opts.auth,
+            )
+            .await?;
             setup_logging();
 
+            if opts.trash_retention_days > 0 {
+                actix_web::rt::spawn(purge_expired_task(state.clone(), opts.trash_retention_days));
+            }
+            if state.versions.keep_days > 0 {
+                actix_web::rt::spawn(prune_versions_task(state.clone()));
+            }
+            actix_web::rt::spawn(expire_tokens_task(state.clone()));
+            actix_web::rt::spawn(remove_expired_uploads_task(state.clone()));
+
             HttpServer::new(move || setup_app(state.clone(), login_governor.clone()))
                 .bind(opts.bind)?
                 .workers(opts.workers)
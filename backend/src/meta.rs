@@ -1,9 +1,18 @@
 use std::path::PathBuf;
 
 use actix_files::NamedFile;
-use actix_web::{get, head, web, HttpResponse};
+use actix_web::{
+    get, head,
+    web::{self, ReqData},
+    HttpResponse,
+};
 
-use crate::{folder::BANNER, state::AppState};
+use crate::{
+    folder::BANNER,
+    quota::quota_usage,
+    state::{AppState, Authorized},
+    storage::StorageError,
+};
 use serde::{Serialize, Serializer};
 use tracing;
 
@@ -19,14 +28,34 @@ where
 pub struct Stats {
     #[serde(serialize_with = "serialize_duration")]
     pub uptime: chrono::Duration,
+    /// How many bytes the files of the logged in user take up.
+    pub usage: u64,
+    /// How many bytes the logged in user can store, if there is a limit.
+    pub quota: Option<u64>,
+    /// How many more bytes the logged in user can store, if there is a limit.
+    pub remaining: Option<u64>,
 }
 
 #[get("/stats")]
 #[tracing::instrument(skip(state))]
-async fn get_stats(state: web::Data<AppState>) -> web::Json<Stats> {
-    web::Json(Stats {
+async fn get_stats(
+    state: web::Data<AppState>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<web::Json<Stats>, StorageError> {
+    let username = match authorized.as_deref() {
+        Some(Authorized::User(username))
+        | Some(Authorized::Both(username))
+        | Some(Authorized::Shared(username, _))
+        | Some(Authorized::Key(username, _)) => username,
+        _ => return Err(StorageError::NotAuthorized),
+    };
+    let usage = quota_usage(&state, &username.0).await?;
+    Ok(web::Json(Stats {
         uptime: chrono::Local::now() - state.started_at,
-    })
+        usage: usage.usage,
+        quota: usage.quota,
+        remaining: usage.remaining(),
+    }))
 }
 
 #[head("/stats")]
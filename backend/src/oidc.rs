@@ -0,0 +1,702 @@
+//! Single sign-on with OpenID Connect.
+//!
+//! Users are sent to the identity provider with the authorization code flow,
+//! protected with PKCE. When they come back, the code is exchanged for an ID
+//! token and the `preferred_username` claim in it picks the user they are
+//! logged in as. The first login ties the account to the `iss` and `sub`
+//! claims, which identify the user at the identity provider, and later logins
+//! find the account by them instead. The basic pages get a cookie right away. The app gets a
+//! one-time code instead, which it trades for a token with
+//! [`post_oidc_token`], so the token itself never shows up in a URL.
+//!
+//! Two-factor authentication is left to the identity provider, codes set up
+//! here aren't asked for when logging in with single sign-on.
+use std::{convert::Infallible, str::FromStr};
+
+use actix_web::{
+    cookie::{time, Cookie, SameSite},
+    get,
+    http::{header, StatusCode},
+    post, web, HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use nanoid::nanoid;
+use reqwest::Url;
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, DbErr, EntityTrait, QueryFilter, Set,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing_unwrap::ResultExt;
+
+use crate::{
+    auth::{make_session_token, provision_user, validate_username, USER_NOBODY},
+    entity::{sso_login, user},
+    pages::{sso_error_page, sso_login_page},
+    session::client_info,
+    state::{AppState, Token},
+};
+
+#[cfg(feature = "generate_types")]
+use typescript_type_def::TypeDef;
+
+/// Users have this long to log in at the identity provider, and the app has
+/// this long to pick up the token afterwards.
+const LOGIN_TIMEOUT_MINS: i64 = 10;
+
+/// The length of the PKCE code verifier, the spec allows 43 to 128.
+const CODE_VERIFIER_LENGTH: usize = 64;
+
+/// Where the app picks up the token after logging in.
+const APP_LOGIN_PAGE: &str = "/login";
+
+/// The browser that starts a login gets a cookie with the hash of the state,
+/// so that only that browser can finish it.
+const STATE_COOKIE_NAME: &str = "bulgur-cloud-oidc-state";
+/// The cookie is only needed by the callback.
+const STATE_COOKIE_PATH: &str = "/auth/oidc";
+
+/// The client secret, kept out of the logs.
+#[derive(Clone, simple_secrecy::Debug)]
+pub struct ClientSecret(String);
+
+impl ClientSecret {
+    /// Drop the secret, and reveal what's inside.
+    pub fn reveal(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for ClientSecret {
+    type Err = Infallible;
+
+    fn from_str(secret: &str) -> Result<Self, Self::Err> {
+        Ok(ClientSecret(secret.to_string()))
+    }
+}
+
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct OidcOptions {
+    #[clap(long, env = "BULGUR_CLOUD_OIDC_ISSUER")]
+    /// The OpenID Connect identity provider, like
+    /// `https://id.example.com/realms/main`. Users can log in with single
+    /// sign-on if this is set.
+    pub oidc_issuer: Option<String>,
+
+    #[clap(long, env = "BULGUR_CLOUD_OIDC_CLIENT_ID")]
+    /// The client ID this server is registered with at the identity provider.
+    /// Required with `--oidc-issuer`.
+    pub oidc_client_id: Option<String>,
+
+    #[clap(long, env = "BULGUR_CLOUD_OIDC_CLIENT_SECRET")]
+    /// The client secret, if the identity provider gave one.
+    pub oidc_client_secret: Option<ClientSecret>,
+
+    #[clap(long, env = "BULGUR_CLOUD_OIDC_REDIRECT_URL")]
+    /// Where the identity provider sends users back to. This is
+    /// `/auth/oidc/callback` on the public address of this server, like
+    /// `https://cloud.example.com/auth/oidc/callback`. Required with
+    /// `--oidc-issuer`.
+    pub oidc_redirect_url: Option<String>,
+
+    #[clap(long, env = "BULGUR_CLOUD_OIDC_CREATE_USERS")]
+    /// Add users the first time they log in with single sign-on. Otherwise
+    /// only users that already exist can log in with it.
+    pub oidc_create_users: bool,
+
+    #[clap(long, env = "BULGUR_CLOUD_OIDC_LINK_USERS")]
+    /// Let users that were added here, rather than with single sign-on, log
+    /// in with it too. The first login with the same username takes over the
+    /// account, so only use this if users can't pick their own username at
+    /// the identity provider.
+    pub oidc_link_users: bool,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum OidcSetupError {
+    #[error("The {0} option is required with `--oidc-issuer`")]
+    Missing(&'static str),
+    #[error("Failed to set up the HTTP client: {0}")]
+    Client(#[from] reqwest::Error),
+}
+
+#[derive(Debug)]
+pub struct OidcClient {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<ClientSecret>,
+    redirect_url: String,
+    create_users: bool,
+    link_users: bool,
+    http: reqwest::Client,
+}
+
+/// Sets up single sign-on, if an identity provider is configured.
+pub fn setup_oidc(options: &OidcOptions) -> Result<Option<OidcClient>, OidcSetupError> {
+    let Some(issuer) = options.oidc_issuer.clone() else {
+        return Ok(None);
+    };
+    Ok(Some(OidcClient {
+        issuer,
+        client_id: options
+            .oidc_client_id
+            .clone()
+            .ok_or(OidcSetupError::Missing("`--oidc-client-id`"))?,
+        client_secret: options.oidc_client_secret.clone(),
+        redirect_url: options
+            .oidc_redirect_url
+            .clone()
+            .ok_or(OidcSetupError::Missing("`--oidc-redirect-url`"))?,
+        create_users: options.oidc_create_users,
+        link_users: options.oidc_link_users,
+        http: reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(std::time::Duration::from_secs(30))
+            .build()?,
+    }))
+}
+
+#[derive(Debug, derive_more::Display, thiserror::Error)]
+pub enum OidcError {
+    #[display(fmt = "Single sign-on is not set up")]
+    NotEnabled,
+    #[display(fmt = "The login expired, try logging in again")]
+    LoginExpired,
+    #[display(fmt = "The login was started in another browser, try logging in again")]
+    OtherBrowser,
+    #[display(fmt = "The identity provider refused the login: {}", _0)]
+    Denied(String),
+    #[display(fmt = "Failed to reach the identity provider: {}", _0)]
+    Provider(String),
+    #[display(fmt = "The identity provider sent an invalid ID token: {}", _0)]
+    InvalidToken(String),
+    #[display(fmt = "The identity provider didn't send a username that can be used")]
+    BadUsername,
+    #[display(fmt = "There is no account for {}", _0)]
+    NoAccount(String),
+    #[display(fmt = "The account {} belongs to someone else", _0)]
+    OtherIdentity(String),
+    #[display(fmt = "The account {} can't be logged into with single sign-on", _0)]
+    NotLinked(String),
+    #[display(fmt = "Database error {}", _0)]
+    Database(#[from] DbErr),
+    #[display(fmt = "{}", _0)]
+    Failed(String),
+}
+
+impl Serialize for OidcError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let s = format!("{}", self);
+        serializer.serialize_str(&s)
+    }
+}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(err: reqwest::Error) -> Self {
+        OidcError::Provider(err.to_string())
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for OidcError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        OidcError::InvalidToken(err.to_string())
+    }
+}
+
+impl actix_web::error::ResponseError for OidcError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            OidcError::NotEnabled => StatusCode::NOT_FOUND,
+            OidcError::LoginExpired | OidcError::OtherBrowser => StatusCode::BAD_REQUEST,
+            OidcError::Denied(_) | OidcError::InvalidToken(_) => StatusCode::UNAUTHORIZED,
+            OidcError::Provider(_) => StatusCode::BAD_GATEWAY,
+            OidcError::BadUsername
+            | OidcError::NoAccount(_)
+            | OidcError::OtherIdentity(_)
+            | OidcError::NotLinked(_) => StatusCode::FORBIDDEN,
+            OidcError::Database(_) | OidcError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponseBuilder::new(self.status_code()).json(self)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct OidcStatus {
+    /// True if users can log in with single sign-on.
+    pub enabled: bool,
+}
+
+/// The one-time code the app gets after logging in with single sign-on.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct OidcCode {
+    pub code: String,
+}
+
+/// A login with single sign-on, the app doesn't know who logged in until
+/// then.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct OidcLoginResponse {
+    pub username: String,
+    pub access_token: Token,
+    /// When the token stops working, unless it is refreshed before then.
+    pub expires_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OidcLoginQuery {
+    /// Log into the basic pages, instead of the app.
+    #[serde(default)]
+    pub basic: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OidcCallbackQuery {
+    pub state: Option<String>,
+    pub code: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+/// The parts of the discovery document that are needed.
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdClaims {
+    sub: String,
+    nonce: Option<String>,
+    preferred_username: Option<String>,
+}
+
+/// Who logged in at the identity provider.
+#[derive(Debug)]
+struct Identity {
+    issuer: String,
+    subject: String,
+    username: Option<String>,
+}
+
+impl OidcClient {
+    async fn discover(&self) -> Result<ProviderMetadata, OidcError> {
+        let issuer = self.issuer.trim_end_matches('/');
+        let metadata: ProviderMetadata = self
+            .http
+            .get(format!("{issuer}/.well-known/openid-configuration"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if metadata.issuer.trim_end_matches('/') != issuer {
+            return Err(OidcError::Provider(format!(
+                "The issuer is {}, but {issuer} was expected",
+                metadata.issuer
+            )));
+        }
+        Ok(metadata)
+    }
+
+    fn authorization_url(
+        &self,
+        provider: &ProviderMetadata,
+        state: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<Url, OidcError> {
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+        let mut url = Url::parse(&provider.authorization_endpoint)
+            .map_err(|err| OidcError::Provider(err.to_string()))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("scope", "openid profile")
+            .append_pair("state", state)
+            .append_pair("nonce", nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256");
+        Ok(url)
+    }
+
+    /// Trades the authorization code for an ID token, and returns who it
+    /// says logged in.
+    async fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<Identity, OidcError> {
+        let provider = self.discover().await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.redirect_url),
+            ("client_id", &self.client_id),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(secret) = &self.client_secret {
+            form.push(("client_secret", secret.reveal()));
+        }
+        let response = self
+            .http
+            .post(&provider.token_endpoint)
+            .form(&form)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            return Err(match response.json::<TokenErrorResponse>().await {
+                Ok(err) => OidcError::Denied(err.error_description.unwrap_or(err.error)),
+                Err(_) => OidcError::Provider(format!("The token endpoint responded {status}")),
+            });
+        }
+        let tokens: TokenResponse = response.json().await?;
+        let claims = self.verify_id_token(&provider, &tokens.id_token).await?;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(OidcError::InvalidToken(
+                "The nonce doesn't match".to_string(),
+            ));
+        }
+        Ok(Identity {
+            issuer: provider.issuer,
+            subject: claims.sub,
+            username: claims.preferred_username,
+        })
+    }
+
+    /// Checks the signature of the ID token with the keys of the provider,
+    /// and checks that it was issued by the provider for this client.
+    async fn verify_id_token(
+        &self,
+        provider: &ProviderMetadata,
+        id_token: &str,
+    ) -> Result<IdClaims, OidcError> {
+        let header = decode_header(id_token)?;
+        // Only signatures made with the keys of the provider are accepted
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(OidcError::InvalidToken(format!(
+                "{:?} signatures are not supported",
+                header.alg
+            )));
+        }
+        let keys: JwkSet = self
+            .http
+            .get(&provider.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let key = match &header.kid {
+            Some(kid) => keys.find(kid),
+            None if keys.keys.len() == 1 => keys.keys.first(),
+            None => None,
+        }
+        .ok_or_else(|| OidcError::InvalidToken("The signing key is unknown".to_string()))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&provider.issuer]);
+        validation.set_audience(&[&self.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        Ok(decode::<IdClaims>(id_token, &DecodingKey::from_jwk(key)?, &validation)?.claims)
+    }
+}
+
+fn oidc_client(state: &AppState) -> Result<&OidcClient, OidcError> {
+    state.oidc.as_ref().ok_or(OidcError::NotEnabled)
+}
+
+/// Saves a login that is waiting for the identity provider, or for the app to
+/// pick up its token. Returns the code that identifies it, only a hash of the
+/// code is saved.
+async fn save_login(state: &AppState, mut login: sso_login::ActiveModel) -> Result<Token, DbErr> {
+    let code = Token::new();
+    login.id = Set(state.hash_token(&code));
+    login.created_at = Set(Utc::now().to_rfc3339());
+    login.insert(&state.db).await?;
+    Ok(code)
+}
+
+/// Finds a saved login and deletes it, so each one can only be used once.
+/// `finished` picks logins that are waiting for the app, rather than for the
+/// identity provider.
+async fn take_login(
+    state: &AppState,
+    code: &str,
+    finished: bool,
+) -> Result<sso_login::Model, OidcError> {
+    let id = state.hash_token(&Token::read(code));
+    let cutoff = Utc::now() - Duration::minutes(LOGIN_TIMEOUT_MINS);
+    let username = if finished {
+        sso_login::Column::Username.is_not_null()
+    } else {
+        sso_login::Column::Username.is_null()
+    };
+    let login = sso_login::Entity::find_by_id(&id)
+        .filter(username)
+        .filter(sso_login::Column::CreatedAt.gt(cutoff.to_rfc3339()))
+        .one(&state.db)
+        .await?
+        .ok_or(OidcError::LoginExpired)?;
+    let deleted = sso_login::Entity::delete_by_id(&id)
+        .exec(&state.db)
+        .await?
+        .rows_affected;
+    if deleted == 0 {
+        // Someone else used it in the meantime
+        return Err(OidcError::LoginExpired);
+    }
+    Ok(login)
+}
+
+/// Deletes logins that were never finished. Returns how many were deleted.
+pub async fn delete_expired_logins(state: &AppState) -> Result<u64, DbErr> {
+    let cutoff = Utc::now() - Duration::minutes(LOGIN_TIMEOUT_MINS);
+    Ok(sso_login::Entity::delete_many()
+        .filter(sso_login::Column::CreatedAt.lt(cutoff.to_rfc3339()))
+        .exec(&state.db)
+        .await?
+        .rows_affected)
+}
+
+/// Starts logging in, returns the URL of the identity provider the user has
+/// to be sent to, and the state the identity provider will send back.
+pub async fn start_login(state: &AppState, basic: bool) -> Result<(String, Token), OidcError> {
+    let oidc = oidc_client(state)?;
+    let provider = oidc.discover().await?;
+    let code_verifier = nanoid!(CODE_VERIFIER_LENGTH);
+    let nonce = nanoid!();
+    let login_state = save_login(
+        state,
+        sso_login::ActiveModel {
+            code_verifier: Set(Some(code_verifier.clone())),
+            nonce: Set(Some(nonce.clone())),
+            basic: Set(basic),
+            username: Set(None),
+            ..Default::default()
+        },
+    )
+    .await?;
+    let url = oidc.authorization_url(&provider, login_state.reveal(), &code_verifier, &nonce)?;
+    Ok((url.to_string(), login_state))
+}
+
+/// Remembers which login the browser started.
+fn state_cookie(state: &AppState, login_state: &Token) -> Cookie<'static> {
+    let mut cookie = Cookie::new(STATE_COOKIE_NAME, state.hash_token(login_state));
+    cookie.set_path(STATE_COOKIE_PATH);
+    cookie.set_http_only(true);
+    // The identity provider sends the user back with a cross-site redirect,
+    // a strict cookie wouldn't be sent with it
+    cookie.set_same_site(SameSite::Lax);
+    cookie.set_max_age(time::Duration::minutes(LOGIN_TIMEOUT_MINS));
+    cookie
+}
+
+/// Finishes logging in once the identity provider sends the user back.
+/// `browser_state` is the cookie the browser got when it started the login,
+/// otherwise someone could get others to finish a login they started, and be
+/// logged in as them. Returns the user, and if they logged into the basic
+/// pages.
+pub async fn finish_login(
+    state: &AppState,
+    query: &OidcCallbackQuery,
+    browser_state: Option<&str>,
+) -> Result<(String, bool), OidcError> {
+    let oidc = oidc_client(state)?;
+    let login_state = query.state.as_deref().ok_or(OidcError::LoginExpired)?;
+    let started_here = browser_state.is_some_and(|hash| state.token_key.verify(login_state, hash));
+    if !started_here {
+        return Err(OidcError::OtherBrowser);
+    }
+    let login = take_login(state, login_state, false).await?;
+    if let Some(error) = &query.error {
+        return Err(OidcError::Denied(
+            query.error_description.clone().unwrap_or(error.clone()),
+        ));
+    }
+    let code = query
+        .code
+        .as_deref()
+        .ok_or_else(|| OidcError::Denied("No authorization code".to_string()))?;
+    let identity = oidc
+        .exchange_code(
+            code,
+            login.code_verifier.as_deref().unwrap_or_default(),
+            login.nonce.as_deref().unwrap_or_default(),
+        )
+        .await?;
+    let username = find_account(state, oidc, identity).await?;
+    Ok((username, login.basic))
+}
+
+/// Finds the account of someone who logged in with single sign-on, and
+/// returns its username. The first time, the account is picked by username
+/// and tied to the identity, so nobody else can log into it by taking the
+/// username at the identity provider later.
+async fn find_account(
+    state: &AppState,
+    oidc: &OidcClient,
+    identity: Identity,
+) -> Result<String, OidcError> {
+    let linked = user::Entity::find()
+        .filter(user::Column::SsoIssuer.eq(&identity.issuer))
+        .filter(user::Column::SsoSubject.eq(&identity.subject))
+        .one(&state.db)
+        .await?;
+    if let Some(user) = linked {
+        return Ok(user.username);
+    }
+
+    let username = identity
+        .username
+        .filter(|username| validate_username(username).is_ok() && username != USER_NOBODY)
+        .ok_or(OidcError::BadUsername)?;
+    let existing = user::Entity::find()
+        .filter(user::Column::Username.eq(&username))
+        .one(&state.db)
+        .await?;
+    match existing {
+        Some(user) if user.sso_subject.is_some() => {
+            return Err(OidcError::OtherIdentity(username));
+        }
+        Some(_) if !oidc.link_users => return Err(OidcError::NotLinked(username)),
+        Some(_) => {}
+        None if !oidc.create_users => return Err(OidcError::NoAccount(username)),
+        None => {
+            provision_user(state, &username, None)
+                .await
+                .map_err(|err| OidcError::Failed(err.to_string()))?;
+        }
+    }
+
+    let linked = user::Entity::update_many()
+        .col_expr(user::Column::SsoIssuer, Expr::value(identity.issuer))
+        .col_expr(user::Column::SsoSubject, Expr::value(identity.subject))
+        .filter(user::Column::Username.eq(&username))
+        .filter(user::Column::SsoSubject.is_null())
+        .exec(&state.db)
+        .await?
+        .rows_affected;
+    if linked == 0 {
+        // Someone else logged into it in the meantime
+        return Err(OidcError::OtherIdentity(username));
+    }
+    tracing::info!(username, "Tied the account to the single sign-on user");
+    Ok(username)
+}
+
+#[tracing::instrument(skip(state))]
+#[get("/oidc")]
+pub async fn get_oidc(state: web::Data<AppState>) -> web::Json<OidcStatus> {
+    web::Json(OidcStatus {
+        enabled: state.oidc.is_some(),
+    })
+}
+
+#[tracing::instrument(skip(state))]
+#[get("/oidc/login")]
+pub async fn get_oidc_login(
+    state: web::Data<AppState>,
+    query: web::Query<OidcLoginQuery>,
+) -> Result<HttpResponse, OidcError> {
+    let (url, login_state) = start_login(&state, query.basic).await?;
+    Ok(HttpResponse::SeeOther()
+        .cookie(state_cookie(&state, &login_state))
+        .append_header((header::LOCATION, url))
+        .finish())
+}
+
+#[tracing::instrument(skip(req, state, query))]
+#[get("/oidc/callback")]
+pub async fn get_oidc_callback(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<OidcCallbackQuery>,
+) -> HttpResponse {
+    let browser_state = req.cookie(STATE_COOKIE_NAME);
+    let browser_state = browser_state.as_ref().map(|cookie| cookie.value());
+    let mut response = match finish_login(&state, &query, browser_state).await {
+        Ok((username, true)) => {
+            let token = make_session_token(&state, &username, client_info(&req))
+                .await
+                .unwrap_or_log();
+            sso_login_page(&token, &username)
+        }
+        Ok((username, false)) => {
+            let login = sso_login::ActiveModel {
+                basic: Set(false),
+                username: Set(Some(username)),
+                ..Default::default()
+            };
+            let code = save_login(&state, login).await.unwrap_or_log();
+            let location = format!("{APP_LOGIN_PAGE}?sso={}", code.reveal());
+            HttpResponse::SeeOther()
+                .append_header((header::LOCATION, location))
+                .finish()
+        }
+        Err(err) => {
+            tracing::warn!(error = %err, "Single sign-on failed");
+            sso_error_page(&err)
+        }
+    };
+    // Either way the login is over
+    let mut cookie = Cookie::named(STATE_COOKIE_NAME);
+    cookie.set_path(STATE_COOKIE_PATH);
+    response.add_removal_cookie(&cookie).unwrap_or_log();
+    response
+}
+
+#[tracing::instrument(skip(req, state, body))]
+#[post("/oidc/token")]
+pub async fn post_oidc_token(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<OidcCode>,
+) -> Result<web::Json<OidcLoginResponse>, OidcError> {
+    oidc_client(&state)?;
+    let login = take_login(&state, &body.code, true).await?;
+    let username = login.username.unwrap_or_default();
+    let access_token = make_session_token(&state, &username, client_info(&req))
+        .await
+        .map_err(|err| OidcError::Failed(err.to_string()))?;
+    let expires_at = state
+        .tokens
+        .expires_at(Utc::now())
+        .map(|at| at.to_rfc3339());
+    Ok(web::Json(OidcLoginResponse {
+        username,
+        access_token,
+        expires_at,
+    }))
+}
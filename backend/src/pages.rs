@@ -1,37 +1,77 @@
 use std::{ops::Deref, path::PathBuf};
 
-use actix_files::NamedFile;
 use actix_web::{
     cookie::Cookie,
     delete, get, post, put,
     web::{self, ReqData},
-    Either, HttpResponse,
+    Either, HttpRequest, HttpResponse, HttpResponseBuilder, ResponseError,
 };
 
 use actix_multipart::Multipart;
 use askama_actix::Template;
+use sea_orm::EntityTrait;
 use serde::{Deserialize, Serialize};
-use tokio::fs;
 use tracing_unwrap::ResultExt;
 
 use crate::{
-    auth::{make_token, verify_pass, Password},
-    auth_middleware::AUTH_COOKIE_NAME,
-    state::{AppState, Authorized},
+    archive::{archive_response, ArchiveFormat},
+    auth::{make_session_token, verify_login, LoginFailed, Password},
+    auth_middleware::{get_user_token, AUTH_COOKIE_NAME},
+    entity::path_token,
+    folder,
+    oidc::OidcError,
+    proxy_auth::verify_proxy_user,
+    ratelimit_middleware::{too_many_requests, RateLimit},
+    session::{
+        change_password, client_info, delete_token, is_session_active, list_sessions,
+        revoke_session, revoke_sessions, PasswordChange, Session, SessionError,
+    },
+    share::{
+        create_share, drop_files, is_active, list_shares, revoke_share, share_grant_cookie,
+        share_grant_from_request, verify_share_grant, verify_share_password, Share, ShareOptions,
+    },
+    state::{AppState, Authorized, Token},
     storage::{
         common_delete, get_authorized_path, get_storage_internal, write_files, FolderEntry,
-        StorageError,
+        StorageError, UploadQuery,
     },
+    totp::{
+        confirm_enrollment, disable_totp, pending_enrollment, regenerate_recovery_codes,
+        start_enrollment, totp_status, TotpEnrollment, TotpError, TotpStatus,
+    },
+    trash::{empty_trash, list_trash, purge, restore, TrashItem},
 };
 
 #[derive(Template)]
 #[template(path = "login.html")]
-pub struct LoginPage {}
+pub struct LoginPage {
+    error_text: Option<String>,
+    /// Show a link to log in with single sign-on.
+    sso: bool,
+}
 
-#[tracing::instrument]
+#[tracing::instrument(skip(req, state))]
 #[get("/basic/")]
-pub async fn page_login_get() -> LoginPage {
-    LoginPage {}
+pub async fn page_login_get(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    // Users a trusted proxy logged in don't need to log in again
+    let proxy_user = state
+        .proxy_auth
+        .as_ref()
+        .and_then(|proxy_auth| proxy_auth.username(&req));
+    if let Some(username) = proxy_user {
+        if verify_proxy_user(&state, &username).await.is_ok() {
+            return HttpResponse::SeeOther()
+                .append_header(("Location", format!("/basic/{username}/")))
+                .finish();
+        }
+    }
+    html_response(
+        HttpResponse::Ok(),
+        LoginPage {
+            error_text: None,
+            sso: state.oidc.is_some(),
+        },
+    )
 }
 
 #[derive(Template)]
@@ -48,35 +88,89 @@ pub async fn not_found() -> HttpResponse {
 pub struct LoginFormData {
     pub username: String,
     pub password: Password,
+    /// Only needed for users with two-factor authentication.
+    #[serde(default)]
+    pub code: Option<String>,
 }
 
-#[tracing::instrument(skip(form))]
+#[tracing::instrument(skip(req, form))]
 #[post("/basic/")]
 pub async fn page_login_post(
+    req: HttpRequest,
     form: web::Form<LoginFormData>,
     state: web::Data<AppState>,
 ) -> HttpResponse {
-    if verify_pass(&form.username, &form.password, &state.db)
-        .await
-        .is_ok()
-    {
-        let token = make_token(&state, &form.username).await.unwrap_or_log();
-
-        HttpResponse::SeeOther()
-            .cookie(Cookie::new(
-                AUTH_COOKIE_NAME,
-                format!("{}; SameSite=Strict", token.reveal()),
-            ))
-            .append_header(("Location", format!("/basic/{}/", form.username)))
-            .finish()
-    } else {
-        HttpResponse::Unauthorized().finish()
+    match verify_login(&state, &form.username, &form.password, form.code.as_deref()).await {
+        Ok(()) => {
+            let token = make_session_token(&state, &form.username, client_info(&req))
+                .await
+                .unwrap_or_log();
+
+            HttpResponse::SeeOther()
+                .cookie(login_cookie(&token))
+                .append_header(("Location", format!("/basic/{}/", form.username)))
+                .finish()
+        }
+        Err(LoginFailed::WrongPassword) => HttpResponse::Unauthorized().finish(),
+        Err(err) => html_response(
+            HttpResponse::Unauthorized(),
+            LoginPage {
+                error_text: Some(err.to_string()),
+                sso: state.oidc.is_some(),
+            },
+        ),
     }
 }
 
-#[tracing::instrument]
+fn login_cookie(token: &Token) -> Cookie<'static> {
+    Cookie::new(
+        AUTH_COOKIE_NAME,
+        format!("{}; SameSite=Strict", token.reveal()),
+    )
+}
+
+#[derive(Template)]
+#[template(path = "redirect.html")]
+pub struct RedirectPage {
+    redirect_link: String,
+}
+
+/// Logs the user into the basic pages after they logged in with single
+/// sign-on. The identity provider is on another site, so the cookie wouldn't
+/// be sent if it redirected straight to the store. This page does the
+/// redirect instead.
+pub(crate) fn sso_login_page(token: &Token, username: &str) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response.cookie(login_cookie(token));
+    html_response(
+        response,
+        RedirectPage {
+            redirect_link: format!("/basic/{username}/"),
+        },
+    )
+}
+
+pub(crate) fn sso_error_page(err: &OidcError) -> HttpResponse {
+    html_response(
+        HttpResponseBuilder::new(err.status_code()),
+        ErrorPage {
+            error_text: Some(err.to_string()),
+            redirect_link: "/".to_string(),
+        },
+    )
+}
+
+#[tracing::instrument(skip(req, state))]
 #[post("/basic/logout")]
-pub async fn page_logout() -> HttpResponse {
+pub async fn page_logout(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    // Removing the cookie is not enough, the token would still work
+    if let Some(token) = get_user_token(&req) {
+        delete_token(&state, &token).await.unwrap_or_log();
+    }
+    logout_response()
+}
+
+fn logout_response() -> HttpResponse {
     let mut remove_cookie = Cookie::named(AUTH_COOKIE_NAME);
     remove_cookie.make_removal();
 
@@ -90,6 +184,10 @@ pub async fn page_logout() -> HttpResponse {
 #[template(path = "folder-list.html")]
 pub struct FolderListPage {
     username: String,
+    /// False if the store belongs to someone else, who shared a path in it.
+    /// Group members own the store of the group.
+    is_owner: bool,
+    store: String,
     path: String,
     parent_path: Option<String>,
     folder_list: Vec<FolderEntry>,
@@ -102,10 +200,12 @@ pub struct ErrorPage {
     redirect_link: String,
 }
 
-#[tracing::instrument(skip(payload))]
+#[tracing::instrument(skip(state, payload))]
 #[put("/{store}/{path:.*}")]
 pub async fn page_folder_upload(
+    state: web::Data<AppState>,
     params: web::Path<(String, String)>,
+    query: web::Query<UploadQuery>,
     authorized: Option<ReqData<Authorized>>,
     mut payload: Multipart,
 ) -> Result<HttpResponse, StorageError> {
@@ -113,7 +213,7 @@ pub async fn page_folder_upload(
     let store_path = get_authorized_path(&authorized, store, Some(path))?;
     let folder_path = format!("/basic/{store}/{path}");
 
-    match write_files(&mut payload, &store_path).await {
+    match write_files(&state, &mut payload, store, &store_path, query.conflict).await {
         // If upload was successful, get the browser to refresh the page with a get request.
         Ok(_) => Ok(HttpResponse::SeeOther()
             .append_header(("Location", folder_path))
@@ -130,14 +230,15 @@ pub async fn page_folder_upload(
     }
 }
 
-#[tracing::instrument]
+#[tracing::instrument(skip(state))]
 #[delete("/{store}/{path:.*}")]
 pub async fn page_delete(
+    state: web::Data<AppState>,
     params: web::Path<(String, String)>,
     authorized: Option<ReqData<Authorized>>,
 ) -> Result<HttpResponse, StorageError> {
     let (store, path) = params.as_ref();
-    common_delete(&authorized, store, Some(path)).await?;
+    common_delete(&state, &authorized, store, Some(path)).await?;
     // We want to redirect the user back to the folder they were in.
     let mut path = PathBuf::from(path);
     path.pop();
@@ -155,8 +256,9 @@ pub struct CreateFolderForm {
     pub folder: String,
 }
 
-#[tracing::instrument]
+#[tracing::instrument(skip(state))]
 pub async fn page_create_folder(
+    state: web::Data<AppState>,
     params: web::Path<(String, String)>,
     authorized: Option<ReqData<Authorized>>,
     form: web::Form<CreateFolderForm>,
@@ -166,43 +268,222 @@ pub async fn page_create_folder(
 
     let folder_name = sanitize_filename::sanitize(&form.folder);
     store_path.push(&folder_name);
-    fs::create_dir(&store_path).await?;
+    state.storage.create_dir(&store_path).await?;
 
     Ok(HttpResponse::SeeOther()
         .append_header(("Location", format!("/basic/{store}/{path}{folder_name}/")))
         .finish())
 }
 
-#[tracing::instrument]
+#[derive(Template)]
+#[template(path = "trash.html")]
+pub struct TrashPage {
+    username: String,
+    store: String,
+    items: Vec<TrashItem>,
+}
+
+/// The read-only page visitors see when they open a folder that was shared
+/// with them.
+#[derive(Template)]
+#[template(path = "shared-folder.html")]
+pub struct SharedFolderPage {
+    path: String,
+    parent_path: Option<String>,
+    token: String,
+    folder_list: Vec<FolderEntry>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct FolderListQuery {
+    pub archive: Option<ArchiveFormat>,
+    /// Show the trash of the store instead of the folder.
+    pub trash: Option<String>,
+    /// The path token, if the folder was shared.
+    pub token: Option<String>,
+    /// Show the share links of the store instead of the folder.
+    pub shares: Option<String>,
+    /// Show the password and sessions of the user instead of the folder.
+    pub account: Option<String>,
+}
+
+/// True if the visitor is not the owner of the store, and can only see it
+/// because it was shared with a path token.
+fn is_shared_visit(authorized: &Option<ReqData<Authorized>>, store: &str) -> bool {
+    match authorized.as_deref() {
+        Some(Authorized::User(user))
+        | Some(Authorized::Both(user))
+        | Some(Authorized::Key(user, _)) => user.0 != store,
+        // Group stores and paths shared with the user don't need a token
+        Some(Authorized::Shared(_, _)) => false,
+        _ => true,
+    }
+}
+
+/// Finds the folder a path token was made for, relative to the storage.
+async fn shared_folder(state: &AppState, token: &str) -> Result<PathBuf, StorageError> {
+    let token = path_token::Entity::find_by_id(state.token_key.hash(token))
+        .one(&state.db)
+        .await?
+        .ok_or(StorageError::NotAuthorized)?;
+    PathBuf::from(token.path.trim_start_matches('/'))
+        .strip_prefix(folder::STORAGE)
+        .map(|path| path.to_path_buf())
+        .map_err(|_| StorageError::NotAuthorized)
+}
+
+fn page_username(authorized: &Option<ReqData<Authorized>>) -> Result<String, StorageError> {
+    match authorized {
+        Some(user) => Ok(match user.deref() {
+            Authorized::User(user) => user.0.clone(),
+            Authorized::Path => "anonymous".to_string(),
+            Authorized::Both(user) => user.0.clone(),
+            Authorized::Shared(user, _) => user.0.clone(),
+            Authorized::Key(user, _) => user.0.clone(),
+        }),
+        None => Err(StorageError::NotAuthorized),
+    }
+}
+
+/// The share links of a store, and a form to share the file or folder at
+/// `path`.
+#[derive(Template)]
+#[template(path = "shares.html")]
+pub struct SharesPage {
+    username: String,
+    store: String,
+    path: String,
+    shares: Vec<Share>,
+    /// The link of the share that was just made. Links can't be shown again
+    /// later, only hashes of their tokens are kept.
+    created: Option<String>,
+}
+
+async fn shares_page(
+    state: &AppState,
+    authorized: &Option<ReqData<Authorized>>,
+    store: String,
+    path: &str,
+    created: Option<String>,
+) -> Result<SharesPage, StorageError> {
+    let path = PathBuf::from(&store).join(path);
+    Ok(SharesPage {
+        username: page_username(authorized)?,
+        shares: list_shares(state, &store).await?,
+        path: path.to_string_lossy().to_string(),
+        store,
+        created,
+    })
+}
+
+/// Where users change their password and log out of their sessions.
+#[derive(Template)]
+#[template(path = "account.html")]
+pub struct AccountPage {
+    username: String,
+    sessions: Vec<Session>,
+    totp: TotpStatus,
+    /// Shown while the user is setting up two-factor authentication.
+    enrollment: Option<TotpEnrollment>,
+    /// New recovery codes, these can only be shown once.
+    recovery_codes: Option<Vec<String>>,
+}
+
+async fn account_page(
+    state: &AppState,
+    req: &HttpRequest,
+    username: String,
+    recovery_codes: Option<Vec<String>>,
+) -> Result<AccountPage, TotpError> {
+    let sessions = list_sessions(state, &username, get_user_token(req).as_ref())
+        .await
+        .map_err(|_| TotpError::NotAuthorized)?;
+    Ok(AccountPage {
+        sessions,
+        totp: totp_status(state, &username).await?,
+        enrollment: pending_enrollment(state, &username).await?,
+        recovery_codes,
+        username,
+    })
+}
+
+/// The pages that can be shown for a folder.
+type FolderPage = Either<
+    FolderListPage,
+    Either<TrashPage, Either<SharedFolderPage, Either<SharesPage, AccountPage>>>,
+>;
+
+/// The user the account page is for. The page is only shown in the user's
+/// own store.
+fn account_username(authorized: &Option<ReqData<Authorized>>, store: &str) -> Option<String> {
+    authorized
+        .as_deref()
+        .and_then(|authorized| authorized.username())
+        .filter(|user| user.0 == store)
+        .map(|user| user.0.clone())
+}
+
+#[tracing::instrument(skip(state))]
 #[get("/{store}/{path:.*}")]
 pub async fn page_folder_list(
+    req: HttpRequest,
+    state: web::Data<AppState>,
     params: web::Path<(String, String)>,
+    query: web::Query<FolderListQuery>,
     authorized: Option<ReqData<Authorized>>,
     // TODO: Add a new error type with an HTML responder here
-) -> Result<Either<NamedFile, FolderListPage>, StorageError> {
+) -> Result<Either<HttpResponse, FolderPage>, StorageError> {
     let (store, path) = params.clone();
+    let shared_visit = is_shared_visit(&authorized, &store);
+    if query.trash.is_some() {
+        // The trash belongs to the whole store, not to a shared folder
+        if shared_visit {
+            return Err(StorageError::NotAuthorized);
+        }
+        get_authorized_path(&authorized, &store, None)?;
+        return Ok(Either::Right(Either::Right(Either::Left(TrashPage {
+            username: page_username(&authorized)?,
+            items: list_trash(&state, &store).await?,
+            store,
+        }))));
+    }
+    if query.shares.is_some() {
+        // Visitors can't see or make other share links
+        if shared_visit {
+            return Err(StorageError::NotAuthorized);
+        }
+        get_authorized_path(&authorized, &store, None)?;
+        get_authorized_path(&authorized, &store, Some(&path))?;
+        return Ok(Either::Right(Either::Right(Either::Right(Either::Right(
+            Either::Left(shares_page(&state, &authorized, store, &path, None).await?),
+        )))));
+    }
+    if query.account.is_some() {
+        let username = account_username(&authorized, &store).ok_or(StorageError::NotAuthorized)?;
+        let page = account_page(&state, &req, username, None)
+            .await
+            .map_err(|_| StorageError::NotAuthorized)?;
+        return Ok(Either::Right(Either::Right(Either::Right(Either::Right(
+            Either::Right(page),
+        )))));
+    }
+    if let Some(format) = query.archive {
+        let store_path = get_authorized_path(&authorized, &store, Some(&path))?;
+        return Ok(Either::Left(
+            archive_response(state, store_path, format).await?,
+        ));
+    }
     let mut store_path = PathBuf::from(&store);
     if !path.is_empty() {
         store_path.push(&path);
     }
     tracing::debug!("{:?}, {:?}, {:?}", &store, &path, &store_path);
 
-    let out = get_storage_internal((&store, &path), &authorized).await?;
+    let out = get_storage_internal(&req, &state, (&store, &path), &authorized).await?;
 
     match out {
         Either::Left(file) => Ok(Either::Left(file)),
         Either::Right(folder_list) => {
-            let username = match &authorized {
-                Some(user) => match user.deref() {
-                    Authorized::User(user) => user.0.as_str(),
-                    Authorized::Path => "anonymous",
-                    Authorized::Both(user) => user.0.as_str(),
-                },
-                None => {
-                    return Err(StorageError::NotAuthorized);
-                }
-            }
-            .to_string();
             let parent_path = store_path
                 .parent()
                 .map(|parent| parent.to_string_lossy().to_string())
@@ -215,12 +496,526 @@ pub async fn page_folder_list(
                     }
                 });
             tracing::debug!(parent_path, "parent_path");
-            Ok(Either::Right(FolderListPage {
+            if shared_visit {
+                let token = query.token.clone().ok_or(StorageError::NotAuthorized)?;
+                let shared = shared_folder(&state, &token).await?;
+                // Visitors can't go up past the folder that was shared
+                let parent_path =
+                    parent_path.filter(|parent| PathBuf::from(parent).starts_with(&shared));
+                return Ok(Either::Right(Either::Right(Either::Right(Either::Left(
+                    SharedFolderPage {
+                        path: store_path
+                            .to_string_lossy()
+                            .trim_end_matches('/')
+                            .to_string(),
+                        folder_list: folder_list.0.entries,
+                        parent_path,
+                        token,
+                    },
+                )))));
+            }
+            let username = page_username(&authorized)?;
+            Ok(Either::Right(Either::Left(FolderListPage {
+                is_owner: get_authorized_path(&authorized, &store, None).is_ok(),
                 username,
+                store,
                 path: store_path.to_string_lossy().to_string(),
                 folder_list: folder_list.0.entries,
                 parent_path,
-            }))
+            })))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TrashItemForm {
+    pub item: Option<String>,
+}
+
+fn redirect_to_trash(store: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .append_header(("Location", format!("/basic/{store}/?trash")))
+        .finish()
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn page_trash_restore(
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    form: web::Form<TrashItemForm>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, _) = params.as_ref();
+    get_authorized_path(&authorized, store, None)?;
+    let item = form.item.as_deref().ok_or(StorageError::BadPath)?;
+    if let Err(err) = restore(&state, store, item).await {
+        return Ok(HttpResponse::build(err.status_code()).body(
+            ErrorPage {
+                error_text: Some(err.to_string()),
+                redirect_link: format!("/basic/{store}/?trash"),
+            }
+            .render()
+            .unwrap_or_log(),
+        ));
+    }
+    Ok(redirect_to_trash(store))
+}
+
+/// Permanently deletes one item from the trash, or everything in the trash if
+/// no item is picked.
+#[tracing::instrument(skip(state))]
+pub async fn page_trash_purge(
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    form: web::Form<TrashItemForm>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, _) = params.as_ref();
+    get_authorized_path(&authorized, store, None)?;
+    match form.item.as_deref() {
+        Some(item) => purge(&state, store, item).await?,
+        None => empty_trash(&state, store).await?,
+    }
+    Ok(redirect_to_trash(store))
+}
+
+/// The fields of the form to make a share link. Empty fields are sent as empty
+/// strings, so these are parsed by hand.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ShareForm {
+    #[serde(default)]
+    pub label: String,
+    #[serde(default)]
+    pub valid_for_hours: String,
+    #[serde(default)]
+    pub password: String,
+    #[serde(default)]
+    pub max_downloads: String,
+    /// The checkbox is only sent if it's checked.
+    #[serde(default)]
+    pub file_drop: Option<String>,
+    /// In megabytes, to keep the form simple.
+    #[serde(default)]
+    pub max_file_size: String,
+    #[serde(default)]
+    pub max_files: String,
+}
+
+const MEGABYTE: u64 = 1024 * 1024;
+
+impl TryFrom<&ShareForm> for ShareOptions {
+    type Error = String;
+
+    fn try_from(form: &ShareForm) -> Result<Self, Self::Error> {
+        fn parse_number(field: &str, value: &str) -> Result<Option<u32>, String> {
+            let value = value.trim();
+            if value.is_empty() {
+                return Ok(None);
+            }
+            value
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("{field} must be a whole number"))
+        }
+        fn non_empty(value: &str) -> Option<String> {
+            Some(value.to_string()).filter(|value| !value.is_empty())
         }
+        Ok(ShareOptions {
+            label: non_empty(form.label.trim()),
+            valid_for_hours: parse_number("Expiry", &form.valid_for_hours)?,
+            password: non_empty(&form.password).map(Password),
+            max_downloads: parse_number("Download limit", &form.max_downloads)?,
+            file_drop: form.file_drop.is_some(),
+            max_file_size: parse_number("File size limit", &form.max_file_size)?
+                .map(|megabytes| u64::from(megabytes) * MEGABYTE),
+            max_files: parse_number("File limit", &form.max_files)?,
+        })
+    }
+}
+
+fn redirect_to_shares(store: &str, path: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .append_header(("Location", format!("/basic/{store}/{path}?shares")))
+        .finish()
+}
+
+#[tracing::instrument(skip(state, form))]
+pub async fn page_share_create(
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    form: web::Form<ShareForm>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, path) = params.as_ref();
+    // Only the owner can make share links
+    get_authorized_path(&authorized, store, None)?;
+    let store_path = get_authorized_path(&authorized, store, Some(path))?;
+    let created = match ShareOptions::try_from(form.deref()) {
+        Ok(options) => create_share(&state, store, &store_path, options)
+            .await
+            .map_err(|err| err.to_string()),
+        Err(err) => Err(err),
+    };
+    let share = match created {
+        Ok(share) => share,
+        Err(err) => {
+            return Ok(HttpResponse::BadRequest().body(
+                ErrorPage {
+                    error_text: Some(err),
+                    redirect_link: format!("/basic/{store}/{path}?shares"),
+                }
+                .render()
+                .unwrap_or_log(),
+            ))
+        }
+    };
+    // The link is only known now, so the page is shown instead of redirecting
+    let link = share
+        .token
+        .map(|token| format!("/share/{}", token.reveal()));
+    Ok(html_response(
+        HttpResponse::Ok(),
+        shares_page(&state, &authorized, store.to_string(), path, link).await?,
+    ))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RevokeShareForm {
+    /// The ID of the share link.
+    pub share: String,
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn page_share_revoke(
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    form: web::Form<RevokeShareForm>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, path) = params.as_ref();
+    get_authorized_path(&authorized, store, None)?;
+    revoke_share(&state, store, &form.share).await?;
+    Ok(redirect_to_shares(store, path))
+}
+
+/// Asks visitors for the password of a share link.
+#[derive(Template)]
+#[template(path = "share-password.html")]
+pub struct SharePasswordPage {
+    token: String,
+    error_text: Option<String>,
+}
+
+/// Where visitors of a share link end up: the basic page of a folder, or the
+/// contents of a file.
+fn share_location(share: &path_token::Model, token: &str) -> String {
+    if share.file_drop {
+        format!("/share/{token}")
+    } else if share.is_folder {
+        let path = share
+            .path
+            .trim_start_matches('/')
+            .trim_start_matches(folder::STORAGE)
+            .trim_matches('/');
+        format!("/basic/{path}/?token={token}")
+    } else {
+        format!("{}?token={token}", share.path)
+    }
+}
+
+async fn find_active_share(
+    state: &AppState,
+    token: &str,
+) -> Result<Option<path_token::Model>, StorageError> {
+    Ok(path_token::Entity::find_by_id(state.token_key.hash(token))
+        .one(&state.db)
+        .await?
+        .filter(|token| is_active(token, chrono::Utc::now())))
+}
+
+/// The upload page of a file drop.
+#[derive(Template)]
+#[template(path = "file-drop.html")]
+pub struct FileDropPage {
+    token: String,
+    label: Option<String>,
+    max_file_size: Option<String>,
+    files_left: Option<i32>,
+    /// How many files were just uploaded.
+    uploaded: Option<usize>,
+}
+
+impl FileDropPage {
+    fn new(share: path_token::Model, token: String, uploaded: Option<usize>) -> Self {
+        FileDropPage {
+            max_file_size: share
+                .max_file_size
+                .map(|max| format!("{:.1} MB", max as f64 / MEGABYTE as f64)),
+            files_left: share.max_files.map(|max| max - share.uploads),
+            token,
+            label: share.label,
+            uploaded,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct FileDropQuery {
+    pub uploaded: Option<usize>,
+}
+
+fn html_response(mut response: HttpResponseBuilder, page: impl Template) -> HttpResponse {
+    response
+        .content_type("text/html")
+        .body(page.render().unwrap_or_log())
+}
+
+/// The link that is handed out for share links. Visitors are sent on to the
+/// shared file or folder, after entering the password if there is one. File
+/// drops show a page to upload files instead.
+#[tracing::instrument(skip(state, req))]
+#[get("/{token}")]
+pub async fn page_share_get(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    token: web::Path<String>,
+    query: web::Query<FileDropQuery>,
+) -> Result<HttpResponse, StorageError> {
+    let Some(share) = find_active_share(&state, &token).await? else {
+        return Ok(not_found().await);
+    };
+    if share.file_drop {
+        let grant = share_grant_from_request(&req, &token);
+        if verify_share_grant(&state, &share, grant.as_deref()) {
+            return Ok(html_response(
+                HttpResponse::Ok(),
+                FileDropPage::new(share, token.into_inner(), query.uploaded),
+            ));
+        }
+    } else if share.password_hash.is_none() {
+        return Ok(HttpResponse::SeeOther()
+            .append_header(("Location", share_location(&share, &token)))
+            .finish());
+    }
+    Ok(HttpResponse::Ok().content_type("text/html").body(
+        SharePasswordPage {
+            token: token.into_inner(),
+            error_text: None,
+        }
+        .render()
+        .unwrap_or_log(),
+    ))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SharePasswordForm {
+    pub password: Password,
+}
+
+#[tracing::instrument(skip(req, state, rate_limit, form))]
+#[post("/{token}")]
+pub async fn page_share_post(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    rate_limit: web::Data<RateLimit>,
+    token: web::Path<String>,
+    form: web::Form<SharePasswordForm>,
+) -> Result<HttpResponse, StorageError> {
+    // Visitors that guessed wrong too many times can't try again yet
+    if let Some(retry_after) = rate_limit.retry_after(&req) {
+        return Ok(too_many_requests(retry_after));
+    }
+    let Some(share) = find_active_share(&state, &token).await? else {
+        return Ok(not_found().await);
+    };
+    if !verify_share_password(&share, Some(&form.password)).await {
+        rate_limit.count_failure(&req);
+        return Ok(HttpResponse::Unauthorized().content_type("text/html").body(
+            SharePasswordPage {
+                token: token.into_inner(),
+                error_text: Some("Wrong password, please try again.".to_string()),
+            }
+            .render()
+            .unwrap_or_log(),
+        ));
+    }
+    // The requests made with the link only check the cookie, the password is
+    // only checked here where guesses are rate limited
+    Ok(HttpResponse::SeeOther()
+        .cookie(share_grant_cookie(&state, &token, &share))
+        .append_header(("Location", share_location(&share, &token)))
+        .finish())
+}
+
+/// Uploads files to a file drop. The upload page sends the files here, but
+/// they can also be uploaded with a `PUT` request.
+#[tracing::instrument(skip(state, req, payload))]
+#[put("/{token}")]
+pub async fn page_file_drop(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    token: web::Path<String>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, StorageError> {
+    let share = match find_active_share(&state, &token).await? {
+        Some(share) if share.file_drop => share,
+        _ => return Ok(not_found().await),
+    };
+    let grant = share_grant_from_request(&req, &token);
+    if !verify_share_grant(&state, &share, grant.as_deref()) {
+        return Err(StorageError::NotAuthorized);
+    }
+    match drop_files(&state, &share, &mut payload).await {
+        Ok(uploaded) => Ok(HttpResponse::SeeOther()
+            .append_header(("Location", format!("/share/{token}?uploaded={uploaded}")))
+            .finish()),
+        Err(err) => Ok(html_response(
+            HttpResponse::build(err.status_code()),
+            ErrorPage {
+                error_text: Some(err.to_string()),
+                redirect_link: format!("/share/{token}"),
+            },
+        )),
+    }
+}
+
+fn redirect_to_account(store: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .append_header(("Location", format!("/basic/{store}/?account")))
+        .finish()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PasswordForm {
+    pub current_password: Password,
+    pub new_password: Password,
+}
+
+#[tracing::instrument(skip(req, state, form))]
+pub async fn page_password_change(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    authorized: Option<ReqData<Authorized>>,
+    form: web::Form<PasswordForm>,
+) -> Result<HttpResponse, SessionError> {
+    let (store, _) = params.as_ref();
+    let username = account_username(&authorized, store).ok_or(SessionError::NotAuthorized)?;
+    let form = form.into_inner();
+    let change = PasswordChange {
+        current_password: form.current_password,
+        new_password: form.new_password,
+    };
+    match change_password(&state, &username, &change, get_user_token(&req).as_ref()).await {
+        Ok(_) => Ok(redirect_to_account(store)),
+        Err(err @ (SessionError::WrongPassword | SessionError::EmptyPassword)) => {
+            Ok(HttpResponse::build(err.status_code()).body(
+                ErrorPage {
+                    error_text: Some(err.to_string()),
+                    redirect_link: format!("/basic/{store}/?account"),
+                }
+                .render()
+                .unwrap_or_log(),
+            ))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionForm {
+    /// The session to log out of, or all sessions if missing.
+    pub session: Option<String>,
+}
+
+#[tracing::instrument(skip(req, state))]
+pub async fn page_session_revoke(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    authorized: Option<ReqData<Authorized>>,
+    form: web::Form<SessionForm>,
+) -> Result<HttpResponse, SessionError> {
+    let (store, _) = params.as_ref();
+    let username = account_username(&authorized, store).ok_or(SessionError::NotAuthorized)?;
+    match &form.session {
+        Some(session) => revoke_session(&state, &username, session).await?,
+        None => revoke_sessions(&state, &username, None).await?,
+    };
+    // The user may have logged themselves out too
+    let logged_in = match get_user_token(&req) {
+        Some(token) => is_session_active(&state, &token).await?,
+        None => false,
+    };
+    if logged_in {
+        Ok(redirect_to_account(store))
+    } else {
+        Ok(logout_response())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum TotpAction {
+    /// Make a new secret to add to an authenticator app.
+    Enroll,
+    /// Turn on two-factor authentication, with a code from the app.
+    Confirm,
+    /// Replace the recovery codes, with a code from the app.
+    Recovery,
+    /// Turn off two-factor authentication, with the password of the user.
+    Disable,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TotpForm {
+    pub action: TotpAction,
+    #[serde(default)]
+    pub code: Option<String>,
+    #[serde(default)]
+    pub password: Option<Password>,
+}
+
+#[tracing::instrument(skip(req, state, form))]
+pub async fn page_totp(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    authorized: Option<ReqData<Authorized>>,
+    form: web::Form<TotpForm>,
+) -> Result<HttpResponse, TotpError> {
+    let (store, _) = params.as_ref();
+    let username = account_username(&authorized, store).ok_or(TotpError::NotAuthorized)?;
+    let code = form.code.as_deref().unwrap_or_default();
+    let result = match form.action {
+        TotpAction::Enroll => start_enrollment(&state, &username).await.map(|_| None),
+        TotpAction::Confirm => confirm_enrollment(&state, &username, code).await.map(Some),
+        TotpAction::Recovery => regenerate_recovery_codes(&state, &username, code)
+            .await
+            .map(Some),
+        TotpAction::Disable => match &form.password {
+            Some(password) => disable_totp(&state, &username, password)
+                .await
+                .map(|_| None),
+            None => Err(TotpError::WrongPassword),
+        },
+    };
+    match result {
+        Ok(None) => Ok(redirect_to_account(store)),
+        // Recovery codes can't be shown again later, so the page is shown
+        // instead of redirecting
+        Ok(Some(codes)) => Ok(html_response(
+            HttpResponse::Ok(),
+            account_page(&state, &req, username, Some(codes.codes)).await?,
+        )),
+        Err(TotpError::Database(err)) => Err(TotpError::Database(err)),
+        Err(err) => Ok(HttpResponse::build(err.status_code()).body(
+            ErrorPage {
+                error_text: Some(err.to_string()),
+                redirect_link: format!("/basic/{store}/?account"),
+            }
+            .render()
+            .unwrap_or_log(),
+        )),
     }
 }
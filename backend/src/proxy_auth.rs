@@ -0,0 +1,87 @@
+//! Trusts the username an authenticating reverse proxy sends in a header, with
+//! the `--proxy-auth-header` option.
+//!
+//! Proxies like oauth2-proxy or Authelia log users in before passing their
+//! requests on, and add a header like `X-Remote-User` with the username. The
+//! header is only trusted when the request comes directly from one of the
+//! `--proxy-auth-trusted` networks, otherwise anyone could send it. The
+//! connecting address is used for this even with `BULGUR_CLOUD_BEHIND_PROXY`,
+//! since `X-Forwarded-For` can be forged just as easily. Users are added to
+//! the datastore and get a store the first time they show up.
+use actix_web::{http::header::HeaderName, HttpRequest};
+use ipnet::IpNet;
+
+use crate::{
+    auth::{provision_user, validate_username, USER_NOBODY},
+    state::AppState,
+};
+
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ProxyAuthOptions {
+    #[clap(long, env = "BULGUR_CLOUD_PROXY_AUTH_HEADER")]
+    /// The header an authenticating reverse proxy puts the username in, like
+    /// `X-Remote-User`. Requests with this header are logged in as that user
+    /// without a token, if they come from a trusted proxy.
+    pub proxy_auth_header: Option<String>,
+
+    #[clap(long, env = "BULGUR_CLOUD_PROXY_AUTH_TRUSTED", value_delimiter = ',')]
+    /// The networks of the proxies that are trusted to set the username
+    /// header, like `10.0.0.0/8,127.0.0.1/32`. Required with
+    /// `--proxy-auth-header`.
+    pub proxy_auth_trusted: Vec<IpNet>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProxyAuthSetupError {
+    #[error("The `--proxy-auth-trusted` option is required with `--proxy-auth-header`")]
+    NoTrustedProxies,
+    #[error("The `--proxy-auth-header` option is not a valid header name: {0}")]
+    BadHeader(#[from] actix_web::http::header::InvalidHeaderName),
+}
+
+#[derive(Debug)]
+pub struct ProxyAuth {
+    header: HeaderName,
+    trusted: Vec<IpNet>,
+}
+
+impl ProxyAuth {
+    /// The username the proxy sent, if the request came from a trusted
+    /// proxy.
+    pub fn username(&self, request: &HttpRequest) -> Option<String> {
+        let value = request.headers().get(&self.header)?;
+        let peer = request.peer_addr()?.ip().to_canonical();
+        if !self.trusted.iter().any(|net| net.contains(&peer)) {
+            tracing::warn!(%peer, "Ignoring the username header from an untrusted address");
+            return None;
+        }
+        let username = value.to_str().ok()?.trim();
+        (!username.is_empty()).then(|| username.to_string())
+    }
+}
+
+/// Reads the options, returns nothing if the proxy isn't trusted to log users
+/// in.
+pub fn setup_proxy_auth(
+    options: &ProxyAuthOptions,
+) -> Result<Option<ProxyAuth>, ProxyAuthSetupError> {
+    let Some(header) = &options.proxy_auth_header else {
+        return Ok(None);
+    };
+    if options.proxy_auth_trusted.is_empty() {
+        return Err(ProxyAuthSetupError::NoTrustedProxies);
+    }
+    Ok(Some(ProxyAuth {
+        header: HeaderName::try_from(header.as_str())?,
+        trusted: options.proxy_auth_trusted.clone(),
+    }))
+}
+
+/// Makes sure the user the proxy logged in has an account and a store.
+pub(crate) async fn verify_proxy_user(state: &AppState, username: &str) -> anyhow::Result<()> {
+    validate_username(username)?;
+    if username == USER_NOBODY {
+        anyhow::bail!("{USER_NOBODY} can't log in");
+    }
+    provision_user(state, username, None).await
+}
@@ -0,0 +1,278 @@
+//! Limits on how much each user can keep in their store.
+//!
+//! Admins can give users a quota in bytes, users without one can store as much
+//! as they like. The space used by each store is tracked in the `user` table
+//! and updated whenever files are added to or removed from the store. If the
+//! usage is not known, for example right after upgrading, it is calculated
+//! from the files in the store the next time it's needed.
+//!
+//! Files in the trash and previous versions of files don't count when
+//! checking if something fits, but they have to fit in the quota too. Once a
+//! store goes over its quota, the oldest of them are removed for good to make
+//! room, see [`reclaim_space`].
+//!
+//! Only users have quotas. Group stores don't have an owner to charge, so
+//! they can hold as much as they like.
+use std::{io, path::Path, path::PathBuf};
+
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter,
+    Set,
+};
+use serde::Serialize;
+
+use crate::{
+    entity::{file_version, trash, user},
+    folder,
+    kv::KVBackend,
+    state::AppState,
+    storage::StorageError,
+    trash::purge_item,
+    versions::remove_version,
+};
+
+/// Adds up the size of a file, or of all files inside a folder.
+pub async fn path_size(storage: &dyn KVBackend, path: &Path) -> io::Result<u64> {
+    let meta = storage.metadata(path).await?;
+    if meta.is_file {
+        return Ok(meta.size);
+    }
+    let mut size = 0;
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(folder) = pending.pop() {
+        for entry in storage.read_dir(&folder).await? {
+            if entry.meta.is_file {
+                size += entry.meta.size;
+            } else {
+                pending.push(folder.join(entry.name));
+            }
+        }
+    }
+    Ok(size)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaUsage {
+    /// How many bytes the files in the store take up.
+    pub usage: u64,
+    /// How many bytes the store may hold, if there is a limit.
+    pub quota: Option<u64>,
+}
+
+impl QuotaUsage {
+    /// How many more bytes fit in the store, if there is a limit.
+    pub fn remaining(&self) -> Option<u64> {
+        self.quota.map(|quota| quota.saturating_sub(self.usage))
+    }
+}
+
+async fn find_user(state: &AppState, store: &str) -> Result<Option<user::Model>, StorageError> {
+    Ok(user::Entity::find()
+        .filter(user::Column::Username.eq(store))
+        .one(&state.db)
+        .await?)
+}
+
+/// Reads the quota and usage of a store, calculating the usage if it is not
+/// known yet.
+pub async fn quota_usage(state: &AppState, store: &str) -> Result<QuotaUsage, StorageError> {
+    let Some(user) = find_user(state, store).await? else {
+        return Ok(QuotaUsage {
+            usage: 0,
+            quota: None,
+        });
+    };
+    let quota = user.quota.map(|quota| quota as u64);
+    let usage = match user.usage {
+        Some(usage) => usage as u64,
+        None => {
+            let store_path = PathBuf::from(folder::STORAGE).join(store);
+            let usage = match path_size(state.storage.as_ref(), &store_path).await {
+                Ok(usage) => usage,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+                Err(err) => return Err(err.into()),
+            };
+            tracing::debug!(store, usage, "Calculated the usage of the store");
+            let mut user: user::ActiveModel = user.into();
+            user.usage = Set(Some(usage as i64));
+            user.update(&state.db).await?;
+            usage
+        }
+    };
+    Ok(QuotaUsage { usage, quota })
+}
+
+/// Fails with [`StorageError::QuotaExceeded`] if `bytes` more don't fit in
+/// the store.
+pub async fn check_quota(state: &AppState, store: &str, bytes: u64) -> Result<(), StorageError> {
+    match quota_usage(state, store).await?.remaining() {
+        Some(remaining) if bytes > remaining => Err(StorageError::QuotaExceeded),
+        _ => Ok(()),
+    }
+}
+
+/// Records that the files in the store grew or shrank by `delta` bytes.
+pub async fn add_usage(state: &AppState, store: &str, delta: i64) -> Result<(), StorageError> {
+    if delta == 0 {
+        return Ok(());
+    }
+    // If the usage is not known yet, this leaves it unknown
+    user::Entity::update_many()
+        .col_expr(
+            user::Column::Usage,
+            Expr::cust_with_values("MAX(\"usage\" + ?, 0)", [delta]),
+        )
+        .filter(user::Column::Username.eq(store))
+        .exec(&state.db)
+        .await?;
+    if delta > 0 {
+        reclaim_space(state, store).await?;
+    }
+    Ok(())
+}
+
+/// Something kept around after it was deleted or replaced.
+enum Retained {
+    Trash(trash::Model),
+    Version(file_version::Model),
+}
+
+impl Retained {
+    fn size(&self) -> u64 {
+        match self {
+            Retained::Trash(item) => item.size as u64,
+            Retained::Version(version) => version.size as u64,
+        }
+    }
+
+    /// When it was deleted or replaced.
+    fn since(&self) -> &str {
+        match self {
+            Retained::Trash(item) => &item.deleted_at,
+            Retained::Version(version) => &version.created_at,
+        }
+    }
+}
+
+/// Permanently removes the oldest items in the trash and the oldest versions
+/// of files in a store, until they fit in the quota along with the files in
+/// the store. Does nothing if the store has no quota.
+#[tracing::instrument(skip(state))]
+pub async fn reclaim_space(state: &AppState, store: &str) -> Result<(), StorageError> {
+    let usage = quota_usage(state, store).await?;
+    let Some(quota) = usage.quota else {
+        return Ok(());
+    };
+    let trash = trash::Entity::find()
+        .filter(trash::Column::Store.eq(store))
+        .all(&state.db)
+        .await?;
+    let versions = file_version::Entity::find()
+        .filter(file_version::Column::Store.eq(store))
+        .all(&state.db)
+        .await?;
+    let mut retained: Vec<Retained> = trash
+        .into_iter()
+        .map(Retained::Trash)
+        .chain(versions.into_iter().map(Retained::Version))
+        .collect();
+    let mut total = usage.usage + retained.iter().map(Retained::size).sum::<u64>();
+    if total <= quota {
+        return Ok(());
+    }
+
+    // The dates are all RFC 3339 in UTC, so they sort as strings
+    retained.sort_by(|a, b| a.since().cmp(b.since()));
+    let mut removed = 0;
+    for item in retained {
+        if total <= quota {
+            break;
+        }
+        total = total.saturating_sub(item.size());
+        removed += 1;
+        match item {
+            Retained::Trash(item) => purge_item(state, item).await?,
+            Retained::Version(version) => remove_version(state, version).await?,
+        }
+    }
+    tracing::info!(
+        store,
+        removed,
+        "Removed old trash and versions to stay in the quota"
+    );
+    Ok(())
+}
+
+/// Forgets the usage of a store, so it is calculated again the next time it's
+/// needed. Use this when files may have been added or removed without
+/// knowing their sizes.
+pub async fn invalidate_usage(state: &AppState, store: &str) -> Result<(), StorageError> {
+    user::Entity::update_many()
+        .col_expr(user::Column::Usage, Expr::value(Option::<i64>::None))
+        .filter(user::Column::Username.eq(store))
+        .exec(&state.db)
+        .await?;
+    Ok(())
+}
+
+/// Sets the quota of a user in bytes. `None` removes the limit.
+pub async fn set_quota(
+    db: &DatabaseConnection,
+    username: &str,
+    quota: Option<u64>,
+) -> anyhow::Result<()> {
+    let user = user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(db)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("User {username} does not exist"))?;
+    let mut user: user::ActiveModel = user.into();
+    user.quota = Set(quota.map(|quota| quota as i64));
+    user.update(db).await?;
+    Ok(())
+}
+
+/// Keeps track of how much has been written while a file is being streamed
+/// into a store, so the upload can be stopped as soon as it goes over the
+/// quota.
+#[derive(Debug)]
+pub struct QuotaBudget {
+    remaining: Option<u64>,
+    used: u64,
+}
+
+impl QuotaBudget {
+    pub async fn for_store(state: &AppState, store: &str) -> Result<Self, StorageError> {
+        Ok(QuotaBudget {
+            remaining: quota_usage(state, store).await?.remaining(),
+            used: 0,
+        })
+    }
+
+    pub fn unlimited() -> Self {
+        QuotaBudget {
+            remaining: None,
+            used: 0,
+        }
+    }
+
+    /// Makes room for `bytes`, for example when a file is going to be
+    /// replaced.
+    pub fn credit(&mut self, bytes: u64) {
+        self.remaining = self.remaining.map(|remaining| remaining + bytes);
+    }
+
+    /// Records that `bytes` are about to be written. Fails if they don't fit.
+    pub fn spend(&mut self, bytes: u64) -> Result<(), StorageError> {
+        self.used += bytes;
+        match self.remaining {
+            Some(remaining) if self.used > remaining => Err(StorageError::QuotaExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    /// How many bytes have been written so far.
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+}
@@ -1,38 +1,112 @@
+use std::collections::HashMap;
 use std::future::{ready, Ready};
 use std::num::NonZeroU32;
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
 use actix_web::body::EitherBody;
 use actix_web::dev::{self, ServiceRequest, ServiceResponse};
 use actix_web::dev::{Service, Transform};
 use actix_web::http::header;
-use actix_web::{Error, HttpResponse};
+use actix_web::{Error, HttpRequest, HttpResponse};
 use futures::future::LocalBoxFuture;
 use governor::clock::MonotonicClock;
-use governor::middleware::NoOpMiddleware;
+use governor::middleware::StateInformationMiddleware;
 use governor::state::keyed::DefaultKeyedStateStore;
 use governor::{Quota, RateLimiter};
 
 type Limiter =
-    RateLimiter<String, DefaultKeyedStateStore<String>, MonotonicClock, NoOpMiddleware<Instant>>;
+    RateLimiter<String, DefaultKeyedStateStore<String>, MonotonicClock, StateInformationMiddleware>;
 
 #[derive(Clone)]
 pub struct RateLimit {
     limiter: Arc<Limiter>,
     use_header: bool,
+    /// Clients that ran out of failed logins, and when they can try again.
+    blocked: Arc<Mutex<HashMap<String, Instant>>>,
+    /// Keeps the attempts counted by this limit apart from other limits made
+    /// with [`RateLimit::scoped`].
+    scope: &'static str,
 }
 
 impl RateLimit {
     pub fn new(per_minute: u32, use_header: bool) -> Self {
         RateLimit {
-            limiter: Arc::new(RateLimiter::keyed(Quota::per_minute(
-                NonZeroU32::new(per_minute).unwrap(),
-            ))),
+            limiter: Arc::new(
+                RateLimiter::keyed(Quota::per_minute(NonZeroU32::new(per_minute).unwrap()))
+                    .with_middleware::<StateInformationMiddleware>(),
+            ),
             use_header,
+            blocked: Default::default(),
+            scope: "",
         }
     }
+
+    /// A limit with the same quota, which counts its attempts separately.
+    /// Clients that run out of attempts in one scope can still use the
+    /// others.
+    pub fn scoped(&self, scope: &'static str) -> Self {
+        RateLimit {
+            scope,
+            ..self.clone()
+        }
+    }
+
+    fn client_key(&self, request: &HttpRequest) -> String {
+        let address = if self.use_header {
+            request
+                .connection_info()
+                .realip_remote_addr()
+                .map(|v| v.to_string())
+        } else {
+            request.connection_info().peer_addr().map(|v| v.to_string())
+        }
+        // I think this only happens during testing
+        .unwrap_or_else(|| "".to_string());
+        format!("{}/{address}", self.scope)
+    }
+
+    /// For logins that are checked outside of the throttled scopes, like HTTP
+    /// Basic authentication. The seconds until the client can try again, if
+    /// it used up its attempts.
+    pub fn retry_after(&self, request: &HttpRequest) -> Option<u64> {
+        let key = self.client_key(request);
+        let mut blocked = self.blocked.lock().unwrap();
+        let until = *blocked.get(&key)?;
+        let now = Instant::now();
+        if until > now {
+            Some((until - now).as_secs().max(1))
+        } else {
+            blocked.remove(&key);
+            None
+        }
+    }
+
+    /// Uses up one of the attempts of the client. Unlike the throttled
+    /// scopes, only failed logins count so that clients sending the password
+    /// with every request aren't slowed down.
+    pub fn count_failure(&self, request: &HttpRequest) {
+        let key = self.client_key(request);
+        let until = match self.limiter.check_key(&key) {
+            // That was the last attempt, the next one has to wait
+            Ok(state) if state.remaining_burst_capacity() == 0 => {
+                Instant::now() + state.quota().replenish_interval()
+            }
+            Ok(_) => return,
+            Err(not_until) => not_until.earliest_possible(),
+        };
+        self.blocked.lock().unwrap().insert(key, until);
+    }
+}
+
+/// The response for clients that made too many attempts.
+pub fn too_many_requests(retry_after: u64) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .append_header((header::RETRY_AFTER, retry_after))
+        .body(format!(
+            "Too many requests, retry after {retry_after} seconds"
+        ))
 }
 
 impl<S: 'static, B> Transform<S, ServiceRequest> for RateLimit
@@ -49,15 +123,13 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ready(Ok(RateLimitMiddleware {
             service: Rc::new(service),
-            limiter: self.limiter.clone(),
-            use_header: self.use_header,
+            rate_limit: self.clone(),
         }))
     }
 }
 pub struct RateLimitMiddleware<S> {
     service: Rc<S>,
-    limiter: Arc<Limiter>,
-    use_header: bool,
+    rate_limit: RateLimit,
 }
 
 impl<S: 'static, B> Service<ServiceRequest> for RateLimitMiddleware<S>
@@ -72,18 +144,10 @@ where
     dev::forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let ip = if self.use_header {
-            req.connection_info()
-                .realip_remote_addr()
-                .map(|v| v.to_string())
-        } else {
-            req.connection_info().peer_addr().map(|v| v.to_string())
-        }
-        // I think this only happens during testing
-        .unwrap_or_else(|| "".to_string());
+        let ip = self.rate_limit.client_key(req.request());
 
         let service = self.service.clone();
-        let limiter = self.limiter.clone();
+        let limiter = self.rate_limit.limiter.clone();
 
         Box::pin(async move {
             match limiter.check_key(&ip) {
@@ -93,12 +157,7 @@ where
                     .map(ServiceResponse::map_into_left_body),
                 Err(not_until) => {
                     let retry_after = not_until.wait_time_from(Instant::now()).as_secs();
-                    let response = HttpResponse::TooManyRequests()
-                        .append_header((header::RETRY_AFTER, retry_after))
-                        .body(format!(
-                            "Too many requests, retry after {retry_after} seconds"
-                        ))
-                        .map_into_right_body();
+                    let response = too_many_requests(retry_after).map_into_right_body();
                     Ok(req.into_response(response))
                 }
             }
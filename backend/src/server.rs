@@ -1,17 +1,44 @@
 use std::{env, path::PathBuf, str::FromStr};
 
 use crate::{
-    auth::{create_nobody, login},
-    auth_middleware, folder,
+    admin::{delete_user_account, get_users, post_user, put_user_password, put_user_type},
+    admin_middleware::RequireAdmin,
+    api_key::{delete_api_key, get_api_keys, post_api_key},
+    auth::{create_nobody, login, setup_auth, AuthOptions},
+    auth_middleware,
+    dav::{
+        dav_copy, dav_delete, dav_get, dav_head, dav_lock, dav_method, dav_mkcol, dav_move,
+        dav_options, dav_propfind, dav_put, dav_unlock, DAV_PREFIX,
+    },
+    folder,
+    group::{delete_group_member, get_group_members, get_groups, put_group_member},
+    kv::{self, KVOptions},
     meta::{get_banner_login, get_banner_page, get_stats, head_stats, is_bulgur_cloud},
+    oidc::{get_oidc, get_oidc_callback, get_oidc_login, post_oidc_token, setup_oidc},
     pages::{
-        not_found, page_create_folder, page_delete, page_folder_list, page_folder_upload,
-        page_login_get, page_login_post, page_logout,
+        not_found, page_create_folder, page_delete, page_file_drop, page_folder_list,
+        page_folder_upload, page_login_get, page_login_post, page_logout, page_password_change,
+        page_session_revoke, page_share_create, page_share_get, page_share_post, page_share_revoke,
+        page_totp, page_trash_purge, page_trash_restore,
     },
+    proxy_auth::setup_proxy_auth,
     ratelimit_middleware::RateLimit,
+    session::{
+        delete_session, delete_sessions, get_sessions, post_logout, post_refresh, put_password,
+        TokenOptions,
+    },
+    share::{delete_share, get_shares, post_share},
     state::AppState,
     static_files::{get_basic_assets, ui_pages},
     storage::{delete_storage, get_storage, head_storage, meta_storage, post_storage, put_storage},
+    totp::{delete_totp, get_totp, post_recovery_codes, post_totp, put_totp},
+    trash::{delete_trash, delete_trash_item, get_trash, post_trash_item},
+    tus::{
+        is_tus_request, tus_create, tus_delete, tus_head, tus_patch, TUS_REQUEST_HEADERS,
+        TUS_RESPONSE_HEADERS,
+    },
+    user_share::{delete_user_share, get_shared_with_me, get_user_shares, post_user_share},
+    versions::{get_versions, post_version, VersionOptions},
 };
 
 use actix_service::ServiceFactory;
@@ -21,6 +48,7 @@ use actix_cors::Cors;
 use actix_web::{
     body::MessageBody,
     dev::{ServiceRequest, ServiceResponse},
+    guard,
     http::{self, Method},
     middleware,
     web::{self, Data},
@@ -29,7 +57,6 @@ use actix_web::{
 
 use actix_web_query_method_middleware::QueryMethod;
 use sea_orm::DatabaseConnection;
-use tokio::fs;
 use tracing_actix_web::TracingLogger;
 
 fn setup_cors() -> Cors {
@@ -49,6 +76,8 @@ fn setup_cors() -> Cors {
             http::header::ACCEPT,
             http::header::CONTENT_TYPE,
         ])
+        .allowed_headers(TUS_REQUEST_HEADERS)
+        .expose_headers(TUS_RESPONSE_HEADERS)
         .max_age(86400);
     let origin = env::var("BULGUR_CLOUD_CORS_ORIGIN").unwrap_or_else(|_| "*".to_string());
     if origin.eq("*") {
@@ -79,28 +108,121 @@ pub fn setup_app(
     let api_guard = auth_middleware::CheckLogin {
         state: state.clone(),
         allow_path_tokens: false,
+        basic_auth: None,
     };
     let storage_guard = auth_middleware::CheckLogin {
         state: state.clone(),
         allow_path_tokens: true,
+        basic_auth: None,
+    };
+    let dav_guard = auth_middleware::CheckLogin {
+        state: state.clone(),
+        allow_path_tokens: false,
+        // Failed WebDAV logins count against the same limit as other logins
+        basic_auth: Some(login_governor.clone()),
     };
 
     // Login scope just handles logins. It is heavily throttled to resist brute force attacks.
-    let login_scope = web::scope("/auth").wrap(login_governor).service(login);
+    let login_scope = web::scope("/auth")
+        .wrap(login_governor.clone())
+        .service(login)
+        .service(get_oidc)
+        .service(get_oidc_login)
+        .service(get_oidc_callback)
+        .service(post_oidc_token);
+    // Share scope is where visitors open share links, and enter the password
+    // if they need one. Wrong passwords are throttled like logins, but they
+    // are counted separately so visitors can't lock anyone out of logging in.
+    let share_scope = web::scope("/share")
+        .app_data(Data::new(login_governor.scoped("share")))
+        .wrap(QueryMethod::new())
+        .service(page_share_get)
+        .service(page_share_post)
+        .service(page_file_drop);
+    // Admin scope lets admins manage users, it sits inside the API scope
+    let admin_scope = web::scope("/admin")
+        .wrap(RequireAdmin {
+            state: state.clone(),
+        })
+        .service(get_users)
+        .service(post_user)
+        .service(delete_user_account)
+        .service(put_user_password)
+        .service(put_user_type);
     // API scope handles all api functionality (anything except storage)
     let api_scope = web::scope("/api")
         .wrap(api_guard.clone())
         .service(get_stats)
-        .service(head_stats);
+        .service(head_stats)
+        .service(get_trash)
+        .service(delete_trash)
+        .service(post_trash_item)
+        .service(delete_trash_item)
+        .service(get_versions)
+        .service(post_version)
+        .service(get_shares)
+        .service(post_share)
+        .service(delete_share)
+        .service(get_user_shares)
+        .service(post_user_share)
+        .service(delete_user_share)
+        .service(get_shared_with_me)
+        .service(get_groups)
+        .service(get_group_members)
+        .service(put_group_member)
+        .service(delete_group_member)
+        .service(get_sessions)
+        .service(delete_sessions)
+        .service(delete_session)
+        .service(post_logout)
+        .service(post_refresh)
+        .service(put_password)
+        .service(get_totp)
+        .service(post_totp)
+        .service(put_totp)
+        .service(delete_totp)
+        .service(post_recovery_codes)
+        .service(get_api_keys)
+        .service(post_api_key)
+        .service(delete_api_key)
+        .service(admin_scope);
     // Storage scope handles the actual files and folders
     let storage_scope = web::scope("/storage")
         .wrap(storage_guard.clone())
+        // Resumable uploads, these need to come first to take over the
+        // requests that carry tus headers
+        .service(
+            web::resource("/{store}/{path:.*}")
+                .guard(guard::fn_guard(is_tus_request))
+                .route(web::post().to(tus_create))
+                .route(web::head().to(tus_head))
+                .route(web::patch().to(tus_patch))
+                .route(web::delete().to(tus_delete)),
+        )
         .service(get_storage)
         .service(put_storage)
         .service(head_storage)
         .service(meta_storage)
         .service(post_storage)
         .service(delete_storage);
+    // WebDAV scope exposes the same files and folders for file managers
+    let dav_path = "/{store_and_path:.*}";
+    let dav_scope = web::scope(DAV_PREFIX)
+        .wrap(dav_guard)
+        .route(dav_path, web::method(Method::OPTIONS).to(dav_options))
+        .route(dav_path, web::get().to(dav_get))
+        .route(dav_path, web::head().to(dav_head))
+        .route(dav_path, web::put().to(dav_put))
+        .route(dav_path, web::delete().to(dav_delete))
+        .route(
+            dav_path,
+            web::method(dav_method("PROPFIND")).to(dav_propfind),
+        )
+        .route(dav_path, web::method(dav_method("MKCOL")).to(dav_mkcol))
+        .route(dav_path, web::method(dav_method("MOVE")).to(dav_move))
+        .route(dav_path, web::method(dav_method("COPY")).to(dav_copy))
+        .route(dav_path, web::method(dav_method("LOCK")).to(dav_lock))
+        .route(dav_path, web::method(dav_method("UNLOCK")).to(dav_unlock));
     // Basic HTML scopes are for the javascript-free basic interface.
     let authenticated_basic_html_scope = web::scope("/basic")
         .wrap(storage_guard)
@@ -111,6 +233,34 @@ pub fn setup_app(
         .route(
             "/{store}/{path:.*}",
             web::method(Method::try_from("CREATE").unwrap()).to(page_create_folder),
+        )
+        .route(
+            "/{store}/{path:.*}",
+            web::method(Method::try_from("RESTORE").unwrap()).to(page_trash_restore),
+        )
+        .route(
+            "/{store}/{path:.*}",
+            web::method(Method::try_from("PURGE").unwrap()).to(page_trash_purge),
+        )
+        .route(
+            "/{store}/{path:.*}",
+            web::method(Method::try_from("SHARE").unwrap()).to(page_share_create),
+        )
+        .route(
+            "/{store}/{path:.*}",
+            web::method(Method::try_from("REVOKE").unwrap()).to(page_share_revoke),
+        )
+        .route(
+            "/{store}/{path:.*}",
+            web::method(Method::try_from("PASSWORD").unwrap()).to(page_password_change),
+        )
+        .route(
+            "/{store}/{path:.*}",
+            web::method(Method::try_from("LOGOUT").unwrap()).to(page_session_revoke),
+        )
+        .route(
+            "/{store}/{path:.*}",
+            web::method(Method::try_from("TOTP").unwrap()).to(page_totp),
         );
     let basic_html_scope = web::scope("")
         .service(page_login_get)
@@ -137,8 +287,10 @@ pub fn setup_app(
         .service(login_scope)
         .service(api_scope)
         .service(storage_scope)
+        .service(dav_scope)
         .service(is_bulgur_cloud)
         .service(banner_scope)
+        .service(share_scope)
         .service(basic_html_scope)
         .default_service(web::to(not_found))
 }
@@ -151,14 +303,35 @@ const MAX_LOGIN_ATTEMPTS_PER_MIN: u32 = 100_000_000;
 const MAX_LOGIN_ATTEMPTS_PER_MIN: u32 = 10;
 
 pub async fn setup_app_deps(
-    _base_folder: PathBuf,
+    base_folder: PathBuf,
     connection: DatabaseConnection,
+    kv_options: &KVOptions,
+    version_options: &VersionOptions,
+    token_options: &TokenOptions,
+    auth_options: &AuthOptions,
 ) -> anyhow::Result<(Data<AppState>, RateLimit)> {
+    let token_key = token_options.load_key(&base_folder).await?;
+    let auth = setup_auth(auth_options)?;
+    let oidc = setup_oidc(&auth_options.oidc)?;
+    let proxy_auth = setup_proxy_auth(&auth_options.proxy)?;
+    // Catch bad hashing parameters before anyone tries to log in
+    auth_options.hashing.params()?;
+    let storage = kv::setup_backend(base_folder, kv_options).await?;
     // Make sure the needed folders are available
-    fs::create_dir_all(PathBuf::from(folder::STORAGE)).await?;
+    storage
+        .create_dir_all(&PathBuf::from(folder::STORAGE))
+        .await?;
     let state = web::Data::new(AppState {
         started_at: chrono::Local::now(),
         db: connection,
+        storage,
+        versions: version_options.clone(),
+        tokens: token_options.clone(),
+        token_key,
+        auth,
+        oidc,
+        proxy_auth,
+        password_hashing: auth_options.hashing.clone(),
     });
 
     let login_governor = RateLimit::new(
@@ -167,7 +340,7 @@ pub async fn setup_app_deps(
     );
 
     // Make sure the nobody user is created if it doesn't exist
-    create_nobody(&state.db).await?;
+    create_nobody(&state).await?;
 
     Ok((state, login_governor))
 }
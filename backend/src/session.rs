@@ -0,0 +1,517 @@
+//! Lets users see where they are logged in, log out of any of those sessions,
+//! and change their own password.
+//!
+//! Every user token is a session. The token itself is never shown, sessions
+//! are told apart by a separate ID so they can be revoked without leaking the
+//! tokens of other sessions.
+//!
+//! Tokens expire some time after they were made, or if they go unused for too
+//! long. Clients can refresh their token before it expires to get a new one.
+use std::{io::ErrorKind, path::Path, time::Duration};
+
+use actix_web::{
+    delete, get,
+    http::{header, StatusCode},
+    post, put,
+    web::{self, ReqData},
+    HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+use chrono::{DateTime, Utc};
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, Condition, DbErr, EntityTrait, QueryFilter, QueryOrder,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    auth::{set_password, verify_pass, LoginResponse, Password},
+    auth_middleware::get_user_token,
+    entity::{path_token, user, user_token},
+    oidc::delete_expired_logins,
+    state::{AppState, Authorized, Token, TokenKey, Username},
+    storage::empty_ok_response,
+};
+
+#[cfg(feature = "generate_types")]
+use typescript_type_def::TypeDef;
+
+/// Client info longer than this is cut off, it's only there to help the user
+/// recognize the session.
+const MAX_CLIENT_LENGTH: usize = 256;
+
+/// How often the background task looks for expired tokens.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// When a token was last used is only saved if it changed by at least this
+/// much, so not every request has to write to the database.
+const LAST_USED_PRECISION_SECS: i64 = 60;
+
+/// Where the token key is saved in the base folder, if it isn't set with an
+/// option.
+const TOKEN_KEY_FILE: &str = "token.key";
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct TokenOptions {
+    #[clap(
+        long = "token-max-age-hours",
+        env = "BULGUR_CLOUD_TOKEN_MAX_AGE_HOURS",
+        default_value_t = 720
+    )]
+    /// Users are logged out this many hours after they log in, even if the
+    /// token is refreshed before then. Set to 0 to keep tokens regardless of
+    /// their age.
+    pub max_age_hours: u32,
+
+    #[clap(
+        long = "token-idle-hours",
+        env = "BULGUR_CLOUD_TOKEN_IDLE_HOURS",
+        default_value_t = 168
+    )]
+    /// Users are logged out if they don't use their token for this many
+    /// hours. Set to 0 to keep unused tokens.
+    pub idle_hours: u32,
+
+    #[clap(long = "token-key", env = "BULGUR_CLOUD_TOKEN_KEY")]
+    /// The secret key that tokens are hashed with before they are stored. If
+    /// not set, a random key is made and saved in the `token.key` file.
+    /// Changing the key logs out all users and breaks all share links.
+    pub key: Option<TokenKey>,
+}
+
+impl Default for TokenOptions {
+    fn default() -> Self {
+        TokenOptions {
+            max_age_hours: 720,
+            idle_hours: 168,
+            key: None,
+        }
+    }
+}
+
+impl TokenOptions {
+    fn max_age(&self) -> Option<chrono::Duration> {
+        (self.max_age_hours > 0).then(|| chrono::Duration::hours(self.max_age_hours.into()))
+    }
+
+    fn idle(&self) -> Option<chrono::Duration> {
+        (self.idle_hours > 0).then(|| chrono::Duration::hours(self.idle_hours.into()))
+    }
+
+    /// When a token made at `created_at` stops working, even if it keeps
+    /// being used.
+    pub fn expires_at(&self, created_at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.max_age().map(|max_age| created_at + max_age)
+    }
+
+    /// The key set in the options, or the one saved in the base folder. If
+    /// there is neither, a new key is made and saved.
+    pub async fn load_key(&self, base_folder: &Path) -> anyhow::Result<TokenKey> {
+        if let Some(key) = &self.key {
+            return Ok(key.clone());
+        }
+        let path = base_folder.join(TOKEN_KEY_FILE);
+        match tokio::fs::read_to_string(&path).await {
+            Ok(key) if !key.trim().is_empty() => return Ok(TokenKey::read(key.trim().as_bytes())),
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        tracing::info!("Saving a new token key to {}", path.to_string_lossy());
+        let key = TokenKey::new();
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // Only the server should be able to read the key
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&path).await?;
+        file.write_all(key.reveal()).await?;
+        Ok(key)
+    }
+
+    /// True if the token is too old, or went unused for too long.
+    pub fn is_expired(&self, token: &user_token::Model, now: DateTime<Utc>) -> bool {
+        // If we can't tell when the token was made or used, better to assume it expired
+        let too_old = |timestamp: &str, limit: Option<chrono::Duration>| match limit {
+            Some(limit) => DateTime::parse_from_rfc3339(timestamp)
+                .map_or(true, |timestamp| timestamp + limit <= now),
+            None => false,
+        };
+        too_old(&token.created_at, self.max_age()) || too_old(&token.last_used_at, self.idle())
+    }
+}
+
+#[derive(Debug, derive_more::Display, thiserror::Error)]
+pub enum SessionError {
+    #[display(fmt = "User is not authorized to view this.")]
+    NotAuthorized,
+    #[display(fmt = "Session {} does not exist", _0)]
+    NotFound(String),
+    #[display(fmt = "The current password is incorrect")]
+    WrongPassword,
+    #[display(fmt = "The new password can't be empty")]
+    EmptyPassword,
+    #[display(fmt = "Passwords are managed by the directory server, change it there")]
+    ExternalPassword,
+    #[display(fmt = "Database error {}", _0)]
+    Database(#[from] DbErr),
+    #[display(fmt = "{}", _0)]
+    Failed(String),
+}
+
+impl Serialize for SessionError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let s = format!("{}", self);
+        serializer.serialize_str(&s)
+    }
+}
+
+impl From<anyhow::Error> for SessionError {
+    fn from(err: anyhow::Error) -> Self {
+        SessionError::Failed(err.to_string())
+    }
+}
+
+impl actix_web::error::ResponseError for SessionError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SessionError::NotAuthorized => StatusCode::UNAUTHORIZED,
+            SessionError::NotFound(_) => StatusCode::NOT_FOUND,
+            SessionError::WrongPassword => StatusCode::FORBIDDEN,
+            SessionError::EmptyPassword => StatusCode::BAD_REQUEST,
+            SessionError::ExternalPassword => StatusCode::CONFLICT,
+            SessionError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            SessionError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponseBuilder::new(self.status_code()).json(self)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct Session {
+    pub id: String,
+    pub created_at: String,
+    /// The user agent of the client that logged in, if it sent one.
+    pub client: Option<String>,
+    /// True for the session that made this request.
+    pub current: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct Sessions {
+    pub sessions: Vec<Session>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct PasswordChange {
+    pub current_password: Password,
+    pub new_password: Password,
+}
+
+/// Describes the client making the request, to be saved with a new session.
+pub fn client_info(req: &HttpRequest) -> Option<String> {
+    let user_agent = req.headers().get(header::USER_AGENT)?.to_str().ok()?;
+    Some(user_agent.chars().take(MAX_CLIENT_LENGTH).collect())
+}
+
+fn request_user(authorized: &Option<ReqData<Authorized>>) -> Result<&Username, SessionError> {
+    authorized
+        .as_deref()
+        .and_then(|authorized| authorized.username())
+        .ok_or(SessionError::NotAuthorized)
+}
+
+async fn find_user_id(state: &AppState, username: &str) -> Result<String, SessionError> {
+    user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(&state.db)
+        .await?
+        .map(|user| user.id)
+        .ok_or(SessionError::NotAuthorized)
+}
+
+/// Lists the sessions of the user, newest first.
+pub async fn list_sessions(
+    state: &AppState,
+    username: &str,
+    current: Option<&Token>,
+) -> Result<Vec<Session>, SessionError> {
+    let user_id = find_user_id(state, username).await?;
+    let current = current.map(|current| state.hash_token(current));
+    let sessions = user_token::Entity::find()
+        .filter(user_token::Column::UserId.eq(user_id))
+        .order_by_desc(user_token::Column::CreatedAt)
+        .all(&state.db)
+        .await?
+        .into_iter()
+        .map(|token| Session {
+            current: current
+                .as_ref()
+                .is_some_and(|current| *current == token.token),
+            id: token.session_id,
+            created_at: token.created_at,
+            client: token.client,
+        })
+        .collect();
+    Ok(sessions)
+}
+
+/// Logs the user out of one of their sessions.
+pub async fn revoke_session(
+    state: &AppState,
+    username: &str,
+    session_id: &str,
+) -> Result<(), SessionError> {
+    let user_id = find_user_id(state, username).await?;
+    let result = user_token::Entity::delete_many()
+        .filter(user_token::Column::UserId.eq(user_id))
+        .filter(user_token::Column::SessionId.eq(session_id))
+        .exec(&state.db)
+        .await?;
+    if result.rows_affected == 0 {
+        return Err(SessionError::NotFound(session_id.to_string()));
+    }
+    Ok(())
+}
+
+/// Logs the user out of every session, except the one using `keep` if there
+/// is one.
+pub async fn revoke_sessions(
+    state: &AppState,
+    username: &str,
+    keep: Option<&Token>,
+) -> Result<(), SessionError> {
+    let user_id = find_user_id(state, username).await?;
+    let mut query =
+        user_token::Entity::delete_many().filter(user_token::Column::UserId.eq(user_id));
+    if let Some(keep) = keep {
+        query = query.filter(user_token::Column::Token.ne(state.hash_token(keep)));
+    }
+    query.exec(&state.db).await?;
+    Ok(())
+}
+
+/// Deletes a user token, so it can't be used again.
+pub async fn delete_token(state: &AppState, token: &Token) -> Result<(), DbErr> {
+    user_token::Entity::delete_many()
+        .filter(user_token::Column::Token.eq(state.hash_token(token)))
+        .exec(&state.db)
+        .await?;
+    Ok(())
+}
+
+/// Checks that the token hasn't expired, and remembers that it was used.
+/// Expired tokens are deleted.
+pub(crate) async fn use_token(state: &AppState, token: &user_token::Model) -> Result<bool, DbErr> {
+    let now = Utc::now();
+    if state.tokens.is_expired(token, now) {
+        tracing::debug!("User token expired");
+        user_token::Entity::delete_by_id(&token.token)
+            .exec(&state.db)
+            .await?;
+        return Ok(false);
+    }
+    let recently_used = DateTime::parse_from_rfc3339(&token.last_used_at).is_ok_and(|last_used| {
+        (now - last_used.with_timezone(&Utc)).num_seconds() < LAST_USED_PRECISION_SECS
+    });
+    if !recently_used {
+        user_token::Entity::update_many()
+            .col_expr(
+                user_token::Column::LastUsedAt,
+                Expr::value(now.to_rfc3339()),
+            )
+            .filter(user_token::Column::Token.eq(&token.token))
+            .exec(&state.db)
+            .await?;
+    }
+    Ok(true)
+}
+
+/// Replaces the token with a new one, and resets the idle time. The session
+/// stays the same, so the new token still stops working once the session is
+/// older than the maximum age.
+pub async fn refresh_token(state: &AppState, token: &Token) -> Result<LoginResponse, SessionError> {
+    let session = user_token::Entity::find_by_id(state.hash_token(token))
+        .one(&state.db)
+        .await?
+        .ok_or(SessionError::NotAuthorized)?;
+    let started_at = DateTime::parse_from_rfc3339(&session.created_at)
+        .map_err(|_| SessionError::NotAuthorized)?
+        .with_timezone(&Utc);
+    let access_token = Token::new();
+    let now = Utc::now();
+    let result = user_token::Entity::update_many()
+        .col_expr(
+            user_token::Column::Token,
+            Expr::value(state.hash_token(&access_token)),
+        )
+        .col_expr(
+            user_token::Column::LastUsedAt,
+            Expr::value(now.to_rfc3339()),
+        )
+        .filter(user_token::Column::Token.eq(&session.token))
+        .exec(&state.db)
+        .await?;
+    if result.rows_affected == 0 {
+        return Err(SessionError::NotAuthorized);
+    }
+    Ok(LoginResponse {
+        access_token,
+        expires_at: state
+            .tokens
+            .expires_at(started_at)
+            .map(|at| at.to_rfc3339()),
+    })
+}
+
+/// Deletes user tokens that expired, path tokens that are past their expiry
+/// time, and single sign-on logins that were never finished. Returns how many
+/// were deleted.
+#[tracing::instrument(skip(state))]
+pub async fn delete_expired_tokens(state: &AppState) -> Result<u64, DbErr> {
+    let now = Utc::now();
+    let mut expired = Condition::any();
+    if let Some(max_age) = state.tokens.max_age() {
+        expired = expired.add(user_token::Column::CreatedAt.lt((now - max_age).to_rfc3339()));
+    }
+    if let Some(idle) = state.tokens.idle() {
+        expired = expired.add(user_token::Column::LastUsedAt.lt((now - idle).to_rfc3339()));
+    }
+    let mut count = 0;
+    if !expired.is_empty() {
+        count += user_token::Entity::delete_many()
+            .filter(expired)
+            .exec(&state.db)
+            .await?
+            .rows_affected;
+    }
+    count += path_token::Entity::delete_many()
+        .filter(path_token::Column::ValidUntil.lt(now.to_rfc3339()))
+        .exec(&state.db)
+        .await?
+        .rows_affected;
+    count += delete_expired_logins(state).await?;
+    Ok(count)
+}
+
+/// Periodically deletes expired tokens. This never returns, spawn it in the
+/// background.
+pub async fn expire_tokens_task(state: web::Data<AppState>) {
+    let mut interval = actix_web::rt::time::interval(EXPIRE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match delete_expired_tokens(&state).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!(count, "Deleted expired tokens"),
+            Err(err) => tracing::error!(error = ?err, "Failed to delete expired tokens"),
+        }
+    }
+}
+
+/// True if the token still belongs to a session.
+pub async fn is_session_active(state: &AppState, token: &Token) -> Result<bool, DbErr> {
+    Ok(user_token::Entity::find()
+        .filter(user_token::Column::Token.eq(state.hash_token(token)))
+        .one(&state.db)
+        .await?
+        .is_some())
+}
+
+/// Changes the password of the user, if they know the current one. All other
+/// sessions are logged out, the one using `current` stays logged in.
+pub async fn change_password(
+    state: &AppState,
+    username: &str,
+    change: &PasswordChange,
+    current: Option<&Token>,
+) -> Result<(), SessionError> {
+    if !state.auth.manages_passwords() {
+        return Err(SessionError::ExternalPassword);
+    }
+    if change.new_password.0.is_empty() {
+        return Err(SessionError::EmptyPassword);
+    }
+    verify_pass(state, username, &change.current_password)
+        .await
+        .map_err(|_| SessionError::WrongPassword)?;
+    set_password(state, username, &change.new_password.0).await?;
+    revoke_sessions(state, username, current).await
+}
+
+#[tracing::instrument(skip(state, req))]
+#[get("/sessions")]
+pub async fn get_sessions(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<web::Json<Sessions>, SessionError> {
+    let username = request_user(&authorized)?;
+    let sessions = list_sessions(&state, &username.0, get_user_token(&req).as_ref()).await?;
+    Ok(web::Json(Sessions { sessions }))
+}
+
+#[tracing::instrument(skip(state))]
+#[delete("/sessions")]
+pub async fn delete_sessions(
+    state: web::Data<AppState>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, SessionError> {
+    let username = request_user(&authorized)?;
+    revoke_sessions(&state, &username.0, None).await?;
+    Ok(empty_ok_response())
+}
+
+#[tracing::instrument(skip(state))]
+#[delete("/sessions/{id}")]
+pub async fn delete_session(
+    state: web::Data<AppState>,
+    id: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, SessionError> {
+    let username = request_user(&authorized)?;
+    revoke_session(&state, &username.0, &id).await?;
+    Ok(empty_ok_response())
+}
+
+#[tracing::instrument(skip(state, req))]
+#[post("/logout")]
+pub async fn post_logout(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, SessionError> {
+    if let Some(token) = get_user_token(&req) {
+        delete_token(&state, &token).await?;
+    }
+    Ok(empty_ok_response())
+}
+
+#[tracing::instrument(skip(state, req))]
+#[post("/refresh")]
+pub async fn post_refresh(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<web::Json<LoginResponse>, SessionError> {
+    let token = get_user_token(&req).ok_or(SessionError::NotAuthorized)?;
+    Ok(web::Json(refresh_token(&state, &token).await?))
+}
+
+#[tracing::instrument(skip(state, req, change))]
+#[put("/password")]
+pub async fn put_password(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    change: web::Json<PasswordChange>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, SessionError> {
+    let username = request_user(&authorized)?;
+    change_password(&state, &username.0, &change, get_user_token(&req).as_ref()).await?;
+    Ok(empty_ok_response())
+}
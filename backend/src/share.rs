@@ -0,0 +1,472 @@
+//! Share links give people without an account access to a file or folder.
+//!
+//! Share links are path tokens with a few extra options picked by the owner:
+//! when the link expires, a password visitors have to enter, how many times
+//! files can be downloaded through it, and a label to tell the links apart.
+//! The quick path tokens the web UI makes to download files are path tokens
+//! too, but these expire after a day and aren't marked as shares, so they
+//! don't show up in the list.
+//!
+//! A share link for a folder can also be a file drop. Visitors can upload
+//! files into the folder through a file drop, but they can't see what is in
+//! the folder, including the files they uploaded.
+use std::path::{Path, PathBuf};
+
+use actix_multipart::Multipart;
+use actix_web::{
+    cookie::{time, Cookie, SameSite},
+    delete, get,
+    http::{
+        header::{self, ByteRangeSpec, Header},
+        Method,
+    },
+    post,
+    web::{self, ReqData},
+    HttpRequest, HttpResponse,
+};
+use chrono::{DateTime, Duration, Utc};
+use nanoid::nanoid;
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, Condition, EntityTrait, QueryFilter,
+    QueryOrder, Set,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{hash_password, verify_password_hash, Password},
+    entity::path_token,
+    folder,
+    state::{AppState, Authorized, Token},
+    storage::{
+        empty_ok_response, get_authorized_path, write_files_limited, ConflictPolicy, StorageError,
+        UploadLimits,
+    },
+};
+
+#[cfg(feature = "generate_types")]
+use typescript_type_def::TypeDef;
+
+/// Once visitors enter the password of a share link, they get a cookie with
+/// this prefix followed by the token of the link.
+pub static SHARE_GRANT_COOKIE_PREFIX: &str = "bulgur-cloud-share-";
+
+/// How long visitors can use a share link after entering its password.
+const SHARE_GRANT_MINUTES: i64 = 60;
+
+/// Added to responses that send the contents of files, these count towards
+/// the download limit of share links.
+#[derive(Debug, Clone, Copy)]
+pub struct DownloadResponse;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct ShareOptions {
+    /// A name to tell the share links apart.
+    pub label: Option<String>,
+    /// How long the link works for. The link never expires if this is empty.
+    pub valid_for_hours: Option<u32>,
+    /// Visitors have to enter this password to use the link.
+    pub password: Option<Password>,
+    /// How many times files can be downloaded through the link. A download
+    /// that is resumed later only counts once.
+    pub max_downloads: Option<u32>,
+    /// Visitors can only upload files into the shared folder, and can't see
+    /// anything in it.
+    #[serde(default)]
+    pub file_drop: bool,
+    /// The largest file that can be uploaded to a file drop, in bytes.
+    pub max_file_size: Option<u64>,
+    /// How many files can be uploaded to a file drop.
+    pub max_files: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct Share {
+    /// Identifies the share link, to revoke it.
+    pub id: String,
+    /// The token of the link. Only tokens of new share links can be seen,
+    /// afterwards only a hash of the token is kept.
+    pub token: Option<Token>,
+    /// The shared file or folder, relative to the store.
+    pub path: String,
+    pub is_folder: bool,
+    pub label: Option<String>,
+    pub created_at: String,
+    pub valid_until: Option<String>,
+    pub has_password: bool,
+    pub max_downloads: Option<u32>,
+    pub downloads: u32,
+    pub file_drop: bool,
+    pub max_file_size: Option<u64>,
+    pub max_files: Option<u32>,
+    pub uploads: u32,
+}
+
+impl From<path_token::Model> for Share {
+    fn from(token: path_token::Model) -> Self {
+        let store_path = Path::new(token.path.trim_start_matches('/'));
+        let path = store_path
+            .strip_prefix(folder::STORAGE)
+            .ok()
+            .and_then(|path| token.store.as_ref().and_then(|s| path.strip_prefix(s).ok()))
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Share {
+            id: token.id,
+            token: None,
+            path,
+            is_folder: token.is_folder,
+            label: token.label,
+            created_at: token.created_at,
+            valid_until: token.valid_until,
+            has_password: token.password_hash.is_some(),
+            max_downloads: token.max_downloads.map(|max| max as u32),
+            downloads: token.downloads as u32,
+            file_drop: token.file_drop,
+            max_file_size: token.max_file_size.map(|max| max as u64),
+            max_files: token.max_files.map(|max| max as u32),
+            uploads: token.uploads as u32,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct ShareResults {
+    pub shares: Vec<Share>,
+}
+
+/// True if the token has expired.
+pub fn is_expired(token: &path_token::Model, now: DateTime<Utc>) -> bool {
+    token
+        .valid_until
+        .as_ref()
+        .map(|valid_until| {
+            DateTime::parse_from_rfc3339(valid_until)
+                // If we can't tell when it expires, better to assume it did
+                .map_or(true, |valid_until| valid_until <= now)
+        })
+        .unwrap_or(false)
+}
+
+/// True if the token has not expired, and still has downloads or uploads
+/// left.
+pub fn is_active(token: &path_token::Model, now: DateTime<Utc>) -> bool {
+    let expired = is_expired(token, now);
+    let used_up = token
+        .max_downloads
+        .map(|max| token.downloads >= max)
+        .unwrap_or(false);
+    let full = token
+        .max_files
+        .map(|max| token.uploads >= max)
+        .unwrap_or(false);
+    !expired && !used_up && !full
+}
+
+/// What the grant cookie signs: the link it is for, and when it expires.
+fn share_grant_message(share: &path_token::Model, expires: i64) -> String {
+    format!("share-grant:{}:{expires}", share.id)
+}
+
+/// The cookie that lets a visitor use a share link for a while, once they
+/// entered the right password. The cookie is signed and only works for this
+/// link, so the password doesn't have to be checked again with every request.
+pub fn share_grant_cookie(
+    state: &AppState,
+    token: &str,
+    share: &path_token::Model,
+) -> Cookie<'static> {
+    let expires = (Utc::now() + Duration::minutes(SHARE_GRANT_MINUTES)).timestamp();
+    let signature = state.token_key.hash(&share_grant_message(share, expires));
+    let mut cookie = Cookie::new(
+        format!("{SHARE_GRANT_COOKIE_PREFIX}{token}"),
+        format!("{expires}.{signature}"),
+    );
+    cookie.set_path("/");
+    cookie.set_http_only(true);
+    cookie.set_same_site(SameSite::Strict);
+    cookie.set_max_age(time::Duration::minutes(SHARE_GRANT_MINUTES));
+    cookie
+}
+
+/// Reads the cookie a visitor got for a share link.
+pub fn share_grant_from_request(request: &HttpRequest, token: &str) -> Option<String> {
+    let cookie = request.cookie(&format!("{SHARE_GRANT_COOKIE_PREFIX}{token}"))?;
+    Some(cookie.value().to_string())
+}
+
+/// True if the visitor can use the share link. Links without a password
+/// don't need a cookie, the others need one that hasn't expired.
+pub fn verify_share_grant(
+    state: &AppState,
+    share: &path_token::Model,
+    grant: Option<&str>,
+) -> bool {
+    if share.password_hash.is_none() {
+        return true;
+    }
+    let Some((expires, signature)) = grant.and_then(|grant| grant.split_once('.')) else {
+        return false;
+    };
+    let Ok(expires) = expires.parse::<i64>() else {
+        return false;
+    };
+    expires > Utc::now().timestamp()
+        && state
+            .token_key
+            .verify(&share_grant_message(share, expires), signature)
+}
+
+/// Checks the password a visitor entered for a share link. Links without a
+/// password don't need one. This is slow on purpose, so it is only done when
+/// the visitor enters the password on the rate limited share page.
+pub async fn verify_share_password(token: &path_token::Model, password: Option<&Password>) -> bool {
+    let Some(hash) = token.password_hash.clone() else {
+        return true;
+    };
+    let Some(password) = password.map(|password| password.0.clone()) else {
+        return false;
+    };
+    web::block(move || verify_password_hash(&hash, &password).is_ok())
+        .await
+        .unwrap_or(false)
+}
+
+/// Creates a share link for a file or folder. `store_path` includes the
+/// storage folder and the store.
+#[tracing::instrument(skip(state, options))]
+pub async fn create_share(
+    state: &AppState,
+    store: &str,
+    store_path: &Path,
+    options: ShareOptions,
+) -> Result<Share, StorageError> {
+    let meta = state.storage.metadata(store_path).await?;
+    // Files can only be dropped into folders
+    if options.file_drop && meta.is_file {
+        return Err(StorageError::BadPath);
+    }
+    let password_hash = match options.password {
+        Some(password) => Some(
+            hash_password(&state.password_hashing, &password.0)
+                .await
+                .map_err(|err| StorageError::PasswordHash(err.to_string()))?,
+        ),
+        None => None,
+    };
+    let now = Utc::now();
+    let token = Token::new();
+    let share = path_token::ActiveModel {
+        token: Set(state.hash_token(&token)),
+        id: Set(nanoid!()),
+        path: Set(format!("/{}", store_path.to_string_lossy())),
+        created_at: Set(now.to_rfc3339()),
+        is_folder: Set(!meta.is_file),
+        store: Set(Some(store.to_string())),
+        label: Set(options.label.filter(|label| !label.is_empty())),
+        valid_until: Set(options
+            .valid_for_hours
+            .map(|hours| (now + chrono::Duration::hours(hours.into())).to_rfc3339())),
+        password_hash: Set(password_hash),
+        max_downloads: Set(options.max_downloads.map(|max| max as i32)),
+        downloads: Set(0),
+        file_drop: Set(options.file_drop),
+        max_file_size: Set(options
+            .max_file_size
+            .filter(|_| options.file_drop)
+            .map(|max| max as i64)),
+        max_files: Set(options
+            .max_files
+            .filter(|_| options.file_drop)
+            .map(|max| max as i32)),
+        uploads: Set(0),
+        is_share: Set(true),
+    };
+    Ok(Share {
+        token: Some(token),
+        ..share.insert(&state.db).await?.into()
+    })
+}
+
+/// Lists the share links of a store that can still be used, newest first.
+pub async fn list_shares(state: &AppState, store: &str) -> Result<Vec<Share>, StorageError> {
+    let now = Utc::now();
+    Ok(path_token::Entity::find()
+        .filter(path_token::Column::Store.eq(store))
+        .filter(path_token::Column::IsShare.eq(true))
+        .order_by_desc(path_token::Column::CreatedAt)
+        .all(&state.db)
+        .await?
+        .into_iter()
+        .filter(|token| is_active(token, now))
+        .map(Share::from)
+        .collect())
+}
+
+/// Deletes a share link, so it can't be used any more.
+#[tracing::instrument(skip(state))]
+pub async fn revoke_share(state: &AppState, store: &str, id: &str) -> Result<(), StorageError> {
+    let result = path_token::Entity::delete_many()
+        .filter(path_token::Column::Id.eq(id))
+        .filter(path_token::Column::Store.eq(store))
+        .filter(path_token::Column::IsShare.eq(true))
+        .exec(&state.db)
+        .await?;
+    if result.rows_affected == 0 {
+        return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into());
+    }
+    Ok(())
+}
+
+/// True if the request uses up one of the downloads of a share link. Requests
+/// for the rest of a file, like when a download is resumed or a video is
+/// skipped ahead, belong to a download that was already counted.
+pub fn counts_as_download(request: &HttpRequest) -> bool {
+    if request.method() != Method::GET {
+        return false;
+    }
+    match header::Range::parse(request) {
+        // Only the first range is ever served
+        Ok(header::Range::Bytes(ranges)) => matches!(
+            ranges.first(),
+            Some(ByteRangeSpec::FromTo(0, _) | ByteRangeSpec::From(0))
+        ),
+        _ => true,
+    }
+}
+
+/// Uses up one of the downloads of a share link, before the file is sent.
+/// Returns false if there are none left. The check and the update are a
+/// single query, so downloads that happen at the same time can't go over the
+/// limit.
+pub async fn take_download(
+    state: &AppState,
+    share: &path_token::Model,
+) -> Result<bool, StorageError> {
+    let result = path_token::Entity::update_many()
+        .col_expr(
+            path_token::Column::Downloads,
+            Expr::col(path_token::Column::Downloads).add(1),
+        )
+        .filter(path_token::Column::Token.eq(&share.token))
+        .filter(
+            Condition::any()
+                .add(path_token::Column::MaxDownloads.is_null())
+                .add(
+                    Expr::col(path_token::Column::Downloads)
+                        .lt(Expr::col(path_token::Column::MaxDownloads)),
+                ),
+        )
+        .exec(&state.db)
+        .await?;
+    Ok(result.rows_affected > 0)
+}
+
+/// Gives back a download taken with [`take_download`], if nothing was
+/// downloaded in the end. For example, the link was used to list a folder.
+pub async fn return_download(
+    state: &AppState,
+    share: &path_token::Model,
+) -> Result<(), StorageError> {
+    path_token::Entity::update_many()
+        .col_expr(
+            path_token::Column::Downloads,
+            Expr::col(path_token::Column::Downloads).sub(1),
+        )
+        .filter(path_token::Column::Token.eq(&share.token))
+        .filter(path_token::Column::Downloads.gt(0))
+        .exec(&state.db)
+        .await?;
+    Ok(())
+}
+
+/// Saves the files uploaded to a file drop into the shared folder. Files never
+/// replace what is already in the folder. Returns how many files were saved.
+#[tracing::instrument(skip(state, payload))]
+pub async fn drop_files(
+    state: &AppState,
+    share: &path_token::Model,
+    payload: &mut Multipart,
+) -> Result<usize, StorageError> {
+    let (Some(store), true) = (share.store.as_deref(), share.file_drop) else {
+        return Err(StorageError::NotAuthorized);
+    };
+    let store_path = PathBuf::from(share.path.trim_start_matches('/'));
+    let limits = UploadLimits {
+        max_file_size: share.max_file_size.map(|max| max as u64),
+        max_files: share
+            .max_files
+            .map(|max| max.saturating_sub(share.uploads).max(0) as u32),
+        keep_existing: true,
+    };
+    let mut files_written = vec![];
+    let written = write_files_limited(
+        state,
+        payload,
+        store,
+        &store_path,
+        ConflictPolicy::Rename,
+        limits,
+        &mut files_written,
+    )
+    .await;
+    // Files that made it in count even if a later one failed
+    let count = files_written.len();
+    if count > 0 {
+        path_token::Entity::update_many()
+            .col_expr(
+                path_token::Column::Uploads,
+                Expr::col(path_token::Column::Uploads).add(count as i32),
+            )
+            .filter(path_token::Column::Token.eq(&share.token))
+            .exec(&state.db)
+            .await?;
+    }
+    written?;
+    Ok(count)
+}
+
+#[tracing::instrument(skip(state))]
+#[get("/shares/{store}")]
+pub async fn get_shares(
+    state: web::Data<AppState>,
+    store: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<web::Json<ShareResults>, StorageError> {
+    get_authorized_path(&authorized, &store, None)?;
+    Ok(web::Json(ShareResults {
+        shares: list_shares(&state, &store).await?,
+    }))
+}
+
+#[tracing::instrument(skip(state, options))]
+#[post("/shares/{store}/{path:.*}")]
+pub async fn post_share(
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    options: web::Json<ShareOptions>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<web::Json<Share>, StorageError> {
+    let (store, path) = params.as_ref();
+    // Only the owner can make share links
+    get_authorized_path(&authorized, store, None)?;
+    let store_path = get_authorized_path(&authorized, store, Some(path))?;
+    Ok(web::Json(
+        create_share(&state, store, &store_path, options.into_inner()).await?,
+    ))
+}
+
+#[tracing::instrument(skip(state))]
+#[delete("/shares/{store}/{id}")]
+pub async fn delete_share(
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, id) = params.as_ref();
+    get_authorized_path(&authorized, store, None)?;
+    revoke_share(&state, store, id).await?;
+    Ok(empty_ok_response())
+}
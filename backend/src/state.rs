@@ -1,15 +1,26 @@
-use std::str::FromStr;
+use std::{convert::Infallible, str::FromStr};
 
+use hmac::{Hmac, Mac};
 use nanoid::nanoid;
 use sea_orm::DatabaseConnection;
-use serde::{Deserialize, Serialize, Serializer};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 
 use simple_secrecy;
 
 #[cfg(feature = "generate_types")]
 use typescript_type_def::TypeDef;
 
-use crate::error::CLIError;
+use crate::{
+    api_key::{ApiKeyAccess, ApiKeyScope},
+    auth::{AuthProvider, PasswordHashOptions},
+    error::CLIError,
+    kv::KVBackend,
+    oidc::OidcClient,
+    proxy_auth::ProxyAuth,
+    session::TokenOptions,
+    versions::VersionOptions,
+};
 
 #[derive(
     Serialize,
@@ -50,46 +61,63 @@ impl Default for Token {
     }
 }
 
-#[derive(Serialize, Deserialize)]
-#[cfg_attr(feature = "generate_types", derive(TypeDef))]
-pub struct PathTokenResponse {
-    pub token: Token,
+/// The secret key that tokens are hashed with before they are stored, so
+/// someone who can read the database can't use the tokens in it.
+#[derive(Clone, simple_secrecy::Debug)]
+pub struct TokenKey(Vec<u8>);
+
+impl TokenKey {
+    pub fn new() -> TokenKey {
+        TokenKey(nanoid!(64).into_bytes())
+    }
+
+    pub fn read(key: &[u8]) -> TokenKey {
+        TokenKey(key.to_vec())
+    }
+
+    /// Drop the key, and reveal the secret inside.
+    pub fn reveal(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The hash of a token, which is what gets stored in the database.
+    pub fn hash(&self, token: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC accepts keys of any length");
+        mac.update(token.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Checks a hash made with [`TokenKey::hash`], in constant time.
+    pub fn verify(&self, token: &str, hash: &str) -> bool {
+        let Ok(hash) = hex::decode(hash) else {
+            return false;
+        };
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC accepts keys of any length");
+        mac.update(token.as_bytes());
+        mac.verify_slice(&hash).is_ok()
+    }
 }
 
-fn serialize_datetime<S>(
-    time: &chrono::DateTime<chrono::Utc>,
-    serializer: S,
-) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    let formatted_time = time.to_rfc3339();
-    serializer.serialize_str(&formatted_time)
+impl Default for TokenKey {
+    fn default() -> Self {
+        TokenKey::new()
+    }
 }
 
-fn deserialize_datetime<'de, D>(deserializer: D) -> Result<chrono::DateTime<chrono::Utc>, D::Error>
-where
-    D: serde::Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    Ok(chrono::DateTime::parse_from_rfc3339(&s)
-        .map_err(serde::de::Error::custom)?
-        .with_timezone(&chrono::Utc))
+impl FromStr for TokenKey {
+    type Err = Infallible;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        Ok(TokenKey::read(key.as_bytes()))
+    }
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct PathTokenStored {
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct PathTokenResponse {
     pub token: Token,
-    #[serde(
-        serialize_with = "serialize_datetime",
-        deserialize_with = "deserialize_datetime"
-    )]
-    pub created_at: chrono::DateTime<chrono::Utc>,
-    #[serde(
-        serialize_with = "serialize_datetime",
-        deserialize_with = "deserialize_datetime"
-    )]
-    pub valid_until: chrono::DateTime<chrono::Utc>,
 }
 
 #[derive(std::fmt::Debug, Serialize, Deserialize, derive_more::Display, Clone)]
@@ -100,6 +128,29 @@ pub struct AppState {
     // for uptime
     pub started_at: chrono::DateTime<chrono::Local>,
     pub db: DatabaseConnection,
+    /// Where the files and folders of the stores are kept.
+    pub storage: Box<dyn KVBackend>,
+    /// How many previous versions of files are kept, and for how long.
+    pub versions: VersionOptions,
+    /// How long user tokens stay valid.
+    pub tokens: TokenOptions,
+    /// The key that tokens are hashed with.
+    pub token_key: TokenKey,
+    /// Where usernames and passwords are checked.
+    pub auth: Box<dyn AuthProvider>,
+    /// The identity provider for single sign-on, if one is set up.
+    pub oidc: Option<OidcClient>,
+    /// The reverse proxy that is trusted to log users in, if one is set up.
+    pub proxy_auth: Option<ProxyAuth>,
+    /// How new password hashes are made.
+    pub password_hashing: PasswordHashOptions,
+}
+
+impl AppState {
+    /// The hash of a token, to store it or look it up in the database.
+    pub fn hash_token(&self, token: &Token) -> String {
+        self.token_key.hash(token.reveal())
+    }
 }
 
 #[derive(Clone, simple_secrecy::Debug, simple_secrecy::Display)]
@@ -107,6 +158,58 @@ pub enum Authorized {
     User(Username),
     Path,
     Both(Username),
+    /// A user who can also access the stores of their groups, or paths other
+    /// users shared with them.
+    Shared(Username, SharedAccess),
+    /// A script using an API key of the user, which only gets what the key
+    /// allows.
+    Key(Username, ApiKeyAccess),
+}
+
+impl Authorized {
+    /// The user that made the request, if they logged in. API keys don't
+    /// count, they can't manage the account of the user.
+    pub fn username(&self) -> Option<&Username> {
+        match self {
+            Authorized::User(user) | Authorized::Both(user) | Authorized::Shared(user, _) => {
+                Some(user)
+            }
+            Authorized::Path | Authorized::Key(_, _) => None,
+        }
+    }
+
+    /// The user that can use the admin API with this request, if they are an
+    /// admin. API keys need the admin scope for this.
+    pub fn admin_username(&self) -> Option<&Username> {
+        match self {
+            Authorized::Key(user, access) if access.scope == ApiKeyScope::Admin => Some(user),
+            authorized => authorized.username(),
+        }
+    }
+}
+
+/// What a user can access besides their own store.
+#[derive(Clone, Debug, Default)]
+pub struct SharedAccess {
+    /// The stores of the groups the user is a member of.
+    pub groups: Vec<String>,
+    /// Paths other users shared with the user. Only the shares that allow the
+    /// method of the request are included.
+    pub paths: Vec<SharedPath>,
+}
+
+impl SharedAccess {
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty() && self.paths.is_empty()
+    }
+}
+
+/// A file or folder in the store of another user.
+#[derive(Clone, Debug)]
+pub struct SharedPath {
+    pub store: String,
+    /// The path inside the store, without leading or trailing slashes.
+    pub path: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -117,16 +220,29 @@ pub struct UserData {
     pub user_type: UserType,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
 /// Type of user. Admins can add and remove users.
 pub enum UserType {
+    #[default]
     User,
     Admin,
 }
 
-impl Default for UserType {
-    fn default() -> Self {
-        UserType::User
+impl UserType {
+    /// The value stored in the `user_type` column of the database.
+    pub fn db_value(&self) -> &'static str {
+        match self {
+            UserType::User => "U",
+            UserType::Admin => "A",
+        }
+    }
+
+    pub fn from_db_value(value: &str) -> UserType {
+        match value {
+            "A" => UserType::Admin,
+            _ => UserType::User,
+        }
     }
 }
 
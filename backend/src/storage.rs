@@ -3,29 +3,40 @@ use std::{
     io,
     ops::Deref,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
-use actix_files::NamedFile;
 use actix_multipart::{Multipart, MultipartError};
 use actix_web::{
     delete, get, head,
-    http::{self, StatusCode},
+    http::{
+        self,
+        header::{self, Header},
+        StatusCode,
+    },
     post, put, route,
     web::{self, ReqData},
-    Either, HttpResponse, HttpResponseBuilder,
+    Either, HttpRequest, HttpResponse, HttpResponseBuilder,
 };
-use chrono::{DateTime, Utc};
+use chrono::Utc;
 use futures::TryStreamExt;
 use nanoid::nanoid;
-use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set};
+use sea_orm::{ActiveModelTrait, DbErr, Set};
 use serde::{Deserialize, Serialize};
-use tokio::{fs, io::AsyncWriteExt};
 use tracing_unwrap::ResultExt;
 
 use crate::{
+    archive::{archive_response, extract_archive, ArchiveQuery},
+    auth_middleware::path_segments,
     entity::path_token,
     folder,
+    kv::{Metadata, PartFile},
+    quota::{add_usage, check_quota, invalidate_usage, path_size, QuotaBudget},
+    share::DownloadResponse,
     state::{AppState, Authorized, PathTokenResponse, Token},
+    trash::move_to_trash,
+    user_share::shared_path_covers,
+    versions::{copy_to_versions, move_to_versions},
 };
 
 #[cfg(feature = "generate_types")]
@@ -43,6 +54,28 @@ pub enum StorageError {
     UploadError(#[from] MultipartError),
     #[display(fmt = "Bad path")]
     BadPath,
+    #[display(fmt = "A file named {} already exists", _0)]
+    FileExists(String),
+    #[display(fmt = "Unknown conflict policy {}", _0)]
+    BadConflictPolicy(String),
+    #[display(fmt = "Can't extract the archive: {}", _0)]
+    BadArchive(String),
+    #[display(fmt = "The archive contains a path that is not allowed: {}", _0)]
+    UnsafeArchivePath(String),
+    #[display(fmt = "The archive is too large to extract")]
+    ArchiveTooLarge,
+    #[display(fmt = "Database error {}", _0)]
+    Database(#[from] DbErr),
+    #[display(fmt = "There is not enough space left in the store")]
+    QuotaExceeded,
+    #[display(fmt = "Failed to hash the password: {}", _0)]
+    PasswordHash(String),
+    #[display(fmt = "The file is larger than allowed")]
+    FileTooLarge,
+    #[display(fmt = "No more files can be uploaded")]
+    TooManyFiles,
+    #[display(fmt = "Can't share with the user {}", _0)]
+    BadShareUser(String),
 }
 
 impl Serialize for StorageError {
@@ -67,6 +100,17 @@ impl actix_web::error::ResponseError for StorageError {
             },
             StorageError::UploadError(_) => StatusCode::BAD_REQUEST,
             StorageError::BadPath => StatusCode::BAD_REQUEST,
+            StorageError::FileExists(_) => StatusCode::CONFLICT,
+            StorageError::BadConflictPolicy(_) => StatusCode::BAD_REQUEST,
+            StorageError::BadArchive(_) => StatusCode::BAD_REQUEST,
+            StorageError::UnsafeArchivePath(_) => StatusCode::BAD_REQUEST,
+            StorageError::ArchiveTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            StorageError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            StorageError::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+            StorageError::PasswordHash(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            StorageError::FileTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            StorageError::TooManyFiles => StatusCode::FORBIDDEN,
+            StorageError::BadShareUser(_) => StatusCode::BAD_REQUEST,
         }
     }
 
@@ -75,11 +119,11 @@ impl actix_web::error::ResponseError for StorageError {
     }
 }
 
-fn parse_params(params: &str) -> (&str, &str) {
+pub fn parse_params(params: &str) -> (&str, &str) {
     let (store, path) = params
         .split_once('/')
         // If there is no `/`, then we just have the store and the path is empty.
-        .unwrap_or_else(|| (params, ""));
+        .unwrap_or((params, ""));
     (store, path)
 }
 
@@ -90,40 +134,34 @@ pub fn get_authorized_path(
     store: &str,
     path: Option<&str>,
 ) -> Result<PathBuf, StorageError> {
-    #[allow(clippy::single_match)]
     match authorized {
         Some(authorized) => {
             let is_authorized = match authorized.deref() {
                 Authorized::User(user) => store.eq(&user.0),
                 Authorized::Path => true,
                 Authorized::Both(_) => true,
+                Authorized::Shared(user, access) => {
+                    store.eq(&user.0)
+                        || access.groups.iter().any(|group| store.eq(group))
+                        || path.is_some_and(|path| shared_path_covers(&access.paths, store, path))
+                }
+                Authorized::Key(user, access) => store.eq(&user.0) && access.covers(path),
             };
             if is_authorized {
                 tracing::debug!("User or path token is authorized");
                 let path_base = PathBuf::from(folder::STORAGE).join(store);
 
+                // Make sure to avoid someone using ".." to escape their
+                // authorized store. Actix cleans relative paths when parsing
+                // the URL, but paths also come from headers and request bodies.
+                if path_segments(store).is_none_or(|store| store.len() != 1)
+                    || path.is_some_and(|path| path_segments(path).is_none())
+                {
+                    tracing::info!("Tried to access path {:?} in {:?}", path, store);
+                    return Err(StorageError::NotAuthorized);
+                }
                 match path {
-                    Some(path) => {
-                        let path_full = path_base.join(path);
-                        // Make sure to avoid someone using ".." to escape their
-                        // authorized store. This may be unnecessary in some
-                        // cases because actix cleans relative paths when
-                        // parsing the URL, but keeping this for safety unless
-                        // it becomes a performance bottleneck.
-                        let relative_path = pathdiff::diff_paths(path_base, &path_full);
-                        match relative_path {
-                            Some(relative_path) => {
-                                if relative_path.starts_with("../")
-                                    || relative_path.eq(&PathBuf::from(""))
-                                {
-                                    return Ok(path_full);
-                                }
-                            }
-                            None => {}
-                        }
-                        tracing::info!("Tried to access path {:?}", path);
-                        Err(StorageError::NotAuthorized)
-                    }
+                    Some(path) => Ok(path_base.join(path)),
                     None => Ok(path_base),
                 }
             } else {
@@ -152,29 +190,82 @@ pub struct FolderEntry {
     pub size: u64,
 }
 
+/// Responds with the contents of a file. If the request asks for a range of
+/// the file, only that range is sent.
+pub async fn file_response(
+    req: &HttpRequest,
+    state: &AppState,
+    path: &Path,
+    meta: &Metadata,
+) -> Result<HttpResponse, StorageError> {
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(mime_guess::from_path(path).first_or_octet_stream())
+        .insert_header((header::ACCEPT_RANGES, "bytes"));
+    response.extensions_mut().insert(DownloadResponse);
+    if let Some(modified) = meta.modified {
+        response.insert_header(header::LastModified(modified.into()));
+    }
+
+    match header::Range::parse(req) {
+        Ok(header::Range::Bytes(ranges)) => {
+            // Multiple ranges are rarely used, we only serve the first one
+            match ranges
+                .first()
+                .and_then(|range| range.to_satisfiable_range(meta.size))
+            {
+                Some((start, end)) => {
+                    let body = state.storage.read(path, Some(start..end + 1)).await?;
+                    Ok(response
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .insert_header((
+                            header::CONTENT_RANGE,
+                            format!("bytes {start}-{end}/{}", meta.size),
+                        ))
+                        .no_chunking(end + 1 - start)
+                        .streaming(body))
+                }
+                None => Ok(HttpResponse::RangeNotSatisfiable()
+                    .insert_header((header::CONTENT_RANGE, format!("bytes */{}", meta.size)))
+                    .finish()),
+            }
+        }
+        _ => {
+            let body = state.storage.read(path, None).await?;
+            Ok(response.no_chunking(meta.size).streaming(body))
+        }
+    }
+}
+
 pub async fn get_storage_internal(
+    req: &HttpRequest,
+    state: &AppState,
     params: (&str, &str),
     authorized: &Option<ReqData<Authorized>>,
-) -> Result<Either<NamedFile, web::Json<FolderResults>>, StorageError> {
+) -> Result<Either<HttpResponse, web::Json<FolderResults>>, StorageError> {
     let (store, path) = params;
 
     let store_path = get_authorized_path(authorized, store, Some(path))?;
     tracing::debug!("Requested path {}", store_path.to_string_lossy());
-    if fs::metadata(&store_path).await?.is_file() {
+    let meta = state.storage.metadata(&store_path).await?;
+    if meta.is_file {
         tracing::debug!("Path is a file");
-        Ok(Either::Left(NamedFile::open_async(store_path).await?))
+        Ok(Either::Left(
+            file_response(req, state, &store_path, &meta).await?,
+        ))
     } else {
         tracing::debug!("Path is a folder");
-        let mut entries = fs::read_dir(store_path).await?;
-        let mut folder_contents: Vec<FolderEntry> = vec![];
-        while let Some(entry) = entries.next_entry().await? {
-            let meta = entry.metadata().await?;
-            folder_contents.push(FolderEntry {
-                is_file: meta.is_file(),
-                name: entry.file_name().to_string_lossy().to_string(),
-                size: meta.len(),
+        let mut folder_contents: Vec<FolderEntry> = state
+            .storage
+            .read_dir(&store_path)
+            .await?
+            .into_iter()
+            .map(|entry| FolderEntry {
+                is_file: entry.meta.is_file,
+                name: entry.name,
+                size: entry.meta.size,
             })
-        }
+            .collect();
         // Sort them folders first, then files. Sorted by name within these
         // groups. The react UI does it's own sorting internally on top of this,
         // but this is helpful for the basic UI.
@@ -198,38 +289,50 @@ pub struct EmptySuccess {
     status: &'static str,
 }
 
-#[tracing::instrument]
+#[tracing::instrument(skip(state))]
 #[get("/{store_and_path:.*}")]
 pub async fn get_storage(
+    req: HttpRequest,
+    state: web::Data<AppState>,
     params: web::Path<String>,
+    query: web::Query<ArchiveQuery>,
     authorized: Option<ReqData<Authorized>>,
-) -> Result<Either<NamedFile, web::Json<FolderResults>>, StorageError> {
+) -> Result<Either<HttpResponse, web::Json<FolderResults>>, StorageError> {
     let (store, path) = parse_params(&params);
-    get_storage_internal((store, path), &authorized).await
+    if let Some(format) = query.archive {
+        let store_path = get_authorized_path(&authorized, store, Some(path))?;
+        return Ok(Either::Left(
+            archive_response(state, store_path, format).await?,
+        ));
+    }
+    get_storage_internal(&req, &state, (store, path), &authorized).await
 }
 
-fn empty_ok_response() -> HttpResponse {
+pub fn empty_ok_response() -> HttpResponse {
     // If we return no body, Firefox gets angry and puts "XML Parsing Error: no
     // root element found" messages in the console for no reason. So we are adding a dummy response to quiet that.
     HttpResponse::Ok().json(EmptySuccess { status: "ok" })
 }
 
-#[tracing::instrument]
+#[tracing::instrument(skip(state))]
 #[delete("/{store}/{path:.*}")]
 async fn delete_storage(
+    state: web::Data<AppState>,
     params: web::Path<(String, String)>,
     authorized: Option<ReqData<Authorized>>,
 ) -> Result<HttpResponse, StorageError> {
     let (store, path) = params.as_ref();
 
-    common_delete(&authorized, store, Some(path)).await?;
+    common_delete(&state, &authorized, store, Some(path)).await?;
     Ok(empty_ok_response())
 }
 
-/// Checks that the user is authorized to delete this path, and then deletes it.
+/// Checks that the user is authorized to delete this path, and then moves it
+/// to the trash of the store.
 ///
 /// Returns the deleted path.
 pub async fn common_delete(
+    state: &AppState,
     authorized: &Option<ReqData<Authorized>>,
     store: &str,
     path: Option<&str>,
@@ -242,13 +345,9 @@ pub async fn common_delete(
                 // deleted
                 Err(StorageError::BadPath)
             } else {
-                if fs::metadata(&store_path).await?.is_file() {
-                    tracing::debug!("Deleting file {:?}", store_path);
-                    fs::remove_file(&store_path).await?;
-                } else {
-                    tracing::debug!("Deleting folder {:?}", store_path);
-                    fs::remove_dir_all(&store_path).await?;
-                }
+                tracing::debug!("Moving {:?} to the trash", store_path);
+                let size = move_to_trash(state, store, path).await?;
+                add_usage(state, store, -(size as i64)).await?;
                 Ok(store_path)
             }
         }
@@ -258,9 +357,10 @@ pub async fn common_delete(
     }
 }
 
-#[tracing::instrument]
+#[tracing::instrument(skip(state))]
 #[head("/{store_and_path:.*}")]
 async fn head_storage(
+    state: web::Data<AppState>,
     params: web::Path<String>,
     authorized: Option<ReqData<Authorized>>,
 ) -> HttpResponse {
@@ -270,12 +370,8 @@ async fn head_storage(
         let store_path = get_authorized_path(&authorized, store, Some(path))?;
         tracing::debug!("Requested path {}", store_path.to_string_lossy());
 
-        let meta = fs::metadata(store_path).await?;
-        if meta.is_file() || meta.is_dir() {
-            Ok::<(), StorageError>(())
-        } else {
-            Err(std::io::Error::new(std::io::ErrorKind::NotFound, "").into())
-        }
+        state.storage.metadata(&store_path).await?;
+        Ok::<(), StorageError>(())
     }
     .await;
 
@@ -293,9 +389,10 @@ pub struct FileMeta {
     pub size: u64,
 }
 
-#[tracing::instrument]
+#[tracing::instrument(skip(state))]
 #[route("/{store_and_path:.*}", method = "META")]
 async fn meta_storage(
+    state: web::Data<AppState>,
     params: web::Path<String>,
     authorized: Option<ReqData<Authorized>>,
 ) -> HttpResponse {
@@ -304,37 +401,81 @@ async fn meta_storage(
     let check = async {
         let store_path = get_authorized_path(&authorized, store, Some(path))?;
         tracing::debug!("Requested path {}", store_path.to_string_lossy());
-        Ok(fs::metadata(store_path).await?)
+        Ok(state.storage.metadata(&store_path).await?)
     }
     .await;
 
     match check {
         Ok(meta) => HttpResponse::Ok().json(FileMeta {
-            is_file: meta.is_file(),
-            size: meta.len(),
+            is_file: meta.is_file,
+            size: meta.size,
         }),
         Err(StorageError::NotAuthorized) => HttpResponse::Unauthorized().finish(),
         Err(_) => HttpResponse::NotFound().finish(),
     }
 }
 
-#[derive(Debug, Serialize)]
+/// What to do when an uploaded file has the same name as an existing file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Save the upload as `name (1).ext`, `name (2).ext` and so on.
+    #[default]
+    Rename,
+    /// Replace the existing file with the upload.
+    Overwrite,
+    /// Reject the upload.
+    Fail,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = StorageError;
+
+    fn from_str(from: &str) -> Result<Self, Self::Err> {
+        match from.trim().to_lowercase().as_str() {
+            "rename" => Ok(ConflictPolicy::Rename),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "fail" => Ok(ConflictPolicy::Fail),
+            s => Err(StorageError::BadConflictPolicy(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct UploadQuery {
+    #[serde(default)]
+    pub conflict: ConflictPolicy,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct FileWritten {
+    pub path: String,
+    /// If the file conflicted with an existing file, the policy that was used
+    /// to resolve it.
+    pub conflict: Option<ConflictPolicy>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 #[cfg_attr(feature = "generate_types", derive(TypeDef))]
 pub struct PutStoragePayload {
-    pub files_written: Vec<String>,
+    pub files_written: Vec<FileWritten>,
 }
 
-#[tracing::instrument(skip(payload))]
+#[tracing::instrument(skip(state, payload))]
 #[put("/{store_and_path:.*}")]
 async fn put_storage(
+    state: web::Data<AppState>,
     params: web::Path<String>,
+    query: web::Query<UploadQuery>,
     authorized: Option<ReqData<Authorized>>,
     mut payload: Multipart,
 ) -> Result<web::Json<PutStoragePayload>, StorageError> {
     let (store, path) = parse_params(&params);
     let store_path = get_authorized_path(&authorized, store, Some(path))?;
 
-    tokio::fs::create_dir(&store_path).await.or_else(|err| {
+    state.storage.create_dir(&store_path).await.or_else(|err| {
         tracing::debug!("Error: {:?}", err);
         if err.kind() == std::io::ErrorKind::AlreadyExists {
             // It's fine if the folder already exists
@@ -345,94 +486,255 @@ async fn put_storage(
         }
     })?;
 
-    match write_files(&mut payload, &store_path).await {
-        Ok(files_written) => Ok(web::Json(PutStoragePayload {
-            files_written: files_written
-                .iter()
-                .map(|file| file.to_string_lossy().to_string())
-                .collect(),
-        })),
-        Err(err) => Err(err),
-    }
+    let files_written =
+        write_files(&state, &mut payload, store, &store_path, query.conflict).await?;
+    Ok(web::Json(PutStoragePayload { files_written }))
 }
 
 /// If there's more than this many files with the same name in the folder, fail the upload.
 static MAX_RENAME_ATTEMPTS: u32 = 100;
 
-#[tracing::instrument(skip(payload))]
+/// The name of the form field that can change the conflict policy. It applies
+/// to all files that come after it in the form.
+const CONFLICT_FIELD: &str = "conflict";
+
+/// Limits on the files that can be uploaded, used for file drops.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UploadLimits {
+    /// The largest file that can be uploaded, in bytes.
+    pub max_file_size: Option<u64>,
+    /// How many files can be uploaded.
+    pub max_files: Option<u32>,
+    /// Never replace existing files, ignoring the conflict policy picked by
+    /// the uploader.
+    pub keep_existing: bool,
+}
+
+#[tracing::instrument(skip(state, payload))]
 pub async fn write_files(
+    state: &AppState,
+    payload: &mut Multipart,
+    store: &str,
+    store_path: &Path,
+    policy: ConflictPolicy,
+) -> Result<Vec<FileWritten>, StorageError> {
+    let mut files_written: Vec<FileWritten> = vec![];
+    write_files_limited(
+        state,
+        payload,
+        store,
+        store_path,
+        policy,
+        UploadLimits::default(),
+        &mut files_written,
+    )
+    .await?;
+    Ok(files_written)
+}
+
+/// Writes the uploaded files like [`write_files`], but only within the
+/// limits. The files that were written are added to `files_written`, even if
+/// the upload fails part way through.
+#[tracing::instrument(skip(state, payload, files_written))]
+pub async fn write_files_limited(
+    state: &AppState,
     payload: &mut Multipart,
+    store: &str,
     store_path: &Path,
-) -> Result<Vec<PathBuf>, StorageError> {
-    let mut files_written: Vec<PathBuf> = vec![];
+    mut policy: ConflictPolicy,
+    limits: UploadLimits,
+    files_written: &mut Vec<FileWritten>,
+) -> Result<(), StorageError> {
+    if limits.keep_existing {
+        policy = ConflictPolicy::Rename;
+    }
+    let mut budget = QuotaBudget::for_store(state, store).await?;
     while let Some(mut field) = payload.try_next().await? {
         let content_disposition = field.content_disposition();
 
+        if content_disposition.get_filename().is_none()
+            && content_disposition.get_name() == Some(CONFLICT_FIELD)
+            && !limits.keep_existing
+        {
+            let mut value = vec![];
+            while let Some(chunk) = field.try_next().await? {
+                value.extend_from_slice(&chunk);
+                // The field only holds short names, don't let it fill the memory
+                if value.len() > 16 {
+                    return Err(StorageError::BadConflictPolicy(
+                        String::from_utf8_lossy(&value).to_string(),
+                    ));
+                }
+            }
+            policy = String::from_utf8_lossy(&value).parse()?;
+            continue;
+        }
+
+        if let Some(max_files) = limits.max_files {
+            if files_written.len() >= max_files as usize {
+                return Err(StorageError::TooManyFiles);
+            }
+        }
         let filename = content_disposition
             .get_filename()
             .map_or_else(|| nanoid!(), sanitize_filename::sanitize);
-        let basename = PathBuf::from(&filename)
-            .file_stem()
-            .map(|b| b.to_string_lossy().to_string())
-            .unwrap_or_else(|| filename.clone());
-        let part_filename = format!(".{filename}.{}.part", nanoid!(8));
-        let part_filepath = store_path.join(part_filename);
-        tracing::debug!(filename = ?filename, part_filepath = ?part_filepath, "Upload started");
-
-        // First start uploading using a temporary, random file name to make
-        // sure it doesn't conflict with any existing files
-        let mut file = tokio::fs::File::create(&part_filepath).await?;
-        while let Some(chunk) = field.try_next().await? {
-            file.write_all(&chunk).await?;
+        tracing::debug!(filename = ?filename, "Upload started");
+
+        // A file that is going to be overwritten makes room for the upload
+        let replaced = match policy {
+            ConflictPolicy::Overwrite => {
+                match state.storage.metadata(&store_path.join(&filename)).await {
+                    Ok(meta) if meta.is_file => meta.size,
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        };
+        budget.credit(replaced);
+        let used_before = budget.used();
+
+        // First write the upload into a part, so it doesn't conflict with any
+        // existing files
+        let mut part = state.storage.create_part(store_path, &filename).await?;
+        let written =
+            write_part(&mut field, part.as_mut(), &mut budget, limits.max_file_size).await;
+        if let Err(err) = written {
+            if let Err(discard_err) = part.discard().await {
+                tracing::warn!(error = ?discard_err, "Failed to clean up a failed upload");
+            }
+            return Err(err);
         }
 
-        let mut filepath = store_path.join(&filename);
-        let extension = filepath
-            .extension()
-            .map(|ext| {
-                let ext = ext.to_string_lossy();
-                format!(".{ext}")
-            })
-            .unwrap_or_else(|| "".to_string());
-        let mut i: u32 = 0;
-        loop {
-            i += 1;
-            // Once the upload is done, try to rename the file to it's real name
-            let c_filepath = filepath.clone();
-            let c_part_filepath = part_filepath.clone();
-            let success = web::block(move || atomic_rename::rename(c_part_filepath, c_filepath))
+        let committed = match policy {
+            // Keep what is about to be overwritten
+            ConflictPolicy::Overwrite => copy_to_versions(state, &store_path.join(&filename))
                 .await
-                // Very unlikely/unrecoverable
-                .unwrap_or_log();
-            if let Err(err) = &success {
-                if err.kind() == std::io::ErrorKind::AlreadyExists {
-                    // If the rename failed because a file with the same name
-                    // exists, come up with a new file name
-                    filepath.set_file_name(format!("{basename} ({i}){extension}"));
-                } else {
-                    // If the rename failed for any other reason, the upload
-                    // should fail too
-                    success?;
+                .map(|_| ()),
+            _ => Ok(()),
+        };
+        let committed = match committed {
+            Ok(()) => commit_part(part.as_mut(), store_path, &filename, policy).await,
+            Err(err) => Err(err),
+        };
+        if committed.is_err() {
+            if let Err(discard_err) = part.discard().await {
+                tracing::warn!(error = ?discard_err, "Failed to clean up a failed upload");
+            }
+        }
+        let (filepath, conflict) = committed?;
+        let written = budget.used() - used_before;
+        let replaced = match conflict {
+            Some(ConflictPolicy::Overwrite) => replaced,
+            _ => 0,
+        };
+        add_usage(state, store, written as i64 - replaced as i64).await?;
+        files_written.push(FileWritten {
+            path: filepath.to_string_lossy().to_string(),
+            conflict,
+        });
+    }
+    Ok(())
+}
+
+/// Moves a finished upload to its real name. If a file with that name already
+/// exists, the policy decides what happens. With [`ConflictPolicy::Rename`],
+/// the upload is renamed to `name (1).ext`, `name (2).ext` and so on.
+///
+/// Returns where the upload ended up, and which policy was applied if there
+/// was a conflict.
+pub async fn commit_part(
+    part: &mut dyn PartFile,
+    store_path: &Path,
+    filename: &str,
+    policy: ConflictPolicy,
+) -> Result<(PathBuf, Option<ConflictPolicy>), StorageError> {
+    let basename = PathBuf::from(filename)
+        .file_stem()
+        .map(|b| b.to_string_lossy().to_string())
+        .unwrap_or_else(|| filename.to_string());
+    let mut filepath = store_path.join(filename);
+    match policy {
+        ConflictPolicy::Rename => {}
+        ConflictPolicy::Overwrite => {
+            return match part.commit(&filepath).await {
+                Ok(()) => Ok((filepath, None)),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                    // The part is swapped in atomically, so the existing file
+                    // is never seen half written
+                    part.replace(&filepath).await?;
+                    Ok((filepath, Some(ConflictPolicy::Overwrite)))
                 }
+                Err(err) => Err(err.into()),
+            };
+        }
+        ConflictPolicy::Fail => {
+            return match part.commit(&filepath).await {
+                Ok(()) => Ok((filepath, None)),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                    Err(StorageError::FileExists(filename.to_string()))
+                }
+                Err(err) => Err(err.into()),
+            };
+        }
+    }
+    let extension = filepath
+        .extension()
+        .map(|ext| {
+            let ext = ext.to_string_lossy();
+            format!(".{ext}")
+        })
+        .unwrap_or_default();
+    let mut i: u32 = 0;
+    loop {
+        i += 1;
+        // Once the upload is done, try to rename the file to it's real name
+        let success = part.commit(&filepath).await;
+        if let Err(err) = &success {
+            if err.kind() == std::io::ErrorKind::AlreadyExists {
+                // If the rename failed because a file with the same name
+                // exists, come up with a new file name
+                filepath.set_file_name(format!("{basename} ({i}){extension}"));
             } else {
-                // The rename worked, we're done
-                files_written.push(filepath);
-                break;
+                // If the rename failed for any other reason, the upload
+                // should fail too
+                success?;
             }
+        } else {
+            // The rename worked, we're done
+            let conflict = (i > 1).then_some(ConflictPolicy::Rename);
+            return Ok((filepath, conflict));
+        }
 
-            // Avoid too many rename attempts, otherwise this could turn into a
-            // DoS vulnerability
-            if i == MAX_RENAME_ATTEMPTS {
-                // If we're about to hit the max, try a nanoid which is unlikely
-                // to hit another conflict
-                filepath.set_file_name(format!("{filename} ({}){extension}", nanoid!()));
-            }
-            if i > MAX_RENAME_ATTEMPTS {
-                break;
-            }
+        // Avoid too many rename attempts, otherwise this could turn into a
+        // DoS vulnerability
+        if i == MAX_RENAME_ATTEMPTS {
+            // If we're about to hit the max, try a nanoid which is unlikely
+            // to hit another conflict
+            filepath.set_file_name(format!("{filename} ({}){extension}", nanoid!()));
+        }
+        if i > MAX_RENAME_ATTEMPTS {
+            return Err(std::io::Error::from(std::io::ErrorKind::AlreadyExists).into());
         }
     }
-    Ok(files_written)
+}
+
+async fn write_part(
+    field: &mut actix_multipart::Field,
+    part: &mut dyn PartFile,
+    budget: &mut QuotaBudget,
+    max_size: Option<u64>,
+) -> Result<(), StorageError> {
+    let mut size: u64 = 0;
+    while let Some(chunk) = field.try_next().await? {
+        size += chunk.len() as u64;
+        if max_size.is_some_and(|max_size| size > max_size) {
+            return Err(StorageError::FileTooLarge);
+        }
+        // Stop as soon as the upload goes over the quota
+        budget.spend(chunk.len() as u64)?;
+        part.write(&chunk).await?;
+    }
+    Ok(())
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
@@ -440,11 +742,17 @@ pub async fn write_files(
 #[serde(tag = "action")]
 pub enum StorageAction {
     MakePathToken,
-    Move { new_path: String },
+    Move {
+        new_path: String,
+    },
     CreateFolder,
+    /// Extracts a zip, tar or tar.gz archive into the folder at `target`.
+    Extract {
+        target: String,
+    },
 }
 
-#[tracing::instrument]
+#[tracing::instrument(skip(state))]
 #[post("/{store}/{path:.*}")]
 async fn post_storage(
     state: web::Data<AppState>,
@@ -461,11 +769,46 @@ async fn post_storage(
         StorageAction::Move { new_path } => {
             let (to_store, to_path) = parse_store_path(new_path).ok_or(StorageError::BadPath)?;
             let to_store_path = get_authorized_path(&authorized, to_store, Some(&to_path))?;
-            fs::rename(store_path, to_store_path).await?;
+            let size = path_size(state.storage.as_ref(), &store_path).await?;
+            let replaced = match state.storage.metadata(&to_store_path).await {
+                Ok(meta) if meta.is_file => meta.size,
+                _ => 0,
+            };
+            if store != to_store {
+                check_quota(&state, to_store, size.saturating_sub(replaced)).await?;
+            }
+            // Keep the file that is about to be replaced
+            move_to_versions(&state, &to_store_path).await?;
+            state.storage.rename(&store_path, &to_store_path).await?;
+            if store != to_store {
+                add_usage(&state, store, -(size as i64)).await?;
+                add_usage(&state, to_store, size as i64 - replaced as i64).await?;
+            } else {
+                add_usage(&state, store, -(replaced as i64)).await?;
+            }
             Ok(empty_ok_response())
         }
         StorageAction::CreateFolder => {
-            tokio::fs::create_dir(&store_path).await?;
+            state.storage.create_dir(&store_path).await?;
+            Ok(empty_ok_response())
+        }
+        StorageAction::Extract { target } => {
+            let (to_store, to_path) = parse_store_path(target).ok_or(StorageError::BadPath)?;
+            let to_store_path = get_authorized_path(&authorized, to_store, Some(&to_path))?;
+            let mut budget = QuotaBudget::for_store(&state, to_store).await?;
+            let extracted = extract_archive(
+                state.storage.as_ref(),
+                &store_path,
+                &to_store_path,
+                &mut budget,
+            )
+            .await;
+            match extracted {
+                Ok(()) => add_usage(&state, to_store, budget.used() as i64).await?,
+                // Some of the files may have been kept
+                Err(_) => invalidate_usage(&state, to_store).await?,
+            }
+            extracted?;
             Ok(empty_ok_response())
         }
     }
@@ -473,35 +816,37 @@ async fn post_storage(
 
 /// Path tokens are temporary, discard them after this many hours.
 pub const PATH_TOKENS_LIVE_HOURS: i64 = 24;
-/// If a path token is newer than this, reuse it instead of creating a new one.
-pub const PATH_TOKENS_REUSE_HOURS: i64 = 18;
 
+/// Makes a token that gives access to `path` without logging in. If the path
+/// is a folder, the token also gives access to everything inside it.
+///
+/// These tokens only last for a day, use [`crate::share::create_share`] for
+/// links that are meant to be shared with others.
 #[tracing::instrument]
 pub async fn make_path_token(state: &web::Data<AppState>, path: &Path) -> Token {
     let full_path = format!("/{}", path.to_string_lossy());
-
-    let token = path_token::Entity::find()
-        .filter(path_token::Column::Path.eq(&full_path))
-        .one(&state.db)
-        .await
-        .unwrap_or_log();
-
-    if let Some(token) = token {
-        // Only reuse the existing token if it's going to be valid for a while
-        let created_at = DateTime::parse_from_rfc3339(&token.created_at).unwrap_or_log();
-
-        if Utc::now().signed_duration_since(created_at).num_hours() < PATH_TOKENS_REUSE_HOURS {
-            return Token::read(&token.token);
-        }
-    }
+    let is_folder = matches!(state.storage.metadata(path).await, Ok(meta) if !meta.is_file);
+    let store = path
+        .components()
+        .nth(1)
+        .map(|store| store.as_os_str().to_string_lossy().to_string());
+    let now = Utc::now();
 
     let token = Token::new();
     tracing::debug!("Creating a token for {}", full_path);
 
     let path_token = path_token::ActiveModel {
-        token: Set(token.reveal().to_string()),
+        token: Set(state.hash_token(&token)),
+        id: Set(nanoid!()),
         path: Set(full_path.clone()),
-        created_at: Set(chrono::Utc::now().to_rfc3339()),
+        created_at: Set(now.to_rfc3339()),
+        is_folder: Set(is_folder),
+        store: Set(store),
+        valid_until: Set(Some(
+            (now + chrono::Duration::hours(PATH_TOKENS_LIVE_HOURS)).to_rfc3339(),
+        )),
+        is_share: Set(false),
+        ..Default::default()
     };
     path_token.insert(&state.db).await.unwrap_or_log();
     token
@@ -509,9 +854,9 @@ pub async fn make_path_token(state: &web::Data<AppState>, path: &Path) -> Token
 
 #[tracing::instrument]
 /// Parses a path into a store and a path inside that store, sanitizing any path segments.
-fn parse_store_path(path: &str) -> Option<(&str, String)> {
-    let mut segments = path.split('/').filter(|segment| !segment.is_empty());
-    let store = segments.next();
-    let rest: Vec<&str> = segments.collect();
-    store.map(|store| (store, rest.join("/")))
+/// Paths with `.` or `..` segments are refused since they could point outside the store.
+pub fn parse_store_path(path: &str) -> Option<(&str, String)> {
+    let segments = path_segments(path)?;
+    let (store, rest) = segments.split_first()?;
+    Some((store, rest.join("/")))
 }
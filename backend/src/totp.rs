@@ -0,0 +1,488 @@
+//! Two-factor authentication with time-based one-time passwords (TOTP, RFC
+//! 6238), the codes authenticator apps show.
+//!
+//! Users first enroll, which makes a secret for their authenticator app but
+//! doesn't change how they log in yet. Once they confirm that the app works by
+//! entering a code from it, every login needs a code too. Users also get
+//! recovery codes at that point, each of which can be used once instead of a
+//! code if they lose their authenticator.
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post, put,
+    web::{self, ReqData},
+    HttpResponse, HttpResponseBuilder,
+};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use qrcode::{render::svg, QrCode};
+use scrypt::password_hash::rand_core::{OsRng, RngCore};
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, Condition, DatabaseConnection, DbErr,
+    EntityTrait, PaginatorTrait, QueryFilter, Set,
+};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::{
+    auth::{verify_pass, Password},
+    entity::{recovery_code, user},
+    state::{AppState, Authorized},
+    storage::empty_ok_response,
+};
+
+#[cfg(feature = "generate_types")]
+use typescript_type_def::TypeDef;
+
+/// How long each code is valid for, in seconds.
+const STEP_SECS: u64 = 30;
+/// How many digits codes have.
+const DIGITS: u32 = 6;
+/// Codes from this many steps before or after the current one are accepted
+/// too, in case the clock of the server or the authenticator is off.
+const ALLOWED_SKEW_STEPS: u64 = 1;
+/// The size of the secret in bytes, this is the size of the SHA-1 output.
+const SECRET_LENGTH: usize = 20;
+/// How secrets are encoded for the user, and in the database.
+const SECRET_ENCODING: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+/// The name authenticator apps show for the account.
+const ISSUER: &str = "Bulgur Cloud";
+
+/// How many recovery codes a user gets.
+const RECOVERY_CODE_COUNT: usize = 10;
+/// Recovery codes are made of these characters. Characters that are easy to
+/// confuse with each other are left out.
+const RECOVERY_CODE_ALPHABET: [char; 32] = [
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'j', 'k', 'm', 'n', 'p', 'q', 'r', 's', 't', 'u', 'v',
+    'w', 'x', 'y', 'z', '2', '3', '4', '5', '6', '7', '8', '9', '0',
+];
+/// How many characters are on each side of the dash in a recovery code.
+const RECOVERY_CODE_HALF_LENGTH: usize = 5;
+
+#[derive(Debug, derive_more::Display, thiserror::Error)]
+pub enum TotpError {
+    #[display(fmt = "User is not authorized to view this.")]
+    NotAuthorized,
+    #[display(fmt = "Two-factor authentication is already enabled")]
+    AlreadyEnabled,
+    #[display(fmt = "Two-factor authentication is not set up")]
+    NotEnrolled,
+    #[display(fmt = "The code is incorrect")]
+    WrongCode,
+    #[display(fmt = "The password is incorrect")]
+    WrongPassword,
+    #[display(fmt = "Database error {}", _0)]
+    Database(#[from] DbErr),
+}
+
+impl Serialize for TotpError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let s = format!("{}", self);
+        serializer.serialize_str(&s)
+    }
+}
+
+impl actix_web::error::ResponseError for TotpError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            TotpError::NotAuthorized => StatusCode::UNAUTHORIZED,
+            TotpError::AlreadyEnabled => StatusCode::CONFLICT,
+            TotpError::NotEnrolled => StatusCode::BAD_REQUEST,
+            TotpError::WrongCode | TotpError::WrongPassword => StatusCode::FORBIDDEN,
+            TotpError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponseBuilder::new(self.status_code()).json(self)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct TotpStatus {
+    /// True if logging in needs a code.
+    pub enabled: bool,
+    /// True if the user enrolled, but hasn't confirmed it with a code yet.
+    pub pending: bool,
+    /// How many unused recovery codes the user has.
+    pub recovery_codes_left: u32,
+}
+
+/// What the user needs to add the account to their authenticator app.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct TotpEnrollment {
+    /// The secret, encoded in base32, for apps that can't scan the URI.
+    pub secret: String,
+    /// An `otpauth://` URI, usually shown as a QR code.
+    pub uri: String,
+}
+
+impl TotpEnrollment {
+    fn new(username: &str, secret: String) -> Self {
+        let label = urlencoding::encode(&format!("{ISSUER}:{username}")).into_owned();
+        let uri = format!(
+            "otpauth://totp/{label}?secret={secret}&issuer={}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECS}",
+            urlencoding::encode(ISSUER),
+        );
+        TotpEnrollment { secret, uri }
+    }
+
+    /// The URI as a QR code, in SVG.
+    pub fn qr_code(&self) -> String {
+        QrCode::new(self.uri.as_bytes())
+            .map(|code| code.render::<svg::Color>().min_dimensions(200, 200).build())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct TotpCode {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct TotpDisable {
+    pub password: Password,
+}
+
+/// New recovery codes. These are only shown once, afterwards only their
+/// hashes are kept.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct RecoveryCodes {
+    pub codes: Vec<String>,
+}
+
+/// The code for the given time step, as described in RFC 4226 and RFC 6238.
+fn totp_code(secret: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    truncated % 10u32.pow(DIGITS)
+}
+
+/// The code an authenticator shows at `timestamp`, in seconds since the Unix
+/// epoch. `secret` is encoded in base32.
+pub fn code_at(secret: &str, timestamp: u64) -> Option<String> {
+    let secret = base32::decode(SECRET_ENCODING, secret)?;
+    let code = totp_code(&secret, timestamp / STEP_SECS);
+    Some(format!("{code:0width$}", width = DIGITS as usize))
+}
+
+/// Finds the time step the code is for, if it is valid now. Only steps after
+/// `last_step` are accepted, so that a code can't be used twice.
+fn code_step(secret: &str, code: &str, now: u64, last_step: Option<i64>) -> Option<u64> {
+    let secret = base32::decode(SECRET_ENCODING, secret)?;
+    let code = code.trim();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = now / STEP_SECS;
+    (current.saturating_sub(ALLOWED_SKEW_STEPS)..=current + ALLOWED_SKEW_STEPS)
+        .filter(|step| last_step.is_none_or(|last| *step as i64 > last))
+        .find(|step| totp_code(&secret, *step) == code)
+}
+
+fn new_secret() -> String {
+    let mut secret = [0u8; SECRET_LENGTH];
+    OsRng.fill_bytes(&mut secret);
+    base32::encode(SECRET_ENCODING, &secret)
+}
+
+fn new_recovery_code() -> String {
+    let half = || nanoid::nanoid!(RECOVERY_CODE_HALF_LENGTH, &RECOVERY_CODE_ALPHABET);
+    format!("{}-{}", half(), half())
+}
+
+/// Recovery codes are compared without the dash, spaces or case, which are
+/// easy to get wrong when typing the code.
+fn hash_recovery_code(state: &AppState, code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect();
+    state.token_key.hash(&code)
+}
+
+fn request_user(authorized: &Option<ReqData<Authorized>>) -> Result<&str, TotpError> {
+    authorized
+        .as_deref()
+        .and_then(|authorized| authorized.username())
+        .map(|user| user.0.as_str())
+        .ok_or(TotpError::NotAuthorized)
+}
+
+async fn find_user(db: &DatabaseConnection, username: &str) -> Result<user::Model, TotpError> {
+    user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(db)
+        .await?
+        .ok_or(TotpError::NotAuthorized)
+}
+
+/// Checks a code from the authenticator of the user, and remembers that it
+/// was used so it can't be used again.
+async fn use_code(state: &AppState, user: &user::Model, code: &str) -> Result<bool, DbErr> {
+    let Some(secret) = &user.totp_secret else {
+        return Ok(false);
+    };
+    let now = Utc::now().timestamp().max(0) as u64;
+    let Some(step) = code_step(secret, code, now, user.totp_last_step) else {
+        return Ok(false);
+    };
+    // Two requests with the same code could race, only one of them gets to
+    // save the step
+    let result = user::Entity::update_many()
+        .col_expr(user::Column::TotpLastStep, Expr::value(step as i64))
+        .filter(user::Column::Id.eq(&user.id))
+        .filter(
+            Condition::any()
+                .add(user::Column::TotpLastStep.is_null())
+                .add(user::Column::TotpLastStep.lt(step as i64)),
+        )
+        .exec(&state.db)
+        .await?;
+    Ok(result.rows_affected > 0)
+}
+
+/// Checks a recovery code of the user, and deletes it so it can't be used
+/// again.
+async fn use_recovery_code(
+    state: &AppState,
+    user: &user::Model,
+    code: &str,
+) -> Result<bool, DbErr> {
+    let result = recovery_code::Entity::delete_many()
+        .filter(recovery_code::Column::UserId.eq(&user.id))
+        .filter(recovery_code::Column::CodeHash.eq(hash_recovery_code(state, code)))
+        .exec(&state.db)
+        .await?;
+    Ok(result.rows_affected > 0)
+}
+
+/// Checks the code the user entered when logging in. This can be a code from
+/// their authenticator, or one of their recovery codes. Users without
+/// two-factor authentication don't need a code.
+pub(crate) async fn verify_login_code(
+    state: &AppState,
+    user: &user::Model,
+    code: &str,
+) -> Result<bool, DbErr> {
+    if !user.totp_enabled {
+        return Ok(true);
+    }
+    Ok(use_code(state, user, code).await? || use_recovery_code(state, user, code).await?)
+}
+
+async fn replace_recovery_codes(
+    state: &AppState,
+    user: &user::Model,
+) -> Result<RecoveryCodes, DbErr> {
+    recovery_code::Entity::delete_many()
+        .filter(recovery_code::Column::UserId.eq(&user.id))
+        .exec(&state.db)
+        .await?;
+    let now = Utc::now().to_rfc3339();
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| new_recovery_code())
+        .collect();
+    recovery_code::Entity::insert_many(codes.iter().map(|code| recovery_code::ActiveModel {
+        code_hash: Set(hash_recovery_code(state, code)),
+        user_id: Set(user.id.clone()),
+        created_at: Set(now.clone()),
+    }))
+    .exec(&state.db)
+    .await?;
+    Ok(RecoveryCodes { codes })
+}
+
+pub async fn totp_status(state: &AppState, username: &str) -> Result<TotpStatus, TotpError> {
+    let user = find_user(&state.db, username).await?;
+    let recovery_codes_left = recovery_code::Entity::find()
+        .filter(recovery_code::Column::UserId.eq(&user.id))
+        .count(&state.db)
+        .await?;
+    Ok(TotpStatus {
+        enabled: user.totp_enabled,
+        pending: !user.totp_enabled && user.totp_secret.is_some(),
+        recovery_codes_left: recovery_codes_left as u32,
+    })
+}
+
+/// The enrollment the user started but hasn't confirmed yet, if there is one.
+pub async fn pending_enrollment(
+    state: &AppState,
+    username: &str,
+) -> Result<Option<TotpEnrollment>, TotpError> {
+    let user = find_user(&state.db, username).await?;
+    Ok(user
+        .totp_secret
+        .filter(|_| !user.totp_enabled)
+        .map(|secret| TotpEnrollment::new(username, secret)))
+}
+
+/// Makes a new secret for the user. Codes aren't needed to log in until the
+/// user confirms it.
+pub async fn start_enrollment(
+    state: &AppState,
+    username: &str,
+) -> Result<TotpEnrollment, TotpError> {
+    let user = find_user(&state.db, username).await?;
+    if user.totp_enabled {
+        return Err(TotpError::AlreadyEnabled);
+    }
+    let secret = new_secret();
+    let mut user: user::ActiveModel = user.into();
+    user.totp_secret = Set(Some(secret.clone()));
+    user.totp_last_step = Set(None);
+    user.update(&state.db).await?;
+    Ok(TotpEnrollment::new(username, secret))
+}
+
+/// Turns on two-factor authentication, if the code shows that the
+/// authenticator of the user was set up right. Returns the recovery codes of
+/// the user.
+pub async fn confirm_enrollment(
+    state: &AppState,
+    username: &str,
+    code: &str,
+) -> Result<RecoveryCodes, TotpError> {
+    let user = find_user(&state.db, username).await?;
+    if user.totp_enabled {
+        return Err(TotpError::AlreadyEnabled);
+    }
+    if user.totp_secret.is_none() {
+        return Err(TotpError::NotEnrolled);
+    }
+    if !use_code(state, &user, code).await? {
+        return Err(TotpError::WrongCode);
+    }
+    let codes = replace_recovery_codes(state, &user).await?;
+    user::Entity::update_many()
+        .col_expr(user::Column::TotpEnabled, Expr::value(true))
+        .filter(user::Column::Id.eq(&user.id))
+        .exec(&state.db)
+        .await?;
+    Ok(codes)
+}
+
+/// Replaces the recovery codes of the user. This needs a code from the
+/// authenticator, so a stolen session can't be used to get codes.
+pub async fn regenerate_recovery_codes(
+    state: &AppState,
+    username: &str,
+    code: &str,
+) -> Result<RecoveryCodes, TotpError> {
+    let user = find_user(&state.db, username).await?;
+    if !user.totp_enabled {
+        return Err(TotpError::NotEnrolled);
+    }
+    if !use_code(state, &user, code).await? {
+        return Err(TotpError::WrongCode);
+    }
+    Ok(replace_recovery_codes(state, &user).await?)
+}
+
+/// Turns off two-factor authentication for a user, after checking their
+/// password.
+pub async fn disable_totp(
+    state: &AppState,
+    username: &str,
+    password: &Password,
+) -> Result<(), TotpError> {
+    if verify_pass(state, username, password).await.is_err() {
+        return Err(TotpError::WrongPassword);
+    }
+    reset_totp(&state.db, username).await
+}
+
+/// Removes the second factor of a user, so they can log in with just their
+/// password and enroll again. Admins can use this if a user lost both their
+/// authenticator and their recovery codes.
+pub async fn reset_totp(db: &DatabaseConnection, username: &str) -> Result<(), TotpError> {
+    let user = find_user(db, username).await?;
+    recovery_code::Entity::delete_many()
+        .filter(recovery_code::Column::UserId.eq(&user.id))
+        .exec(db)
+        .await?;
+    let mut user: user::ActiveModel = user.into();
+    user.totp_secret = Set(None);
+    user.totp_enabled = Set(false);
+    user.totp_last_step = Set(None);
+    user.update(db).await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(state))]
+#[get("/totp")]
+pub async fn get_totp(
+    state: web::Data<AppState>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<web::Json<TotpStatus>, TotpError> {
+    let username = request_user(&authorized)?;
+    Ok(web::Json(totp_status(&state, username).await?))
+}
+
+#[tracing::instrument(skip(state))]
+#[post("/totp")]
+pub async fn post_totp(
+    state: web::Data<AppState>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<web::Json<TotpEnrollment>, TotpError> {
+    let username = request_user(&authorized)?;
+    Ok(web::Json(start_enrollment(&state, username).await?))
+}
+
+#[tracing::instrument(skip(state, body))]
+#[put("/totp")]
+pub async fn put_totp(
+    state: web::Data<AppState>,
+    authorized: Option<ReqData<Authorized>>,
+    body: web::Json<TotpCode>,
+) -> Result<web::Json<RecoveryCodes>, TotpError> {
+    let username = request_user(&authorized)?;
+    Ok(web::Json(
+        confirm_enrollment(&state, username, &body.code).await?,
+    ))
+}
+
+#[tracing::instrument(skip(state, body))]
+#[delete("/totp")]
+pub async fn delete_totp(
+    state: web::Data<AppState>,
+    authorized: Option<ReqData<Authorized>>,
+    body: web::Json<TotpDisable>,
+) -> Result<HttpResponse, TotpError> {
+    let username = request_user(&authorized)?;
+    disable_totp(&state, username, &body.password).await?;
+    Ok(empty_ok_response())
+}
+
+#[tracing::instrument(skip(state, body))]
+#[post("/totp/recovery-codes")]
+pub async fn post_recovery_codes(
+    state: web::Data<AppState>,
+    authorized: Option<ReqData<Authorized>>,
+    body: web::Json<TotpCode>,
+) -> Result<web::Json<RecoveryCodes>, TotpError> {
+    let username = request_user(&authorized)?;
+    Ok(web::Json(
+        regenerate_recovery_codes(&state, username, &body.code).await?,
+    ))
+}
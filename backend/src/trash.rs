@@ -0,0 +1,264 @@
+//! Deleted files and folders are moved into a trash for their store instead of
+//! being removed right away, so they can be restored later.
+//!
+//! Items in the trash are kept under `trash/{store}/{id}`, outside of the
+//! stores so they don't show up in folder listings. The database remembers
+//! where each item originally was and when it was deleted. Items older than
+//! the retention period are purged by a background task.
+use std::{path::PathBuf, time::Duration};
+
+use actix_web::{
+    delete, get, post,
+    web::{self, ReqData},
+    HttpResponse,
+};
+use chrono::Utc;
+use nanoid::nanoid;
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, EntityTrait, ModelTrait, QueryFilter, QueryOrder, Set,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    entity::trash,
+    folder,
+    quota::{add_usage, check_quota, path_size},
+    state::{AppState, Authorized},
+    storage::{empty_ok_response, get_authorized_path, StorageError},
+};
+
+#[cfg(feature = "generate_types")]
+use typescript_type_def::TypeDef;
+
+/// How often the background task looks for expired items in the trash.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct TrashItem {
+    pub id: String,
+    /// Where the item was before it was deleted, relative to the store.
+    pub path: String,
+    pub is_file: bool,
+    pub size: u64,
+    pub deleted_at: String,
+}
+
+impl From<trash::Model> for TrashItem {
+    fn from(item: trash::Model) -> Self {
+        TrashItem {
+            id: item.id,
+            path: item.path,
+            is_file: item.is_file,
+            size: item.size as u64,
+            deleted_at: item.deleted_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct TrashResults {
+    pub items: Vec<TrashItem>,
+}
+
+fn trash_path(store: &str, id: &str) -> PathBuf {
+    PathBuf::from(folder::TRASH).join(store).join(id)
+}
+
+/// Moves a file or folder from the store into the trash of that store.
+/// Returns the size of the files that were moved.
+///
+/// `path` is the path inside the store, it must not be empty.
+#[tracing::instrument(skip(state))]
+pub async fn move_to_trash(state: &AppState, store: &str, path: &str) -> Result<u64, StorageError> {
+    let store_path = PathBuf::from(folder::STORAGE).join(store).join(path);
+    let meta = state.storage.metadata(&store_path).await?;
+    let size = path_size(state.storage.as_ref(), &store_path).await?;
+
+    let id = nanoid!();
+    let target = trash_path(store, &id);
+    state
+        .storage
+        .create_dir_all(&PathBuf::from(folder::TRASH).join(store))
+        .await?;
+    state.storage.rename(&store_path, &target).await?;
+
+    let item = trash::ActiveModel {
+        id: Set(id),
+        store: Set(store.to_string()),
+        path: Set(path.trim_matches('/').to_string()),
+        is_file: Set(meta.is_file),
+        size: Set(size as i64),
+        deleted_at: Set(Utc::now().to_rfc3339()),
+    };
+    if let Err(err) = item.insert(&state.db).await {
+        // Put it back, otherwise the item would be lost in the trash
+        state.storage.rename(&target, &store_path).await?;
+        return Err(err.into());
+    }
+    Ok(size)
+}
+
+/// Lists the items in the trash of a store, most recently deleted first.
+pub async fn list_trash(state: &AppState, store: &str) -> Result<Vec<TrashItem>, StorageError> {
+    Ok(trash::Entity::find()
+        .filter(trash::Column::Store.eq(store))
+        .order_by_desc(trash::Column::DeletedAt)
+        .all(&state.db)
+        .await?
+        .into_iter()
+        .map(TrashItem::from)
+        .collect())
+}
+
+async fn find_item(state: &AppState, store: &str, id: &str) -> Result<trash::Model, StorageError> {
+    trash::Entity::find_by_id(id)
+        .filter(trash::Column::Store.eq(store))
+        .one(&state.db)
+        .await?
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound).into())
+}
+
+/// Moves an item from the trash back to where it was. Fails if something else
+/// has been put there since.
+#[tracing::instrument(skip(state))]
+pub async fn restore(state: &AppState, store: &str, id: &str) -> Result<(), StorageError> {
+    let item = find_item(state, store, id).await?;
+    let original = PathBuf::from(folder::STORAGE).join(store).join(&item.path);
+    if state.storage.metadata(&original).await.is_ok() {
+        return Err(StorageError::FileExists(item.path));
+    }
+    check_quota(state, store, item.size as u64).await?;
+    // The folder it was in may have been deleted too
+    if let Some(parent) = original.parent() {
+        state.storage.create_dir_all(parent).await?;
+    }
+    state
+        .storage
+        .rename(&trash_path(store, id), &original)
+        .await?;
+    let size = item.size;
+    // Forget the item first, so it isn't counted twice when the usage grows
+    item.delete(&state.db).await?;
+    add_usage(state, store, size).await
+}
+
+pub(crate) async fn purge_item(state: &AppState, item: trash::Model) -> Result<(), StorageError> {
+    match state
+        .storage
+        .remove(&trash_path(&item.store, &item.id))
+        .await
+    {
+        // Already gone, just forget about it
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        result => result?,
+    }
+    item.delete(&state.db).await?;
+    Ok(())
+}
+
+/// Permanently deletes an item in the trash.
+#[tracing::instrument(skip(state))]
+pub async fn purge(state: &AppState, store: &str, id: &str) -> Result<(), StorageError> {
+    let item = find_item(state, store, id).await?;
+    purge_item(state, item).await
+}
+
+/// Permanently deletes everything in the trash of a store.
+#[tracing::instrument(skip(state))]
+pub async fn empty_trash(state: &AppState, store: &str) -> Result<(), StorageError> {
+    let items = trash::Entity::find()
+        .filter(trash::Column::Store.eq(store))
+        .all(&state.db)
+        .await?;
+    for item in items {
+        purge_item(state, item).await?;
+    }
+    Ok(())
+}
+
+/// Permanently deletes items that have been in the trash for longer than
+/// `retention`. Returns how many items were deleted.
+#[tracing::instrument(skip(state))]
+pub async fn purge_expired(
+    state: &AppState,
+    retention: chrono::Duration,
+) -> Result<usize, StorageError> {
+    let cutoff = Utc::now() - retention;
+    let items = trash::Entity::find()
+        .filter(trash::Column::DeletedAt.lt(cutoff.to_rfc3339()))
+        .all(&state.db)
+        .await?;
+    let count = items.len();
+    for item in items {
+        purge_item(state, item).await?;
+    }
+    Ok(count)
+}
+
+/// Periodically purges items that have been in the trash for longer than
+/// `retention_days`. This never returns, spawn it in the background.
+pub async fn purge_expired_task(state: web::Data<AppState>, retention_days: u32) {
+    let retention = chrono::Duration::days(retention_days.into());
+    let mut interval = actix_web::rt::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match purge_expired(&state, retention).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!(count, "Purged expired items from the trash"),
+            Err(err) => tracing::error!(error = ?err, "Failed to purge the trash"),
+        }
+    }
+}
+
+#[tracing::instrument(skip(state))]
+#[get("/trash/{store}")]
+pub async fn get_trash(
+    state: web::Data<AppState>,
+    store: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<web::Json<TrashResults>, StorageError> {
+    get_authorized_path(&authorized, &store, None)?;
+    Ok(web::Json(TrashResults {
+        items: list_trash(&state, &store).await?,
+    }))
+}
+
+#[tracing::instrument(skip(state))]
+#[delete("/trash/{store}")]
+pub async fn delete_trash(
+    state: web::Data<AppState>,
+    store: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    get_authorized_path(&authorized, &store, None)?;
+    empty_trash(&state, &store).await?;
+    Ok(empty_ok_response())
+}
+
+#[tracing::instrument(skip(state))]
+#[post("/trash/{store}/{id}")]
+pub async fn post_trash_item(
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, id) = params.as_ref();
+    get_authorized_path(&authorized, store, None)?;
+    restore(&state, store, id).await?;
+    Ok(empty_ok_response())
+}
+
+#[tracing::instrument(skip(state))]
+#[delete("/trash/{store}/{id}")]
+pub async fn delete_trash_item(
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, id) = params.as_ref();
+    get_authorized_path(&authorized, store, None)?;
+    purge(&state, store, id).await?;
+    Ok(empty_ok_response())
+}
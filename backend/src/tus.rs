@@ -0,0 +1,574 @@
+//! Resumable uploads using the [tus](https://tus.io/protocols/resumable-upload)
+//! protocol. Uploads are created with a `POST` to the folder they should end
+//! up in, then sent in as many `PATCH` requests as the client needs. The
+//! offset of each upload is kept in the database, so an upload can continue
+//! where it left off even if the connection drops or the server restarts.
+//!
+//! These routes live under `/storage` next to the regular storage routes, and
+//! are told apart by the `Tus-Resumable` header that tus clients send with
+//! every request.
+//!
+//! While an upload is in progress, what has been sent so far is kept in the
+//! `uploads` folder outside of the stores, so it doesn't show up in listings.
+//! Uploads that haven't received anything for a day expire and are removed
+//! by [`remove_expired_uploads_task`].
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{
+    guard::GuardContext,
+    http::{
+        header::{self, HttpDate},
+        StatusCode,
+    },
+    web::{self, Payload, ReqData},
+    HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use nanoid::nanoid;
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, Condition, DbErr, EntityTrait, ModelTrait,
+    QueryFilter, Set,
+};
+use serde::Serialize;
+
+use crate::{
+    entity::upload,
+    folder,
+    quota::{add_usage, check_quota},
+    state::{AppState, Authorized},
+    storage::{commit_part, get_authorized_path, ConflictPolicy, StorageError},
+};
+
+/// The only version of the protocol we support.
+pub const TUS_VERSION: &str = "1.0.0";
+/// The extensions of the protocol we support.
+const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+/// Content type of the `PATCH` requests.
+const TUS_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+pub const TUS_RESUMABLE: &str = "Tus-Resumable";
+pub const TUS_VERSION_HEADER: &str = "Tus-Version";
+pub const TUS_EXTENSION: &str = "Tus-Extension";
+pub const UPLOAD_LENGTH: &str = "Upload-Length";
+pub const UPLOAD_OFFSET: &str = "Upload-Offset";
+pub const UPLOAD_METADATA: &str = "Upload-Metadata";
+pub const UPLOAD_EXPIRES: &str = "Upload-Expires";
+
+/// How long an upload is kept after the last chunk was received.
+const UPLOAD_EXPIRY_HOURS: i64 = 24;
+/// How long a `PATCH` may hold on to an upload without writing anything.
+/// This only matters if the server stops in the middle of a request, the
+/// lock is released as soon as the request is done.
+const LOCK_SECONDS: i64 = 60;
+/// How often expired uploads are removed.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Headers the clients send, which need to be allowed for CORS requests.
+pub const TUS_REQUEST_HEADERS: [&str; 4] =
+    [TUS_RESUMABLE, UPLOAD_LENGTH, UPLOAD_OFFSET, UPLOAD_METADATA];
+/// Headers the clients need to read, which need to be exposed for CORS requests.
+pub const TUS_RESPONSE_HEADERS: [&str; 7] = [
+    TUS_RESUMABLE,
+    TUS_VERSION_HEADER,
+    TUS_EXTENSION,
+    UPLOAD_LENGTH,
+    UPLOAD_OFFSET,
+    UPLOAD_EXPIRES,
+    "Location",
+];
+
+#[derive(Debug, derive_more::Display, thiserror::Error)]
+pub enum TusError {
+    #[display(fmt = "{}", _0)]
+    Storage(#[from] StorageError),
+    #[display(fmt = "Database error {}", _0)]
+    Database(#[from] DbErr),
+    #[display(fmt = "Only version {} of the tus protocol is supported", TUS_VERSION)]
+    UnsupportedVersion,
+    #[display(fmt = "Missing or invalid {} header", _0)]
+    BadHeader(&'static str),
+    #[display(fmt = "Uploads must be sent as {}", TUS_CONTENT_TYPE)]
+    BadContentType,
+    #[display(fmt = "The upload offset does not match, the upload is at {}", _0)]
+    OffsetMismatch(i64),
+    #[display(fmt = "The upload is longer than the declared length")]
+    TooLong,
+    #[display(fmt = "Upload not found")]
+    NotFound,
+    #[display(fmt = "The upload is already being sent by another request")]
+    Locked,
+}
+
+impl Serialize for TusError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let s = format!("{}", self);
+        serializer.serialize_str(&s)
+    }
+}
+
+impl From<std::io::Error> for TusError {
+    fn from(err: std::io::Error) -> Self {
+        TusError::Storage(err.into())
+    }
+}
+
+impl actix_web::error::ResponseError for TusError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            TusError::Storage(StorageError::IOError(err))
+                if err.kind() == std::io::ErrorKind::Unsupported =>
+            {
+                StatusCode::NOT_IMPLEMENTED
+            }
+            TusError::Storage(err) => err.status_code(),
+            TusError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            TusError::UnsupportedVersion => StatusCode::PRECONDITION_FAILED,
+            TusError::BadHeader(_) => StatusCode::BAD_REQUEST,
+            TusError::BadContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            TusError::OffsetMismatch(_) => StatusCode::CONFLICT,
+            TusError::TooLong => StatusCode::PAYLOAD_TOO_LARGE,
+            TusError::NotFound => StatusCode::NOT_FOUND,
+            TusError::Locked => StatusCode::LOCKED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponseBuilder::new(self.status_code())
+            .insert_header((TUS_RESUMABLE, TUS_VERSION))
+            .insert_header((TUS_VERSION_HEADER, TUS_VERSION))
+            .json(self)
+    }
+}
+
+/// Matches the requests that are meant for the tus routes.
+pub fn is_tus_request(ctx: &GuardContext) -> bool {
+    ctx.head().headers().contains_key(TUS_RESUMABLE)
+}
+
+fn check_version(req: &HttpRequest) -> Result<(), TusError> {
+    match req.headers().get(TUS_RESUMABLE) {
+        Some(version) if version == TUS_VERSION => Ok(()),
+        _ => Err(TusError::UnsupportedVersion),
+    }
+}
+
+fn read_number(req: &HttpRequest, name: &'static str) -> Result<i64, TusError> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i64>().ok())
+        .filter(|value| *value >= 0)
+        .ok_or(TusError::BadHeader(name))
+}
+
+/// Finds the file name in the `Upload-Metadata` header, which is a comma
+/// separated list of keys and base64 encoded values.
+fn read_filename(req: &HttpRequest) -> Option<String> {
+    let metadata = req.headers().get(UPLOAD_METADATA)?.to_str().ok()?;
+    metadata
+        .split(',')
+        .filter_map(|pair| pair.trim().split_once(' '))
+        // Most clients use `filename`, but Uppy uses `name`
+        .find(|(key, _)| *key == "filename" || *key == "name")
+        .and_then(|(_, value)| STANDARD.decode(value.trim()).ok())
+        .and_then(|value| String::from_utf8(value).ok())
+        .map(sanitize_filename::sanitize)
+        .filter(|filename| !filename.is_empty())
+}
+
+/// The folder where the parts of uploads in progress are kept.
+fn parts_folder() -> PathBuf {
+    PathBuf::from(folder::UPLOADS)
+}
+
+fn expires_at(upload: &upload::Model) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(&upload.expires_at)
+        .ok()
+        .map(|expires_at| expires_at.with_timezone(&Utc))
+}
+
+fn is_expired(upload: &upload::Model, now: DateTime<Utc>) -> bool {
+    expires_at(upload).is_none_or(|expires_at| expires_at <= now)
+}
+
+/// Formats the expiry of an upload for the `Upload-Expires` header.
+fn expires_header(expires_at: DateTime<Utc>) -> HttpDate {
+    HttpDate::from(SystemTime::from(expires_at))
+}
+
+/// Finds an upload from the path of its URL, making sure the user is allowed
+/// to upload into the folder the upload belongs to.
+async fn find_upload(
+    state: &AppState,
+    params: &(String, String),
+    authorized: &Option<ReqData<Authorized>>,
+) -> Result<(upload::Model, PathBuf), TusError> {
+    let (store, path) = params;
+    let (folder, id) = path.rsplit_once('/').unwrap_or(("", path.as_str()));
+    let store_path = get_authorized_path(authorized, store, Some(folder))?;
+    let upload = upload::Entity::find_by_id(id)
+        .one(&state.db)
+        .await?
+        // If the upload belongs to a different folder, the user may not be
+        // authorized for it
+        .filter(|upload| Path::new(&upload.folder) == store_path)
+        // Expired uploads may be removed at any moment
+        .filter(|upload| !is_expired(upload, Utc::now()))
+        .ok_or(TusError::NotFound)?;
+    Ok((upload, store_path))
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn tus_create(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, TusError> {
+    check_version(&req)?;
+    let (store, path) = params.as_ref();
+    let store_path = get_authorized_path(&authorized, store, Some(path))?;
+    if state.storage.metadata(&store_path).await?.is_file {
+        return Err(StorageError::BadPath.into());
+    }
+    let upload_length = read_number(&req, UPLOAD_LENGTH)?;
+    // Refuse uploads that won't fit right away, instead of after the client
+    // has sent most of it
+    check_quota(&state, store, upload_length as u64).await?;
+    let filename = read_filename(&req).unwrap_or_else(|| nanoid!());
+    tracing::debug!(filename = ?filename, upload_length, "Upload created");
+
+    let id = nanoid!();
+    let location = format!("{}/{id}", req.path().trim_end_matches('/'));
+    let parts = parts_folder();
+    state.storage.create_dir_all(&parts).await?;
+    let mut part = state.storage.create_part(&parts, &filename).await?;
+    let expires_at = Utc::now() + chrono::Duration::hours(UPLOAD_EXPIRY_HOURS);
+    if upload_length == 0 {
+        // Nothing will be sent, so the upload is already done
+        commit_part(
+            part.as_mut(),
+            &store_path,
+            &filename,
+            ConflictPolicy::Rename,
+        )
+        .await?;
+    } else {
+        part.flush().await?;
+        upload::ActiveModel {
+            id: Set(id),
+            folder: Set(store_path.to_string_lossy().to_string()),
+            filename: Set(filename),
+            part: Set(part.name()),
+            upload_offset: Set(0),
+            upload_length: Set(upload_length),
+            created_at: Set(Utc::now().to_rfc3339()),
+            expires_at: Set(expires_at.to_rfc3339()),
+            lock_token: Set(None),
+            locked_until: Set(None),
+        }
+        .insert(&state.db)
+        .await?;
+    }
+
+    Ok(HttpResponse::Created()
+        .insert_header((TUS_RESUMABLE, TUS_VERSION))
+        .insert_header((TUS_EXTENSION, TUS_EXTENSIONS))
+        .insert_header((UPLOAD_EXPIRES, expires_header(expires_at)))
+        .insert_header((header::LOCATION, location))
+        .finish())
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn tus_head(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, TusError> {
+    check_version(&req)?;
+    let (upload, _) = find_upload(&state, &params, &authorized).await?;
+    let mut resp = HttpResponse::Ok();
+    resp.insert_header((TUS_RESUMABLE, TUS_VERSION))
+        .insert_header((UPLOAD_OFFSET, upload.upload_offset))
+        .insert_header((UPLOAD_LENGTH, upload.upload_length))
+        // The offset changes with every PATCH
+        .insert_header((header::CACHE_CONTROL, "no-store"));
+    if let Some(expires_at) = expires_at(&upload) {
+        resp.insert_header((UPLOAD_EXPIRES, expires_header(expires_at)));
+    }
+    Ok(resp.finish())
+}
+
+#[tracing::instrument(skip(state, payload))]
+pub async fn tus_patch(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    authorized: Option<ReqData<Authorized>>,
+    mut payload: Payload,
+) -> Result<HttpResponse, TusError> {
+    check_version(&req)?;
+    if req.headers().get(header::CONTENT_TYPE)
+        != Some(&header::HeaderValue::from_static(TUS_CONTENT_TYPE))
+    {
+        return Err(TusError::BadContentType);
+    }
+    let (upload, store_path) = find_upload(&state, &params, &authorized).await?;
+    let offset = read_number(&req, UPLOAD_OFFSET)?;
+    if offset != upload.upload_offset {
+        return Err(TusError::OffsetMismatch(upload.upload_offset));
+    }
+
+    // Chunks can't go past the length of the upload, so the rest of it has to
+    // fit in the store
+    check_quota(&state, &params.0, (upload.upload_length - offset) as u64).await?;
+
+    let mut lock = UploadLock::take(&state, &upload, offset).await?;
+    let mut part = match state
+        .storage
+        .resume_part(&parts_folder(), &upload.part)
+        .await
+    {
+        Ok(part) => part,
+        Err(err) => {
+            lock.release(&state, offset).await?;
+            return Err(err.into());
+        }
+    };
+    let mut new_offset = offset;
+    let mut written: Result<(), TusError> = Ok(());
+    while let Some(chunk) = payload.next().await {
+        written = match chunk {
+            Ok(chunk) if new_offset + chunk.len() as i64 > upload.upload_length => {
+                Err(TusError::TooLong)
+            }
+            Ok(chunk) => match lock.renew(&state).await {
+                Ok(()) => match part.write(&chunk).await {
+                    Ok(()) => {
+                        new_offset += chunk.len() as i64;
+                        Ok(())
+                    }
+                    Err(err) => Err(err.into()),
+                },
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(std::io::Error::other(err).into()),
+        };
+        if written.is_err() {
+            break;
+        }
+    }
+    // Even if the connection dropped, keep what was received so the client
+    // can pick up from there
+    part.flush().await?;
+    if written.is_err() || new_offset < upload.upload_length {
+        let expires_at = lock.release(&state, new_offset).await?;
+        written?;
+        return Ok(HttpResponse::NoContent()
+            .insert_header((TUS_RESUMABLE, TUS_VERSION))
+            .insert_header((UPLOAD_OFFSET, new_offset))
+            .insert_header((UPLOAD_EXPIRES, expires_header(expires_at)))
+            .finish());
+    }
+
+    // Keep the lock while committing, so nothing else touches the part
+    if let Err(err) = commit_part(
+        part.as_mut(),
+        &store_path,
+        &upload.filename,
+        ConflictPolicy::Rename,
+    )
+    .await
+    {
+        lock.release(&state, new_offset).await?;
+        return Err(err.into());
+    }
+    add_usage(&state, &params.0, upload.upload_length).await?;
+    upload.delete(&state.db).await?;
+
+    Ok(HttpResponse::NoContent()
+        .insert_header((TUS_RESUMABLE, TUS_VERSION))
+        .insert_header((UPLOAD_OFFSET, new_offset))
+        .finish())
+}
+
+/// Makes sure only one `PATCH` at a time writes to an upload. The lock is
+/// held in the database until [`UploadLock::release`], or until it runs out
+/// if the request never finishes.
+struct UploadLock {
+    id: String,
+    token: String,
+    until: DateTime<Utc>,
+}
+
+impl UploadLock {
+    /// Locks the upload, if it is still at `offset` and no other request is
+    /// sending it.
+    async fn take(state: &AppState, upload: &upload::Model, offset: i64) -> Result<Self, TusError> {
+        let now = Utc::now();
+        let lock = UploadLock {
+            id: upload.id.clone(),
+            token: nanoid!(),
+            until: now + chrono::Duration::seconds(LOCK_SECONDS),
+        };
+        let taken = upload::Entity::update_many()
+            .col_expr(upload::Column::LockToken, Expr::value(&lock.token))
+            .col_expr(
+                upload::Column::LockedUntil,
+                Expr::value(lock.until.to_rfc3339()),
+            )
+            .filter(upload::Column::Id.eq(&lock.id))
+            .filter(upload::Column::UploadOffset.eq(offset))
+            .filter(
+                Condition::any()
+                    .add(upload::Column::LockedUntil.is_null())
+                    .add(upload::Column::LockedUntil.lt(now.to_rfc3339())),
+            )
+            .exec(&state.db)
+            .await?
+            .rows_affected
+            > 0;
+        if taken {
+            return Ok(lock);
+        }
+        // Tell the client why, it should only retry if the offset moved
+        let upload = upload::Entity::find_by_id(&lock.id)
+            .one(&state.db)
+            .await?
+            .ok_or(TusError::NotFound)?;
+        if upload.upload_offset != offset {
+            Err(TusError::OffsetMismatch(upload.upload_offset))
+        } else {
+            Err(TusError::Locked)
+        }
+    }
+
+    /// Extends the lock once half of it has passed, so slow uploads keep it.
+    /// Fails if another request took over after the lock ran out.
+    async fn renew(&mut self, state: &AppState) -> Result<(), TusError> {
+        let now = Utc::now();
+        if self.until - now > chrono::Duration::seconds(LOCK_SECONDS / 2) {
+            return Ok(());
+        }
+        let until = now + chrono::Duration::seconds(LOCK_SECONDS);
+        let renewed = upload::Entity::update_many()
+            .col_expr(upload::Column::LockedUntil, Expr::value(until.to_rfc3339()))
+            .filter(upload::Column::Id.eq(&self.id))
+            .filter(upload::Column::LockToken.eq(&self.token))
+            .exec(&state.db)
+            .await?
+            .rows_affected
+            > 0;
+        if !renewed {
+            return Err(TusError::Locked);
+        }
+        self.until = until;
+        Ok(())
+    }
+
+    /// Records how much of the upload has been received and unlocks it.
+    /// Returns when the upload now expires.
+    async fn release(self, state: &AppState, offset: i64) -> Result<DateTime<Utc>, TusError> {
+        let expires_at = Utc::now() + chrono::Duration::hours(UPLOAD_EXPIRY_HOURS);
+        let released = upload::Entity::update_many()
+            .col_expr(upload::Column::UploadOffset, Expr::value(offset))
+            .col_expr(
+                upload::Column::ExpiresAt,
+                Expr::value(expires_at.to_rfc3339()),
+            )
+            .col_expr(
+                upload::Column::LockToken,
+                Expr::value(Option::<String>::None),
+            )
+            .col_expr(
+                upload::Column::LockedUntil,
+                Expr::value(Option::<String>::None),
+            )
+            .filter(upload::Column::Id.eq(&self.id))
+            .filter(upload::Column::LockToken.eq(&self.token))
+            .exec(&state.db)
+            .await?
+            .rows_affected
+            > 0;
+        if !released {
+            return Err(TusError::Locked);
+        }
+        Ok(expires_at)
+    }
+}
+
+/// Deletes the part of an upload and forgets about the upload.
+async fn remove_upload(state: &AppState, upload: upload::Model) -> Result<(), TusError> {
+    match state
+        .storage
+        .remove(&parts_folder().join(&upload.part))
+        .await
+    {
+        Ok(()) => {}
+        // The part is already gone, just forget about the upload
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    upload.delete(&state.db).await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn tus_delete(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, TusError> {
+    check_version(&req)?;
+    let (upload, _) = find_upload(&state, &params, &authorized).await?;
+    remove_upload(&state, upload).await?;
+
+    Ok(HttpResponse::NoContent()
+        .insert_header((TUS_RESUMABLE, TUS_VERSION))
+        .finish())
+}
+
+/// Removes uploads that expired, along with what was sent of them. Returns
+/// how many uploads were removed.
+pub async fn remove_expired_uploads(state: &AppState) -> Result<usize, TusError> {
+    let now = Utc::now().to_rfc3339();
+    let expired = upload::Entity::find()
+        .filter(upload::Column::ExpiresAt.lt(now.as_str()))
+        // Leave uploads that are being sent right now alone
+        .filter(
+            Condition::any()
+                .add(upload::Column::LockedUntil.is_null())
+                .add(upload::Column::LockedUntil.lt(now.as_str())),
+        )
+        .all(&state.db)
+        .await?;
+    let count = expired.len();
+    for upload in expired {
+        remove_upload(state, upload).await?;
+    }
+    Ok(count)
+}
+
+/// Periodically removes expired uploads. This never returns, spawn it in the
+/// background.
+pub async fn remove_expired_uploads_task(state: web::Data<AppState>) {
+    let mut interval = actix_web::rt::time::interval(CLEANUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match remove_expired_uploads(&state).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!(count, "Removed expired uploads"),
+            Err(err) => tracing::error!(error = ?err, "Failed to remove expired uploads"),
+        }
+    }
+}
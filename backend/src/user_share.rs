@@ -0,0 +1,310 @@
+//! Users can share files and folders in their store with other users, so
+//! they can work on them together.
+//!
+//! A share lets another user read the path, or read and change it. The auth
+//! middleware looks up the shares of a user when they make a request, and
+//! [`get_authorized_path`] only allows paths in the stores of other users if
+//! one of these shares covers them. Anything that belongs to a whole store,
+//! like the trash or share links, stays with the owner.
+use std::path::PathBuf;
+
+use actix_web::{
+    delete, get,
+    http::Method,
+    post,
+    web::{self, ReqData},
+    HttpResponse,
+};
+use chrono::Utc;
+use nanoid::nanoid;
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DbErr, EntityTrait, IntoActiveModel, QueryFilter, QueryOrder,
+    Set,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth_middleware::path_segments,
+    entity::{user, user_share},
+    folder,
+    state::{AppState, Authorized, SharedPath},
+    storage::{empty_ok_response, get_authorized_path, StorageError},
+};
+
+#[cfg(feature = "generate_types")]
+use typescript_type_def::TypeDef;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+#[serde(rename_all = "snake_case")]
+pub enum SharePermission {
+    /// The user can see and download the files.
+    Read,
+    /// The user can also upload, change and delete files.
+    ReadWrite,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct UserShareOptions {
+    /// The user to share with.
+    pub username: String,
+    pub permission: SharePermission,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct UserShare {
+    pub id: String,
+    /// The store the shared path is in, this is the username of the owner.
+    pub store: String,
+    /// The shared file or folder, relative to the store.
+    pub path: String,
+    /// The user the path is shared with.
+    pub username: String,
+    pub permission: SharePermission,
+    pub created_at: String,
+}
+
+impl From<user_share::Model> for UserShare {
+    fn from(share: user_share::Model) -> Self {
+        UserShare {
+            id: share.id,
+            store: share.store,
+            path: share.path,
+            username: share.username,
+            permission: if share.can_write {
+                SharePermission::ReadWrite
+            } else {
+                SharePermission::Read
+            },
+            created_at: share.created_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct UserShareResults {
+    pub shares: Vec<UserShare>,
+}
+
+/// True if requests with this method only read files. Users with read
+/// permission can only make these requests.
+pub fn is_read_method(method: &Method) -> bool {
+    method.is_safe() || matches!(method.as_str(), "PROPFIND" | "META")
+}
+
+/// Finds the paths shared with a user. If `write` is true, only the paths the
+/// user can change are included.
+pub async fn shared_paths(
+    state: &AppState,
+    username: &str,
+    write: bool,
+) -> Result<Vec<SharedPath>, DbErr> {
+    let mut query = user_share::Entity::find().filter(user_share::Column::Username.eq(username));
+    if write {
+        query = query.filter(user_share::Column::CanWrite.eq(true));
+    }
+    Ok(query
+        .all(&state.db)
+        .await?
+        .into_iter()
+        .map(|share| SharedPath {
+            store: share.store,
+            path: share.path,
+        })
+        .collect())
+}
+
+/// True if `path` in `store` is one of the shared paths, or inside one of
+/// them.
+pub fn shared_path_covers(shared: &[SharedPath], store: &str, path: &str) -> bool {
+    let Some(requested) = path_segments(path) else {
+        return false;
+    };
+    shared.iter().any(|shared| {
+        shared.store == store
+            && path_segments(&shared.path).is_some_and(|folder| requested.starts_with(&folder))
+    })
+}
+
+/// Shares a file or folder in a store with another user. If it was already
+/// shared with them, the permission is updated instead.
+#[tracing::instrument(skip(state))]
+pub async fn create_user_share(
+    state: &AppState,
+    store: &str,
+    path: &str,
+    options: UserShareOptions,
+) -> Result<UserShare, StorageError> {
+    let path = path.trim_matches('/');
+    if path_segments(path).is_none() {
+        return Err(StorageError::BadPath);
+    }
+    if options.username == store {
+        return Err(StorageError::BadShareUser(options.username));
+    }
+    let recipient = user::Entity::find()
+        .filter(user::Column::Username.eq(&options.username))
+        .one(&state.db)
+        .await?;
+    if recipient.is_none() {
+        return Err(StorageError::BadShareUser(options.username));
+    }
+    // Make sure there is something to share
+    state
+        .storage
+        .metadata(&PathBuf::from(folder::STORAGE).join(store).join(path))
+        .await?;
+
+    let can_write = options.permission == SharePermission::ReadWrite;
+    let existing = user_share::Entity::find()
+        .filter(user_share::Column::Store.eq(store))
+        .filter(user_share::Column::Path.eq(path))
+        .filter(user_share::Column::Username.eq(&options.username))
+        .one(&state.db)
+        .await?;
+    let share = match existing {
+        Some(existing) => {
+            let mut share = existing.into_active_model();
+            share.can_write = Set(can_write);
+            share.update(&state.db).await?
+        }
+        None => {
+            user_share::ActiveModel {
+                id: Set(nanoid!()),
+                store: Set(store.to_string()),
+                path: Set(path.to_string()),
+                username: Set(options.username),
+                can_write: Set(can_write),
+                created_at: Set(Utc::now().to_rfc3339()),
+            }
+            .insert(&state.db)
+            .await?
+        }
+    };
+    Ok(share.into())
+}
+
+/// Lists the paths in a store that are shared with other users, newest first.
+pub async fn list_user_shares(
+    state: &AppState,
+    store: &str,
+) -> Result<Vec<UserShare>, StorageError> {
+    Ok(user_share::Entity::find()
+        .filter(user_share::Column::Store.eq(store))
+        .order_by_desc(user_share::Column::CreatedAt)
+        .all(&state.db)
+        .await?
+        .into_iter()
+        .map(UserShare::from)
+        .collect())
+}
+
+/// Lists the paths other users shared with a user, newest first.
+pub async fn list_shared_with(
+    state: &AppState,
+    username: &str,
+) -> Result<Vec<UserShare>, StorageError> {
+    Ok(user_share::Entity::find()
+        .filter(user_share::Column::Username.eq(username))
+        .order_by_desc(user_share::Column::CreatedAt)
+        .all(&state.db)
+        .await?
+        .into_iter()
+        .map(UserShare::from)
+        .collect())
+}
+
+/// Stops sharing a path with a user.
+#[tracing::instrument(skip(state))]
+pub async fn revoke_user_share(
+    state: &AppState,
+    store: &str,
+    id: &str,
+) -> Result<(), StorageError> {
+    let result = user_share::Entity::delete_many()
+        .filter(user_share::Column::Id.eq(id))
+        .filter(user_share::Column::Store.eq(store))
+        .exec(&state.db)
+        .await?;
+    if result.rows_affected == 0 {
+        return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into());
+    }
+    Ok(())
+}
+
+/// Removes everything shared by or with a user. Used when the user is deleted,
+/// so a new user with the same name doesn't get access.
+pub async fn remove_user_shares(state: &AppState, username: &str) -> Result<(), StorageError> {
+    user_share::Entity::delete_many()
+        .filter(
+            user_share::Column::Store
+                .eq(username)
+                .or(user_share::Column::Username.eq(username)),
+        )
+        .exec(&state.db)
+        .await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(state))]
+#[get("/user-shares/{store}")]
+pub async fn get_user_shares(
+    state: web::Data<AppState>,
+    store: web::Path<String>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<web::Json<UserShareResults>, StorageError> {
+    get_authorized_path(&authorized, &store, None)?;
+    Ok(web::Json(UserShareResults {
+        shares: list_user_shares(&state, &store).await?,
+    }))
+}
+
+#[tracing::instrument(skip(state))]
+#[post("/user-shares/{store}/{path:.*}")]
+pub async fn post_user_share(
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    options: web::Json<UserShareOptions>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<web::Json<UserShare>, StorageError> {
+    let (store, path) = params.as_ref();
+    // Only the owner can share, even if the path was shared with someone else
+    get_authorized_path(&authorized, store, None)?;
+    get_authorized_path(&authorized, store, Some(path))?;
+    Ok(web::Json(
+        create_user_share(&state, store, path, options.into_inner()).await?,
+    ))
+}
+
+#[tracing::instrument(skip(state))]
+#[delete("/user-shares/{store}/{id}")]
+pub async fn delete_user_share(
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, id) = params.as_ref();
+    get_authorized_path(&authorized, store, None)?;
+    revoke_user_share(&state, store, id).await?;
+    Ok(empty_ok_response())
+}
+
+#[tracing::instrument(skip(state))]
+#[get("/shared-with-me")]
+pub async fn get_shared_with_me(
+    state: web::Data<AppState>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<web::Json<UserShareResults>, StorageError> {
+    let username = match authorized.as_deref() {
+        Some(Authorized::User(username))
+        | Some(Authorized::Both(username))
+        | Some(Authorized::Shared(username, _)) => username,
+        _ => return Err(StorageError::NotAuthorized),
+    };
+    Ok(web::Json(UserShareResults {
+        shares: list_shared_with(&state, &username.0).await?,
+    }))
+}
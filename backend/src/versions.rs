@@ -0,0 +1,424 @@
+//! Previous contents of files that were overwritten, so they can be looked at
+//! or restored later.
+//!
+//! Whenever a file is replaced by an upload or by moving another file over it,
+//! the old contents are kept as a numbered version under
+//! `versions/{store}/{id}`, outside of the stores so they don't show up in
+//! folder listings. The database remembers which file each version belongs
+//! to. Only a limited number of versions are kept for each file, and old
+//! versions are removed by a background task.
+use std::{
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+use actix_web::{
+    get,
+    http::header::{self, ContentDisposition, DispositionParam, DispositionType},
+    post,
+    web::{self, ReqData},
+    HttpRequest, HttpResponse,
+};
+use chrono::Utc;
+use futures::TryStreamExt;
+use nanoid::nanoid;
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, EntityTrait, ModelTrait, QueryFilter, QueryOrder, Set,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    entity::file_version,
+    folder,
+    quota::{add_usage, check_quota, reclaim_space},
+    state::{AppState, Authorized},
+    storage::{empty_ok_response, file_response, get_authorized_path, StorageError},
+};
+
+#[cfg(feature = "generate_types")]
+use typescript_type_def::TypeDef;
+
+/// How often the background task looks for versions that are too old.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct VersionOptions {
+    #[clap(
+        long = "versions-keep",
+        env = "BULGUR_CLOUD_VERSIONS_KEEP",
+        default_value_t = 10
+    )]
+    /// How many previous versions are kept for each file. Set to 0 to keep
+    /// all of them.
+    pub keep: u32,
+
+    #[clap(
+        long = "versions-keep-days",
+        env = "BULGUR_CLOUD_VERSIONS_KEEP_DAYS",
+        default_value_t = 30
+    )]
+    /// Previous versions of files are removed after this many days. Set to 0
+    /// to keep them regardless of their age.
+    pub keep_days: u32,
+}
+
+impl Default for VersionOptions {
+    fn default() -> Self {
+        VersionOptions {
+            keep: 10,
+            keep_days: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct FileVersion {
+    pub version: u32,
+    pub size: u64,
+    /// When these contents were replaced.
+    pub created_at: String,
+}
+
+impl From<file_version::Model> for FileVersion {
+    fn from(version: file_version::Model) -> Self {
+        FileVersion {
+            version: version.version as u32,
+            size: version.size as u64,
+            created_at: version.created_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "generate_types", derive(TypeDef))]
+pub struct VersionResults {
+    pub versions: Vec<FileVersion>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VersionQuery {
+    pub version: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RestoreVersionQuery {
+    pub version: u32,
+}
+
+fn version_path(store: &str, id: &str) -> PathBuf {
+    PathBuf::from(folder::VERSIONS).join(store).join(id)
+}
+
+/// Splits a path like `storage/{store}/{path}` into the store and the path
+/// inside the store.
+fn split_store_path(store_path: &Path) -> Option<(String, String)> {
+    let mut components = store_path
+        .strip_prefix(folder::STORAGE)
+        .ok()?
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+            _ => None,
+        });
+    let store = components.next()?;
+    let path = components.collect::<Vec<_>>().join("/");
+    if path.is_empty() {
+        return None;
+    }
+    Some((store, path))
+}
+
+async fn find_versions(
+    state: &AppState,
+    store: &str,
+    path: &str,
+) -> Result<Vec<file_version::Model>, StorageError> {
+    Ok(file_version::Entity::find()
+        .filter(file_version::Column::Store.eq(store))
+        .filter(file_version::Column::Path.eq(path))
+        .order_by_desc(file_version::Column::Version)
+        .all(&state.db)
+        .await?)
+}
+
+pub(crate) async fn remove_version(
+    state: &AppState,
+    version: file_version::Model,
+) -> Result<(), StorageError> {
+    match state
+        .storage
+        .remove(&version_path(&version.store, &version.id))
+        .await
+    {
+        // Already gone, just forget about it
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        result => result?,
+    }
+    version.delete(&state.db).await?;
+    Ok(())
+}
+
+/// Keeps the contents of the file at `store_path` as a new version. Returns
+/// false without doing anything if there is no file there.
+///
+/// With `keep_file` the contents are copied and the file stays in place,
+/// otherwise the file is moved out of the store.
+async fn save_version(
+    state: &AppState,
+    store_path: &Path,
+    keep_file: bool,
+) -> Result<bool, StorageError> {
+    let Some((store, path)) = split_store_path(store_path) else {
+        return Ok(false);
+    };
+    let meta = match state.storage.metadata(store_path).await {
+        Ok(meta) if meta.is_file => meta,
+        _ => return Ok(false),
+    };
+
+    let versions = find_versions(state, &store, &path).await?;
+    let next = versions
+        .first()
+        .map(|latest| latest.version + 1)
+        .unwrap_or(1);
+    let id = nanoid!();
+    let target = version_path(&store, &id);
+    state
+        .storage
+        .create_dir_all(&PathBuf::from(folder::VERSIONS).join(&store))
+        .await?;
+    if keep_file {
+        state.storage.copy(store_path, &target).await?;
+    } else {
+        state.storage.rename(store_path, &target).await?;
+    }
+
+    let version = file_version::ActiveModel {
+        id: Set(id),
+        store: Set(store.clone()),
+        path: Set(path.clone()),
+        version: Set(next),
+        size: Set(meta.size as i64),
+        created_at: Set(Utc::now().to_rfc3339()),
+    };
+    version.insert(&state.db).await?;
+    tracing::debug!(store, path, version = next, "Saved a version");
+
+    // Only the newest versions are kept
+    if state.versions.keep > 0 {
+        for old in versions.into_iter().skip(state.versions.keep as usize - 1) {
+            remove_version(state, old).await?;
+        }
+    }
+    // The version has to fit in the quota too
+    reclaim_space(state, &store).await?;
+    Ok(true)
+}
+
+/// Copies the file at `store_path` into a new version before it gets
+/// replaced. Returns false if there is no file there.
+pub async fn copy_to_versions(state: &AppState, store_path: &Path) -> Result<bool, StorageError> {
+    save_version(state, store_path, true).await
+}
+
+/// Moves the file at `store_path` into a new version, making room for
+/// something else. Returns false if there is no file there.
+pub async fn move_to_versions(state: &AppState, store_path: &Path) -> Result<bool, StorageError> {
+    save_version(state, store_path, false).await
+}
+
+/// Lists the previous versions of a file, newest first.
+pub async fn list_versions(
+    state: &AppState,
+    store_path: &Path,
+) -> Result<Vec<FileVersion>, StorageError> {
+    let (store, path) = split_store_path(store_path).ok_or(StorageError::BadPath)?;
+    Ok(find_versions(state, &store, &path)
+        .await?
+        .into_iter()
+        .map(FileVersion::from)
+        .collect())
+}
+
+async fn find_version(
+    state: &AppState,
+    store_path: &Path,
+    version: u32,
+) -> Result<file_version::Model, StorageError> {
+    let (store, path) = split_store_path(store_path).ok_or(StorageError::BadPath)?;
+    file_version::Entity::find()
+        .filter(file_version::Column::Store.eq(store))
+        .filter(file_version::Column::Path.eq(path))
+        .filter(file_version::Column::Version.eq(version as i32))
+        .one(&state.db)
+        .await?
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound).into())
+}
+
+/// Puts the contents of a previous version back in place. The current
+/// contents of the file are kept as a new version, so restoring can be undone.
+#[tracing::instrument(skip(state))]
+pub async fn restore_version(
+    state: &AppState,
+    store_path: &Path,
+    version: u32,
+) -> Result<(), StorageError> {
+    let version = find_version(state, store_path, version).await?;
+    let (Some(folder), Some(filename)) = (store_path.parent(), store_path.file_name()) else {
+        return Err(StorageError::BadPath);
+    };
+    let replaced = match state.storage.metadata(store_path).await {
+        Ok(meta) if meta.is_file => meta.size,
+        _ => 0,
+    };
+    let size = version.size as u64;
+    check_quota(state, &version.store, size.saturating_sub(replaced)).await?;
+    // The folder may have been deleted since
+    state.storage.create_dir_all(folder).await?;
+
+    // Copy the version into a part first, so the version itself is kept even
+    // if it is pruned when the current contents are saved
+    let mut part = state
+        .storage
+        .create_part(folder, &filename.to_string_lossy())
+        .await?;
+    let restored: Result<(), StorageError> = async {
+        let mut body = state
+            .storage
+            .read(&version_path(&version.store, &version.id), None)
+            .await?;
+        while let Some(chunk) = body.try_next().await? {
+            part.write(&chunk).await?;
+        }
+        // The current contents stay in place until the version is swapped in,
+        // so the file is never missing even if restoring fails
+        if copy_to_versions(state, store_path).await? {
+            Ok(part.replace(store_path).await?)
+        } else {
+            // Fails instead of replacing the file if another one was put
+            // there in the meantime
+            part.commit(store_path).await.map_err(|err| {
+                if err.kind() == std::io::ErrorKind::AlreadyExists {
+                    StorageError::FileExists(filename.to_string_lossy().to_string())
+                } else {
+                    err.into()
+                }
+            })
+        }
+    }
+    .await;
+    if restored.is_err() {
+        if let Err(discard_err) = part.discard().await {
+            tracing::warn!(error = ?discard_err, "Failed to clean up a failed restore");
+        }
+    }
+    restored?;
+    add_usage(state, &version.store, size as i64 - replaced as i64).await
+}
+
+/// Removes all versions of all files in a store.
+pub async fn remove_store_versions(state: &AppState, store: &str) -> Result<(), StorageError> {
+    file_version::Entity::delete_many()
+        .filter(file_version::Column::Store.eq(store))
+        .exec(&state.db)
+        .await?;
+    match state
+        .storage
+        .remove(&PathBuf::from(folder::VERSIONS).join(store))
+        .await
+    {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// Removes versions that are older than `max_age`. Returns how many versions
+/// were removed.
+#[tracing::instrument(skip(state))]
+pub async fn prune_versions(
+    state: &AppState,
+    max_age: chrono::Duration,
+) -> Result<usize, StorageError> {
+    let cutoff = Utc::now() - max_age;
+    let versions = file_version::Entity::find()
+        .filter(file_version::Column::CreatedAt.lt(cutoff.to_rfc3339()))
+        .all(&state.db)
+        .await?;
+    let count = versions.len();
+    for version in versions {
+        remove_version(state, version).await?;
+    }
+    Ok(count)
+}
+
+/// Periodically removes versions that are older than the configured number of
+/// days. This never returns, spawn it in the background.
+pub async fn prune_versions_task(state: web::Data<AppState>) {
+    let max_age = chrono::Duration::days(state.versions.keep_days.into());
+    let mut interval = actix_web::rt::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match prune_versions(&state, max_age).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!(count, "Removed old versions"),
+            Err(err) => tracing::error!(error = ?err, "Failed to remove old versions"),
+        }
+    }
+}
+
+#[tracing::instrument(skip(state))]
+#[get("/versions/{store}/{path:.*}")]
+pub async fn get_versions(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    query: web::Query<VersionQuery>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, path) = params.as_ref();
+    let store_path = get_authorized_path(&authorized, store, Some(path))?;
+    match query.version {
+        None => Ok(HttpResponse::Ok().json(VersionResults {
+            versions: list_versions(&state, &store_path).await?,
+        })),
+        Some(version) => {
+            let version = find_version(&state, &store_path, version).await?;
+            let path = version_path(&version.store, &version.id);
+            let meta = state.storage.metadata(&path).await?;
+            let mut response = file_response(&req, &state, &path, &meta).await?;
+            // The stored version has no extension, use the name of the file
+            let name = store_path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let content_type = mime_guess::from_path(&name).first_or_octet_stream();
+            let headers = response.headers_mut();
+            if let Ok(content_type) = content_type.as_ref().parse() {
+                headers.insert(header::CONTENT_TYPE, content_type);
+            }
+            let disposition = ContentDisposition {
+                disposition: DispositionType::Attachment,
+                parameters: vec![DispositionParam::Filename(name)],
+            };
+            if let Ok(disposition) = disposition.to_string().parse() {
+                headers.insert(header::CONTENT_DISPOSITION, disposition);
+            }
+            Ok(response)
+        }
+    }
+}
+
+#[tracing::instrument(skip(state))]
+#[post("/versions/{store}/{path:.*}")]
+pub async fn post_version(
+    state: web::Data<AppState>,
+    params: web::Path<(String, String)>,
+    query: web::Query<RestoreVersionQuery>,
+    authorized: Option<ReqData<Authorized>>,
+) -> Result<HttpResponse, StorageError> {
+    let (store, path) = params.as_ref();
+    let store_path = get_authorized_path(&authorized, store, Some(path))?;
+    restore_version(&state, &store_path, query.version).await?;
+    Ok(empty_ok_response())
+}
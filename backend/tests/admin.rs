@@ -0,0 +1,218 @@
+mod common;
+
+use std::path::PathBuf;
+
+use actix_web::{
+    http::{header, StatusCode},
+    test,
+};
+use bulgur_cloud::{
+    admin::{AdminUser, AdminUsers, NewUser, PasswordReset, UserTypeChange},
+    auth::{add_new_user, make_token, Password},
+    folder::STORAGE,
+    server::setup_app,
+    state::{Token, UserType},
+};
+use common::{login_request, TestEnv};
+
+async fn setup_admin_token(ctx: &TestEnv) -> Token {
+    add_new_user("adminuser", "adminpass", UserType::Admin, &ctx.state())
+        .await
+        .expect("Failed to create admin");
+    make_token(&ctx.state(), "adminuser").await.unwrap()
+}
+
+#[actix_web::test]
+async fn test_admin_required() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/admin/users")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "Login is required");
+
+    let req = test::TestRequest::get()
+        .uri("/api/admin/users")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::FORBIDDEN,
+        "Users who aren't admins are rejected"
+    );
+}
+
+#[actix_web::test]
+async fn test_admin_create_list_delete() {
+    let ctx = TestEnv::setup().await;
+    let admin = setup_admin_token(&ctx).await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/admin/users")
+        .insert_header((header::AUTHORIZATION, admin.reveal()))
+        .set_json(NewUser {
+            username: "testuser".to_string(),
+            password: Password("testpass".to_string()),
+            user_type: UserType::User,
+        })
+        .to_request();
+    let user: AdminUser = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(user.username, "testuser");
+    assert_eq!(user.user_type, UserType::User);
+    assert!(
+        PathBuf::from(STORAGE).join("testuser").exists(),
+        "Store is created"
+    );
+    let req = login_request("testuser", "testpass").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK, "New user can log in");
+
+    let req = test::TestRequest::get()
+        .uri("/api/admin/users")
+        .insert_header((header::AUTHORIZATION, admin.reveal()))
+        .to_request();
+    let resp: AdminUsers = test::call_and_read_body_json(&app, req).await;
+    let usernames: Vec<&str> = resp
+        .users
+        .iter()
+        .map(|user| user.username.as_str())
+        .collect();
+    assert_eq!(usernames, vec!["adminuser", "testuser"]);
+
+    let req = test::TestRequest::delete()
+        .uri("/api/admin/users/testuser?delete_files=true")
+        .insert_header((header::AUTHORIZATION, admin.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(
+        !PathBuf::from(STORAGE).join("testuser").exists(),
+        "Store is deleted"
+    );
+    let req = test::TestRequest::delete()
+        .uri("/api/admin/users/testuser")
+        .insert_header((header::AUTHORIZATION, admin.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn test_admin_create_rejects_bad_names() {
+    let ctx = TestEnv::setup().await;
+    let admin = setup_admin_token(&ctx).await;
+    ctx.add_user("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    for (username, status) in [
+        ("testuser", StatusCode::CONFLICT),
+        ("nobody", StatusCode::CONFLICT),
+        ("../etc", StatusCode::BAD_REQUEST),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/api/admin/users")
+            .insert_header((header::AUTHORIZATION, admin.reveal()))
+            .set_json(NewUser {
+                username: username.to_string(),
+                password: Password("testpass".to_string()),
+                user_type: UserType::User,
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), status, "{username}");
+    }
+}
+
+#[actix_web::test]
+async fn test_admin_reset_password() {
+    let ctx = TestEnv::setup().await;
+    let admin = setup_admin_token(&ctx).await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::put()
+        .uri("/api/admin/users/testuser/password")
+        .insert_header((header::AUTHORIZATION, admin.reveal()))
+        .set_json(PasswordReset {
+            password: Password("newpass".to_string()),
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = login_request("testuser", "testpass").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Old password fails"
+    );
+    let req = login_request("testuser", "newpass").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK, "New password works");
+
+    let req = test::TestRequest::get()
+        .uri("/api/stats")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Old sessions are logged out"
+    );
+}
+
+#[actix_web::test]
+async fn test_admin_change_type() {
+    let ctx = TestEnv::setup().await;
+    let admin = setup_admin_token(&ctx).await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::put()
+        .uri("/api/admin/users/testuser/type")
+        .insert_header((header::AUTHORIZATION, admin.reveal()))
+        .set_json(UserTypeChange {
+            user_type: UserType::Admin,
+        })
+        .to_request();
+    let user: AdminUser = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(user.user_type, UserType::Admin);
+
+    let req = test::TestRequest::get()
+        .uri("/api/admin/users")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Promoted user is an admin");
+
+    let req = test::TestRequest::put()
+        .uri("/api/admin/users/adminuser/type")
+        .insert_header((header::AUTHORIZATION, admin.reveal()))
+        .set_json(UserTypeChange {
+            user_type: UserType::User,
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::BAD_REQUEST,
+        "Admins can't demote themselves"
+    );
+    let req = test::TestRequest::delete()
+        .uri("/api/admin/users/adminuser")
+        .insert_header((header::AUTHORIZATION, admin.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::BAD_REQUEST,
+        "Admins can't delete themselves"
+    );
+}
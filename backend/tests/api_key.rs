@@ -0,0 +1,326 @@
+mod common;
+
+use std::path::PathBuf;
+
+use actix_web::{
+    http::{header, Method, StatusCode},
+    test,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bulgur_cloud::{
+    api_key::{ApiKeyOptions, ApiKeyScope, ApiKeys, NewApiKey},
+    auth::add_new_user,
+    cli::{cli_command, CLIContext, Commands, Key, KeyAdd, KeyRemove, Opt},
+    entity::api_key,
+    folder::STORAGE,
+    server::setup_app,
+    state::{Token, UserType},
+    storage::StorageAction,
+};
+use common::{create_dir, create_file, TestEnv};
+use sea_orm::EntityTrait;
+
+pub struct CLITestContext {}
+impl CLIContext for CLITestContext {
+    fn prompt_password() -> anyhow::Result<String> {
+        Ok("testpass".to_string())
+    }
+}
+
+fn key_options(name: &str, scope: ApiKeyScope, path: Option<&str>) -> ApiKeyOptions {
+    ApiKeyOptions {
+        name: name.to_string(),
+        scope,
+        path: path.map(str::to_string),
+    }
+}
+
+fn create_key_request(token: &Token, options: ApiKeyOptions) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri("/api/keys")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .set_json(options)
+}
+
+fn request(method: Method, uri: &str, key: &Token) -> test::TestRequest {
+    test::TestRequest::default()
+        .method(method)
+        .uri(uri)
+        .insert_header((header::AUTHORIZATION, key.reveal()))
+}
+
+fn create_folder_request(uri: &str, key: &Token) -> test::TestRequest {
+    request(Method::POST, uri, key).set_json(StorageAction::CreateFolder {})
+}
+
+async fn setup_files() {
+    let store = PathBuf::from(STORAGE).join("testuser");
+    create_file(store.join("notes.txt"), "notes").await;
+    create_dir(store.join("backups")).await;
+    create_file(store.join("backups").join("db.sql"), "backup").await;
+}
+
+#[actix_web::test]
+async fn test_api_key_read_scope() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    setup_files().await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = create_key_request(&token, key_options("reader", ApiKeyScope::Read, None));
+    let key: NewApiKey = test::call_and_read_body_json(&app, req.to_request()).await;
+    assert_eq!(key.info.name, "reader");
+    assert_eq!(key.info.scope, ApiKeyScope::Read);
+
+    let req = request(Method::GET, "/storage/testuser/notes.txt", &key.key).to_request();
+    let resp = test::call_and_read_body(&app, req).await;
+    assert_eq!(resp, "notes");
+    let req = request(Method::GET, "/api/stats", &key.key).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = request(Method::DELETE, "/storage/testuser/notes.txt", &key.key).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let req = create_folder_request("/storage/testuser/new", &key.key).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert!(PathBuf::from(STORAGE)
+        .join("testuser")
+        .join("notes.txt")
+        .exists());
+
+    let req = request(Method::GET, "/storage/otheruser/", &key.key).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_api_key_write_scope() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    setup_files().await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = create_key_request(&token, key_options("ci", ApiKeyScope::Write, None));
+    let key: NewApiKey = test::call_and_read_body_json(&app, req.to_request()).await;
+
+    let req = create_folder_request("/storage/testuser/new", &key.key).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let req = request(Method::DELETE, "/storage/testuser/notes.txt", &key.key).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Keys can't manage the account
+    for (method, uri) in [
+        (Method::GET, "/api/keys"),
+        (Method::GET, "/api/sessions"),
+        (Method::GET, "/api/totp"),
+        (Method::GET, "/api/admin/users"),
+    ] {
+        let req = request(method, uri, &key.key).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_client_error(), "{uri} is refused");
+    }
+    let req = create_key_request(&key.key, key_options("more", ApiKeyScope::Write, None));
+    let resp = test::call_service(&app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_api_key_path() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    setup_files().await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = create_key_request(
+        &token,
+        key_options("backup", ApiKeyScope::Write, Some("/backups/")),
+    );
+    let key: NewApiKey = test::call_and_read_body_json(&app, req.to_request()).await;
+    assert_eq!(key.info.path.as_deref(), Some("backups"));
+
+    let req = request(Method::GET, "/storage/testuser/backups/db.sql", &key.key).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let req = create_folder_request("/storage/testuser/backups/today", &key.key).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    for uri in [
+        "/storage/testuser/notes.txt",
+        "/storage/testuser/",
+        "/storage/testuser/backups-old",
+        "/api/trash/testuser",
+        "/api/shares/testuser",
+    ] {
+        let req = request(Method::GET, uri, &key.key).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_client_error(), "{uri} is refused");
+    }
+
+    let req = create_key_request(
+        &token,
+        key_options("escape", ApiKeyScope::Read, Some("backups/../")),
+    );
+    let resp = test::call_service(&app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn test_api_key_admin_scope() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    add_new_user("admin", "adminpass", UserType::Admin, &ctx.state())
+        .await
+        .unwrap();
+    let admin_token = ctx.setup_user_token("admin", "adminpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = create_key_request(&token, key_options("admin", ApiKeyScope::Admin, None));
+    let resp = test::call_service(&app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN, "Only for admins");
+
+    let req = create_key_request(&admin_token, key_options("write", ApiKeyScope::Write, None));
+    let write_key: NewApiKey = test::call_and_read_body_json(&app, req.to_request()).await;
+    let req = create_key_request(&admin_token, key_options("admin", ApiKeyScope::Admin, None));
+    let admin_key: NewApiKey = test::call_and_read_body_json(&app, req.to_request()).await;
+
+    let req = request(Method::GET, "/api/admin/users", &write_key.key).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let req = request(Method::GET, "/api/admin/users", &admin_key.key).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn test_api_key_manage() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let other = ctx.setup_user_token("otheruser", "otherpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = create_key_request(&token, key_options("ci", ApiKeyScope::Read, None));
+    let key: NewApiKey = test::call_and_read_body_json(&app, req.to_request()).await;
+    let req = create_key_request(&token, key_options("ci", ApiKeyScope::Write, None));
+    let resp = test::call_service(&app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT, "Names are unique");
+    let req = create_key_request(&token, key_options(" ", ApiKeyScope::Read, None));
+    let resp = test::call_service(&app, req.to_request()).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let req = request(Method::GET, "/api/keys", &token).to_request();
+    let keys: ApiKeys = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(keys.keys.len(), 1);
+    assert_eq!(keys.keys[0].id, key.info.id);
+    let stored = api_key::Entity::find_by_id(key.info.id.clone())
+        .one(&ctx.state().db)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_ne!(stored.token, key.key.reveal(), "Only the hash is stored");
+
+    let uri = format!("/api/keys/{}", key.info.id);
+    let req = request(Method::DELETE, &uri, &other).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::NOT_FOUND,
+        "Others can't revoke it"
+    );
+    let req = request(Method::GET, "/api/stats", &key.key).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = request(Method::DELETE, &uri, &token).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let req = request(Method::GET, "/api/stats", &key.key).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "Key is revoked");
+}
+
+#[actix_web::test]
+async fn test_api_key_dav() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    setup_files().await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = create_key_request(&token, key_options("rclone", ApiKeyScope::Read, None));
+    let key: NewApiKey = test::call_and_read_body_json(&app, req.to_request()).await;
+    let basic_auth = |username: &str| {
+        format!(
+            "Basic {}",
+            STANDARD.encode(format!("{username}:{}", key.key.reveal()))
+        )
+    };
+
+    let req = test::TestRequest::get()
+        .uri("/dav/testuser/notes.txt")
+        .insert_header((header::AUTHORIZATION, basic_auth("testuser")))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let req = test::TestRequest::delete()
+        .uri("/dav/testuser/notes.txt")
+        .insert_header((header::AUTHORIZATION, basic_auth("testuser")))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let req = test::TestRequest::get()
+        .uri("/dav/testuser/notes.txt")
+        .insert_header((header::AUTHORIZATION, basic_auth("otheruser")))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Key is for testuser"
+    );
+}
+
+#[actix_web::test]
+async fn test_cli_api_key() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let opt = |command| Opt {
+        command: Some(command),
+        bind: Default::default(),
+        datastore: ctx.datastore(),
+        workers: 1,
+        trash_retention_days: 30,
+        kv: Default::default(),
+        versions: Default::default(),
+        tokens: Default::default(),
+        auth: Default::default(),
+    };
+
+    let command = Commands::Key(Key::KeyAdd(KeyAdd {
+        username: "testuser".to_string(),
+        name: "ci".to_string(),
+        scope: ApiKeyScope::Write,
+        path: Some("backups".to_string()),
+    }));
+    cli_command::<CLITestContext>(opt(command))
+        .await
+        .expect("Failed to run command");
+    let keys = api_key::Entity::find().all(&ctx.state().db).await.unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0].name, "ci");
+    assert_eq!(keys[0].scope, "write");
+    assert_eq!(keys[0].path.as_deref(), Some("backups"));
+
+    let command = Commands::Key(Key::KeyRemove(KeyRemove {
+        username: "testuser".to_string(),
+        name: "ci".to_string(),
+    }));
+    cli_command::<CLITestContext>(opt(command))
+        .await
+        .expect("Failed to run command");
+    let keys = api_key::Entity::find().all(&ctx.state().db).await.unwrap();
+    assert!(keys.is_empty());
+}
@@ -0,0 +1,376 @@
+mod common;
+
+use std::path::PathBuf;
+
+use actix_web::{
+    cookie::Cookie,
+    http::{header, StatusCode},
+    test,
+};
+use async_compression::tokio::write::GzipEncoder;
+use async_zip::{
+    base::{read::mem::ZipFileReader, write::ZipFileWriter},
+    Compression, ZipEntryBuilder,
+};
+use bulgur_cloud::{
+    auth_middleware::AUTH_COOKIE_NAME, folder::STORAGE, quota::quota_usage, server::setup_app,
+    storage::StorageAction,
+};
+use common::{create_dir, create_file, read_header, TestEnv};
+use tokio::{fs, io::AsyncWriteExt};
+
+/// Reads the archive into a list of file names and contents.
+async fn read_zip(data: Vec<u8>) -> Vec<(String, String)> {
+    let reader = ZipFileReader::new(data)
+        .await
+        .expect("Response is not a zip file");
+    let mut out = vec![];
+    for index in 0..reader.file().entries().len() {
+        let mut entry = reader.reader_with_entry(index).await.unwrap();
+        let name = entry.entry().filename().as_str().unwrap().to_string();
+        let mut contents = String::new();
+        entry.read_to_string_checked(&mut contents).await.unwrap();
+        out.push((name, contents));
+    }
+    out
+}
+
+async fn setup_folder() {
+    let folder = PathBuf::from(STORAGE).join("testuser").join("apple");
+    create_dir(folder.clone()).await;
+    create_dir(folder.join("banana")).await;
+    create_dir(folder.join("empty")).await;
+    create_file(folder.join("test.txt"), "Autem tempore").await;
+    create_file(folder.join("banana").join("nested.txt"), "Et voluptatibu").await;
+}
+
+#[actix_web::test]
+async fn test_download_folder_zip() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    setup_folder().await;
+
+    let req = test::TestRequest::get()
+        .uri("/storage/testuser/apple?archive=zip")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(read_header(&resp, header::CONTENT_TYPE), "application/zip");
+    assert!(
+        read_header(&resp, header::CONTENT_DISPOSITION).contains("apple.zip"),
+        "Archive is named after the folder"
+    );
+
+    let body = test::read_body(resp).await.to_vec();
+    let entries = read_zip(body).await;
+    let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+    assert!(names.contains(&"banana/"), "Archive contains the folder");
+    assert!(names.contains(&"empty/"), "Archive contains empty folders");
+    assert!(
+        entries.contains(&("test.txt".to_string(), "Autem tempore".to_string())),
+        "Archive contains the file"
+    );
+    assert!(
+        entries.contains(&(
+            "banana/nested.txt".to_string(),
+            "Et voluptatibu".to_string()
+        )),
+        "Archive contains the nested file"
+    );
+}
+
+#[actix_web::test]
+async fn test_download_folder_zip_path_token() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let token = ctx.setup_path_token("storage/testuser/apple").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    setup_folder().await;
+
+    let uri = format!(
+        "/storage/testuser/apple?archive=zip&token={}",
+        token.reveal()
+    );
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::OK,
+        "Path tokens can download the folder"
+    );
+    let entries = read_zip(test::read_body(resp).await.to_vec()).await;
+    assert_eq!(
+        entries.len(),
+        4,
+        "Archive contains everything in the folder"
+    );
+}
+
+#[actix_web::test]
+async fn test_download_folder_zip_unauthorized_store() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    ctx.add_user("user2", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::get()
+        .uri("/storage/user2/?archive=zip")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(
+        resp.status().is_client_error(),
+        "Folders in another user's store can't be downloaded"
+    );
+}
+
+#[actix_web::test]
+async fn test_basic_download_all() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    setup_folder().await;
+
+    let req = test::TestRequest::get()
+        .uri("/basic/testuser/apple")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .to_request();
+    let resp = test::call_and_read_body(&app, req).await;
+    let resp_str = String::from_utf8(resp.to_vec()).unwrap();
+    assert!(
+        resp_str.contains("/basic/testuser/apple/?archive=zip"),
+        "Basic UI links to the archive"
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/basic/testuser/apple/?archive=zip")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let entries = read_zip(test::read_body(resp).await.to_vec()).await;
+    assert_eq!(
+        entries.len(),
+        4,
+        "Archive contains everything in the folder"
+    );
+}
+
+/// Builds a zip file with the given file names and contents.
+async fn make_zip(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut zip = ZipFileWriter::new(Vec::new());
+    for (name, contents) in files {
+        let builder = ZipEntryBuilder::new(name.to_string().into(), Compression::Deflate);
+        zip.write_entry_whole(builder, contents.as_bytes())
+            .await
+            .unwrap();
+    }
+    zip.close().await.unwrap()
+}
+
+/// Builds a tar file with the given file names and contents.
+async fn make_tar(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut tar = tokio_tar::Builder::new(Vec::new());
+    for (name, contents) in files {
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, name, contents.as_bytes())
+            .await
+            .unwrap();
+    }
+    tar.into_inner().await.unwrap()
+}
+
+fn extract_request(token: &str, archive: &str, target: &str) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri(&format!("/storage/testuser/{archive}"))
+        .insert_header((header::AUTHORIZATION, token))
+        .set_json(StorageAction::Extract {
+            target: target.to_string(),
+        })
+}
+
+#[actix_web::test]
+async fn test_extract_zip() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let zip = make_zip(&[
+        ("test.txt", "Autem tempore"),
+        ("apple/banana.txt", "Et voluptatibu"),
+    ])
+    .await;
+    fs::write(
+        PathBuf::from(STORAGE).join("testuser").join("test.zip"),
+        zip,
+    )
+    .await
+    .unwrap();
+
+    let req = extract_request(token.reveal(), "test.zip", "/testuser/out").to_request();
+    let status = test::call_service(&app, req).await.status();
+    assert_eq!(status, StatusCode::OK, "Archive is extracted");
+
+    let out = PathBuf::from(STORAGE).join("testuser").join("out");
+    assert_eq!(
+        fs::read_to_string(out.join("test.txt")).await.unwrap(),
+        "Autem tempore"
+    );
+    assert_eq!(
+        fs::read_to_string(out.join("apple").join("banana.txt"))
+            .await
+            .unwrap(),
+        "Et voluptatibu",
+        "Nested files are extracted"
+    );
+}
+
+#[actix_web::test]
+async fn test_extract_tar_gz() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let tar = make_tar(&[
+        ("test.txt", "Autem tempore"),
+        ("apple/banana.txt", "Et voluptatibu"),
+    ])
+    .await;
+    let mut encoder = GzipEncoder::new(Vec::new());
+    encoder.write_all(&tar).await.unwrap();
+    encoder.shutdown().await.unwrap();
+    fs::write(
+        PathBuf::from(STORAGE).join("testuser").join("test.tar.gz"),
+        encoder.into_inner(),
+    )
+    .await
+    .unwrap();
+    // Existing files are kept, just like with uploads
+    create_dir(PathBuf::from(STORAGE).join("testuser").join("out")).await;
+    create_file(
+        PathBuf::from(STORAGE)
+            .join("testuser")
+            .join("out")
+            .join("test.txt"),
+        "Aut suscipit",
+    )
+    .await;
+
+    let req = extract_request(token.reveal(), "test.tar.gz", "/testuser/out").to_request();
+    let status = test::call_service(&app, req).await.status();
+    assert_eq!(status, StatusCode::OK, "Archive is extracted");
+
+    let out = PathBuf::from(STORAGE).join("testuser").join("out");
+    assert_eq!(
+        fs::read_to_string(out.join("test.txt")).await.unwrap(),
+        "Aut suscipit",
+        "Existing file is kept"
+    );
+    assert_eq!(
+        fs::read_to_string(out.join("test (1).txt")).await.unwrap(),
+        "Autem tempore",
+        "Extracted file is renamed"
+    );
+    assert_eq!(
+        fs::read_to_string(out.join("apple").join("banana.txt"))
+            .await
+            .unwrap(),
+        "Et voluptatibu",
+        "Nested files are extracted"
+    );
+}
+
+#[actix_web::test]
+async fn test_extract_zip_slip() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let zip = make_zip(&[
+        ("test.txt", "Autem tempore"),
+        ("../../evil.txt", "Et voluptatibu"),
+    ])
+    .await;
+    fs::write(
+        PathBuf::from(STORAGE).join("testuser").join("test.zip"),
+        zip,
+    )
+    .await
+    .unwrap();
+
+    let req = extract_request(token.reveal(), "test.zip", "/testuser/out").to_request();
+    let status = test::call_service(&app, req).await.status();
+    assert_eq!(
+        status,
+        StatusCode::BAD_REQUEST,
+        "Archives that escape the folder are rejected"
+    );
+    assert!(
+        fs::metadata(PathBuf::from(STORAGE).join("evil.txt"))
+            .await
+            .is_err(),
+        "Nothing is written outside the folder"
+    );
+    assert!(
+        fs::metadata(PathBuf::from(STORAGE).join("testuser").join("out"))
+            .await
+            .is_err(),
+        "Partially extracted folder is cleaned up"
+    );
+}
+
+#[actix_web::test]
+async fn test_extract_unauthorized_store() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    ctx.add_user("user2", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let zip = make_zip(&[("test.txt", "Autem tempore")]).await;
+    fs::write(
+        PathBuf::from(STORAGE).join("testuser").join("test.zip"),
+        zip,
+    )
+    .await
+    .unwrap();
+
+    let req = extract_request(token.reveal(), "test.zip", "/user2/out").to_request();
+    let status = test::call_service(&app, req).await.status();
+    assert!(
+        status.is_client_error(),
+        "Archives can't be extracted into another user's store"
+    );
+}
+
+#[actix_web::test]
+async fn test_extract_parent_segments() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    ctx.add_user("user2", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let zip = make_zip(&[("test.txt", "Autem tempore")]).await;
+    fs::write(
+        PathBuf::from(STORAGE).join("testuser").join("test.zip"),
+        zip,
+    )
+    .await
+    .unwrap();
+
+    for target in ["/testuser/../user2/out", "/testuser/./../user2/out"] {
+        let req = extract_request(token.reveal(), "test.zip", target).to_request();
+        let status = test::call_service(&app, req).await.status();
+        assert!(
+            status.is_client_error(),
+            "Archives can't be extracted into {target}"
+        );
+    }
+    assert!(
+        fs::metadata(PathBuf::from(STORAGE).join("user2").join("out"))
+            .await
+            .is_err(),
+        "Nothing is written into another user's store"
+    );
+    let usage = quota_usage(&ctx.state(), "user2").await.unwrap();
+    assert_eq!(usage.usage, 0, "Nothing is charged to the other user");
+}
@@ -1,10 +1,17 @@
 mod common;
-use actix_web::test;
+use actix_web::{http::StatusCode, test};
+use argon2::{Algorithm, Argon2, Version};
 use bulgur_cloud::{
-    auth::{Login, LoginResponse, Password},
+    auth::{AuthOptions, Login, LoginResponse, Password, PasswordHashOptions},
+    entity::user,
     server::setup_app,
 };
-use common::TestEnv;
+use common::{login_request, TestEnv};
+use scrypt::{
+    password_hash::{rand_core::OsRng, PasswordHasher, Salt, SaltString},
+    Params, Scrypt,
+};
+use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, Set};
 
 #[actix_web::test]
 async fn test_login_fails_no_data() {
@@ -29,6 +36,7 @@ async fn test_login_fails_bad_username() {
     let login = Login {
         username: "someone-else".to_string(),
         password: Password("correct-horse-battery-staple".to_string()),
+        code: None,
     };
 
     let req = test::TestRequest::post()
@@ -53,6 +61,7 @@ async fn test_login_fails_bad_password() {
     let login = Login {
         username: "testuser".to_string(),
         password: Password("hunter2".to_string()),
+        code: None,
     };
 
     let req = test::TestRequest::post()
@@ -77,6 +86,7 @@ async fn test_login() {
     let login = Login {
         username: "testuser".to_string(),
         password: Password("correct-horse-battery-staple".to_string()),
+        code: None,
     };
 
     let req = test::TestRequest::post()
@@ -91,3 +101,139 @@ async fn test_login() {
         "POST /auth/login responds with access token for a good login"
     );
 }
+
+async fn find_user(ctx: &TestEnv, username: &str) -> user::Model {
+    user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(&ctx.state().db)
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+/// Replaces the hash of the user with a scrypt hash, like the ones older
+/// versions made.
+async fn set_scrypt_hash(ctx: &TestEnv, username: &str, password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = Scrypt
+        .hash_password_customized(
+            password.as_bytes(),
+            None,
+            None,
+            Params::new(5, 2, 1, 32).unwrap(),
+            Salt::from(&salt),
+        )
+        .unwrap()
+        .to_string();
+    let mut user: user::ActiveModel = find_user(ctx, username).await.into();
+    user.password_hash = Set(password_hash.clone());
+    user.update(&ctx.state().db).await.unwrap();
+    password_hash
+}
+
+#[actix_web::test]
+async fn test_new_passwords_use_argon2id() {
+    let hashing = PasswordHashOptions {
+        argon2_memory: 512,
+        argon2_iterations: 2,
+        argon2_parallelism: 2,
+    };
+    let ctx = TestEnv::setup_with_auth(AuthOptions {
+        hashing,
+        ..Default::default()
+    })
+    .await;
+    ctx.add_user("testuser", "testpass").await;
+
+    let user = find_user(&ctx, "testuser").await;
+    assert!(
+        user.password_hash
+            .starts_with("$argon2id$v=19$m=512,t=2,p=2$"),
+        "The hash uses the configured parameters: {}",
+        user.password_hash
+    );
+    let nobody = find_user(&ctx, "nobody").await;
+    assert!(nobody.password_hash.starts_with("$argon2id$"));
+}
+
+#[actix_web::test]
+async fn test_login_rehashes_scrypt() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let scrypt_hash = set_scrypt_hash(&ctx, "testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    // A failed login leaves the hash alone
+    let req = login_request("testuser", "hunter2").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_client_error());
+    assert_eq!(find_user(&ctx, "testuser").await.password_hash, scrypt_hash);
+
+    let req = login_request("testuser", "testpass").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Old hashes still work");
+    let user = find_user(&ctx, "testuser").await;
+    assert!(
+        user.password_hash.starts_with("$argon2id$"),
+        "The hash is replaced after the login"
+    );
+
+    // The new hash works too, and isn't replaced again
+    let req = login_request("testuser", "testpass").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        find_user(&ctx, "testuser").await.password_hash,
+        user.password_hash
+    );
+}
+
+#[actix_web::test]
+async fn test_login_rehashes_changed_params() {
+    let ctx = TestEnv::setup_with_auth(AuthOptions {
+        hashing: PasswordHashOptions {
+            argon2_memory: 1024,
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .await;
+    ctx.add_user("testuser", "testpass").await;
+    // A hash made before the parameters were changed
+    let salt = SaltString::generate(&mut OsRng);
+    let old_hash = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        argon2::Params::new(512, 1, 1, None).unwrap(),
+    )
+    .hash_password(b"testpass", &salt)
+    .unwrap()
+    .to_string();
+    let mut user: user::ActiveModel = find_user(&ctx, "testuser").await.into();
+    user.password_hash = Set(old_hash.clone());
+    user.update(&ctx.state().db).await.unwrap();
+
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let req = login_request("testuser", "testpass").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let new_hash = find_user(&ctx, "testuser").await.password_hash;
+    assert_ne!(new_hash, old_hash);
+    assert!(new_hash.contains("m=1024,"), "{new_hash}");
+}
+
+#[actix_web::test]
+async fn test_bad_hashing_params() {
+    let hashing = PasswordHashOptions {
+        argon2_memory: 1,
+        ..Default::default()
+    };
+    assert!(hashing.params().is_err(), "Too little memory is rejected");
+    let hashing = PasswordHashOptions {
+        argon2_iterations: 0,
+        ..Default::default()
+    };
+    assert!(hashing.params().is_err());
+    assert!(PasswordHashOptions::default().params().is_ok());
+}
@@ -31,6 +31,11 @@ async fn test_cli_user_add() {
         bind: Default::default(),
         datastore: ctx.datastore(),
         workers: 1,
+        trash_retention_days: 30,
+        kv: Default::default(),
+        versions: Default::default(),
+        tokens: Default::default(),
+        auth: Default::default(),
     };
     cli_command::<CLITestContext>(opt)
         .await
@@ -61,6 +66,11 @@ async fn test_cli_user_remove() {
         bind: Default::default(),
         datastore: ctx.datastore(),
         workers: 1,
+        trash_retention_days: 30,
+        kv: Default::default(),
+        versions: Default::default(),
+        tokens: Default::default(),
+        auth: Default::default(),
     };
     cli_command::<CLITestContext>(opt)
         .await
@@ -93,6 +103,11 @@ async fn test_cli_user_remove_delete_files() {
         bind: Default::default(),
         datastore: ctx.datastore(),
         workers: 1,
+        trash_retention_days: 30,
+        kv: Default::default(),
+        versions: Default::default(),
+        tokens: Default::default(),
+        auth: Default::default(),
     };
     cli_command::<CLITestContext>(opt)
         .await
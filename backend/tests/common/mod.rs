@@ -3,9 +3,15 @@ use std::{
     path::PathBuf,
 };
 
-use actix_web::{body::MessageBody, dev::ServiceResponse, http::header::AsHeaderName, web::Data};
+use actix_web::{
+    body::MessageBody,
+    dev::ServiceResponse,
+    http::header::{self, AsHeaderName},
+    test,
+    web::Data,
+};
 use bulgur_cloud::{
-    auth::{add_new_user, create_user_folder, make_token},
+    auth::{add_new_user, create_user_folder, make_token, AuthOptions, Login, Password},
     db::get_db,
     ratelimit_middleware::RateLimit,
     server::setup_app_deps,
@@ -20,19 +26,35 @@ pub struct TestEnv {
     state: Data<AppState>,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Copy)]
 pub struct TestKeyExtractor {}
 
 impl TestEnv {
+    #[allow(dead_code)]
     pub async fn setup() -> TestEnv {
+        TestEnv::setup_with_auth(Default::default()).await
+    }
+
+    /// Sets up the test environment, checking passwords with the given
+    /// authentication provider.
+    #[allow(dead_code)]
+    pub async fn setup_with_auth(auth: AuthOptions) -> TestEnv {
         let folder = temp_dir().join(format!("bulgur-cloud-{}", nanoid::nanoid!()));
         std::fs::create_dir_all(&folder).expect("Failed to create test dir");
         env::set_current_dir(&folder).expect("Failed to switch to the test dir");
         let datastore = "sqlite://data.sqlite?mode=rwc".to_string();
         let connection = get_db(&datastore).await.unwrap();
-        let (state, _) = setup_app_deps(folder.clone(), connection)
-            .await
-            .expect("Failed to set up app dependencies");
+        let (state, _) = setup_app_deps(
+            folder.clone(),
+            connection,
+            &Default::default(),
+            &Default::default(),
+            &Default::default(),
+            &auth,
+        )
+        .await
+        .expect("Failed to set up app dependencies");
         TestEnv {
             folder,
             state,
@@ -61,11 +83,11 @@ impl TestEnv {
             user,
             password,
             bulgur_cloud::state::UserType::User,
-            &self.state.db,
+            &self.state,
         )
         .await
         .expect("Failed to create user");
-        create_user_folder(user)
+        create_user_folder(&self.state, user)
             .await
             .expect("Failed to create user folder");
     }
@@ -111,3 +133,26 @@ pub fn read_header(resp: &ServiceResponse<impl MessageBody>, header: impl AsHead
         .expect("Failed to parse header")
         .to_string()
 }
+
+/// Logs in with a username and password, like the login page does.
+#[allow(dead_code)]
+pub fn login_request(username: &str, password: &str) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri("/auth/login")
+        .set_json(Login {
+            username: username.to_string(),
+            password: Password(password.to_string()),
+            code: None,
+        })
+}
+
+/// Uploads a file with a multipart form, like the app does. `uri` is the
+/// folder the file goes into.
+#[allow(dead_code)]
+pub fn upload_request(token: &str, uri: &str, filename: &str, contents: &str) -> test::TestRequest {
+    test::TestRequest::put()
+        .uri(uri)
+        .insert_header((header::AUTHORIZATION, token))
+        .set_payload(format!("--zzz\r\nContent-Disposition: form-data; name=\"{filename}\"; filename=\"{filename}\"\r\n\r\n{contents}\r\n--zzz--\r\n\r\n"))
+        .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=zzz"))
+}
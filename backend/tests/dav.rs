@@ -0,0 +1,269 @@
+mod common;
+
+use std::path::PathBuf;
+
+use actix_web::{
+    http::{header, Method, StatusCode},
+    test,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bulgur_cloud::{folder::STORAGE, ratelimit_middleware::RateLimit, server::setup_app};
+use common::{create_dir, create_file, TestEnv};
+use tokio::fs;
+
+fn basic_auth(username: &str, password: &str) -> String {
+    format!(
+        "Basic {}",
+        STANDARD.encode(format!("{username}:{password}"))
+    )
+}
+
+fn method(name: &str) -> Method {
+    Method::from_bytes(name.as_bytes()).unwrap()
+}
+
+#[actix_web::test]
+async fn test_dav_requires_login() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::default()
+        .method(method("PROPFIND"))
+        .uri("/dav/testuser/")
+        .insert_header((header::AUTHORIZATION, basic_auth("testuser", "wrongpass")))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "PROPFIND rejects bad credentials"
+    );
+    assert!(
+        resp.headers().contains_key(header::WWW_AUTHENTICATE),
+        "Clients are asked for credentials"
+    );
+}
+
+#[actix_web::test]
+async fn test_dav_throttles_failed_logins() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), RateLimit::new(3, true))).await;
+    let propfind = |password: &str| {
+        test::TestRequest::default()
+            .method(method("PROPFIND"))
+            .uri("/dav/testuser/")
+            .insert_header((header::AUTHORIZATION, basic_auth("testuser", password)))
+            .to_request()
+    };
+
+    // Logging in doesn't use up the attempts
+    for _ in 0..5 {
+        let resp = test::call_service(&app, propfind("testpass")).await;
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    }
+    for _ in 0..3 {
+        let resp = test::call_service(&app, propfind("wrongpass")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    let resp = test::call_service(&app, propfind("wrongpass")).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::TOO_MANY_REQUESTS,
+        "Repeated bad credentials are throttled"
+    );
+    assert!(resp.headers().contains_key(header::RETRY_AFTER));
+    let resp = test::call_service(&app, propfind("testpass")).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::TOO_MANY_REQUESTS,
+        "The right password isn't checked either until the client can try again"
+    );
+}
+
+#[actix_web::test]
+async fn test_dav_propfind() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    create_dir(PathBuf::from(STORAGE).join("testuser").join("apple")).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("banana.txt"),
+        "Aut suscipit",
+    )
+    .await;
+
+    let req = test::TestRequest::default()
+        .method(method("PROPFIND"))
+        .uri("/dav/testuser/")
+        .insert_header((header::AUTHORIZATION, basic_auth("testuser", "testpass")))
+        .insert_header(("Depth", "1"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status().as_u16(), 207, "PROPFIND responds multistatus");
+
+    let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+    assert!(
+        body.contains("<D:href>/dav/testuser/apple/</D:href>"),
+        "Listing contains the folder"
+    );
+    assert!(
+        body.contains("<D:href>/dav/testuser/banana.txt</D:href>"),
+        "Listing contains the file"
+    );
+    assert!(
+        body.contains("<D:getcontentlength>12</D:getcontentlength>"),
+        "Listing contains the file size"
+    );
+}
+
+#[actix_web::test]
+async fn test_dav_put_and_get() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    for (contents, status) in [
+        ("Autem tempore", StatusCode::CREATED),
+        ("Et voluptatibu", StatusCode::NO_CONTENT),
+    ] {
+        let req = test::TestRequest::put()
+            .uri("/dav/testuser/test.txt")
+            .insert_header((header::AUTHORIZATION, basic_auth("testuser", "testpass")))
+            .set_payload(contents)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), status, "PUT writes the file");
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/dav/testuser/test.txt")
+        .insert_header((header::AUTHORIZATION, basic_auth("testuser", "testpass")))
+        .to_request();
+    let resp = test::call_and_read_body(&app, req).await;
+    assert_eq!(
+        String::from_utf8(resp.to_vec()).unwrap(),
+        "Et voluptatibu",
+        "PUT replaces the existing file"
+    );
+}
+
+#[actix_web::test]
+async fn test_dav_mkcol_and_move() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Aut suscipit",
+    )
+    .await;
+
+    let req = test::TestRequest::default()
+        .method(method("MKCOL"))
+        .uri("/dav/testuser/apple")
+        .insert_header((header::AUTHORIZATION, basic_auth("testuser", "testpass")))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED, "MKCOL creates a folder");
+
+    let req = test::TestRequest::default()
+        .method(method("MOVE"))
+        .uri("/dav/testuser/test.txt")
+        .insert_header((header::AUTHORIZATION, basic_auth("testuser", "testpass")))
+        .insert_header((
+            "Destination",
+            "http://localhost/dav/testuser/apple/moved%20file.txt",
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED, "MOVE moves the file");
+
+    let contents = fs::read_to_string(
+        PathBuf::from(STORAGE)
+            .join("testuser")
+            .join("apple")
+            .join("moved file.txt"),
+    )
+    .await;
+    assert_eq!(
+        contents.expect("Moved file is missing"),
+        "Aut suscipit",
+        "Moved file has the right contents"
+    );
+}
+
+#[actix_web::test]
+async fn test_dav_move_unauthorized_store() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    ctx.add_user("user2", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    create_file(PathBuf::from(STORAGE).join("testuser").join("test.txt"), "").await;
+
+    let req = test::TestRequest::default()
+        .method(method("MOVE"))
+        .uri("/dav/testuser/test.txt")
+        .insert_header((header::AUTHORIZATION, basic_auth("testuser", "testpass")))
+        .insert_header(("Destination", "/dav/user2/test.txt"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(
+        resp.status().is_client_error(),
+        "MOVE into another user's store is rejected"
+    );
+}
+
+#[actix_web::test]
+async fn test_dav_move_rejects_parent_segments() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    ctx.add_user("user2", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Vel quia",
+    )
+    .await;
+    create_file(
+        PathBuf::from(STORAGE).join("user2").join("victim.txt"),
+        "Et omnis",
+    )
+    .await;
+
+    for destination in [
+        "/dav/testuser/../user2/victim.txt",
+        "http://localhost/dav/testuser/%2E%2E/user2/victim.txt",
+        "/dav/testuser/%2e%2e%2fuser2%2fvictim.txt",
+    ] {
+        let req = test::TestRequest::default()
+            .method(method("MOVE"))
+            .uri("/dav/testuser/test.txt")
+            .insert_header((header::AUTHORIZATION, basic_auth("testuser", "testpass")))
+            .insert_header(("Destination", destination))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(
+            matches!(
+                resp.status(),
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+            ),
+            "MOVE to {destination} is rejected"
+        );
+    }
+
+    let contents = fs::read_to_string(PathBuf::from(STORAGE).join("user2").join("victim.txt"))
+        .await
+        .expect("The other user's file is missing");
+    assert_eq!(contents, "Et omnis", "The other user's file is unchanged");
+    let contents = fs::read_to_string(PathBuf::from(STORAGE).join("testuser").join("test.txt"))
+        .await
+        .expect("The moved file is missing");
+    assert_eq!(contents, "Vel quia", "The file wasn't moved");
+}
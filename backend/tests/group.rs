@@ -0,0 +1,281 @@
+mod common;
+
+use std::path::PathBuf;
+
+use actix_web::{
+    http::{header, StatusCode},
+    test,
+};
+use bulgur_cloud::{
+    auth::add_new_user,
+    cli::{
+        cli_command, CLIContext, Commands, Group, GroupAdd, GroupAddMember, GroupRemoveMember, Opt,
+    },
+    folder::STORAGE,
+    group::{
+        add_member, create_group, delete_group, GroupError, GroupMemberOptions, GroupMembers,
+        UserGroups,
+    },
+    server::setup_app,
+    state::UserType,
+};
+use common::{create_file, TestEnv};
+
+pub struct CLITestContext {}
+impl CLIContext for CLITestContext {
+    fn prompt_password() -> anyhow::Result<String> {
+        Ok("testpass".to_string())
+    }
+}
+
+async fn run_group_command(ctx: &TestEnv, command: Group) {
+    let opt = Opt {
+        command: Some(Commands::Group(command)),
+        bind: Default::default(),
+        datastore: ctx.datastore(),
+        workers: 1,
+        trash_retention_days: 30,
+        kv: Default::default(),
+        versions: Default::default(),
+        tokens: Default::default(),
+        auth: Default::default(),
+    };
+    cli_command::<CLITestContext>(opt)
+        .await
+        .expect("Failed to run command");
+}
+
+fn read_request(token: &str) -> test::TestRequest {
+    test::TestRequest::get()
+        .uri("/storage/team/test.txt")
+        .insert_header((header::AUTHORIZATION, token))
+}
+
+fn member_request(token: &str, username: &str, is_admin: bool) -> test::TestRequest {
+    test::TestRequest::put()
+        .uri(&format!("/api/groups/team/members/{username}"))
+        .insert_header((header::AUTHORIZATION, token))
+        .set_json(GroupMemberOptions { is_admin })
+}
+
+#[actix_web::test]
+async fn test_cli_group_membership() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    run_group_command(
+        &ctx,
+        Group::GroupAdd(GroupAdd {
+            name: "team".to_string(),
+        }),
+    )
+    .await;
+    let store = PathBuf::from(STORAGE).join("team");
+    assert!(store.exists(), "Group store is created");
+    create_file(store.join("test.txt"), "Autem tempore").await;
+
+    let req = read_request(token.reveal()).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "Not a member yet");
+
+    run_group_command(
+        &ctx,
+        Group::GroupAddMember(GroupAddMember {
+            group: "team".to_string(),
+            username: "testuser".to_string(),
+            admin: false,
+        }),
+    )
+    .await;
+    let req = read_request(token.reveal()).to_request();
+    let resp = test::call_and_read_body(&app, req).await;
+    assert_eq!(resp, "Autem tempore", "Members can read files");
+    let req = test::TestRequest::delete()
+        .uri("/storage/team/test.txt")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Members can delete files");
+    let req = test::TestRequest::get()
+        .uri("/api/trash/team")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Members can use the trash");
+
+    run_group_command(
+        &ctx,
+        Group::GroupRemoveMember(GroupRemoveMember {
+            group: "team".to_string(),
+            username: "testuser".to_string(),
+        }),
+    )
+    .await;
+    let req = test::TestRequest::get()
+        .uri("/api/trash/team")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Removed members lose access"
+    );
+}
+
+#[actix_web::test]
+async fn test_group_admin_api() {
+    let ctx = TestEnv::setup().await;
+    let admin = ctx.setup_user_token("testuser", "testpass").await;
+    let member = ctx.setup_user_token("otheruser", "otherpass").await;
+    ctx.add_user("thirduser", "thirdpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_group(&ctx.state(), "team").await.unwrap();
+    add_member(&ctx.state(), "team", "testuser", true)
+        .await
+        .unwrap();
+
+    let req = member_request(admin.reveal(), "otheruser", false).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Admins can add members");
+    create_file(
+        PathBuf::from(STORAGE).join("team").join("test.txt"),
+        "Autem",
+    )
+    .await;
+    let req = read_request(member.reveal()).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK, "New member has access");
+
+    let req = member_request(member.reveal(), "thirduser", false).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::FORBIDDEN,
+        "Members who aren't admins can't add members"
+    );
+    let req = member_request(admin.reveal(), "nobodyhere", false).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "Unknown user");
+
+    let req = test::TestRequest::get()
+        .uri("/api/groups/team/members")
+        .insert_header((header::AUTHORIZATION, member.reveal()))
+        .to_request();
+    let resp: GroupMembers = test::call_and_read_body_json(&app, req).await;
+    let members: Vec<(&str, bool)> = resp
+        .members
+        .iter()
+        .map(|member| (member.username.as_str(), member.is_admin))
+        .collect();
+    assert_eq!(members, vec![("otheruser", false), ("testuser", true)]);
+
+    let req = test::TestRequest::get()
+        .uri("/api/groups")
+        .insert_header((header::AUTHORIZATION, admin.reveal()))
+        .to_request();
+    let resp: UserGroups = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.groups.len(), 1);
+    assert_eq!(resp.groups[0].name, "team");
+    assert!(resp.groups[0].is_admin);
+
+    let req = test::TestRequest::delete()
+        .uri("/api/groups/team/members/testuser")
+        .insert_header((header::AUTHORIZATION, admin.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::BAD_REQUEST,
+        "The last admin can't leave"
+    );
+
+    let req = test::TestRequest::delete()
+        .uri("/api/groups/team/members/otheruser")
+        .insert_header((header::AUTHORIZATION, admin.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Admins can remove members");
+    let req = read_request(member.reveal()).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_member_can_leave() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let member = ctx.setup_user_token("otheruser", "otherpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_group(&ctx.state(), "team").await.unwrap();
+    add_member(&ctx.state(), "team", "testuser", true)
+        .await
+        .unwrap();
+    add_member(&ctx.state(), "team", "otheruser", false)
+        .await
+        .unwrap();
+
+    let req = test::TestRequest::delete()
+        .uri("/api/groups/team/members/testuser")
+        .insert_header((header::AUTHORIZATION, member.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::FORBIDDEN,
+        "Members can't remove others"
+    );
+
+    let req = test::TestRequest::delete()
+        .uri("/api/groups/team/members/otheruser")
+        .insert_header((header::AUTHORIZATION, member.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Members can leave");
+    let req = test::TestRequest::get()
+        .uri("/api/groups/team/members")
+        .insert_header((header::AUTHORIZATION, member.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_group_names() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+
+    let result = create_group(&ctx.state(), "testuser").await;
+    assert!(
+        matches!(result, Err(GroupError::NameTaken(_))),
+        "Group can't take the store of a user"
+    );
+    create_group(&ctx.state(), "team").await.unwrap();
+    let result = create_group(&ctx.state(), "team").await;
+    assert!(matches!(result, Err(GroupError::NameTaken(_))));
+    let result = add_new_user("team", "testpass", UserType::User, &ctx.state()).await;
+    assert!(result.is_err(), "User can't take the store of a group");
+}
+
+#[actix_web::test]
+async fn test_delete_group() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_group(&ctx.state(), "team").await.unwrap();
+    add_member(&ctx.state(), "team", "testuser", true)
+        .await
+        .unwrap();
+    create_file(
+        PathBuf::from(STORAGE).join("team").join("test.txt"),
+        "Autem",
+    )
+    .await;
+
+    delete_group(&ctx.state(), "team", true).await.unwrap();
+    assert!(!PathBuf::from(STORAGE).join("team").exists());
+    let req = read_request(token.reveal()).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,170 @@
+//! These tests need an S3 compatible service to talk to. Start one with
+//!
+//! ```sh
+//! docker run -p 9000:9000 -e MINIO_ROOT_USER=minioadmin -e MINIO_ROOT_PASSWORD=minioadmin minio/minio server /data
+//! ```
+//!
+//! create a bucket named `bulgur-cloud-test`, then run the tests with
+//! `AWS_ACCESS_KEY_ID=minioadmin AWS_SECRET_ACCESS_KEY=minioadmin cargo test --features kv_s3 -- --ignored`.
+#![cfg(feature = "kv_s3")]
+
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use bulgur_cloud::kv::{s3::S3, KVBackend, KVKind, KVOptions};
+use futures::TryStreamExt;
+
+async fn setup() -> (S3, PathBuf) {
+    let options = KVOptions {
+        kind: KVKind::S3,
+        s3_bucket: Some("bulgur-cloud-test".to_string()),
+        s3_endpoint: Some(
+            std::env::var("BULGUR_CLOUD_S3_ENDPOINT")
+                .unwrap_or_else(|_| "http://localhost:9000".to_string()),
+        ),
+        s3_region: Some("us-east-1".to_string()),
+    };
+    let backend = S3::new(&options)
+        .await
+        .expect("Failed to set up the S3 backend");
+    let root = PathBuf::from("storage").join(nanoid::nanoid!());
+    backend
+        .create_dir_all(&root)
+        .await
+        .expect("Failed to create the test folder");
+    (backend, root)
+}
+
+async fn read_string(backend: &S3, path: &Path) -> String {
+    let chunks: Vec<_> = backend
+        .read(path, None)
+        .await
+        .expect("Failed to read file")
+        .try_collect()
+        .await
+        .expect("Failed to read file");
+    String::from_utf8(chunks.concat()).expect("File is not UTF-8")
+}
+
+#[actix_web::test]
+#[ignore]
+async fn test_s3_write_and_list() {
+    let (backend, root) = setup().await;
+
+    let mut part = backend
+        .create_part(&root, "test.txt")
+        .await
+        .expect("Failed to create part");
+    part.write(b"Autem tempore").await.unwrap();
+    part.commit(&root.join("test.txt")).await.unwrap();
+    backend.create_dir(&root.join("apple")).await.unwrap();
+
+    let mut entries = backend.read_dir(&root).await.unwrap();
+    entries.sort_by_key(|entry| entry.name.clone());
+    assert_eq!(entries.len(), 2, "Folder listing contains all entries");
+    assert_eq!(entries[0].name, "apple");
+    assert!(!entries[0].meta.is_file, "Folder is marked as a folder");
+    assert_eq!(entries[1].name, "test.txt");
+    assert_eq!(entries[1].meta.size, 13, "File has the right size");
+    assert_eq!(
+        read_string(&backend, &root.join("test.txt")).await,
+        "Autem tempore"
+    );
+
+    backend.remove(&root).await.unwrap();
+}
+
+#[actix_web::test]
+#[ignore]
+async fn test_s3_commit_does_not_overwrite() {
+    let (backend, root) = setup().await;
+
+    for contents in ["first", "second"] {
+        let mut part = backend.create_part(&root, "test.txt").await.unwrap();
+        part.write(contents.as_bytes()).await.unwrap();
+        let committed = part.commit(&root.join("test.txt")).await;
+        if contents == "second" {
+            assert_eq!(
+                committed.expect_err("Commit should fail").kind(),
+                ErrorKind::AlreadyExists
+            );
+            part.discard().await.unwrap();
+        }
+    }
+    assert_eq!(read_string(&backend, &root.join("test.txt")).await, "first");
+
+    backend.remove(&root).await.unwrap();
+}
+
+#[actix_web::test]
+#[ignore]
+async fn test_s3_rename_folder() {
+    let (backend, root) = setup().await;
+
+    backend.create_dir(&root.join("apple")).await.unwrap();
+    let mut part = backend
+        .create_part(&root.join("apple"), "test.txt")
+        .await
+        .unwrap();
+    part.write(b"Et voluptatibu").await.unwrap();
+    part.commit(&root.join("apple").join("test.txt"))
+        .await
+        .unwrap();
+
+    backend
+        .rename(&root.join("apple"), &root.join("banana"))
+        .await
+        .unwrap();
+    assert_eq!(
+        read_string(&backend, &root.join("banana").join("test.txt")).await,
+        "Et voluptatibu"
+    );
+    assert_eq!(
+        backend
+            .metadata(&root.join("apple"))
+            .await
+            .expect_err("Old folder should be gone")
+            .kind(),
+        ErrorKind::NotFound
+    );
+
+    backend.remove(&root).await.unwrap();
+}
+
+#[actix_web::test]
+#[ignore]
+async fn test_s3_large_commit() {
+    let (backend, root) = setup().await;
+    let path = root.join("large.bin");
+    // Large enough to be sent in more than one part
+    let contents = vec![7u8; 9 * 1024 * 1024];
+
+    let mut part = backend.create_part(&root, "large.bin").await.unwrap();
+    part.write(&contents).await.unwrap();
+    part.commit(&path).await.unwrap();
+    assert_eq!(
+        backend.metadata(&path).await.unwrap().size,
+        contents.len() as u64
+    );
+
+    let mut part = backend.create_part(&root, "large.bin").await.unwrap();
+    part.write(&contents[1..]).await.unwrap();
+    assert_eq!(
+        part.commit(&path)
+            .await
+            .expect_err("Commit should fail")
+            .kind(),
+        ErrorKind::AlreadyExists
+    );
+    part.replace(&path).await.unwrap();
+    assert_eq!(
+        backend.metadata(&path).await.unwrap().size,
+        contents.len() as u64 - 1
+    );
+    let entries = backend.read_dir(&root).await.unwrap();
+    assert_eq!(entries.len(), 1, "Nothing is left over from the upload");
+
+    backend.remove(&root).await.unwrap();
+}
@@ -0,0 +1,380 @@
+mod common;
+
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use actix_web::{
+    http::{header, StatusCode},
+    test,
+};
+use bulgur_cloud::{
+    auth::{add_new_user, setup_auth, AuthKind, AuthOptions, LoginResponse, Password},
+    entity::user,
+    folder::STORAGE,
+    ldap::LdapOptions,
+    server::setup_app,
+    session::PasswordChange,
+    state::UserType,
+};
+use bytes::{Buf, BytesMut};
+use common::{login_request, TestEnv};
+use ldap3::asn1::{
+    parse_tag, write, ASNTag, Enumerated, Integer, OctetString, Sequence, StructureTag, Tag,
+    TagClass, PL,
+};
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+const PEOPLE: &str = "ou=people,dc=example,dc=com";
+const ADMINS: &str = "cn=admins,ou=groups,dc=example,dc=com";
+
+const BIND_REQUEST: u64 = 0;
+const BIND_RESPONSE: u64 = 1;
+const UNBIND_REQUEST: u64 = 2;
+const SEARCH_REQUEST: u64 = 3;
+const SEARCH_RESULT_ENTRY: u64 = 4;
+const SEARCH_RESULT_DONE: u64 = 5;
+const EQUALITY_MATCH: u64 = 3;
+
+const SUCCESS: i64 = 0;
+const NO_SUCH_OBJECT: i64 = 32;
+const INVALID_CREDENTIALS: i64 = 49;
+
+/// A directory server that understands just enough LDAP to check passwords:
+/// simple binds, base searches for group members, and unbinds.
+struct MockDirectory {
+    /// The passwords of users, by their DN.
+    passwords: HashMap<String, String>,
+    /// The DNs of the members of groups, by the DN of the group.
+    groups: HashMap<String, Vec<String>>,
+}
+
+fn user_dn(username: &str) -> String {
+    format!("uid={username},{PEOPLE}")
+}
+
+impl MockDirectory {
+    /// A directory with an empty admin group.
+    fn new() -> Self {
+        MockDirectory {
+            passwords: HashMap::new(),
+            groups: HashMap::from([(ADMINS.to_string(), Vec::new())]),
+        }
+    }
+
+    fn with_user(mut self, username: &str, password: &str) -> Self {
+        self.passwords
+            .insert(user_dn(username), password.to_string());
+        self
+    }
+
+    fn with_admin(mut self, username: &str) -> Self {
+        self.groups.get_mut(ADMINS).unwrap().push(user_dn(username));
+        self
+    }
+
+    /// Starts listening on a random port, returns the URL of the server.
+    async fn start(self) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        let directory = Arc::new(self);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(directory.clone().serve(stream));
+            }
+        });
+        url
+    }
+
+    async fn serve(self: Arc<Self>, mut stream: TcpStream) {
+        let mut buf = BytesMut::new();
+        let mut bound: Option<String> = None;
+        loop {
+            let parsed = parse_tag(&buf)
+                .ok()
+                .map(|(rest, tag)| (buf.len() - rest.len(), tag));
+            let Some((length, message)) = parsed else {
+                if stream.read_buf(&mut buf).await.unwrap_or(0) == 0 {
+                    return;
+                }
+                continue;
+            };
+            buf.advance(length);
+
+            let mut parts = children(message).into_iter();
+            let id = integer(parts.next().unwrap());
+            let op = parts.next().unwrap();
+            let mut out = BytesMut::new();
+            match op.id {
+                BIND_REQUEST => {
+                    let mut fields = children(op).into_iter().skip(1);
+                    let dn = string(fields.next().unwrap());
+                    let password = string(fields.next().unwrap());
+                    // Like real servers, an empty password is an anonymous bind
+                    let code = if password.is_empty()
+                        || self.passwords.get(&dn).is_some_and(|p| *p == password)
+                    {
+                        bound = (!password.is_empty()).then_some(dn);
+                        SUCCESS
+                    } else {
+                        INVALID_CREDENTIALS
+                    };
+                    respond(&mut out, id, BIND_RESPONSE, result(code));
+                }
+                SEARCH_REQUEST => {
+                    let mut fields = children(op).into_iter();
+                    let base = string(fields.next().unwrap());
+                    let filter = fields.nth(5).unwrap();
+                    let code = match self.groups.get(&base) {
+                        Some(members) => {
+                            let mut values = Vec::new();
+                            equality_values(filter, &mut values);
+                            let is_member = bound
+                                .as_ref()
+                                .is_some_and(|dn| members.contains(dn) && values.contains(dn));
+                            if is_member {
+                                respond(
+                                    &mut out,
+                                    id,
+                                    SEARCH_RESULT_ENTRY,
+                                    vec![octets(&base), Tag::Sequence(Sequence::default())],
+                                );
+                            }
+                            SUCCESS
+                        }
+                        None => NO_SUCH_OBJECT,
+                    };
+                    respond(&mut out, id, SEARCH_RESULT_DONE, result(code));
+                }
+                UNBIND_REQUEST => return,
+                _ => panic!("Unexpected LDAP operation {}", op.id),
+            }
+            stream.write_all(&out).await.unwrap();
+        }
+    }
+}
+
+fn children(tag: StructureTag) -> Vec<StructureTag> {
+    tag.expect_constructed()
+        .expect("Expected a constructed tag")
+}
+
+fn string(tag: StructureTag) -> String {
+    String::from_utf8(tag.expect_primitive().expect("Expected a primitive tag")).unwrap()
+}
+
+fn integer(tag: StructureTag) -> i64 {
+    tag.expect_primitive()
+        .expect("Expected a primitive tag")
+        .iter()
+        .fold(0, |value, byte| (value << 8) | *byte as i64)
+}
+
+/// Collects the values the filter is looking for.
+fn equality_values(filter: StructureTag, values: &mut Vec<String>) {
+    let is_equality = filter.class == TagClass::Context && filter.id == EQUALITY_MATCH;
+    if let PL::C(inner) = filter.payload {
+        if is_equality {
+            values.push(string(inner.into_iter().nth(1).unwrap()));
+        } else {
+            for tag in inner {
+                equality_values(tag, values);
+            }
+        }
+    }
+}
+
+fn octets(value: &str) -> Tag {
+    Tag::OctetString(OctetString {
+        inner: value.as_bytes().to_vec(),
+        ..Default::default()
+    })
+}
+
+fn result(code: i64) -> Vec<Tag> {
+    vec![
+        Tag::Enumerated(Enumerated {
+            inner: code,
+            ..Default::default()
+        }),
+        octets(""),
+        octets(""),
+    ]
+}
+
+fn respond(out: &mut BytesMut, id: i64, op: u64, inner: Vec<Tag>) {
+    let message = Tag::Sequence(Sequence {
+        inner: vec![
+            Tag::Integer(Integer {
+                inner: id,
+                ..Default::default()
+            }),
+            Tag::Sequence(Sequence {
+                id: op,
+                class: TagClass::Application,
+                inner,
+            }),
+        ],
+        ..Default::default()
+    });
+    write::encode_into(out, message.into_structure()).unwrap();
+}
+
+fn ldap_options(url: String) -> AuthOptions {
+    AuthOptions {
+        kind: AuthKind::Ldap,
+        ldap: LdapOptions {
+            ldap_url: Some(url),
+            ldap_starttls: false,
+            ldap_user_dn: Some(format!("uid={{username}},{PEOPLE}")),
+            ldap_admin_group: Some(ADMINS.to_string()),
+        },
+        ..Default::default()
+    }
+}
+
+async fn find_user(ctx: &TestEnv, username: &str) -> Option<user::Model> {
+    user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(&ctx.state().db)
+        .await
+        .unwrap()
+}
+
+#[actix_web::test]
+async fn test_ldap_login_adds_user() {
+    let url = MockDirectory::new()
+        .with_user("alice", "alicepass")
+        .start()
+        .await;
+    let ctx = TestEnv::setup_with_auth(ldap_options(url)).await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let resp = test::call_service(&app, login_request("alice", "wrongpass").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(
+        find_user(&ctx, "alice").await.is_none(),
+        "Users aren't added until they log in"
+    );
+
+    let resp: LoginResponse =
+        test::call_and_read_body_json(&app, login_request("alice", "alicepass").to_request()).await;
+    let user = find_user(&ctx, "alice").await.expect("User is added");
+    assert_eq!(UserType::from_db_value(&user.user_type), UserType::User);
+    ctx.state()
+        .storage
+        .metadata(&Path::new(STORAGE).join("alice"))
+        .await
+        .expect("User has a store");
+
+    let req = test::TestRequest::get()
+        .uri("/storage/alice/")
+        .insert_header((header::AUTHORIZATION, resp.access_token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Logging in again uses the same account
+    let resp = test::call_service(&app, login_request("alice", "alicepass").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn test_ldap_rejects_bad_logins() {
+    let url = MockDirectory::new()
+        .with_user("alice", "alicepass")
+        .start()
+        .await;
+    let ctx = TestEnv::setup_with_auth(ldap_options(url)).await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    for (username, password) in [
+        ("bob", "alicepass"),
+        ("alice", ""),
+        ("bob", ""),
+        ("nobody", ""),
+        ("../alice", "alicepass"),
+    ] {
+        let resp = test::call_service(&app, login_request(username, password).to_request()).await;
+        assert_eq!(
+            resp.status(),
+            StatusCode::UNAUTHORIZED,
+            "{username} with {password:?}"
+        );
+    }
+    assert!(find_user(&ctx, "bob").await.is_none());
+}
+
+#[actix_web::test]
+async fn test_ldap_admin_group() {
+    let url = MockDirectory::new()
+        .with_user("alice", "alicepass")
+        .with_user("bob", "bobpass")
+        .with_admin("bob")
+        .start()
+        .await;
+    let ctx = TestEnv::setup_with_auth(ldap_options(url)).await;
+    // Alice was made an admin by hand, but isn't in the admin group
+    add_new_user("alice", "localpass", UserType::Admin, &ctx.state())
+        .await
+        .unwrap();
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let resp = test::call_service(&app, login_request("bob", "bobpass").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let bob = find_user(&ctx, "bob").await.unwrap();
+    assert_eq!(UserType::from_db_value(&bob.user_type), UserType::Admin);
+
+    let resp = test::call_service(&app, login_request("alice", "localpass").to_request()).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Local passwords aren't used"
+    );
+    let resp = test::call_service(&app, login_request("alice", "alicepass").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let alice = find_user(&ctx, "alice").await.unwrap();
+    assert_eq!(
+        UserType::from_db_value(&alice.user_type),
+        UserType::User,
+        "Type follows the directory"
+    );
+}
+
+#[actix_web::test]
+async fn test_ldap_password_change_rejected() {
+    let url = MockDirectory::new()
+        .with_user("alice", "alicepass")
+        .start()
+        .await;
+    let ctx = TestEnv::setup_with_auth(ldap_options(url)).await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let resp: LoginResponse =
+        test::call_and_read_body_json(&app, login_request("alice", "alicepass").to_request()).await;
+
+    let req = test::TestRequest::put()
+        .uri("/api/password")
+        .insert_header((header::AUTHORIZATION, resp.access_token.reveal()))
+        .set_json(PasswordChange {
+            current_password: Password("alicepass".to_string()),
+            new_password: Password("newpass".to_string()),
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+}
+
+#[actix_web::test]
+async fn test_ldap_options_required() {
+    let mut options = ldap_options("ldap://127.0.0.1:1".to_string());
+    assert!(setup_auth(&options).is_ok());
+    options.ldap.ldap_user_dn = Some(PEOPLE.to_string());
+    assert!(setup_auth(&options).is_err(), "DN needs the username");
+    options.ldap.ldap_user_dn = None;
+    assert!(setup_auth(&options).is_err());
+    options.ldap.ldap_url = None;
+    options.ldap.ldap_user_dn = Some(format!("uid={{username}},{PEOPLE}"));
+    assert!(setup_auth(&options).is_err());
+}
@@ -241,6 +241,35 @@ async fn test_upload_file() {
     );
 }
 
+#[actix_web::test]
+async fn test_upload_file_overwrite() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+    .uri("/basic/testuser/?_method=PUT")
+    .insert_header((header::AUTHORIZATION, token.reveal()))
+    .set_payload("--zzz\r\nContent-Disposition: form-data; name=\"conflict\"\r\n\r\noverwrite\r\n--zzz\r\nContent-Disposition: form-data; name=\"files\"; filename=\"test.txt\"\r\n\r\nEt voluptatibu\r\n--zzz--\r\n\r\n")
+    .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=zzz"))
+    .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 303, "Responds with a redirect");
+
+    let contents =
+        tokio::fs::read_to_string(PathBuf::from(STORAGE).join("testuser").join("test.txt")).await;
+    assert_eq!(
+        contents.unwrap(),
+        "Et voluptatibu",
+        "The conflict field in the form picks the policy"
+    );
+}
+
 #[actix_web::test]
 async fn test_basic_login() {
     let ctx = TestEnv::setup().await;
@@ -250,6 +279,7 @@ async fn test_basic_login() {
     let login = LoginFormData {
         username: "testuser".to_string(),
         password: Password("testpass".to_string()),
+        code: None,
     };
 
     let req = test::TestRequest::post()
@@ -297,3 +327,80 @@ async fn test_basic_logout() {
         "logout cookie erases the contents"
     );
 }
+
+#[actix_web::test]
+async fn test_shared_folder_page() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let shared = PathBuf::from(STORAGE).join("testuser").join("shared");
+    create_dir(shared.clone()).await;
+    create_dir(shared.join("apple")).await;
+    create_file(shared.join("apple").join("banana.txt"), "Quia dolorum").await;
+    let token = ctx.setup_path_token("storage/testuser/shared").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let uri = format!("/basic/testuser/shared/?token={}", token.reveal());
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let resp = test::call_and_read_body(&app, req).await;
+    let resp_str = String::from_utf8(resp.to_vec()).expect("Failed to read response body");
+    assert!(
+        resp_str.contains(&format!(
+            "/basic/testuser/shared/apple/?token={}",
+            token.reveal()
+        )),
+        "Links keep the token"
+    );
+    assert!(
+        !resp_str.contains("Go up"),
+        "Can't go up past the shared folder"
+    );
+    assert!(!resp_str.contains("Delete"), "Shared folder is read only");
+    assert!(!resp_str.contains("Upload"), "Shared folder is read only");
+
+    let uri = format!("/basic/testuser/shared/apple/?token={}", token.reveal());
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let resp = test::call_and_read_body(&app, req).await;
+    let resp_str = String::from_utf8(resp.to_vec()).expect("Failed to read response body");
+    assert!(resp_str.contains("banana.txt"), "Subfolders can be browsed");
+    assert!(resp_str.contains("Go up"), "Subfolders link back up");
+
+    let uri = format!(
+        "/basic/testuser/shared/apple/banana.txt?token={}",
+        token.reveal()
+    );
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    assert_eq!(body, "Quia dolorum", "Files can be downloaded");
+}
+
+#[actix_web::test]
+async fn test_shared_folder_is_read_only() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let shared = PathBuf::from(STORAGE).join("testuser").join("shared");
+    create_dir(shared.clone()).await;
+    create_file(shared.join("banana.txt"), "Quia dolorum").await;
+    let token = ctx.setup_path_token("storage/testuser/shared").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let uri = format!(
+        "/basic/testuser/shared/banana.txt?_method=DELETE&token={}",
+        token.reveal()
+    );
+    let req = test::TestRequest::post().uri(&uri).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Can't delete files"
+    );
+    assert!(shared.join("banana.txt").exists(), "File is still there");
+
+    let uri = format!("/basic/testuser/shared/?trash&token={}", token.reveal());
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(
+        resp.status().is_client_error(),
+        "Can't see the trash of the store"
+    );
+}
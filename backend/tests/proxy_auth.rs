@@ -0,0 +1,170 @@
+mod common;
+
+use std::{net::SocketAddr, path::Path};
+
+use actix_web::{
+    http::{header, StatusCode},
+    test,
+};
+use bulgur_cloud::{
+    auth::AuthOptions,
+    entity::user,
+    folder::STORAGE,
+    proxy_auth::{setup_proxy_auth, ProxyAuthOptions},
+    server::setup_app,
+};
+use common::{read_header, TestEnv};
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+
+const USER_HEADER: &str = "X-Remote-User";
+
+fn proxy_options() -> AuthOptions {
+    AuthOptions {
+        proxy: ProxyAuthOptions {
+            proxy_auth_header: Some(USER_HEADER.to_string()),
+            proxy_auth_trusted: vec!["10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()],
+        },
+        ..Default::default()
+    }
+}
+
+fn from_address(address: &str) -> SocketAddr {
+    format!("{address}:40000").parse().unwrap()
+}
+
+fn proxied_request(uri: &str, username: &str, address: &str) -> test::TestRequest {
+    test::TestRequest::get()
+        .uri(uri)
+        .peer_addr(from_address(address))
+        .insert_header((USER_HEADER, username))
+}
+
+async fn find_user(ctx: &TestEnv, username: &str) -> Option<user::Model> {
+    user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(&ctx.state().db)
+        .await
+        .unwrap()
+}
+
+#[actix_web::test]
+async fn test_proxy_auth_adds_user() {
+    let ctx = TestEnv::setup_with_auth(proxy_options()).await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = proxied_request("/storage/alice/", "alice", "10.1.2.3").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(find_user(&ctx, "alice").await.is_some(), "User is added");
+    ctx.state()
+        .storage
+        .metadata(&Path::new(STORAGE).join("alice"))
+        .await
+        .expect("User has a store");
+
+    // The proxy can also be an IPv6 address
+    let req = proxied_request("/api/stats", "alice", "[::1]").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Users can only see their own stores
+    let req = proxied_request("/storage/bob/", "alice", "10.1.2.3").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_proxy_auth_existing_user() {
+    let ctx = TestEnv::setup_with_auth(proxy_options()).await;
+    ctx.add_user("alice", "alicepass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = proxied_request("/storage/alice/", "alice", "10.1.2.3").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Tokens still work for requests that don't come through the proxy
+    let token = ctx.setup_user_token("alice", "alicepass").await;
+    let req = test::TestRequest::get()
+        .uri("/storage/alice/")
+        .peer_addr(from_address("192.168.1.1"))
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn test_proxy_auth_untrusted() {
+    let ctx = TestEnv::setup_with_auth(proxy_options()).await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = proxied_request("/storage/alice/", "alice", "192.168.1.1").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    // Forwarding headers don't make the request trusted
+    let req = proxied_request("/storage/alice/", "alice", "192.168.1.1")
+        .insert_header(("X-Forwarded-For", "10.1.2.3"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(find_user(&ctx, "alice").await.is_none());
+
+    // The header is ignored unless the option is set
+    let ctx = TestEnv::setup().await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let req = proxied_request("/storage/alice/", "alice", "10.1.2.3").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(find_user(&ctx, "alice").await.is_none());
+}
+
+#[actix_web::test]
+async fn test_proxy_auth_bad_usernames() {
+    let ctx = TestEnv::setup_with_auth(proxy_options()).await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    for username in ["nobody", "../alice", ""] {
+        let req = proxied_request("/api/stats", username, "10.1.2.3").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.status(),
+            StatusCode::UNAUTHORIZED,
+            "{username:?} is rejected"
+        );
+    }
+}
+
+#[actix_web::test]
+async fn test_proxy_auth_login_page() {
+    let ctx = TestEnv::setup_with_auth(proxy_options()).await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = proxied_request("/basic/", "alice", "10.1.2.3").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    assert_eq!(read_header(&resp, header::LOCATION), "/basic/alice/");
+    let req = proxied_request("/basic/alice/", "alice", "10.1.2.3").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = proxied_request("/basic/", "alice", "192.168.1.1").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Others have to log in");
+}
+
+#[actix_web::test]
+async fn test_proxy_auth_options_required() {
+    let mut options = proxy_options();
+    assert!(setup_proxy_auth(&options.proxy).unwrap().is_some());
+    options.proxy.proxy_auth_header = Some("Not a header".to_string());
+    assert!(setup_proxy_auth(&options.proxy).is_err());
+    options.proxy.proxy_auth_header = Some(USER_HEADER.to_string());
+    options.proxy.proxy_auth_trusted = vec![];
+    assert!(
+        setup_proxy_auth(&options.proxy).is_err(),
+        "The trusted proxies have to be set"
+    );
+    options.proxy.proxy_auth_header = None;
+    assert!(setup_proxy_auth(&options.proxy).unwrap().is_none());
+}
@@ -0,0 +1,260 @@
+mod common;
+
+use std::path::PathBuf;
+
+use actix_web::{
+    http::{header, StatusCode},
+    test,
+};
+use bulgur_cloud::{
+    cli::{cli_command, CLIContext, Commands, Opt, User, UserQuota},
+    folder::{STORAGE, VERSIONS},
+    quota::{quota_usage, set_quota},
+    server::setup_app,
+    storage::StorageAction,
+    trash::list_trash,
+    versions::list_versions,
+};
+use common::{create_file, upload_request, TestEnv};
+use serde::Deserialize;
+use tokio::fs;
+
+#[derive(Deserialize)]
+struct StatsUsage {
+    usage: u64,
+    quota: Option<u64>,
+    remaining: Option<u64>,
+}
+
+fn stats_request(token: &str) -> test::TestRequest {
+    test::TestRequest::get()
+        .uri("/api/stats")
+        .insert_header((header::AUTHORIZATION, token))
+}
+
+#[actix_web::test]
+async fn test_stats_show_usage() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+
+    let req = stats_request(token.reveal()).to_request();
+    let resp: StatsUsage = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.usage, 13, "Usage is calculated from the store");
+    assert_eq!(resp.quota, None, "There is no limit by default");
+    assert_eq!(resp.remaining, None);
+
+    set_quota(&ctx.state().db, "testuser", Some(100))
+        .await
+        .unwrap();
+    let req = stats_request(token.reveal()).to_request();
+    let resp: StatsUsage = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.quota, Some(100));
+    assert_eq!(resp.remaining, Some(87));
+}
+
+#[actix_web::test]
+async fn test_usage_follows_upload_and_delete() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    set_quota(&ctx.state().db, "testuser", Some(100))
+        .await
+        .unwrap();
+
+    let req = upload_request(
+        token.reveal(),
+        "/storage/testuser/",
+        "test.txt",
+        "Et voluptatibu",
+    )
+    .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let usage = quota_usage(&ctx.state(), "testuser").await.unwrap();
+    assert_eq!(usage.usage, 14, "Upload is counted");
+
+    let req = test::TestRequest::delete()
+        .uri("/storage/testuser/test.txt")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    test::call_service(&app, req).await;
+    let usage = quota_usage(&ctx.state(), "testuser").await.unwrap();
+    assert_eq!(usage.usage, 0, "Deleted file is not counted");
+}
+
+#[actix_web::test]
+async fn test_upload_over_quota() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    set_quota(&ctx.state().db, "testuser", Some(10))
+        .await
+        .unwrap();
+
+    let req = upload_request(
+        token.reveal(),
+        "/storage/testuser/",
+        "test.txt",
+        "Et voluptatibu",
+    )
+    .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::INSUFFICIENT_STORAGE,
+        "Upload over the quota is rejected"
+    );
+
+    let mut entries = fs::read_dir(PathBuf::from(STORAGE).join("testuser"))
+        .await
+        .unwrap();
+    assert!(
+        entries.next_entry().await.unwrap().is_none(),
+        "Nothing is left behind, including the part file"
+    );
+    let usage = quota_usage(&ctx.state(), "testuser").await.unwrap();
+    assert_eq!(usage.usage, 0);
+}
+
+#[actix_web::test]
+async fn test_move_over_file_frees_space() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("other.txt"),
+        "Et voluptatibu",
+    )
+    .await;
+    let usage = quota_usage(&ctx.state(), "testuser").await.unwrap();
+    assert_eq!(usage.usage, 27);
+
+    let req = test::TestRequest::post()
+        .uri("/storage/testuser/other.txt")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .set_json(StorageAction::Move {
+            new_path: "/testuser/test.txt".to_string(),
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let usage = quota_usage(&ctx.state(), "testuser").await.unwrap();
+    assert_eq!(usage.usage, 14, "Replaced file is no longer counted");
+}
+
+#[actix_web::test]
+async fn test_trash_and_versions_fit_in_quota() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    set_quota(&ctx.state().db, "testuser", Some(20))
+        .await
+        .unwrap();
+
+    let req = upload_request(
+        token.reveal(),
+        "/storage/testuser/",
+        "test.txt",
+        "Et voluptatibu",
+    )
+    .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = test::TestRequest::delete()
+        .uri("/storage/testuser/test.txt")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    assert_eq!(list_trash(&ctx.state(), "testuser").await.unwrap().len(), 1);
+
+    let req = upload_request(
+        token.reveal(),
+        "/storage/testuser/",
+        "test.txt",
+        "Et voluptatibu",
+    )
+    .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    assert!(
+        list_trash(&ctx.state(), "testuser")
+            .await
+            .unwrap()
+            .is_empty(),
+        "The trash is emptied to make room"
+    );
+
+    let req = upload_request(
+        token.reveal(),
+        "/storage/testuser/",
+        "test.txt",
+        "Autem tempore",
+    )
+    .uri("/storage/testuser/?conflict=overwrite")
+    .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let store_path = PathBuf::from(STORAGE).join("testuser").join("test.txt");
+    assert!(
+        list_versions(&ctx.state(), &store_path)
+            .await
+            .unwrap()
+            .is_empty(),
+        "Versions that don't fit are removed"
+    );
+    assert_eq!(
+        fs::read_to_string(&store_path).await.unwrap(),
+        "Autem tempore",
+        "The file itself is kept"
+    );
+    let mut versions = fs::read_dir(PathBuf::from(VERSIONS).join("testuser"))
+        .await
+        .unwrap();
+    assert!(
+        versions.next_entry().await.unwrap().is_none(),
+        "Removed versions are gone from the disk"
+    );
+}
+
+pub struct CLITestContext {}
+impl CLIContext for CLITestContext {
+    fn prompt_password() -> anyhow::Result<String> {
+        Ok("testpass".to_string())
+    }
+}
+
+#[actix_web::test]
+async fn test_cli_user_quota() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+
+    let command = Commands::User(User::UserQuota(UserQuota {
+        username: "testuser".to_string(),
+        quota: Some(1024),
+    }));
+    let opt = Opt {
+        command: Some(command),
+        bind: Default::default(),
+        datastore: ctx.datastore(),
+        workers: 1,
+        trash_retention_days: 30,
+        kv: Default::default(),
+        versions: Default::default(),
+        tokens: Default::default(),
+        auth: Default::default(),
+    };
+    cli_command::<CLITestContext>(opt)
+        .await
+        .expect("Failed to run command");
+
+    let usage = quota_usage(&ctx.state(), "testuser").await.unwrap();
+    assert_eq!(usage.quota, Some(1024), "Quota is set");
+}
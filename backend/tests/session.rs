@@ -0,0 +1,497 @@
+mod common;
+
+use std::path::Path;
+
+use actix_web::{
+    cookie::Cookie,
+    http::{header, StatusCode},
+    test,
+};
+use bulgur_cloud::{
+    auth::{LoginResponse, Password},
+    auth_middleware::AUTH_COOKIE_NAME,
+    entity::{path_token, user_token},
+    pages::{PasswordForm, SessionForm},
+    server::setup_app,
+    session::{delete_expired_tokens, PasswordChange, Sessions, TokenOptions},
+    state::Token,
+};
+use chrono::{Duration, Utc};
+use common::{login_request, read_header, TestEnv};
+use sea_orm::{sea_query::Expr, ColumnTrait, EntityTrait, QueryFilter};
+
+/// Logs in as `testuser` from a client with the given name.
+fn client_login_request(password: &str, client: &str) -> test::TestRequest {
+    login_request("testuser", password).insert_header((header::USER_AGENT, client))
+}
+
+/// Pretends the token was made and last used this many hours ago.
+async fn age_token(ctx: &TestEnv, token: &Token, created_hours: i64, used_hours: i64) {
+    let created_at = Utc::now() - Duration::hours(created_hours);
+    let last_used_at = Utc::now() - Duration::hours(used_hours);
+    user_token::Entity::update_many()
+        .col_expr(
+            user_token::Column::CreatedAt,
+            Expr::value(created_at.to_rfc3339()),
+        )
+        .col_expr(
+            user_token::Column::LastUsedAt,
+            Expr::value(last_used_at.to_rfc3339()),
+        )
+        .filter(user_token::Column::Token.eq(ctx.state().hash_token(token)))
+        .exec(&ctx.state().db)
+        .await
+        .unwrap();
+}
+
+async fn find_token(ctx: &TestEnv, token: &Token) -> Option<user_token::Model> {
+    user_token::Entity::find_by_id(ctx.state().hash_token(token))
+        .one(&ctx.state().db)
+        .await
+        .unwrap()
+}
+
+fn stats_request(token: &Token) -> test::TestRequest {
+    test::TestRequest::get()
+        .uri("/api/stats")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+}
+
+#[actix_web::test]
+async fn test_list_and_revoke_sessions() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let other = ctx.setup_user_token("otheruser", "otherpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = client_login_request("testpass", "Phone").to_request();
+    let phone: LoginResponse = test::call_and_read_body_json(&app, req).await;
+    let req = client_login_request("testpass", "Laptop").to_request();
+    let laptop: LoginResponse = test::call_and_read_body_json(&app, req).await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/sessions")
+        .insert_header((header::AUTHORIZATION, laptop.access_token.reveal()))
+        .to_request();
+    let resp: Sessions = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.sessions.len(), 2);
+    let current: Vec<(Option<&str>, bool)> = resp
+        .sessions
+        .iter()
+        .map(|session| (session.client.as_deref(), session.current))
+        .collect();
+    assert!(current.contains(&(Some("Laptop"), true)));
+    assert!(current.contains(&(Some("Phone"), false)));
+    let phone_session = resp
+        .sessions
+        .iter()
+        .find(|session| !session.current)
+        .unwrap();
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/api/sessions/{}", phone_session.id))
+        .insert_header((header::AUTHORIZATION, other.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::NOT_FOUND,
+        "Users can't revoke the sessions of others"
+    );
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/api/sessions/{}", phone_session.id))
+        .insert_header((header::AUTHORIZATION, laptop.access_token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, stats_request(&phone.access_token).to_request()).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Session is revoked"
+    );
+    let resp = test::call_service(&app, stats_request(&laptop.access_token).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Other sessions still work");
+}
+
+#[actix_web::test]
+async fn test_logout_everywhere() {
+    let ctx = TestEnv::setup().await;
+    let first = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let req = client_login_request("testpass", "Laptop").to_request();
+    let second: LoginResponse = test::call_and_read_body_json(&app, req).await;
+
+    let req = test::TestRequest::delete()
+        .uri("/api/sessions")
+        .insert_header((header::AUTHORIZATION, first.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    for token in [&first, &second.access_token] {
+        let resp = test::call_service(&app, stats_request(token).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[actix_web::test]
+async fn test_logout_deletes_token() {
+    let ctx = TestEnv::setup().await;
+    let api_token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/logout")
+        .insert_header((header::AUTHORIZATION, api_token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, stats_request(&api_token).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let req = client_login_request("testpass", "Browser").to_request();
+    let basic: LoginResponse = test::call_and_read_body_json(&app, req).await;
+    let req = test::TestRequest::post()
+        .uri("/basic/logout")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, basic.access_token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    let resp = test::call_service(&app, stats_request(&basic.access_token).to_request()).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Basic logout deletes the token, not just the cookie"
+    );
+}
+
+#[actix_web::test]
+async fn test_change_password() {
+    let ctx = TestEnv::setup().await;
+    let other_session = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let req = client_login_request("testpass", "Laptop").to_request();
+    let current: LoginResponse = test::call_and_read_body_json(&app, req).await;
+
+    let req = test::TestRequest::put()
+        .uri("/api/password")
+        .insert_header((header::AUTHORIZATION, current.access_token.reveal()))
+        .set_json(PasswordChange {
+            current_password: Password("wrongpass".to_string()),
+            new_password: Password("newpass".to_string()),
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::FORBIDDEN,
+        "The current password is checked"
+    );
+
+    let req = test::TestRequest::put()
+        .uri("/api/password")
+        .insert_header((header::AUTHORIZATION, current.access_token.reveal()))
+        .set_json(PasswordChange {
+            current_password: Password("testpass".to_string()),
+            new_password: Password("newpass".to_string()),
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = test::call_service(&app, stats_request(&current.access_token).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Current session stays");
+    let resp = test::call_service(&app, stats_request(&other_session).to_request()).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Other sessions are logged out"
+    );
+    let resp = test::call_service(
+        &app,
+        client_login_request("testpass", "Laptop").to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp =
+        test::call_service(&app, client_login_request("newpass", "Laptop").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn test_basic_account_page() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    ctx.add_user("otheruser", "otherpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::get()
+        .uri("/basic/testuser/?account")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .to_request();
+    let resp = test::call_and_read_body(&app, req).await;
+    let page = String::from_utf8(resp.to_vec()).unwrap();
+    assert!(page.contains("This session"));
+    assert!(page.contains("Change password"));
+
+    let req = test::TestRequest::get()
+        .uri("/basic/otheruser/?account")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::post()
+        .uri("/basic/testuser/?_method=PASSWORD")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .set_form(PasswordForm {
+            current_password: Password("testpass".to_string()),
+            new_password: Password("newpass".to_string()),
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    let resp = test::call_service(
+        &app,
+        client_login_request("newpass", "Browser").to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK, "Password is changed");
+
+    // Logging out another session keeps this one logged in
+    let req = test::TestRequest::get()
+        .uri("/api/sessions")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp: Sessions = test::call_and_read_body_json(&app, req).await;
+    let other_session = resp
+        .sessions
+        .iter()
+        .find(|session| !session.current)
+        .unwrap();
+    let req = test::TestRequest::post()
+        .uri("/basic/testuser/?_method=LOGOUT")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .set_form(SessionForm {
+            session: Some(other_session.id.clone()),
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    assert_eq!(
+        read_header(&resp, header::LOCATION),
+        "/basic/testuser/?account",
+        "Still logged in"
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/basic/testuser/?_method=LOGOUT")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .set_form(SessionForm { session: None })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    let resp = test::call_service(&app, stats_request(&token).to_request()).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Logged out everywhere"
+    );
+}
+
+#[actix_web::test]
+async fn test_token_expiry() {
+    let ctx = TestEnv::setup().await;
+    let too_old = ctx.setup_user_token("testuser", "testpass").await;
+    let idle = ctx.setup_user_token("otheruser", "otherpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let req = client_login_request("testpass", "Laptop").to_request();
+    let active: LoginResponse = test::call_and_read_body_json(&app, req).await;
+
+    age_token(&ctx, &too_old, 24 * 31, 1).await;
+    age_token(&ctx, &idle, 24 * 10, 24 * 8).await;
+    age_token(&ctx, &active.access_token, 24 * 10, 2).await;
+
+    for token in [&too_old, &idle] {
+        let resp = test::call_service(&app, stats_request(token).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(
+            find_token(&ctx, token).await.is_none(),
+            "Expired tokens are deleted"
+        );
+    }
+
+    let resp = test::call_service(&app, stats_request(&active.access_token).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let last_used_at = find_token(&ctx, &active.access_token)
+        .await
+        .unwrap()
+        .last_used_at;
+    let last_used_at = chrono::DateTime::parse_from_rfc3339(&last_used_at).unwrap();
+    assert!(
+        Utc::now() - Duration::minutes(1) < last_used_at,
+        "Using the token extends it"
+    );
+}
+
+#[actix_web::test]
+async fn test_refresh_token() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let req = client_login_request("testpass", "Laptop").to_request();
+    let login: LoginResponse = test::call_and_read_body_json(&app, req).await;
+    assert!(login.expires_at.is_some());
+    let session_id = find_token(&ctx, &login.access_token)
+        .await
+        .unwrap()
+        .session_id;
+    age_token(&ctx, &login.access_token, 24 * 29, 1).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/refresh")
+        .insert_header((header::AUTHORIZATION, login.access_token.reveal()))
+        .to_request();
+    let refreshed: LoginResponse = test::call_and_read_body_json(&app, req).await;
+    assert_ne!(refreshed.access_token, login.access_token);
+
+    let resp = test::call_service(&app, stats_request(&login.access_token).to_request()).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Old token is replaced"
+    );
+    let resp = test::call_service(&app, stats_request(&refreshed.access_token).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let token = find_token(&ctx, &refreshed.access_token).await.unwrap();
+    assert_eq!(token.session_id, session_id, "Session stays the same");
+    let last_used_at = chrono::DateTime::parse_from_rfc3339(&token.last_used_at).unwrap();
+    assert!(Utc::now() - Duration::minutes(1) < last_used_at);
+}
+
+#[actix_web::test]
+async fn test_refresh_keeps_max_age() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let req = client_login_request("testpass", "Laptop").to_request();
+    let login: LoginResponse = test::call_and_read_body_json(&app, req).await;
+    age_token(&ctx, &login.access_token, 24 * 29, 1).await;
+    let created_at = find_token(&ctx, &login.access_token)
+        .await
+        .unwrap()
+        .created_at;
+    let created_at = chrono::DateTime::parse_from_rfc3339(&created_at).unwrap();
+
+    let mut token = login.access_token;
+    for _ in 0..3 {
+        let req = test::TestRequest::post()
+            .uri("/api/refresh")
+            .insert_header((header::AUTHORIZATION, token.reveal()))
+            .to_request();
+        let refreshed: LoginResponse = test::call_and_read_body_json(&app, req).await;
+        let expires_at =
+            chrono::DateTime::parse_from_rfc3339(&refreshed.expires_at.unwrap()).unwrap();
+        assert_eq!(
+            expires_at,
+            created_at + Duration::hours(720),
+            "Refreshing doesn't extend the session"
+        );
+        token = refreshed.access_token;
+    }
+    let stored = find_token(&ctx, &token).await.unwrap();
+    assert_eq!(
+        chrono::DateTime::parse_from_rfc3339(&stored.created_at).unwrap(),
+        created_at,
+        "The session keeps its start"
+    );
+
+    // Once the session is past the maximum age, refreshing doesn't help
+    age_token(&ctx, &token, 721, 1).await;
+    let req = test::TestRequest::post()
+        .uri("/api/refresh")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_delete_expired_tokens() {
+    let ctx = TestEnv::setup().await;
+    let too_old = ctx.setup_user_token("testuser", "testpass").await;
+    let idle = ctx.setup_user_token("otheruser", "otherpass").await;
+    let active = ctx.setup_user_token("thirduser", "thirdpass").await;
+    age_token(&ctx, &too_old, 24 * 31, 1).await;
+    age_token(&ctx, &idle, 24 * 10, 24 * 8).await;
+    let expired_path = ctx.setup_path_token("storage/testuser/a.txt").await;
+    let valid_path = ctx.setup_path_token("storage/testuser/b.txt").await;
+    path_token::Entity::update_many()
+        .col_expr(
+            path_token::Column::ValidUntil,
+            Expr::value((Utc::now() - Duration::hours(1)).to_rfc3339()),
+        )
+        .filter(path_token::Column::Token.eq(ctx.state().hash_token(&expired_path)))
+        .exec(&ctx.state().db)
+        .await
+        .unwrap();
+
+    let count = delete_expired_tokens(&ctx.state()).await.unwrap();
+    assert_eq!(count, 3);
+    assert!(find_token(&ctx, &too_old).await.is_none());
+    assert!(find_token(&ctx, &idle).await.is_none());
+    assert!(find_token(&ctx, &active).await.is_some());
+    let paths: Vec<String> = path_token::Entity::find()
+        .all(&ctx.state().db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|token| token.token)
+        .collect();
+    assert_eq!(paths, vec![ctx.state().hash_token(&valid_path)]);
+}
+
+#[actix_web::test]
+async fn test_tokens_are_hashed() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let path = ctx.setup_path_token("storage/testuser/a.txt").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let stored = user_token::Entity::find()
+        .one(&ctx.state().db)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_ne!(stored.token, token.reveal(), "User token is not stored");
+    let stored_path = path_token::Entity::find()
+        .one(&ctx.state().db)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_ne!(stored_path.token, path.reveal(), "Path token is not stored");
+
+    let resp = test::call_service(&app, stats_request(&token).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(
+        &app,
+        stats_request(&Token::read(&stored.token)).to_request(),
+    )
+    .await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Stored hash can't be used as a token"
+    );
+
+    let key = TokenOptions::default()
+        .load_key(Path::new("."))
+        .await
+        .unwrap();
+    assert_eq!(
+        key.hash(token.reveal()),
+        stored.token,
+        "The saved key is loaded again"
+    );
+}
@@ -0,0 +1,894 @@
+mod common;
+
+use std::path::PathBuf;
+
+use actix_web::{
+    cookie::Cookie,
+    http::{header, StatusCode},
+    test,
+};
+use bulgur_cloud::{
+    auth::Password,
+    auth_middleware::AUTH_COOKIE_NAME,
+    folder::STORAGE,
+    pages::{RevokeShareForm, ShareForm, SharePasswordForm},
+    ratelimit_middleware::RateLimit,
+    server::setup_app,
+    share::{Share, ShareOptions, ShareResults, SHARE_GRANT_COOKIE_PREFIX},
+    state::{PathTokenResponse, Token},
+    storage::StorageAction,
+};
+use common::{create_dir, create_file, login_request, read_header, TestEnv};
+use futures::future::join_all;
+use tokio::fs;
+
+fn share_request(token: &str, path: &str, options: &ShareOptions) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri(&format!("/api/shares/testuser/{path}"))
+        .insert_header((header::AUTHORIZATION, token))
+        .set_json(options)
+}
+
+fn list_request(token: &str) -> test::TestRequest {
+    test::TestRequest::get()
+        .uri("/api/shares/testuser")
+        .insert_header((header::AUTHORIZATION, token))
+}
+
+fn drop_request(share: &Token, files: &[(&str, &str)]) -> test::TestRequest {
+    let mut payload = String::new();
+    for (name, contents) in files {
+        payload.push_str(&format!("--zzz\r\nContent-Disposition: form-data; name=\"files\"; filename=\"{name}\"\r\n\r\n{contents}\r\n"));
+    }
+    payload.push_str("--zzz--\r\n\r\n");
+    test::TestRequest::put()
+        .uri(&format!("/share/{}", share.reveal()))
+        .set_payload(payload)
+        .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=zzz"))
+}
+
+/// The token of a new share link. Only shares that were just made have one.
+fn share_token(share: &Share) -> &Token {
+    share.token.as_ref().expect("New share has a token")
+}
+
+/// Reads the token of the new share link shown on the shares page.
+fn created_token(page: &str) -> Token {
+    let start = page.find("href=\"/share/").expect("New link is shown") + "href=\"/share/".len();
+    let end = start + page[start..].find('"').expect("Link ends");
+    Token::read(&page[start..end])
+}
+
+fn file_request(share: &Token) -> test::TestRequest {
+    test::TestRequest::get().uri(&format!(
+        "/storage/testuser/test.txt?token={}",
+        share.reveal()
+    ))
+}
+
+#[actix_web::test]
+async fn test_create_list_revoke_share() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+
+    let options = ShareOptions {
+        label: Some("For Alex".to_string()),
+        ..Default::default()
+    };
+    let req = share_request(token.reveal(), "test.txt", &options).to_request();
+    let share: Share = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(share.path, "test.txt");
+    assert!(!share.is_folder);
+    assert_eq!(share.valid_until, None, "Share doesn't expire by default");
+
+    let req = file_request(share_token(&share)).to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    assert_eq!(body, "Autem tempore", "Share link works");
+
+    let req = list_request(token.reveal()).to_request();
+    let resp: ShareResults = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.shares.len(), 1);
+    assert_eq!(resp.shares[0].label.as_deref(), Some("For Alex"));
+    assert_eq!(resp.shares[0].downloads, 1, "Download is counted");
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/api/shares/testuser/{}", share.id))
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = file_request(share_token(&share)).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Revoked link stops working"
+    );
+    let req = list_request(token.reveal()).to_request();
+    let resp: ShareResults = test::call_and_read_body_json(&app, req).await;
+    assert!(resp.shares.is_empty(), "Revoked link is not listed");
+}
+
+#[actix_web::test]
+async fn test_quick_tokens_are_not_listed() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/storage/testuser/test.txt")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .set_json(StorageAction::MakePathToken)
+        .to_request();
+    let resp: PathTokenResponse = test::call_and_read_body_json(&app, req).await;
+    let req = file_request(&resp.token).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Quick token works");
+
+    let req = list_request(token.reveal()).to_request();
+    let resp: ShareResults = test::call_and_read_body_json(&app, req).await;
+    assert!(
+        resp.shares.is_empty(),
+        "Quick tokens don't show up as share links"
+    );
+}
+
+#[actix_web::test]
+async fn test_share_expires() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+
+    let options = ShareOptions {
+        valid_for_hours: Some(0),
+        ..Default::default()
+    };
+    let req = share_request(token.reveal(), "test.txt", &options).to_request();
+    let share: Share = test::call_and_read_body_json(&app, req).await;
+    assert!(share.valid_until.is_some());
+
+    let req = file_request(share_token(&share)).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Expired link doesn't work"
+    );
+    let req = list_request(token.reveal()).to_request();
+    let resp: ShareResults = test::call_and_read_body_json(&app, req).await;
+    assert!(resp.shares.is_empty(), "Expired link is not listed");
+}
+
+#[actix_web::test]
+async fn test_share_download_limit() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let folder = PathBuf::from(STORAGE).join("testuser").join("shared");
+    create_dir(folder.clone()).await;
+    create_file(folder.join("test.txt"), "Autem tempore").await;
+
+    let options = ShareOptions {
+        max_downloads: Some(2),
+        ..Default::default()
+    };
+    let req = share_request(token.reveal(), "shared", &options).to_request();
+    let share: Share = test::call_and_read_body_json(&app, req).await;
+    assert!(share.is_folder);
+
+    let listing = format!(
+        "/storage/testuser/shared?token={}",
+        share_token(&share).reveal()
+    );
+    let file = format!(
+        "/storage/testuser/shared/test.txt?token={}",
+        share_token(&share).reveal()
+    );
+    for _ in 0..3 {
+        let req = test::TestRequest::get().uri(&listing).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK, "Listings are not counted");
+    }
+    for _ in 0..2 {
+        let req = test::TestRequest::get().uri(&file).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    let req = test::TestRequest::get().uri(&file).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Link stops working after the download limit"
+    );
+}
+
+#[actix_web::test]
+async fn test_share_ranged_downloads() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+
+    let options = ShareOptions {
+        max_downloads: Some(2),
+        ..Default::default()
+    };
+    let req = share_request(token.reveal(), "test.txt", &options).to_request();
+    let share: Share = test::call_and_read_body_json(&app, req).await;
+    let ranged = |range: &str| {
+        file_request(share_token(&share))
+            .insert_header((header::RANGE, range.to_string()))
+            .to_request()
+    };
+
+    let resp = test::call_service(&app, ranged("bytes=0-4")).await;
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    for _ in 0..3 {
+        let resp = test::call_service(&app, ranged("bytes=6-")).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(test::read_body(resp).await, "tempore");
+    }
+    let req = list_request(token.reveal()).to_request();
+    let resp: ShareResults = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        resp.shares[0].downloads, 1,
+        "Only the start of the download is counted"
+    );
+
+    let resp = test::call_service(&app, file_request(share_token(&share)).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, file_request(share_token(&share)).to_request()).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Link stops working after the download limit"
+    );
+    let resp = test::call_service(&app, ranged("bytes=6-")).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::PARTIAL_CONTENT,
+        "Downloads that already started can be finished"
+    );
+}
+
+#[actix_web::test]
+async fn test_share_concurrent_downloads() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+
+    let options = ShareOptions {
+        max_downloads: Some(2),
+        ..Default::default()
+    };
+    let req = share_request(token.reveal(), "test.txt", &options).to_request();
+    let share: Share = test::call_and_read_body_json(&app, req).await;
+
+    let responses = join_all(
+        (0..6).map(|_| test::call_service(&app, file_request(share_token(&share)).to_request())),
+    )
+    .await;
+    let downloads = responses
+        .iter()
+        .filter(|resp| resp.status() == StatusCode::OK)
+        .count();
+    assert_eq!(
+        downloads, 2,
+        "Downloads at the same time don't go over the limit"
+    );
+}
+
+#[actix_web::test]
+async fn test_owner_downloads_are_not_counted() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+
+    let options = ShareOptions {
+        max_downloads: Some(1),
+        ..Default::default()
+    };
+    let req = share_request(token.reveal(), "test.txt", &options).to_request();
+    let share: Share = test::call_and_read_body_json(&app, req).await;
+
+    for _ in 0..2 {
+        let req = file_request(share_token(&share))
+            .insert_header((header::AUTHORIZATION, token.reveal()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    let req = file_request(share_token(&share)).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Visitor can still download");
+}
+
+#[actix_web::test]
+async fn test_share_password() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+
+    let options = ShareOptions {
+        password: Some(Password("sharepass".to_string())),
+        ..Default::default()
+    };
+    let req = share_request(token.reveal(), "test.txt", &options).to_request();
+    let share: Share = test::call_and_read_body_json(&app, req).await;
+    assert!(share.has_password);
+    let cookie_name = format!(
+        "{SHARE_GRANT_COOKIE_PREFIX}{}",
+        share_token(&share).reveal()
+    );
+
+    let req = file_request(share_token(&share)).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Password is needed"
+    );
+
+    for value in ["wrongpass", "sharepass", "9999999999.abcd"] {
+        let req = file_request(share_token(&share))
+            .cookie(Cookie::new(cookie_name.clone(), value))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.status(),
+            StatusCode::UNAUTHORIZED,
+            "The password is only accepted on the share page, not in the cookie"
+        );
+    }
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/share/{}", share_token(&share).reveal()))
+        .set_form(SharePasswordForm {
+            password: Password("sharepass".to_string()),
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let grant = resp
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == cookie_name)
+        .expect("Visitor gets a cookie")
+        .value()
+        .to_string();
+    assert!(
+        !grant.contains("sharepass"),
+        "The cookie isn't the password"
+    );
+    let req = file_request(share_token(&share))
+        .cookie(Cookie::new(cookie_name.clone(), grant.clone()))
+        .to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    assert_eq!(body, "Autem tempore", "The cookie works");
+
+    // The cookie only works for the link it was made for
+    let req = share_request(token.reveal(), "test.txt", &options).to_request();
+    let other: Share = test::call_and_read_body_json(&app, req).await;
+    let req = file_request(share_token(&other))
+        .cookie(Cookie::new(
+            format!(
+                "{SHARE_GRANT_COOKIE_PREFIX}{}",
+                share_token(&other).reveal()
+            ),
+            grant,
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_share_password_page() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+    let options = ShareOptions {
+        password: Some(Password("sharepass".to_string())),
+        ..Default::default()
+    };
+    let req = share_request(token.reveal(), "test.txt", &options).to_request();
+    let share: Share = test::call_and_read_body_json(&app, req).await;
+    let link = format!("/share/{}", share_token(&share).reveal());
+
+    let req = test::TestRequest::get().uri(&link).to_request();
+    let resp = test::call_and_read_body(&app, req).await;
+    let resp_str = String::from_utf8(resp.to_vec()).expect("Failed to read response body");
+    assert!(resp_str.contains("password"), "Asks for the password");
+
+    let req = test::TestRequest::post()
+        .uri(&link)
+        .set_form(SharePasswordForm {
+            password: Password("wrongpass".to_string()),
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::post()
+        .uri(&link)
+        .set_form(SharePasswordForm {
+            password: Password("sharepass".to_string()),
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_redirection());
+    assert_eq!(
+        read_header(&resp, header::LOCATION),
+        format!(
+            "/storage/testuser/test.txt?token={}",
+            share_token(&share).reveal()
+        ),
+        "Goes on to the shared file"
+    );
+    let cookie = resp
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name().starts_with(SHARE_GRANT_COOKIE_PREFIX))
+        .expect("Password is remembered");
+    let req = file_request(share_token(&share))
+        .cookie(Cookie::new(
+            cookie.name().to_string(),
+            cookie.value().to_string(),
+        ))
+        .to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    assert_eq!(body, "Autem tempore");
+}
+
+#[actix_web::test]
+async fn test_share_password_throttled() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), RateLimit::new(3, true))).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+    let options = ShareOptions {
+        password: Some(Password("sharepass".to_string())),
+        ..Default::default()
+    };
+    let req = share_request(token.reveal(), "test.txt", &options).to_request();
+    let share: Share = test::call_and_read_body_json(&app, req).await;
+    let link = format!("/share/{}", share_token(&share).reveal());
+    let password_request = |password: &str| {
+        test::TestRequest::post()
+            .uri(&link)
+            .set_form(SharePasswordForm {
+                password: Password(password.to_string()),
+            })
+            .to_request()
+    };
+
+    // Opening the link and entering the right password don't use up attempts
+    for _ in 0..5 {
+        let req = test::TestRequest::get().uri(&link).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, password_request("sharepass")).await;
+        assert!(resp.status().is_redirection());
+    }
+    for _ in 0..3 {
+        let resp = test::call_service(&app, password_request("wrongpass")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    let resp = test::call_service(&app, password_request("sharepass")).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::TOO_MANY_REQUESTS,
+        "Repeated wrong passwords are throttled"
+    );
+    assert!(resp.headers().contains_key(header::RETRY_AFTER));
+
+    let req = login_request("testuser", "testpass").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::OK,
+        "Wrong share passwords don't lock anyone out of logging in"
+    );
+}
+
+#[actix_web::test]
+async fn test_share_link_without_password_redirects() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_dir(PathBuf::from(STORAGE).join("testuser").join("shared")).await;
+    let req = share_request(token.reveal(), "shared", &Default::default()).to_request();
+    let share: Share = test::call_and_read_body_json(&app, req).await;
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/share/{}", share_token(&share).reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        read_header(&resp, header::LOCATION),
+        format!(
+            "/basic/testuser/shared/?token={}",
+            share_token(&share).reveal()
+        ),
+        "Goes straight to the shared folder"
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/share/not-a-real-token")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn test_basic_shares_page() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/basic/testuser/test.txt?_method=SHARE")
+        .set_form(ShareForm {
+            label: "For Alex".to_string(),
+            max_downloads: "3".to_string(),
+            ..Default::default()
+        })
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .to_request();
+    let resp = test::call_and_read_body(&app, req).await;
+    let resp_str = String::from_utf8(resp.to_vec()).expect("Failed to read response body");
+    let link = created_token(&resp_str);
+    let req = file_request(&link).to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    assert_eq!(body, "Autem tempore", "New link works");
+
+    let req = list_request(token.reveal()).to_request();
+    let resp: ShareResults = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.shares.len(), 1);
+    assert_eq!(resp.shares[0].max_downloads, Some(3));
+    assert!(
+        resp.shares[0].token.is_none(),
+        "Listed shares have no token"
+    );
+    let share = resp.shares[0].id.clone();
+
+    let req = test::TestRequest::get()
+        .uri("/basic/testuser/test.txt?shares")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .to_request();
+    let resp = test::call_and_read_body(&app, req).await;
+    let resp_str = String::from_utf8(resp.to_vec()).expect("Failed to read response body");
+    assert!(resp_str.contains("For Alex"), "Share is listed");
+    assert!(
+        !resp_str.contains(link.reveal()),
+        "Link can't be shown again"
+    );
+    assert!(resp_str.contains("1 of 3 downloads"), "Downloads are shown");
+
+    let req = test::TestRequest::post()
+        .uri("/basic/testuser/test.txt?_method=REVOKE")
+        .set_form(RevokeShareForm { share })
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_redirection());
+    let req = list_request(token.reveal()).to_request();
+    let resp: ShareResults = test::call_and_read_body_json(&app, req).await;
+    assert!(resp.shares.is_empty(), "Share is revoked");
+}
+
+#[actix_web::test]
+async fn test_basic_share_form_errors() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/basic/testuser/test.txt?_method=SHARE")
+        .set_form(ShareForm {
+            valid_for_hours: "soon".to_string(),
+            ..Default::default()
+        })
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn test_share_unauthorized_store() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    ctx.add_user("user2", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/shares/user2/")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .set_json(ShareOptions::default())
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::get()
+        .uri("/api/shares/user2")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_file_drop() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let inbox = PathBuf::from(STORAGE).join("testuser").join("inbox");
+    create_dir(inbox.clone()).await;
+    create_file(inbox.join("other.txt"), "Neque porro").await;
+
+    let options = ShareOptions {
+        label: Some("Send us your files".to_string()),
+        file_drop: true,
+        ..Default::default()
+    };
+    let req = share_request(token.reveal(), "inbox", &options).to_request();
+    let share: Share = test::call_and_read_body_json(&app, req).await;
+    assert!(share.file_drop);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/share/{}", share_token(&share).reveal()))
+        .to_request();
+    let resp = test::call_and_read_body(&app, req).await;
+    let resp_str = String::from_utf8(resp.to_vec()).expect("Failed to read response body");
+    assert!(
+        resp_str.contains("Send us your files"),
+        "Upload page is shown"
+    );
+    assert!(!resp_str.contains("other.txt"), "Files are not listed");
+
+    let req = drop_request(share_token(&share), &[("test.txt", "Autem tempore")]).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    assert_eq!(
+        read_header(&resp, header::LOCATION),
+        format!("/share/{}?uploaded=1", share_token(&share).reveal())
+    );
+    assert_eq!(
+        fs::read_to_string(inbox.join("test.txt")).await.unwrap(),
+        "Autem tempore",
+        "File is uploaded"
+    );
+
+    for uri in [
+        format!(
+            "/storage/testuser/inbox?token={}",
+            share_token(&share).reveal()
+        ),
+        format!(
+            "/storage/testuser/inbox/test.txt?token={}",
+            share_token(&share).reveal()
+        ),
+        format!(
+            "/basic/testuser/inbox/?token={}",
+            share_token(&share).reveal()
+        ),
+    ] {
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.status(),
+            StatusCode::UNAUTHORIZED,
+            "File drop can't read {uri}"
+        );
+    }
+
+    let req = list_request(token.reveal()).to_request();
+    let resp: ShareResults = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.shares[0].uploads, 1, "Upload is counted");
+}
+
+#[actix_web::test]
+async fn test_file_drop_keeps_existing_files() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let inbox = PathBuf::from(STORAGE).join("testuser").join("inbox");
+    create_dir(inbox.clone()).await;
+    create_file(inbox.join("test.txt"), "Neque porro").await;
+    let options = ShareOptions {
+        file_drop: true,
+        ..Default::default()
+    };
+    let req = share_request(token.reveal(), "inbox", &options).to_request();
+    let share: Share = test::call_and_read_body_json(&app, req).await;
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/share/{}", share_token(&share).reveal()))
+        .set_payload("--zzz\r\nContent-Disposition: form-data; name=\"conflict\"\r\n\r\noverwrite\r\n--zzz\r\nContent-Disposition: form-data; name=\"files\"; filename=\"test.txt\"\r\n\r\nAutem tempore\r\n--zzz--\r\n\r\n")
+        .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=zzz"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    assert_eq!(
+        fs::read_to_string(inbox.join("test.txt")).await.unwrap(),
+        "Neque porro",
+        "Existing file is not replaced"
+    );
+    assert_eq!(
+        fs::read_to_string(inbox.join("test (1).txt"))
+            .await
+            .unwrap(),
+        "Autem tempore",
+        "Upload is renamed"
+    );
+}
+
+#[actix_web::test]
+async fn test_file_drop_limits() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let inbox = PathBuf::from(STORAGE).join("testuser").join("inbox");
+    create_dir(inbox.clone()).await;
+    let options = ShareOptions {
+        file_drop: true,
+        max_file_size: Some(13),
+        max_files: Some(2),
+        ..Default::default()
+    };
+    let req = share_request(token.reveal(), "inbox", &options).to_request();
+    let share: Share = test::call_and_read_body_json(&app, req).await;
+
+    let req = drop_request(share_token(&share), &[("big.txt", "Et voluptatibu")]).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "Large file is rejected"
+    );
+    let mut entries = fs::read_dir(&inbox).await.unwrap();
+    assert!(
+        entries.next_entry().await.unwrap().is_none(),
+        "Nothing is left behind"
+    );
+
+    let req = drop_request(
+        share_token(&share),
+        &[("a.txt", "Autem"), ("b.txt", "tempore"), ("c.txt", "quia")],
+    )
+    .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN, "Too many files");
+    assert!(
+        inbox.join("b.txt").exists(),
+        "Files within the limit are kept"
+    );
+    assert!(!inbox.join("c.txt").exists());
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/share/{}", share_token(&share).reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::NOT_FOUND,
+        "File drop is closed once it is full"
+    );
+}
+
+#[actix_web::test]
+async fn test_file_drop_needs_folder() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+    let options = ShareOptions {
+        file_drop: true,
+        ..Default::default()
+    };
+    let req = share_request(token.reveal(), "test.txt", &options).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn test_basic_file_drop_upload() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let inbox = PathBuf::from(STORAGE).join("testuser").join("inbox");
+    create_dir(inbox.clone()).await;
+
+    let req = test::TestRequest::post()
+        .uri("/basic/testuser/inbox?_method=SHARE")
+        .set_form(ShareForm {
+            file_drop: Some("on".to_string()),
+            max_file_size: "1".to_string(),
+            ..Default::default()
+        })
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .to_request();
+    let resp = test::call_and_read_body(&app, req).await;
+    let resp_str = String::from_utf8(resp.to_vec()).expect("Failed to read response body");
+    let link = created_token(&resp_str);
+    let req = list_request(token.reveal()).to_request();
+    let resp: ShareResults = test::call_and_read_body_json(&app, req).await;
+    let share = &resp.shares[0];
+    assert!(share.file_drop);
+    assert_eq!(share.max_file_size, Some(1024 * 1024));
+
+    let req = drop_request(&link, &[("test.txt", "Autem tempore")])
+        .method(actix_web::http::Method::POST)
+        .uri(&format!("/share/{}?_method=PUT", link.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER, "Form upload works");
+    assert!(inbox.join("test.txt").exists());
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/share/{}?uploaded=1", link.reveal()))
+        .to_request();
+    let resp = test::call_and_read_body(&app, req).await;
+    let resp_str = String::from_utf8(resp.to_vec()).expect("Failed to read response body");
+    assert!(resp_str.contains("Uploaded 1 files"));
+}
@@ -3,14 +3,14 @@ mod common;
 use std::path::PathBuf;
 
 use actix_web::{
-    http::{header, Method},
+    http::{header, Method, StatusCode},
     test,
 };
 use bulgur_cloud::{
     folder::STORAGE,
     server::setup_app,
     state::PathTokenResponse,
-    storage::{FolderResults, StorageAction},
+    storage::{ConflictPolicy, FolderResults, PutStoragePayload, StorageAction},
 };
 use common::{create_dir, create_file, TestEnv};
 use tokio::fs;
@@ -179,12 +179,9 @@ async fn test_get_folder_listing() {
 
     assert_eq!(resp.entries.len(), 2, "Folder listing contains all entries");
     assert_eq!(resp.entries[0].name, "apple", "Folder entry exists");
-    assert_eq!(
-        resp.entries[0].is_file, false,
-        "Folder is marked as a folder"
-    );
+    assert!(!resp.entries[0].is_file, "Folder is marked as a folder");
     assert_eq!(resp.entries[1].name, "banana.txt", "File entry exists");
-    assert_eq!(resp.entries[1].is_file, true, "File is marked as a file");
+    assert!(resp.entries[1].is_file, "File is marked as a file");
 }
 
 #[actix_web::test]
@@ -212,6 +209,35 @@ async fn test_get_file() {
     );
 }
 
+#[actix_web::test]
+async fn test_get_file_range() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("banana.txt"),
+        "Aut suscipit amet hic",
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/storage/testuser/banana.txt")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .insert_header((header::RANGE, "bytes=4-11"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::PARTIAL_CONTENT,
+        "Range request gets a partial response"
+    );
+    let body = test::read_body(resp).await;
+
+    let resp_str = String::from_utf8(body.to_vec()).expect("Failed to read response string");
+    assert_eq!("suscipit", resp_str, "Only the requested range is sent");
+}
+
 #[actix_web::test]
 async fn test_rename_file() {
     let ctx = TestEnv::setup().await;
@@ -335,6 +361,85 @@ async fn test_make_path_token() {
     );
 }
 
+#[actix_web::test]
+async fn test_folder_path_token() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let shared = PathBuf::from(STORAGE).join("testuser").join("shared");
+    create_dir(shared.clone()).await;
+    create_dir(shared.join("inner")).await;
+    create_file(shared.join("inner").join("test.txt"), "Quia dolorum").await;
+    let token = ctx.setup_path_token("storage/testuser/shared").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let uri = format!("/storage/testuser/shared?token={}", token.reveal());
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let resp: FolderResults = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.entries.len(), 1, "Folder token lists the folder");
+
+    let uri = format!(
+        "/storage/testuser/shared/inner/test.txt?token={}",
+        token.reveal()
+    );
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    assert_eq!(body, "Quia dolorum", "Folder token can read files inside");
+}
+
+#[actix_web::test]
+async fn test_folder_path_token_outside_folder() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let store = PathBuf::from(STORAGE).join("testuser");
+    create_dir(store.join("shared")).await;
+    create_dir(store.join("shared-private")).await;
+    create_file(store.join("secret.txt"), "Neque porro").await;
+    create_file(
+        store.join("shared-private").join("secret.txt"),
+        "Neque porro",
+    )
+    .await;
+    let token = ctx.setup_path_token("storage/testuser/shared").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    for path in [
+        "/storage/testuser/secret.txt",
+        "/storage/testuser/",
+        "/storage/testuser/shared-private/secret.txt",
+        "/storage/testuser/shared/%2e%2e/secret.txt",
+        "/storage/testuser/shared%2F..%2Fsecret.txt",
+        "/storage/testuser/shared/%252e%252e/secret.txt",
+        "/api/stats",
+    ] {
+        let uri = format!("{path}?token={}", token.reveal());
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(
+            resp.status().is_client_error(),
+            "Folder token can't access {path}"
+        );
+    }
+}
+
+#[actix_web::test]
+async fn test_file_path_token_is_exact() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let store = PathBuf::from(STORAGE).join("testuser");
+    create_file(store.join("test.txt"), "Quia dolorum").await;
+    create_file(store.join("test.txt2"), "Neque porro").await;
+    let token = ctx.setup_path_token("storage/testuser/test.txt").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let uri = format!("/storage/testuser/test.txt2?token={}", token.reveal());
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(
+        resp.status().is_client_error(),
+        "File token only works for that file"
+    );
+}
+
 #[actix_web::test]
 async fn test_delete_file() {
     let ctx = TestEnv::setup().await;
@@ -431,3 +536,106 @@ async fn test_upload_file_duplicate_name() {
         "Second uploaded file has the right contents"
     );
 }
+
+#[actix_web::test]
+async fn test_upload_file_overwrite() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+
+    let req = test::TestRequest::put()
+        .uri("/storage/testuser/?conflict=overwrite")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .set_payload("--zzz\r\nContent-Disposition: form-data; name=\"test.txt\"; filename=\"test.txt\"\r\n\r\nEt voluptatibu\r\n--zzz--\r\n\r\n")
+        .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=zzz"))
+        .to_request();
+    let resp: PutStoragePayload = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.files_written.len(), 1);
+    assert_eq!(resp.files_written[0].path, "storage/testuser/test.txt");
+    assert_eq!(
+        resp.files_written[0].conflict,
+        Some(ConflictPolicy::Overwrite),
+        "Response reports that the file was overwritten"
+    );
+
+    let contents =
+        fs::read_to_string(PathBuf::from(STORAGE).join("testuser").join("test.txt")).await;
+    assert_eq!(
+        contents.unwrap(),
+        "Et voluptatibu",
+        "Existing file is replaced"
+    );
+}
+
+#[actix_web::test]
+async fn test_upload_file_fail_on_conflict() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+
+    let req = test::TestRequest::put()
+        .uri("/storage/testuser/?conflict=fail")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .set_payload("--zzz\r\nContent-Disposition: form-data; name=\"test.txt\"; filename=\"test.txt\"\r\n\r\nEt voluptatibu\r\n--zzz--\r\n\r\n")
+        .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=zzz"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::CONFLICT,
+        "Upload fails if the file exists"
+    );
+
+    let contents =
+        fs::read_to_string(PathBuf::from(STORAGE).join("testuser").join("test.txt")).await;
+    assert_eq!(contents.unwrap(), "Autem tempore", "Existing file is kept");
+    let mut entries = fs::read_dir(PathBuf::from(STORAGE).join("testuser"))
+        .await
+        .unwrap();
+    let mut count = 0;
+    while entries.next_entry().await.unwrap().is_some() {
+        count += 1;
+    }
+    assert_eq!(count, 1, "Failed upload is cleaned up");
+}
+
+#[actix_web::test]
+async fn test_upload_file_reports_rename() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+
+    let req = test::TestRequest::put()
+        .uri("/storage/testuser/")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .set_payload("--zzz\r\nContent-Disposition: form-data; name=\"test.txt\"; filename=\"test.txt\"\r\n\r\nEt voluptatibu\r\n--zzz\r\nContent-Disposition: form-data; name=\"other.txt\"; filename=\"other.txt\"\r\n\r\nAut suscipit\r\n--zzz--\r\n\r\n")
+        .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=zzz"))
+        .to_request();
+    let resp: PutStoragePayload = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.files_written.len(), 2);
+    assert_eq!(resp.files_written[0].path, "storage/testuser/test (1).txt");
+    assert_eq!(
+        resp.files_written[0].conflict,
+        Some(ConflictPolicy::Rename),
+        "Response reports that the file was renamed"
+    );
+    assert_eq!(
+        resp.files_written[1].conflict, None,
+        "Response reports no conflict for new files"
+    );
+}
@@ -0,0 +1,354 @@
+mod common;
+
+use actix_web::{
+    cookie::Cookie,
+    http::{header, StatusCode},
+    test,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bulgur_cloud::{
+    auth::{Login, LoginResponse, Password},
+    auth_middleware::AUTH_COOKIE_NAME,
+    cli::{cli_command, CLIContext, Commands, Opt, User, UserResetTotp},
+    pages::{LoginFormData, TotpAction, TotpForm},
+    server::setup_app,
+    state::Token,
+    totp::{
+        code_at, confirm_enrollment, pending_enrollment, start_enrollment, RecoveryCodes, TotpCode,
+        TotpDisable, TotpEnrollment, TotpStatus,
+    },
+};
+use chrono::Utc;
+use common::TestEnv;
+
+pub struct CLITestContext {}
+impl CLIContext for CLITestContext {
+    fn prompt_password() -> anyhow::Result<String> {
+        Ok("testpass".to_string())
+    }
+}
+
+/// The code the authenticator shows now, or `steps` periods later.
+fn current_code(secret: &str, steps: u64) -> String {
+    code_at(secret, Utc::now().timestamp() as u64 + steps * 30).expect("Secret is valid")
+}
+
+fn login_request(code: Option<String>) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri("/auth/login")
+        .set_json(Login {
+            username: "testuser".to_string(),
+            password: Password("testpass".to_string()),
+            code,
+        })
+}
+
+fn status_request(token: &Token) -> test::TestRequest {
+    test::TestRequest::get()
+        .uri("/api/totp")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+}
+
+/// Turns on two-factor authentication for the user. Returns the secret and
+/// the recovery codes.
+async fn enable_totp(ctx: &TestEnv, username: &str) -> (String, Vec<String>) {
+    let enrollment = start_enrollment(&ctx.state(), username).await.unwrap();
+    let codes = confirm_enrollment(&ctx.state(), username, &current_code(&enrollment.secret, 0))
+        .await
+        .unwrap();
+    (enrollment.secret, codes.codes)
+}
+
+#[actix_web::test]
+async fn test_rfc_6238_vectors() {
+    // The SHA-1 test vectors from RFC 6238, the secret is "12345678901234567890"
+    let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+    for (time, code) in [
+        (59, "287082"),
+        (1111111109, "081804"),
+        (1111111111, "050471"),
+        (1234567890, "005924"),
+        (2000000000, "279037"),
+    ] {
+        assert_eq!(code_at(secret, time).as_deref(), Some(code), "{time}");
+    }
+}
+
+#[actix_web::test]
+async fn test_enroll_and_login() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/totp")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let enrollment: TotpEnrollment = test::call_and_read_body_json(&app, req).await;
+    assert!(enrollment.uri.starts_with("otpauth://totp/"));
+    assert!(enrollment.uri.contains(&enrollment.secret));
+    let status: TotpStatus =
+        test::call_and_read_body_json(&app, status_request(&token).to_request()).await;
+    assert!(status.pending);
+    assert!(!status.enabled);
+    let resp = test::call_service(&app, login_request(None).to_request()).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::OK,
+        "Codes aren't needed until enrollment is confirmed"
+    );
+
+    let req = test::TestRequest::put()
+        .uri("/api/totp")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .set_json(TotpCode {
+            code: "000000".to_string(),
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::FORBIDDEN,
+        "Wrong code is rejected"
+    );
+
+    let code = current_code(&enrollment.secret, 0);
+    let req = test::TestRequest::put()
+        .uri("/api/totp")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .set_json(TotpCode { code: code.clone() })
+        .to_request();
+    let codes: RecoveryCodes = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(codes.codes.len(), 10);
+    let status: TotpStatus =
+        test::call_and_read_body_json(&app, status_request(&token).to_request()).await;
+    assert!(status.enabled);
+    assert_eq!(status.recovery_codes_left, 10);
+
+    let resp = test::call_service(&app, login_request(None).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN, "Code is required");
+    let resp = test::call_service(&app, login_request(Some(code)).to_request()).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Codes can't be used twice"
+    );
+    let resp = test::call_service(
+        &app,
+        login_request(Some(current_code(&enrollment.secret, 1))).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK, "Next code works");
+}
+
+#[actix_web::test]
+async fn test_recovery_codes() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let (secret, codes) = enable_totp(&ctx, "testuser").await;
+
+    // Recovery codes are accepted regardless of case and dashes
+    let typed = codes[0].replace('-', " ").to_uppercase();
+    let req = login_request(Some(typed.clone())).to_request();
+    let resp: LoginResponse = test::call_and_read_body_json(&app, req).await;
+    let status: TotpStatus =
+        test::call_and_read_body_json(&app, status_request(&resp.access_token).to_request()).await;
+    assert_eq!(status.recovery_codes_left, 9);
+    let resp = test::call_service(&app, login_request(Some(typed)).to_request()).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Recovery codes only work once"
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/api/totp/recovery-codes")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .set_json(TotpCode {
+            code: current_code(&secret, 1),
+        })
+        .to_request();
+    let new_codes: RecoveryCodes = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(new_codes.codes.len(), 10);
+    let resp = test::call_service(&app, login_request(Some(codes[1].clone())).to_request()).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Old recovery codes are replaced"
+    );
+    let resp = test::call_service(
+        &app,
+        login_request(Some(new_codes.codes[0].clone())).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK, "New recovery codes work");
+}
+
+#[actix_web::test]
+async fn test_disable_totp() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    enable_totp(&ctx, "testuser").await;
+
+    let req = test::TestRequest::delete()
+        .uri("/api/totp")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .set_json(TotpDisable {
+            password: Password("wrongpass".to_string()),
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN, "Password is checked");
+
+    let req = test::TestRequest::delete()
+        .uri("/api/totp")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .set_json(TotpDisable {
+            password: Password("testpass".to_string()),
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, login_request(None).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Code is no longer needed");
+}
+
+#[actix_web::test]
+async fn test_cli_reset_totp() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    enable_totp(&ctx, "testuser").await;
+
+    let opt = Opt {
+        command: Some(Commands::User(User::UserResetTotp(UserResetTotp {
+            username: "testuser".to_string(),
+        }))),
+        bind: Default::default(),
+        datastore: ctx.datastore(),
+        workers: 1,
+        trash_retention_days: 30,
+        kv: Default::default(),
+        versions: Default::default(),
+        tokens: Default::default(),
+        auth: Default::default(),
+    };
+    cli_command::<CLITestContext>(opt)
+        .await
+        .expect("Failed to run command");
+
+    let resp = test::call_service(&app, login_request(None).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Code is no longer needed");
+    let status: TotpStatus =
+        test::call_and_read_body_json(&app, status_request(&token).to_request()).await;
+    assert!(!status.enabled);
+    assert!(!status.pending);
+    assert_eq!(status.recovery_codes_left, 0);
+}
+
+#[actix_web::test]
+async fn test_basic_auth_rejected_with_totp() {
+    let ctx = TestEnv::setup().await;
+    ctx.add_user("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    enable_totp(&ctx, "testuser").await;
+
+    let req = test::TestRequest::default()
+        .method(actix_web::http::Method::from_bytes(b"PROPFIND").unwrap())
+        .uri("/dav/testuser/")
+        .insert_header((
+            header::AUTHORIZATION,
+            format!("Basic {}", STANDARD.encode("testuser:testpass")),
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Password alone is not enough"
+    );
+}
+
+#[actix_web::test]
+async fn test_basic_totp_pages() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::post()
+        .uri("/basic/testuser/?_method=TOTP")
+        .set_form(TotpForm {
+            action: TotpAction::Enroll,
+            code: None,
+            password: None,
+        })
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_redirection(), "Goes back to the account");
+
+    let enrollment = pending_enrollment(&ctx.state(), "testuser")
+        .await
+        .unwrap()
+        .expect("Enrollment was started");
+    let req = test::TestRequest::get()
+        .uri("/basic/testuser/?account")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .to_request();
+    let resp = test::call_and_read_body(&app, req).await;
+    let resp_str = String::from_utf8(resp.to_vec()).expect("Failed to read response body");
+    assert!(resp_str.contains("<svg"), "QR code is shown");
+    assert!(resp_str.contains(&enrollment.secret), "Secret is shown");
+
+    let req = test::TestRequest::post()
+        .uri("/basic/testuser/?_method=TOTP")
+        .set_form(TotpForm {
+            action: TotpAction::Confirm,
+            code: Some(current_code(&enrollment.secret, 0)),
+            password: None,
+        })
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = test::read_body(resp).await;
+    let resp_str = String::from_utf8(body.to_vec()).expect("Failed to read response body");
+    assert!(
+        resp_str.contains("Save these recovery codes"),
+        "Recovery codes are shown"
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/basic/")
+        .set_form(LoginFormData {
+            username: "testuser".to_string(),
+            password: Password("testpass".to_string()),
+            code: None,
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body = test::read_body(resp).await;
+    let resp_str = String::from_utf8(body.to_vec()).expect("Failed to read response body");
+    assert!(
+        resp_str.contains("Enter the code"),
+        "Login page asks for the code"
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/basic/")
+        .set_form(LoginFormData {
+            username: "testuser".to_string(),
+            password: Password("testpass".to_string()),
+            code: Some(current_code(&enrollment.secret, 1)),
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::SEE_OTHER,
+        "Login with code works"
+    );
+}
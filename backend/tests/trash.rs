@@ -0,0 +1,341 @@
+mod common;
+
+use std::path::PathBuf;
+
+use actix_web::{
+    cookie::Cookie,
+    http::{header, StatusCode},
+    test,
+};
+use bulgur_cloud::{
+    auth_middleware::AUTH_COOKIE_NAME,
+    folder::{STORAGE, TRASH},
+    pages::TrashItemForm,
+    server::setup_app,
+    trash::{purge_expired, TrashResults},
+};
+use common::{create_dir, create_file, TestEnv};
+use tokio::fs;
+
+async fn setup_files() {
+    let folder = PathBuf::from(STORAGE).join("testuser").join("apple");
+    create_dir(folder.clone()).await;
+    create_file(folder.join("test.txt"), "Autem tempore").await;
+}
+
+#[actix_web::test]
+async fn test_delete_moves_to_trash() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    setup_files().await;
+
+    let req = test::TestRequest::delete()
+        .uri("/storage/testuser/apple")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(
+        fs::metadata(PathBuf::from(STORAGE).join("testuser").join("apple"))
+            .await
+            .is_err(),
+        "Folder is removed from the store"
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/api/trash/testuser")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp: TrashResults = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.items.len(), 1, "Folder is in the trash");
+    let item = &resp.items[0];
+    assert_eq!(item.path, "apple");
+    assert!(!item.is_file);
+    assert_eq!(item.size, 13, "Size includes the files in the folder");
+    assert_eq!(
+        fs::read_to_string(
+            PathBuf::from(TRASH)
+                .join("testuser")
+                .join(&item.id)
+                .join("test.txt")
+        )
+        .await
+        .unwrap(),
+        "Autem tempore",
+        "Contents are kept in the trash"
+    );
+}
+
+#[actix_web::test]
+async fn test_restore_from_trash() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    setup_files().await;
+
+    // Delete the file and then the folder it was in
+    for uri in [
+        "/storage/testuser/apple/test.txt",
+        "/storage/testuser/apple",
+    ] {
+        let req = test::TestRequest::delete()
+            .uri(uri)
+            .insert_header((header::AUTHORIZATION, token.reveal()))
+            .to_request();
+        test::call_service(&app, req).await;
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/api/trash/testuser")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp: TrashResults = test::call_and_read_body_json(&app, req).await;
+    let file = resp
+        .items
+        .iter()
+        .find(|item| item.path == "apple/test.txt")
+        .expect("File is in the trash");
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/trash/testuser/{}", file.id))
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        fs::read_to_string(
+            PathBuf::from(STORAGE)
+                .join("testuser")
+                .join("apple")
+                .join("test.txt")
+        )
+        .await
+        .unwrap(),
+        "Autem tempore",
+        "File is restored, along with the missing folder"
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/api/trash/testuser")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp: TrashResults = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        resp.items.len(),
+        1,
+        "Restored file is no longer in the trash"
+    );
+}
+
+#[actix_web::test]
+async fn test_restore_conflict() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let file = PathBuf::from(STORAGE).join("testuser").join("test.txt");
+    create_file(file.clone(), "Autem tempore").await;
+
+    let req = test::TestRequest::delete()
+        .uri("/storage/testuser/test.txt")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    test::call_service(&app, req).await;
+    create_file(file.clone(), "Aut suscipit").await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/trash/testuser")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp: TrashResults = test::call_and_read_body_json(&app, req).await;
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/trash/testuser/{}", resp.items[0].id))
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::CONFLICT,
+        "Restoring over a new file is rejected"
+    );
+    assert_eq!(
+        fs::read_to_string(file).await.unwrap(),
+        "Aut suscipit",
+        "New file is kept"
+    );
+}
+
+#[actix_web::test]
+async fn test_purge_from_trash() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    setup_files().await;
+
+    let req = test::TestRequest::delete()
+        .uri("/storage/testuser/apple")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    test::call_service(&app, req).await;
+    let req = test::TestRequest::get()
+        .uri("/api/trash/testuser")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp: TrashResults = test::call_and_read_body_json(&app, req).await;
+    let id = resp.items[0].id.clone();
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/api/trash/testuser/{id}"))
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(
+        fs::metadata(PathBuf::from(TRASH).join("testuser").join(&id))
+            .await
+            .is_err(),
+        "Purged folder is deleted"
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/api/trash/testuser")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp: TrashResults = test::call_and_read_body_json(&app, req).await;
+    assert!(resp.items.is_empty(), "Trash is empty");
+}
+
+#[actix_web::test]
+async fn test_trash_unauthorized_store() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    ctx.add_user("user2", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/trash/user2")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(
+        resp.status().is_client_error(),
+        "Can't look into another user's trash"
+    );
+
+    let req = test::TestRequest::delete()
+        .uri("/api/trash/user2")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(
+        resp.status().is_client_error(),
+        "Can't empty another user's trash"
+    );
+}
+
+#[actix_web::test]
+async fn test_purge_expired() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    setup_files().await;
+
+    let req = test::TestRequest::delete()
+        .uri("/storage/testuser/apple")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    test::call_service(&app, req).await;
+
+    let purged = purge_expired(&ctx.state(), chrono::Duration::days(30))
+        .await
+        .unwrap();
+    assert_eq!(purged, 0, "Recently deleted items are kept");
+
+    let purged = purge_expired(&ctx.state(), chrono::Duration::seconds(-1))
+        .await
+        .unwrap();
+    assert_eq!(purged, 1, "Items past the retention period are purged");
+    let req = test::TestRequest::get()
+        .uri("/api/trash/testuser")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp: TrashResults = test::call_and_read_body_json(&app, req).await;
+    assert!(resp.items.is_empty(), "Trash is empty");
+}
+
+#[actix_web::test]
+async fn test_basic_trash() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    setup_files().await;
+
+    let req = test::TestRequest::post()
+        .uri("/basic/testuser/apple/test.txt?_method=DELETE")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .to_request();
+    test::call_service(&app, req).await;
+
+    let req = test::TestRequest::get()
+        .uri("/basic/testuser/?trash")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .to_request();
+    let resp = test::call_and_read_body(&app, req).await;
+    let resp_str = String::from_utf8(resp.to_vec()).unwrap();
+    assert!(
+        resp_str.contains("apple/test.txt"),
+        "Deleted file is listed in the trash"
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/api/trash/testuser")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let items: TrashResults = test::call_and_read_body_json(&app, req).await;
+    let id = items.items[0].id.clone();
+    assert!(
+        resp_str.contains("/basic/testuser/?_method=RESTORE") && resp_str.contains(&id),
+        "Page has a button to restore"
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/basic/testuser/?_method=RESTORE")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .set_form(TrashItemForm { item: Some(id) })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    assert!(
+        fs::metadata(
+            PathBuf::from(STORAGE)
+                .join("testuser")
+                .join("apple")
+                .join("test.txt")
+        )
+        .await
+        .is_ok(),
+        "File is restored"
+    );
+
+    // Delete it again, then empty the trash
+    let req = test::TestRequest::post()
+        .uri("/basic/testuser/apple/test.txt?_method=DELETE")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .to_request();
+    test::call_service(&app, req).await;
+    let req = test::TestRequest::post()
+        .uri("/basic/testuser/?_method=PURGE")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, token.reveal()))
+        .set_form(TrashItemForm { item: None })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+
+    let req = test::TestRequest::get()
+        .uri("/api/trash/testuser")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let items: TrashResults = test::call_and_read_body_json(&app, req).await;
+    assert!(items.items.is_empty(), "Trash is emptied");
+}
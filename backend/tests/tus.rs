@@ -0,0 +1,369 @@
+mod common;
+
+use std::path::PathBuf;
+
+use actix_web::{
+    http::{header, Method, StatusCode},
+    test,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bulgur_cloud::{
+    entity::upload,
+    folder::{STORAGE, UPLOADS},
+    server::setup_app,
+    tus::remove_expired_uploads,
+};
+use common::{create_dir, create_file, read_header, TestEnv};
+use sea_orm::{ActiveModelTrait, EntityTrait, Set};
+use tokio::fs;
+
+const CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+fn metadata(filename: &str) -> String {
+    format!("filename {}", STANDARD.encode(filename))
+}
+
+#[actix_web::test]
+async fn test_tus_upload_in_chunks() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_dir(PathBuf::from(STORAGE).join("testuser").join("apple")).await;
+
+    let req = test::TestRequest::post()
+        .uri("/storage/testuser/apple")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .insert_header(("Upload-Length", "22"))
+        .insert_header(("Upload-Metadata", metadata("test.txt")))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED, "Upload is created");
+    let location = read_header(&resp, header::LOCATION);
+    assert!(
+        location.starts_with("/storage/testuser/apple/"),
+        "Upload is placed in the folder"
+    );
+
+    let req = test::TestRequest::patch()
+        .uri(&location)
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .insert_header(("Upload-Offset", "0"))
+        .insert_header((header::CONTENT_TYPE, CONTENT_TYPE))
+        .set_payload("Autem tempore ")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::NO_CONTENT,
+        "First chunk is saved"
+    );
+    assert_eq!(read_header(&resp, "Upload-Offset"), "14");
+
+    // A client that lost the connection asks where to continue from
+    let req = test::TestRequest::default()
+        .method(Method::HEAD)
+        .uri(&location)
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(read_header(&resp, "Upload-Offset"), "14");
+    assert_eq!(read_header(&resp, "Upload-Length"), "22");
+
+    let req = test::TestRequest::patch()
+        .uri(&location)
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .insert_header(("Upload-Offset", "14"))
+        .insert_header((header::CONTENT_TYPE, CONTENT_TYPE))
+        .set_payload("suscipit")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT, "Last chunk is saved");
+    assert_eq!(read_header(&resp, "Upload-Offset"), "22");
+
+    let contents = fs::read_to_string(
+        PathBuf::from(STORAGE)
+            .join("testuser")
+            .join("apple")
+            .join("test.txt"),
+    )
+    .await;
+    assert_eq!(
+        contents.expect("Uploaded file is missing"),
+        "Autem tempore suscipit",
+        "Uploaded file has the right contents"
+    );
+
+    let req = test::TestRequest::default()
+        .method(Method::HEAD)
+        .uri(&location)
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::NOT_FOUND,
+        "Finished uploads are forgotten"
+    );
+}
+
+#[actix_web::test]
+async fn test_tus_offset_mismatch() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::post()
+        .uri("/storage/testuser/")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .insert_header(("Upload-Length", "10"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let location = read_header(&resp, header::LOCATION);
+
+    let req = test::TestRequest::patch()
+        .uri(&location)
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .insert_header(("Upload-Offset", "5"))
+        .insert_header((header::CONTENT_TYPE, CONTENT_TYPE))
+        .set_payload("tempore")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::CONFLICT,
+        "Chunks at the wrong offset are rejected"
+    );
+}
+
+#[actix_web::test]
+async fn test_tus_renames_on_conflict() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Aut suscipit",
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/storage/testuser/")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .insert_header(("Upload-Length", "5"))
+        .insert_header(("Upload-Metadata", metadata("test.txt")))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let location = read_header(&resp, header::LOCATION);
+
+    let req = test::TestRequest::patch()
+        .uri(&location)
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .insert_header(("Upload-Offset", "0"))
+        .insert_header((header::CONTENT_TYPE, CONTENT_TYPE))
+        .set_payload("Autem")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+    let original =
+        fs::read_to_string(PathBuf::from(STORAGE).join("testuser").join("test.txt")).await;
+    assert_eq!(original.unwrap(), "Aut suscipit", "Existing file is kept");
+    let renamed =
+        fs::read_to_string(PathBuf::from(STORAGE).join("testuser").join("test (1).txt")).await;
+    assert_eq!(renamed.unwrap(), "Autem", "Upload is renamed");
+}
+
+#[actix_web::test]
+async fn test_tus_unauthorized_store() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    ctx.add_user("user2", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::post()
+        .uri("/storage/user2/")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .insert_header(("Upload-Length", "10"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(
+        resp.status().is_client_error(),
+        "Uploads into another user's store are rejected"
+    );
+}
+
+#[actix_web::test]
+async fn test_tus_parts_not_listed() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::post()
+        .uri("/storage/testuser/")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .insert_header(("Upload-Length", "22"))
+        .insert_header(("Upload-Metadata", metadata("test.txt")))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let location = read_header(&resp, header::LOCATION);
+
+    let req = test::TestRequest::patch()
+        .uri(&location)
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .insert_header(("Upload-Offset", "0"))
+        .insert_header((header::CONTENT_TYPE, CONTENT_TYPE))
+        .set_payload("Autem tempore ")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+    let mut entries = fs::read_dir(PathBuf::from(STORAGE).join("testuser"))
+        .await
+        .unwrap();
+    assert!(
+        entries.next_entry().await.unwrap().is_none(),
+        "Unfinished uploads are not in the store"
+    );
+}
+
+#[actix_web::test]
+async fn test_tus_expired_uploads_removed() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::post()
+        .uri("/storage/testuser/")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .insert_header(("Upload-Length", "22"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(
+        !read_header(&resp, "Upload-Expires").is_empty(),
+        "Client is told when the upload expires"
+    );
+    let location = read_header(&resp, header::LOCATION);
+
+    let state = ctx.state();
+    assert_eq!(
+        remove_expired_uploads(&state).await.unwrap(),
+        0,
+        "Uploads in progress are kept"
+    );
+
+    let upload = upload::Entity::find()
+        .one(&state.db)
+        .await
+        .unwrap()
+        .unwrap();
+    let part = PathBuf::from(UPLOADS).join(&upload.part);
+    assert!(fs::metadata(&part).await.is_ok(), "Part is kept for now");
+    let mut upload: upload::ActiveModel = upload.into();
+    upload.expires_at = Set((chrono::Utc::now() - chrono::Duration::hours(1)).to_rfc3339());
+    upload.update(&state.db).await.unwrap();
+
+    let req = test::TestRequest::default()
+        .method(Method::HEAD)
+        .uri(&location)
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::NOT_FOUND,
+        "Expired uploads can't be continued"
+    );
+
+    assert_eq!(remove_expired_uploads(&state).await.unwrap(), 1);
+    assert!(fs::metadata(&part).await.is_err(), "Part is removed");
+    assert!(
+        upload::Entity::find()
+            .one(&state.db)
+            .await
+            .unwrap()
+            .is_none(),
+        "Expired upload is forgotten"
+    );
+}
+
+#[actix_web::test]
+async fn test_tus_one_patch_at_a_time() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::post()
+        .uri("/storage/testuser/")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .insert_header(("Upload-Length", "5"))
+        .insert_header(("Upload-Metadata", metadata("test.txt")))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let location = read_header(&resp, header::LOCATION);
+
+    // Another request is in the middle of sending the upload
+    let state = ctx.state();
+    let upload = upload::Entity::find()
+        .one(&state.db)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut upload: upload::ActiveModel = upload.into();
+    upload.lock_token = Set(Some("other".to_string()));
+    upload.locked_until = Set(Some(
+        (chrono::Utc::now() + chrono::Duration::minutes(1)).to_rfc3339(),
+    ));
+    let upload = upload.update(&state.db).await.unwrap();
+
+    let patch = || {
+        test::TestRequest::patch()
+            .uri(&location)
+            .insert_header((header::AUTHORIZATION, token.reveal()))
+            .insert_header(("Tus-Resumable", "1.0.0"))
+            .insert_header(("Upload-Offset", "0"))
+            .insert_header((header::CONTENT_TYPE, CONTENT_TYPE))
+            .set_payload("Autem")
+            .to_request()
+    };
+    let resp = test::call_service(&app, patch()).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::LOCKED,
+        "Upload can't be sent twice at the same time"
+    );
+
+    // The other request never finished
+    let mut upload: upload::ActiveModel = upload.into();
+    upload.locked_until = Set(Some(
+        (chrono::Utc::now() - chrono::Duration::minutes(1)).to_rfc3339(),
+    ));
+    upload.update(&state.db).await.unwrap();
+
+    let resp = test::call_service(&app, patch()).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::NO_CONTENT,
+        "Upload continues once the lock runs out"
+    );
+    let contents =
+        fs::read_to_string(PathBuf::from(STORAGE).join("testuser").join("test.txt")).await;
+    assert_eq!(contents.unwrap(), "Autem");
+}
@@ -0,0 +1,407 @@
+mod common;
+
+use std::path::PathBuf;
+
+use actix_web::{
+    cookie::Cookie,
+    http::{header, Method, StatusCode},
+    test,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bulgur_cloud::{
+    auth::delete_user,
+    auth_middleware::AUTH_COOKIE_NAME,
+    folder::STORAGE,
+    server::setup_app,
+    user_share::{SharePermission, UserShare, UserShareOptions, UserShareResults},
+};
+use common::{create_dir, create_file, upload_request, TestEnv};
+
+fn share_request(
+    token: &str,
+    path: &str,
+    username: &str,
+    permission: SharePermission,
+) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri(&format!("/api/user-shares/testuser/{path}"))
+        .insert_header((header::AUTHORIZATION, token))
+        .set_json(UserShareOptions {
+            username: username.to_string(),
+            permission,
+        })
+}
+
+fn shared_with_me_request(token: &str) -> test::TestRequest {
+    test::TestRequest::get()
+        .uri("/api/shared-with-me")
+        .insert_header((header::AUTHORIZATION, token))
+}
+
+async fn setup_files() {
+    let store = PathBuf::from(STORAGE).join("testuser");
+    create_dir(store.join("projects")).await;
+    create_file(store.join("projects").join("test.txt"), "Autem tempore").await;
+    create_file(store.join("private.txt"), "Neque porro").await;
+}
+
+#[actix_web::test]
+async fn test_read_share() {
+    let ctx = TestEnv::setup().await;
+    let owner = ctx.setup_user_token("testuser", "testpass").await;
+    let other = ctx.setup_user_token("otheruser", "otherpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    setup_files().await;
+
+    let req = test::TestRequest::get()
+        .uri("/storage/testuser/projects/test.txt")
+        .insert_header((header::AUTHORIZATION, other.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Nothing is shared yet"
+    );
+
+    let req = share_request(
+        owner.reveal(),
+        "projects",
+        "otheruser",
+        SharePermission::Read,
+    )
+    .to_request();
+    let share: UserShare = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(share.path, "projects");
+    assert_eq!(share.username, "otheruser");
+    assert_eq!(share.permission, SharePermission::Read);
+
+    let req = test::TestRequest::get()
+        .uri("/storage/testuser/projects/test.txt")
+        .insert_header((header::AUTHORIZATION, other.reveal()))
+        .to_request();
+    let resp = test::call_and_read_body(&app, req).await;
+    assert_eq!(resp, "Autem tempore", "Shared file can be read");
+
+    let req = test::TestRequest::get()
+        .uri("/storage/testuser/private.txt")
+        .insert_header((header::AUTHORIZATION, other.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Files outside the share are still private"
+    );
+
+    let req = upload_request(
+        other.reveal(),
+        "/storage/testuser/projects/",
+        "new.txt",
+        "Et voluptatibu",
+    )
+    .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "Read only");
+    let req = test::TestRequest::delete()
+        .uri("/storage/testuser/projects/test.txt")
+        .insert_header((header::AUTHORIZATION, other.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "Read only");
+    assert!(PathBuf::from(STORAGE)
+        .join("testuser")
+        .join("projects")
+        .join("test.txt")
+        .exists());
+
+    let req = shared_with_me_request(other.reveal()).to_request();
+    let resp: UserShareResults = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.shares.len(), 1);
+    assert_eq!(resp.shares[0].store, "testuser");
+    assert_eq!(resp.shares[0].path, "projects");
+}
+
+#[actix_web::test]
+async fn test_read_write_share() {
+    let ctx = TestEnv::setup().await;
+    let owner = ctx.setup_user_token("testuser", "testpass").await;
+    let other = ctx.setup_user_token("otheruser", "otherpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    setup_files().await;
+
+    let req = share_request(
+        owner.reveal(),
+        "projects",
+        "otheruser",
+        SharePermission::ReadWrite,
+    )
+    .to_request();
+    test::call_service(&app, req).await;
+
+    let req = upload_request(
+        other.reveal(),
+        "/storage/testuser/projects/",
+        "new.txt",
+        "Et voluptatibu",
+    )
+    .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Can upload into the share");
+    assert!(PathBuf::from(STORAGE)
+        .join("testuser")
+        .join("projects")
+        .join("new.txt")
+        .exists());
+
+    let req = test::TestRequest::delete()
+        .uri("/storage/testuser/projects/test.txt")
+        .insert_header((header::AUTHORIZATION, other.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK, "Can delete in the share");
+
+    let req = upload_request(
+        other.reveal(),
+        "/storage/testuser/",
+        "new.txt",
+        "Et voluptatibu",
+    )
+    .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Can't upload outside the share"
+    );
+
+    // Things that belong to the whole store stay with the owner
+    for uri in ["/api/trash/testuser", "/api/shares/testuser"] {
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .insert_header((header::AUTHORIZATION, other.reveal()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{uri}");
+    }
+    let req = test::TestRequest::post()
+        .uri("/api/shares/testuser/projects")
+        .insert_header((header::AUTHORIZATION, other.reveal()))
+        .set_json(bulgur_cloud::share::ShareOptions::default())
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Can't make share links for someone else's files"
+    );
+    let req = test::TestRequest::post()
+        .uri("/api/user-shares/testuser/projects")
+        .insert_header((header::AUTHORIZATION, other.reveal()))
+        .set_json(UserShareOptions {
+            username: "otheruser".to_string(),
+            permission: SharePermission::ReadWrite,
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Can't share someone else's files"
+    );
+}
+
+#[actix_web::test]
+async fn test_update_and_revoke_share() {
+    let ctx = TestEnv::setup().await;
+    let owner = ctx.setup_user_token("testuser", "testpass").await;
+    let other = ctx.setup_user_token("otheruser", "otherpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    setup_files().await;
+
+    let req = share_request(
+        owner.reveal(),
+        "projects",
+        "otheruser",
+        SharePermission::Read,
+    )
+    .to_request();
+    test::call_service(&app, req).await;
+    let req = share_request(
+        owner.reveal(),
+        "projects/",
+        "otheruser",
+        SharePermission::ReadWrite,
+    )
+    .to_request();
+    test::call_service(&app, req).await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/user-shares/testuser")
+        .insert_header((header::AUTHORIZATION, owner.reveal()))
+        .to_request();
+    let resp: UserShareResults = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.shares.len(), 1, "Sharing again updates the share");
+    assert_eq!(resp.shares[0].permission, SharePermission::ReadWrite);
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/api/user-shares/testuser/{}", resp.shares[0].id))
+        .insert_header((header::AUTHORIZATION, owner.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .uri("/storage/testuser/projects/test.txt")
+        .insert_header((header::AUTHORIZATION, other.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "Revoked share no longer works"
+    );
+    let req = shared_with_me_request(other.reveal()).to_request();
+    let resp: UserShareResults = test::call_and_read_body_json(&app, req).await;
+    assert!(resp.shares.is_empty());
+}
+
+#[actix_web::test]
+async fn test_share_validation() {
+    let ctx = TestEnv::setup().await;
+    let owner = ctx.setup_user_token("testuser", "testpass").await;
+    ctx.add_user("otheruser", "otherpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    setup_files().await;
+
+    let req = share_request(
+        owner.reveal(),
+        "projects",
+        "nobodyhere",
+        SharePermission::Read,
+    )
+    .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "Unknown user");
+
+    let req = share_request(
+        owner.reveal(),
+        "projects",
+        "testuser",
+        SharePermission::Read,
+    )
+    .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "Sharing with self");
+
+    let req = share_request(
+        owner.reveal(),
+        "missing",
+        "otheruser",
+        SharePermission::Read,
+    )
+    .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND, "Missing path");
+}
+
+#[actix_web::test]
+async fn test_deleted_user_loses_shares() {
+    let ctx = TestEnv::setup().await;
+    let owner = ctx.setup_user_token("testuser", "testpass").await;
+    ctx.add_user("otheruser", "otherpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    setup_files().await;
+
+    let req = share_request(
+        owner.reveal(),
+        "projects",
+        "otheruser",
+        SharePermission::Read,
+    )
+    .to_request();
+    test::call_service(&app, req).await;
+    delete_user(&ctx.state(), "otheruser", false).await.unwrap();
+
+    let other = ctx.setup_user_token("otheruser", "newpass").await;
+    let req = test::TestRequest::get()
+        .uri("/storage/testuser/projects/test.txt")
+        .insert_header((header::AUTHORIZATION, other.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.status(),
+        StatusCode::UNAUTHORIZED,
+        "A new user with the same name doesn't get the share"
+    );
+}
+
+#[actix_web::test]
+async fn test_dav_read_share() {
+    let ctx = TestEnv::setup().await;
+    let owner = ctx.setup_user_token("testuser", "testpass").await;
+    ctx.add_user("otheruser", "otherpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    setup_files().await;
+    let req = share_request(
+        owner.reveal(),
+        "projects",
+        "otheruser",
+        SharePermission::Read,
+    )
+    .to_request();
+    test::call_service(&app, req).await;
+    let auth = format!("Basic {}", STANDARD.encode("otheruser:otherpass"));
+
+    let req = test::TestRequest::default()
+        .method(Method::from_bytes(b"PROPFIND").unwrap())
+        .uri("/dav/testuser/projects/")
+        .insert_header((header::AUTHORIZATION, auth.clone()))
+        .insert_header(("Depth", "1"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status().as_u16(), 207, "Shared folder can be listed");
+
+    let req = test::TestRequest::put()
+        .uri("/dav/testuser/projects/new.txt")
+        .insert_header((header::AUTHORIZATION, auth))
+        .set_payload("Et voluptatibu")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "Read only");
+}
+
+#[actix_web::test]
+async fn test_basic_shared_folder() {
+    let ctx = TestEnv::setup().await;
+    let owner = ctx.setup_user_token("testuser", "testpass").await;
+    let other = ctx.setup_user_token("otheruser", "otherpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    setup_files().await;
+    let req = share_request(
+        owner.reveal(),
+        "projects",
+        "otheruser",
+        SharePermission::Read,
+    )
+    .to_request();
+    test::call_service(&app, req).await;
+
+    let req = test::TestRequest::get()
+        .uri("/basic/testuser/projects/")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, other.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = test::read_body(resp).await;
+    let body = String::from_utf8(body.to_vec()).expect("Failed to read response body");
+    assert!(body.contains("test.txt"), "Shared files are listed");
+    assert!(!body.contains("?trash"), "Trash is only for the owner");
+
+    let req = test::TestRequest::get()
+        .uri("/basic/testuser/projects/?shares")
+        .cookie(Cookie::new(AUTH_COOKIE_NAME, other.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
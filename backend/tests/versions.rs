@@ -0,0 +1,265 @@
+mod common;
+
+use std::path::PathBuf;
+
+use actix_web::{
+    http::{header, StatusCode},
+    test,
+};
+use bulgur_cloud::{
+    folder::STORAGE,
+    server::setup_app,
+    storage::StorageAction,
+    versions::{prune_versions, VersionResults},
+};
+use common::{create_file, read_header, upload_request, TestEnv};
+use tokio::fs;
+
+fn list_request(token: &str) -> test::TestRequest {
+    test::TestRequest::get()
+        .uri("/api/versions/testuser/test.txt")
+        .insert_header((header::AUTHORIZATION, token))
+}
+
+#[actix_web::test]
+async fn test_overwrite_keeps_version() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+
+    let req = upload_request(
+        token.reveal(),
+        "/storage/testuser/?conflict=overwrite",
+        "test.txt",
+        "Et voluptatibu",
+    )
+    .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = list_request(token.reveal()).to_request();
+    let resp: VersionResults = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.versions.len(), 1, "Overwritten contents are kept");
+    assert_eq!(resp.versions[0].version, 1);
+    assert_eq!(resp.versions[0].size, 13);
+
+    let req = test::TestRequest::get()
+        .uri("/api/versions/testuser/test.txt?version=1")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(read_header(&resp, header::CONTENT_TYPE).starts_with("text/plain"));
+    assert!(read_header(&resp, header::CONTENT_DISPOSITION).contains("test.txt"));
+    let body = test::read_body(resp).await;
+    assert_eq!(body, "Autem tempore", "Version has the old contents");
+}
+
+#[actix_web::test]
+async fn test_move_over_file_keeps_version() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("other.txt"),
+        "Et voluptatibu",
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/storage/testuser/other.txt")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .set_json(StorageAction::Move {
+            new_path: "/testuser/test.txt".to_string(),
+        })
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        fs::read_to_string(PathBuf::from(STORAGE).join("testuser").join("test.txt"))
+            .await
+            .unwrap(),
+        "Et voluptatibu"
+    );
+
+    let req = list_request(token.reveal()).to_request();
+    let resp: VersionResults = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.versions.len(), 1, "Replaced file is kept");
+}
+
+#[actix_web::test]
+async fn test_restore_version() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let file = PathBuf::from(STORAGE).join("testuser").join("test.txt");
+    create_file(file.clone(), "Autem tempore").await;
+    let req = upload_request(
+        token.reveal(),
+        "/storage/testuser/?conflict=overwrite",
+        "test.txt",
+        "Et voluptatibu",
+    )
+    .to_request();
+    test::call_service(&app, req).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/versions/testuser/test.txt?version=1")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        fs::read_to_string(&file).await.unwrap(),
+        "Autem tempore",
+        "Old contents are restored"
+    );
+
+    let req = list_request(token.reveal()).to_request();
+    let resp: VersionResults = test::call_and_read_body_json(&app, req).await;
+    let versions: Vec<u32> = resp.versions.iter().map(|v| v.version).collect();
+    assert_eq!(
+        versions,
+        vec![2, 1],
+        "Contents before the restore are kept as a new version"
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/api/versions/testuser/test.txt?version=2")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    assert_eq!(body, "Et voluptatibu");
+}
+
+#[actix_web::test]
+async fn test_restore_deleted_file() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let file = PathBuf::from(STORAGE).join("testuser").join("test.txt");
+    create_file(file.clone(), "Autem tempore").await;
+    let req = upload_request(
+        token.reveal(),
+        "/storage/testuser/?conflict=overwrite",
+        "test.txt",
+        "Et voluptatibu",
+    )
+    .to_request();
+    test::call_service(&app, req).await;
+    let req = test::TestRequest::delete()
+        .uri("/storage/testuser/test.txt")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    test::call_service(&app, req).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/versions/testuser/test.txt?version=1")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        fs::read_to_string(&file).await.unwrap(),
+        "Autem tempore",
+        "Deleted file is restored"
+    );
+
+    let req = list_request(token.reveal()).to_request();
+    let resp: VersionResults = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        resp.versions.len(),
+        1,
+        "There were no current contents to keep"
+    );
+}
+
+#[actix_web::test]
+async fn test_restore_missing_version() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "Autem tempore",
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/versions/testuser/test.txt?version=3")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn test_versions_are_limited() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+    let keep = ctx.state().versions.keep;
+    create_file(
+        PathBuf::from(STORAGE).join("testuser").join("test.txt"),
+        "0",
+    )
+    .await;
+
+    for i in 1..=keep + 2 {
+        let req = upload_request(
+            token.reveal(),
+            "/storage/testuser/?conflict=overwrite",
+            "test.txt",
+            &i.to_string(),
+        )
+        .to_request();
+        test::call_service(&app, req).await;
+    }
+
+    let req = list_request(token.reveal()).to_request();
+    let resp: VersionResults = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        resp.versions.len(),
+        keep as usize,
+        "Only the newest versions are kept"
+    );
+    assert_eq!(resp.versions[0].version, keep + 2);
+    assert_eq!(resp.versions.last().unwrap().version, 3);
+
+    let removed = prune_versions(&ctx.state(), chrono::Duration::days(30))
+        .await
+        .unwrap();
+    assert_eq!(removed, 0, "Recent versions are kept");
+    let removed = prune_versions(&ctx.state(), chrono::Duration::seconds(-1))
+        .await
+        .unwrap();
+    assert_eq!(removed, keep as usize, "Old versions are removed");
+}
+
+#[actix_web::test]
+async fn test_versions_unauthorized_store() {
+    let ctx = TestEnv::setup().await;
+    let token = ctx.setup_user_token("testuser", "testpass").await;
+    ctx.add_user("user2", "testpass").await;
+    let app = test::init_service(setup_app(ctx.state(), ctx.login_governor())).await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/versions/user2/test.txt")
+        .insert_header((header::AUTHORIZATION, token.reveal()))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(
+        resp.status().is_client_error(),
+        "Can't list versions in another user's store"
+    );
+}
@@ -5,9 +5,19 @@
 use std::{env, fs, path::PathBuf};
 
 use bulgur_cloud::{
+    admin::{AdminUsers, NewUser, PasswordReset, UserTypeChange},
+    api_key::{ApiKey, ApiKeyOptions, ApiKeys, NewApiKey},
     auth::{Login, LoginResponse},
+    group::{GroupMemberOptions, GroupMembers, UserGroups},
+    oidc::{OidcCode, OidcLoginResponse, OidcStatus},
+    session::{PasswordChange, Sessions},
+    share::{Share, ShareOptions, ShareResults},
     state::PathTokenResponse,
     storage::{FileMeta, FolderResults, PutStoragePayload, StorageAction},
+    totp::{RecoveryCodes, TotpCode, TotpDisable, TotpEnrollment, TotpStatus},
+    trash::TrashResults,
+    user_share::{UserShare, UserShareOptions, UserShareResults},
+    versions::VersionResults,
 };
 use typescript_type_def::{write_definition_file, DefinitionFileOptions};
 
@@ -19,8 +29,44 @@ type ApiTypes = (
     StorageAction,
     PutStoragePayload,
     FileMeta,
+    TrashResults,
+    VersionResults,
+    Sessions,
+    PasswordChange,
+    TotpTypes,
+    OidcTypes,
+    ApiKeyTypes,
+    SharingTypes,
+    AdminTypes,
 );
 
+// Tuples can only hold so many types, so these are grouped separately.
+type SharingTypes = (
+    ShareOptions,
+    Share,
+    ShareResults,
+    UserShareOptions,
+    UserShare,
+    UserShareResults,
+    UserGroups,
+    GroupMembers,
+    GroupMemberOptions,
+);
+
+type TotpTypes = (
+    TotpStatus,
+    TotpEnrollment,
+    TotpCode,
+    TotpDisable,
+    RecoveryCodes,
+);
+
+type OidcTypes = (OidcStatus, OidcCode, OidcLoginResponse);
+
+type ApiKeyTypes = (ApiKeyOptions, ApiKey, ApiKeys, NewApiKey);
+
+type AdminTypes = (AdminUsers, NewUser, PasswordReset, UserTypeChange);
+
 fn main() {
     let mut buf = Vec::new();
     let options = DefinitionFileOptions {
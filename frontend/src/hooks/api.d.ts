@@ -2,14 +2,55 @@
 export default api;
 export namespace api{
 export type Password=string;
-export type Login={"username":string;"password":api.Password;};
+export type Login={"username":string;"password":api.Password;"code"?:(string|null);};
 export type Token=string;
-export type LoginResponse={"access_token":api.Token;};
+export type LoginResponse={"access_token":api.Token;"expires_at":(string|null);};
 export type U64=number;
 export type FolderEntry={"is_file":boolean;"name":string;"size":api.U64;};
 export type FolderResults={"entries":(api.FolderEntry)[];};
 export type PathTokenResponse={"token":api.Token;};
-export type StorageAction=({"action":"MakePathToken";}|({"action":"Move";}&{"new_path":string;})|{"action":"CreateFolder";});
-export type PutStoragePayload={"files_written":(string)[];};
+export type StorageAction=({"action":"MakePathToken";}|({"action":"Move";}&{"new_path":string;})|{"action":"CreateFolder";}|({"action":"Extract";}&{"target":string;}));
+export type ConflictPolicy=("rename"|"overwrite"|"fail");
+export type FileWritten={"path":string;"conflict":(api.ConflictPolicy|null);};
+export type PutStoragePayload={"files_written":(api.FileWritten)[];};
 export type FileMeta={"is_file":boolean;"size":api.U64;};
+export type TrashItem={"id":string;"path":string;"is_file":boolean;"size":api.U64;"deleted_at":string;};
+export type TrashResults={"items":(api.TrashItem)[];};
+export type U32=number;
+export type FileVersion={"version":api.U32;"size":api.U64;"created_at":string;};
+export type VersionResults={"versions":(api.FileVersion)[];};
+export type Session={"id":string;"created_at":string;"client":(string|null);"current":boolean;};
+export type Sessions={"sessions":(api.Session)[];};
+export type PasswordChange={"current_password":api.Password;"new_password":api.Password;};
+export type TotpStatus={"enabled":boolean;"pending":boolean;"recovery_codes_left":api.U32;};
+export type TotpEnrollment={"secret":string;"uri":string;};
+export type TotpCode={"code":string;};
+export type TotpDisable={"password":api.Password;};
+export type RecoveryCodes={"codes":(string)[];};
+export type OidcStatus={"enabled":boolean;};
+export type OidcCode={"code":string;};
+export type OidcLoginResponse={"username":string;"access_token":api.Token;"expires_at":(string|null);};
+export type ApiKeyScope=("read"|"write"|"admin");
+export type ApiKeyOptions={"name":string;"scope":api.ApiKeyScope;"path":(string|null);};
+export type ApiKey={"id":string;"name":string;"scope":api.ApiKeyScope;"path":(string|null);"created_at":string;};
+export type ApiKeys={"keys":(api.ApiKey)[];};
+export type NewApiKey={"key":api.Token;"info":api.ApiKey;};
+export type ShareOptions={"label":(string|null);"valid_for_hours":(api.U32|null);"password":(api.Password|null);"max_downloads":(api.U32|null);"file_drop"?:boolean;"max_file_size":(api.U64|null);"max_files":(api.U32|null);};
+export type Share={"id":string;"token":(api.Token|null);"path":string;"is_folder":boolean;"label":(string|null);"created_at":string;"valid_until":(string|null);"has_password":boolean;"max_downloads":(api.U32|null);"downloads":api.U32;"file_drop":boolean;"max_file_size":(api.U64|null);"max_files":(api.U32|null);"uploads":api.U32;};
+export type ShareResults={"shares":(api.Share)[];};
+export type SharePermission=("read"|"read_write");
+export type UserShareOptions={"username":string;"permission":api.SharePermission;};
+export type UserShare={"id":string;"store":string;"path":string;"username":string;"permission":api.SharePermission;"created_at":string;};
+export type UserShareResults={"shares":(api.UserShare)[];};
+export type UserGroup={"name":string;"is_admin":boolean;};
+export type UserGroups={"groups":(api.UserGroup)[];};
+export type GroupMember={"username":string;"is_admin":boolean;};
+export type GroupMembers={"members":(api.GroupMember)[];};
+export type GroupMemberOptions={"is_admin"?:boolean;};
+export type UserType=("User"|"Admin");
+export type AdminUser={"username":string;"user_type":api.UserType;"quota":(api.U64|null);"usage":(api.U64|null);};
+export type AdminUsers={"users":(api.AdminUser)[];};
+export type NewUser={"username":string;"password":api.Password;"user_type"?:api.UserType;};
+export type PasswordReset={"password":api.Password;};
+export type UserTypeChange={"user_type":api.UserType;};
 }
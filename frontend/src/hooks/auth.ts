@@ -10,7 +10,7 @@ import {
   useAppSelector,
 } from "../utils/store";
 import { isString } from "../utils/type";
-import { useEffect } from "react";
+import { useEffect, useState } from "react";
 import { HttpStatusCode, isOkResponse, useRunAsync } from "./base";
 import { axiosThrowless } from "./request";
 import { useRouter } from "next/router";
@@ -22,8 +22,14 @@ function isLoginResponse(data: any): data is api.LoginResponse {
   return isString(data?.access_token);
 }
 
-export function useTokenCheck() {
-  async function doTokenCheck({
+function isOidcLoginResponse(data: any): data is api.OidcLoginResponse {
+  return isString(data?.username) && isLoginResponse(data);
+}
+
+export function useTokenRefresh() {
+  /** Swaps the token for a new one, so the user stays logged in. Returns
+   * nothing if the token is no longer valid. */
+  async function doTokenRefresh({
     site,
     token,
   }: {
@@ -31,22 +37,25 @@ export function useTokenCheck() {
     token: string;
   }) {
     try {
-      const out = await axiosThrowless<never, never>({
-        method: "HEAD",
-        url: "/api/stats",
+      const out = await axiosThrowless<never, api.LoginResponse>({
+        method: "POST",
+        url: "/api/refresh",
         headers: {
           authorization: token,
         },
         baseURL: site,
       });
 
-      return isOkResponse(out.status);
+      if (isOkResponse(out.status) && isLoginResponse(out.data)) {
+        return out.data.access_token;
+      }
     } catch {
-      return false;
+      // The token can't be refreshed, the user will have to log in again
     }
+    return undefined;
   }
 
-  return { doTokenCheck };
+  return { doTokenRefresh };
 }
 
 export function useLogin() {
@@ -56,10 +65,12 @@ export function useLogin() {
     site,
     username,
     password,
+    code,
   }: {
     site: string;
     username: string;
     password: string;
+    code?: string;
   }) {
     dispatch(authSlice.actions.markLoading());
 
@@ -67,6 +78,7 @@ export function useLogin() {
       const data: api.Login = {
         username,
         password,
+        code,
       };
       const out = await axiosThrowless<api.Login, api.LoginResponse>({
         url: "/auth/login",
@@ -83,6 +95,15 @@ export function useLogin() {
         });
       }
 
+      if (out.status === HttpStatusCode.FORBIDDEN) {
+        throw new BError({
+          code: "login_code_required",
+          title: "Two-factor authentication code required",
+          description:
+            "Enter the code from your authenticator app, or a recovery code.",
+        });
+      }
+
       if (!isLoginResponse(out.data)) {
         throw new BError({
           code: "login_failed",
@@ -107,7 +128,60 @@ export function useLogin() {
     }
   }
 
-  return { doLogin };
+  /** Finishes a single sign-on login, using the one-time code the server
+   * sent the user back with. */
+  async function doSsoLogin({ site, code }: { site: string; code: string }) {
+    dispatch(authSlice.actions.markLoading());
+
+    try {
+      const data: api.OidcCode = { code };
+      const out = await axiosThrowless<api.OidcCode, api.OidcLoginResponse>({
+        url: "/auth/oidc/token",
+        baseURL: site,
+        method: "POST",
+        data,
+      });
+
+      if (!isOidcLoginResponse(out.data)) {
+        throw new BError({
+          code: "login_sso_failed",
+          title: "Failed to log in with single sign-on",
+          description:
+            "The login may have taken too long, or the page was reloaded. Try logging in again.",
+        });
+      }
+
+      const { username, access_token } = out.data;
+      const payload: LoginPayload = { username, access_token, site };
+      await Persist.set(PERSIST_AUTH_KEY, payload);
+      dispatch(authSlice.actions.login(payload));
+      return { username };
+    } catch (err) {
+      dispatch(authSlice.actions.logout());
+      throw err;
+    }
+  }
+
+  return { doLogin, doSsoLogin };
+}
+
+/** Checks if users can log in with single sign-on. */
+export function useSsoEnabled(site: string) {
+  const [enabled, setEnabled] = useState(false);
+
+  useEffect(() => {
+    axiosThrowless<never, api.OidcStatus>({
+      url: "/auth/oidc",
+      baseURL: site,
+      method: "GET",
+    })
+      .then((out) =>
+        setEnabled(isOkResponse(out.status) && !!out.data?.enabled),
+      )
+      .catch(() => setEnabled(false));
+  }, [site]);
+
+  return enabled;
 }
 
 export function useLogout() {
@@ -156,7 +230,7 @@ export function useEnsureAuthInitialized() {
   const { runAsync } = useRunAsync();
   const state = useAppSelector((selector) => selector.auth.state);
   const dispatch = useAppDispatch();
-  const { doTokenCheck } = useTokenCheck();
+  const { doTokenRefresh } = useTokenRefresh();
   const { doLogout } = useLogout();
 
   useEffect(() => {
@@ -176,13 +250,15 @@ export function useEnsureAuthInitialized() {
       runAsync(async () => {
         const out = await Persist.get(PERSIST_AUTH_KEY);
         console.log("Found saved state", out);
-        if (
-          isAuthState(out) &&
-          (await doTokenCheck({ site: out.site, token: out.access_token }))
-        ) {
-          // Saved token is still valid, so keep using it
+        const access_token = isAuthState(out)
+          ? await doTokenRefresh({ site: out.site, token: out.access_token })
+          : undefined;
+        if (isAuthState(out) && access_token) {
+          // Saved token was still valid, keep using the refreshed one
           console.log("Saved token was good, resuming as logged in");
-          dispatch(authSlice.actions.login(out));
+          const payload: LoginPayload = { ...out, access_token };
+          await Persist.set(PERSIST_AUTH_KEY, payload);
+          dispatch(authSlice.actions.login(payload));
         } else {
           console.log("Saved state was bad or did not exist");
           Persist.delete(PERSIST_AUTH_KEY);
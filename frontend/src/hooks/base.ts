@@ -27,6 +27,7 @@ export function useRunAsync() {
 export enum HttpStatusCode {
   BAD_REQUEST = 400,
   UNAUTHORIZED = 401,
+  FORBIDDEN = 403,
   NOT_FOUND = 404,
 }
 
@@ -1,8 +1,8 @@
-import { useLogin } from "@/hooks/auth";
+import { useLogin, useSsoEnabled } from "@/hooks/auth";
 import { useRunAsync } from "@/hooks/base";
 import LabelledInput from "@/components/LabelledInput";
 import Head from "next/head";
-import { useCallback, useEffect, useState } from "react";
+import { useCallback, useEffect, useRef, useState } from "react";
 import { useRouter } from "next/router";
 import { pick, shallowEquals } from "@/utils/object";
 import { useAppSelector } from "@/utils/store";
@@ -21,33 +21,67 @@ export default function Login() {
   );
   const router = useRouter();
   const { runAsync } = useRunAsync();
-  const { doLogin } = useLogin();
+  const { doLogin, doSsoLogin } = useLogin();
   const [error, setError] = useState<string>("");
   const [username, setUsername] = useState("");
   const [password, setPassword] = useState("");
+  const [code, setCode] = useState("");
+  const [needsCode, setNeedsCode] = useState(false);
   const site =
     process.env.NODE_ENV === "development"
       ? "http://localhost:8000"
       : `${getWindow()?.location.protocol}//${getWindow()?.location.host}`;
+  const ssoEnabled = useSsoEnabled(site);
 
   const login = useCallback(() => {
     setError("");
     runAsync(async () => {
       try {
-        await doLogin({ username, password, site });
+        await doLogin({ username, password, site, code: code || undefined });
       } catch (err) {
         if (BError.isBError(err) && err.code === "login_bad") {
           setError(err.description);
           return;
+        } else if (
+          BError.isBError(err) &&
+          err.code === "login_code_required"
+        ) {
+          setNeedsCode(true);
+          setError(err.description);
+          return;
         } else {
           throw err;
         }
       }
       setUsername("");
       setPassword("");
+      setCode("");
       router.push(`/s/${username}`);
     });
-  }, [doLogin, password, runAsync, site, username, router]);
+  }, [doLogin, password, code, runAsync, site, username, router]);
+
+  // After a single sign-on login, the server sends the user back here with a
+  // one-time code to swap for a token. The code only works once, so make sure
+  // it's not sent again when the page re-renders.
+  const ssoCode = router.query.sso;
+  const ssoCodeUsed = useRef(false);
+  useEffect(() => {
+    if (typeof ssoCode !== "string" || ssoCodeUsed.current) return;
+    ssoCodeUsed.current = true;
+    router.replace("/login");
+    runAsync(async () => {
+      try {
+        const { username } = await doSsoLogin({ site, code: ssoCode });
+        router.push(`/s/${username}`);
+      } catch (err) {
+        if (BError.isBError(err)) {
+          setError(err.description);
+          return;
+        }
+        throw err;
+      }
+    });
+  }, [doSsoLogin, router, runAsync, site, ssoCode]);
 
   useEffect(() => {
     console.log(loggedInUsername, access_token, authState);
@@ -88,12 +122,30 @@ export default function Login() {
         >
           Password
         </LabelledInput>
+        {needsCode ? (
+          <LabelledInput
+            onSubmit={login}
+            onChange={setCode}
+            id="code"
+            placeholder="123456"
+          >
+            Authentication code
+          </LabelledInput>
+        ) : null}
         <input
           className="btn btn-primary mt-8 px-8"
           type="button"
           value="Log in"
           onClick={login}
         />
+        {ssoEnabled ? (
+          <a
+            className="btn btn-outline mt-8 ml-4 px-8"
+            href={`${site}/auth/oidc/login`}
+          >
+            Log in with SSO
+          </a>
+        ) : null}
       </main>
     </>
   );
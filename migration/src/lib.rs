@@ -1,12 +1,50 @@
 pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_init;
+mod m20231101_000001_upload;
+mod m20231115_000001_trash;
+mod m20231120_000001_file_version;
+mod m20231125_000001_user_quota;
+mod m20231201_000001_folder_path_token;
+mod m20231205_000001_share_link;
+mod m20231210_000001_file_drop;
+mod m20231215_000001_user_share;
+mod m20231220_000001_group;
+mod m20231225_000001_token_session;
+mod m20231230_000001_token_last_used;
+mod m20240101_000001_token_hash;
+mod m20240105_000001_totp;
+mod m20240110_000001_sso_login;
+mod m20240115_000001_api_key;
+mod m20240120_000001_share_kind;
+mod m20240125_000001_upload_expiry;
+mod m20240130_000001_user_sso_identity;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m20220101_000001_init::Migration)]
+        vec![
+            Box::new(m20220101_000001_init::Migration),
+            Box::new(m20231101_000001_upload::Migration),
+            Box::new(m20231115_000001_trash::Migration),
+            Box::new(m20231120_000001_file_version::Migration),
+            Box::new(m20231125_000001_user_quota::Migration),
+            Box::new(m20231201_000001_folder_path_token::Migration),
+            Box::new(m20231205_000001_share_link::Migration),
+            Box::new(m20231210_000001_file_drop::Migration),
+            Box::new(m20231215_000001_user_share::Migration),
+            Box::new(m20231220_000001_group::Migration),
+            Box::new(m20231225_000001_token_session::Migration),
+            Box::new(m20231230_000001_token_last_used::Migration),
+            Box::new(m20240101_000001_token_hash::Migration),
+            Box::new(m20240105_000001_totp::Migration),
+            Box::new(m20240110_000001_sso_login::Migration),
+            Box::new(m20240115_000001_api_key::Migration),
+            Box::new(m20240120_000001_share_kind::Migration),
+            Box::new(m20240125_000001_upload_expiry::Migration),
+            Box::new(m20240130_000001_user_sso_identity::Migration),
+        ]
     }
 }
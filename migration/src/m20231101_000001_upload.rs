@@ -0,0 +1,56 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Upload::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(Upload::Id).string().not_null().primary_key())
+                    .col(ColumnDef::new(Upload::Folder).string().not_null())
+                    .col(ColumnDef::new(Upload::Filename).string().not_null())
+                    .col(ColumnDef::new(Upload::Part).string().not_null())
+                    .col(
+                        ColumnDef::new(Upload::UploadOffset)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(Upload::UploadLength)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(Upload::CreatedAt).string().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Upload::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+#[allow(clippy::enum_variant_names)]
+enum Upload {
+    Table,
+    Id,
+    Folder,
+    Filename,
+    Part,
+    UploadOffset,
+    UploadLength,
+    CreatedAt,
+}
@@ -0,0 +1,55 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Trash::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(Trash::Id).string().not_null().primary_key())
+                    .col(ColumnDef::new(Trash::Store).string().not_null())
+                    .col(ColumnDef::new(Trash::Path).string().not_null())
+                    .col(ColumnDef::new(Trash::IsFile).boolean().not_null())
+                    .col(ColumnDef::new(Trash::Size).big_integer().not_null())
+                    .col(ColumnDef::new(Trash::DeletedAt).string().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-trash-store")
+                    .table(Trash::Table)
+                    .col(Trash::Store)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Trash::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Trash {
+    Table,
+    Id,
+    Store,
+    Path,
+    IsFile,
+    Size,
+    DeletedAt,
+}
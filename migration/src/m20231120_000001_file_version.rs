@@ -0,0 +1,61 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(FileVersion::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(FileVersion::Id)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(FileVersion::Store).string().not_null())
+                    .col(ColumnDef::new(FileVersion::Path).string().not_null())
+                    .col(ColumnDef::new(FileVersion::Version).integer().not_null())
+                    .col(ColumnDef::new(FileVersion::Size).big_integer().not_null())
+                    .col(ColumnDef::new(FileVersion::CreatedAt).string().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-file_version-store-path")
+                    .table(FileVersion::Table)
+                    .col(FileVersion::Store)
+                    .col(FileVersion::Path)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(FileVersion::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum FileVersion {
+    Table,
+    Id,
+    Store,
+    Path,
+    Version,
+    Size,
+    CreatedAt,
+}
@@ -0,0 +1,92 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite can only add one column at a time
+        let columns = [
+            ColumnDef::new(PathToken::Store).string().null().to_owned(),
+            ColumnDef::new(PathToken::Label).string().null().to_owned(),
+            ColumnDef::new(PathToken::ValidUntil)
+                .string()
+                .null()
+                .to_owned(),
+            ColumnDef::new(PathToken::PasswordHash)
+                .string()
+                .null()
+                .to_owned(),
+            ColumnDef::new(PathToken::MaxDownloads)
+                .integer()
+                .null()
+                .to_owned(),
+            ColumnDef::new(PathToken::Downloads)
+                .integer()
+                .not_null()
+                .default(0)
+                .to_owned(),
+        ];
+        for mut column in columns {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(PathToken::Table)
+                        .add_column(&mut column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        // Tokens without an expiry are valid forever now, existing tokens
+        // were only meant to last for a day after they were created.
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(PathToken::Table)
+                    .value(
+                        PathToken::ValidUntil,
+                        Expr::cust("strftime('%Y-%m-%dT%H:%M:%SZ', \"created_at\", '+1 day')"),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let columns = [
+            PathToken::Downloads,
+            PathToken::MaxDownloads,
+            PathToken::PasswordHash,
+            PathToken::ValidUntil,
+            PathToken::Label,
+            PathToken::Store,
+        ];
+        for column in columns {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(PathToken::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum PathToken {
+    Table,
+    Store,
+    Label,
+    ValidUntil,
+    PasswordHash,
+    MaxDownloads,
+    Downloads,
+}
@@ -0,0 +1,73 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite can only add one column at a time
+        let columns = [
+            ColumnDef::new(PathToken::FileDrop)
+                .boolean()
+                .not_null()
+                .default(false)
+                .to_owned(),
+            ColumnDef::new(PathToken::MaxFileSize)
+                .big_integer()
+                .null()
+                .to_owned(),
+            ColumnDef::new(PathToken::MaxFiles)
+                .integer()
+                .null()
+                .to_owned(),
+            ColumnDef::new(PathToken::Uploads)
+                .integer()
+                .not_null()
+                .default(0)
+                .to_owned(),
+        ];
+        for mut column in columns {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(PathToken::Table)
+                        .add_column(&mut column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let columns = [
+            PathToken::Uploads,
+            PathToken::MaxFiles,
+            PathToken::MaxFileSize,
+            PathToken::FileDrop,
+        ];
+        for column in columns {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(PathToken::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum PathToken {
+    Table,
+    FileDrop,
+    MaxFileSize,
+    MaxFiles,
+    Uploads,
+}
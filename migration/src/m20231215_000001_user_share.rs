@@ -0,0 +1,70 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(UserShare::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(UserShare::Id)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(UserShare::Store).string().not_null())
+                    .col(ColumnDef::new(UserShare::Path).string().not_null())
+                    .col(ColumnDef::new(UserShare::Username).string().not_null())
+                    .col(ColumnDef::new(UserShare::CanWrite).boolean().not_null())
+                    .col(ColumnDef::new(UserShare::CreatedAt).string().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-user_share-store")
+                    .table(UserShare::Table)
+                    .col(UserShare::Store)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-user_share-username")
+                    .table(UserShare::Table)
+                    .col(UserShare::Username)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(UserShare::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum UserShare {
+    Table,
+    Id,
+    Store,
+    Path,
+    Username,
+    CanWrite,
+    CreatedAt,
+}
@@ -0,0 +1,91 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Group::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(Group::Id).string().not_null().primary_key())
+                    .col(ColumnDef::new(Group::Name).string().not_null().unique_key())
+                    .col(ColumnDef::new(Group::CreatedAt).string().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(GroupMember::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(GroupMember::Id)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(GroupMember::GroupName).string().not_null())
+                    .col(ColumnDef::new(GroupMember::Username).string().not_null())
+                    .col(ColumnDef::new(GroupMember::IsAdmin).boolean().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-group_member-group_name-username")
+                    .table(GroupMember::Table)
+                    .col(GroupMember::GroupName)
+                    .col(GroupMember::Username)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-group_member-username")
+                    .table(GroupMember::Table)
+                    .col(GroupMember::Username)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(GroupMember::Table).to_owned())
+            .await?;
+        manager
+            .drop_table(Table::drop().table(Group::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Group {
+    Table,
+    Id,
+    Name,
+    CreatedAt,
+}
+
+#[derive(DeriveIden)]
+enum GroupMember {
+    Table,
+    Id,
+    GroupName,
+    Username,
+    IsAdmin,
+}
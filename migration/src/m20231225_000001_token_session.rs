@@ -0,0 +1,87 @@
+use sea_orm_migration::{prelude::*, sea_orm::DatabaseBackend};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite can only add one column at a time
+        let columns = [
+            ColumnDef::new(UserToken::SessionId)
+                .string()
+                .not_null()
+                .default("")
+                .to_owned(),
+            ColumnDef::new(UserToken::Client).string().null().to_owned(),
+        ];
+        for mut column in columns {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(UserToken::Table)
+                        .add_column(&mut column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        // Existing tokens need a session ID too, so users can see and revoke them
+        let random_id = match manager.get_database_backend() {
+            DatabaseBackend::Postgres => "md5(random()::text || token)",
+            _ => "lower(hex(randomblob(16)))",
+        };
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(UserToken::Table)
+                    .value(UserToken::SessionId, Expr::cust(random_id))
+                    .and_where(Expr::col(UserToken::SessionId).eq(""))
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-user_token-session_id")
+                    .table(UserToken::Table)
+                    .col(UserToken::SessionId)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx-user_token-session_id")
+                    .table(UserToken::Table)
+                    .to_owned(),
+            )
+            .await?;
+        for column in [UserToken::Client, UserToken::SessionId] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(UserToken::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum UserToken {
+    Table,
+    SessionId,
+    Client,
+}
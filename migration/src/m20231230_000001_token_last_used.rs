@@ -0,0 +1,56 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(UserToken::Table)
+                    .add_column(
+                        ColumnDef::new(UserToken::LastUsedAt)
+                            .string()
+                            .not_null()
+                            .default(""),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // Existing tokens count as last used when they were made
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(UserToken::Table)
+                    .value(UserToken::LastUsedAt, Expr::col(UserToken::CreatedAt))
+                    .and_where(Expr::col(UserToken::LastUsedAt).eq(""))
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(UserToken::Table)
+                    .drop_column(UserToken::LastUsedAt)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum UserToken {
+    Table,
+    CreatedAt,
+    LastUsedAt,
+}
@@ -0,0 +1,86 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Tokens are now stored hashed with a key that the database doesn't
+        // have, so the plaintext tokens can't be converted. They are deleted
+        // instead: users have to log in again, and share links made again.
+        manager
+            .exec_stmt(Query::delete().from_table(UserToken::Table).to_owned())
+            .await?;
+        manager
+            .exec_stmt(Query::delete().from_table(PathToken::Table).to_owned())
+            .await?;
+
+        // Share links are managed by an ID, since the token itself is no
+        // longer known after the link is made
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PathToken::Table)
+                    .add_column(
+                        ColumnDef::new(PathToken::Id)
+                            .string()
+                            .not_null()
+                            .default(""),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-path_token-id")
+                    .table(PathToken::Table)
+                    .col(PathToken::Id)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx-path_token-id")
+                    .table(PathToken::Table)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PathToken::Table)
+                    .drop_column(PathToken::Id)
+                    .to_owned(),
+            )
+            .await?;
+        // The hashed tokens are useless once the tokens are stored in plain again
+        manager
+            .exec_stmt(Query::delete().from_table(UserToken::Table).to_owned())
+            .await?;
+        manager
+            .exec_stmt(Query::delete().from_table(PathToken::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum UserToken {
+    Table,
+}
+
+#[derive(DeriveIden)]
+enum PathToken {
+    Table,
+    Id,
+}
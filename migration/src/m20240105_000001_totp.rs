@@ -0,0 +1,105 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite can only add one column at a time
+        let columns = [
+            ColumnDef::new(User::TotpSecret).string().null().to_owned(),
+            ColumnDef::new(User::TotpEnabled)
+                .boolean()
+                .not_null()
+                .default(false)
+                .to_owned(),
+            ColumnDef::new(User::TotpLastStep)
+                .big_integer()
+                .null()
+                .to_owned(),
+        ];
+        for mut column in columns {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(User::Table)
+                        .add_column(&mut column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(RecoveryCode::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(RecoveryCode::CodeHash)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(RecoveryCode::UserId).string().not_null())
+                    .col(ColumnDef::new(RecoveryCode::CreatedAt).string().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from_tbl(RecoveryCode::Table)
+                            .from_col(RecoveryCode::UserId)
+                            .to_tbl(User::Table)
+                            .to_col(User::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-recovery_code-user_id")
+                    .table(RecoveryCode::Table)
+                    .col(RecoveryCode::UserId)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(RecoveryCode::Table).to_owned())
+            .await?;
+        for column in [User::TotpLastStep, User::TotpEnabled, User::TotpSecret] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(User::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum User {
+    Table,
+    Id,
+    TotpSecret,
+    TotpEnabled,
+    TotpLastStep,
+}
+
+#[derive(DeriveIden)]
+enum RecoveryCode {
+    Table,
+    CodeHash,
+    UserId,
+    CreatedAt,
+}
@@ -0,0 +1,65 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(SsoLogin::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(SsoLogin::Id)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(SsoLogin::CodeVerifier).string().null())
+                    .col(ColumnDef::new(SsoLogin::Nonce).string().null())
+                    .col(
+                        ColumnDef::new(SsoLogin::Basic)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .col(ColumnDef::new(SsoLogin::Username).string().null())
+                    .col(ColumnDef::new(SsoLogin::CreatedAt).string().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-sso_login-created_at")
+                    .table(SsoLogin::Table)
+                    .col(SsoLogin::CreatedAt)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(SsoLogin::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum SsoLogin {
+    Table,
+    Id,
+    CodeVerifier,
+    Nonce,
+    Basic,
+    Username,
+    CreatedAt,
+}
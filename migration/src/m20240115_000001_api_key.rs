@@ -0,0 +1,79 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ApiKey::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(ApiKey::Id).string().not_null().primary_key())
+                    .col(ColumnDef::new(ApiKey::UserId).string().not_null())
+                    .col(ColumnDef::new(ApiKey::Name).string().not_null())
+                    .col(
+                        ColumnDef::new(ApiKey::Token)
+                            .string()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(ColumnDef::new(ApiKey::Scope).string().not_null())
+                    .col(ColumnDef::new(ApiKey::Path).string().null())
+                    .col(ColumnDef::new(ApiKey::CreatedAt).string().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .from_tbl(ApiKey::Table)
+                            .from_col(ApiKey::UserId)
+                            .to_tbl(User::Table)
+                            .to_col(User::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // Names tell the keys of a user apart
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-api_key-user_id-name")
+                    .table(ApiKey::Table)
+                    .col(ApiKey::UserId)
+                    .col(ApiKey::Name)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ApiKey::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum User {
+    Table,
+    Id,
+}
+
+#[derive(DeriveIden)]
+enum ApiKey {
+    Table,
+    Id,
+    UserId,
+    Name,
+    Token,
+    Scope,
+    Path,
+    CreatedAt,
+}
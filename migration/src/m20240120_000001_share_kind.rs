@@ -0,0 +1,58 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PathToken::Table)
+                    .add_column(
+                        ColumnDef::new(PathToken::IsShare)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // Share links and quick tokens both have a store, so existing tokens
+        // can't be told apart. Quick tokens expire within a day, so it is
+        // better to list them for a while than to hide share links.
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(PathToken::Table)
+                    .value(PathToken::IsShare, true)
+                    .and_where(Expr::col(PathToken::Store).is_not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PathToken::Table)
+                    .drop_column(PathToken::IsShare)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum PathToken {
+    Table,
+    Store,
+    IsShare,
+}
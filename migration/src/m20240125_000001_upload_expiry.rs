@@ -0,0 +1,66 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite can only add one column at a time. Uploads started before
+        // this have no expiry, so they are cleaned up right away.
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Upload::Table)
+                    .add_column(
+                        ColumnDef::new(Upload::ExpiresAt)
+                            .string()
+                            .not_null()
+                            .default(""),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Upload::Table)
+                    .add_column(ColumnDef::new(Upload::LockToken).string().null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Upload::Table)
+                    .add_column(ColumnDef::new(Upload::LockedUntil).string().null())
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [Upload::ExpiresAt, Upload::LockToken, Upload::LockedUntil] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Upload::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Upload {
+    Table,
+    ExpiresAt,
+    LockToken,
+    LockedUntil,
+}
@@ -0,0 +1,65 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite can only add one column at a time
+        for column in [User::SsoIssuer, User::SsoSubject] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(User::Table)
+                        .add_column(ColumnDef::new(column).string().null())
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-user-sso_identity")
+                    .table(User::Table)
+                    .col(User::SsoIssuer)
+                    .col(User::SsoSubject)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx-user-sso_identity")
+                    .table(User::Table)
+                    .to_owned(),
+            )
+            .await?;
+        for column in [User::SsoSubject, User::SsoIssuer] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(User::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum User {
+    Table,
+    SsoIssuer,
+    SsoSubject,
+}